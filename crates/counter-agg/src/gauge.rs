@@ -0,0 +1,311 @@
+use time_series::TSPoint;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    CounterError,
+    prometheus_extrapolated_delta,
+    range::I64Range,
+    regression::RegressionSummary,
+    to_seconds,
+    ts_to_xy,
+};
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct GaugeSummary {
+    pub first: TSPoint,
+    pub second: TSPoint,
+    pub penultimate: TSPoint,
+    pub last: TSPoint,
+    pub num_changes: u64,
+    pub regress: RegressionSummary,
+    pub bounds: Option<I64Range>,
+}
+
+/// GaugeSummary tracks metrics that can vary up or down freely (temperature, queue depth, etc.),
+/// unlike a CounterSummary a decrease in value is just a decrease, not a reset, so the values are
+/// used as is for the deltas and for the regression.
+impl GaugeSummary {
+    pub fn new(pt: &TSPoint, bounds: Option<I64Range>) -> GaugeSummary {
+        let mut n = GaugeSummary{
+            first: *pt,
+            second: *pt,
+            penultimate: *pt,
+            last: *pt,
+            num_changes: 0,
+            regress: RegressionSummary::new(),
+            bounds,
+        };
+        n.regress.accum(ts_to_xy(*pt)).unwrap();
+        n
+    }
+
+    // expects time-ordered input
+    pub fn add_point(&mut self, incoming: &TSPoint) -> Result<(), CounterError>{
+        if incoming.ts < self.last.ts {
+            return Err(CounterError::OrderError);
+        }
+        if incoming.ts == self.last.ts {
+            // if two points are equal we only use the first we see, same as for counters
+            return Ok(());
+        }
+        if incoming.val != self.last.val{
+            self.num_changes += 1;
+        }
+        if self.first == self.second {
+            self.second = *incoming;
+        }
+        self.penultimate = self.last;
+        self.last = *incoming;
+        self.regress.accum(ts_to_xy(*incoming)).unwrap();
+        Ok(())
+    }
+
+    fn single_value(&self) -> bool {
+        self.last == self.first
+    }
+
+    // combining can only happen for disjoint time ranges
+    pub fn combine(&mut self, incoming: &GaugeSummary) -> Result<(), CounterError> {
+        // this requires that self comes before incoming in time order
+        if self.last.ts >= incoming.first.ts {
+            return Err(CounterError::OrderError);
+        }
+
+        if self.last.val != incoming.first.val{
+            self.num_changes += 1;
+        }
+
+        if incoming.single_value() {
+            self.penultimate = self.last;
+        } else {
+            self.penultimate = incoming.penultimate;
+        }
+        if self.single_value() {
+            self.second = incoming.first;
+        }
+        // no resets for gauges so, unlike counters, there's no need to offset the incoming regression
+        self.last = incoming.last;
+        self.num_changes += incoming.num_changes;
+
+        self.regress = self.regress.combine(incoming.regress).unwrap();
+        self.bounds_extend(incoming.bounds);
+        Ok(())
+    }
+
+    pub fn time_delta(&self) -> f64{
+        to_seconds((self.last.ts - self.first.ts) as f64)
+    }
+
+    pub fn delta(&self) -> f64 {
+        self.last.val - self.first.val
+    }
+
+    pub fn rate(&self) -> Option<f64> {
+        if self.single_value() {
+            return None;
+        }
+        Some(self.delta() / self.time_delta())
+    }
+
+    pub fn idelta_left(&self) -> f64 {
+        self.second.val - self.first.val
+    }
+
+    pub fn idelta_right(&self) -> f64 {
+        self.last.val - self.penultimate.val
+    }
+
+    pub fn irate_left(&self) -> Option<f64>{
+        if self.single_value(){
+            None
+        } else {
+            Some(self.idelta_left() / to_seconds((self.second.ts - self.first.ts) as f64))
+        }
+    }
+
+    pub fn irate_right(&self) -> Option<f64>{
+        if self.single_value() {
+            None
+        } else {
+            Some(self.idelta_right() / to_seconds((self.last.ts - self.penultimate.ts) as f64))
+        }
+    }
+
+    /// the skewness of the values in the summary, see `RegressionSummary::skewness_y`
+    pub fn skewness(&self) -> Option<f64> {
+        self.regress.skewness_y()
+    }
+
+    /// the kurtosis of the values in the summary, see `RegressionSummary::kurtosis_y`
+    pub fn kurtosis(&self) -> Option<f64> {
+        self.regress.kurtosis_y()
+    }
+
+    pub fn bounds_valid(&self) -> bool {
+        match self.bounds{
+            None => true,  // unbounded contains everything
+            Some(b) => b.contains(self.last.ts) && b.contains(self.first.ts)
+        }
+    }
+
+    pub fn bounds_extend(&mut self, in_bounds: Option<I64Range>){
+        match (self.bounds, in_bounds) {
+            (None, _) => {self.bounds = in_bounds},
+            (_, None) => {},
+            (Some(mut a), Some(b)) => {
+                a.extend(&b);
+                self.bounds = Some(a);
+            }
+        };
+    }
+
+    // this is the equivalent of Prometheus's `delta` function, which uses the same
+    // extrapolation as `increase` but does not clamp the extrapolation to a zero point
+    pub fn prometheus_delta(&self) -> Result<Option<f64>, CounterError>{
+        prometheus_extrapolated_delta(self.delta(), &self.first, &self.last, self.regress.n64(), self.bounds, false)
+    }
+
+    pub fn prometheus_rate(&self) -> Result<Option<f64>, CounterError>{
+        let delta = match self.prometheus_delta()? {
+            None => return Ok(None),
+            Some(delta) => delta,
+        };
+        let bounds = self.bounds.unwrap(); // if we got through delta without error then we have bounds
+        let duration = bounds.duration().unwrap(); // only returns None if we have an infinite bound, which is checked in the delta stuff
+        Ok(Some(delta / to_seconds(duration as f64)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+    use super::*;
+
+    fn to_micro(t: f64) -> f64{
+        t * 1_000_000.0
+    }
+
+    #[track_caller]
+    fn assert_close_enough(p1:&GaugeSummary, p2:&GaugeSummary) {
+        assert_eq!(p1.first, p2.first, "first");
+        assert_eq!(p1.second, p2.second, "second");
+        assert_eq!(p1.penultimate, p2.penultimate, "penultimate");
+        assert_eq!(p1.last, p2.last, "last");
+        assert_eq!(p1.num_changes, p2.num_changes, "num_changes");
+        assert_eq!(p1.regress.n, p2.regress.n, "n");
+        assert_relative_eq!(p1.regress.sx, p2.regress.sx);
+        assert_relative_eq!(p1.regress.sxx, p2.regress.sxx);
+        let (m1, m2) = (p1.regress.higher_moments.unwrap(), p2.regress.higher_moments.unwrap());
+        assert_relative_eq!(m1.sx3, m2.sx3);
+        assert_relative_eq!(m1.sx4, m2.sx4);
+        assert_relative_eq!(p1.regress.sy, p2.regress.sy);
+        assert_relative_eq!(p1.regress.syy, p2.regress.syy);
+        assert_relative_eq!(m1.sy3, m2.sy3, max_relative = 1e-12);
+        assert_relative_eq!(m1.sy4, m2.sy4, max_relative = 1e-12);
+        assert_relative_eq!(p1.regress.sxy, p2.regress.sxy);
+    }
+
+    #[test]
+    fn adding_points_to_gauge() {
+        let startpt = TSPoint{ts: 0, val:0.0};
+        let mut summary = GaugeSummary::new(&startpt, None);
+
+        summary.add_point(&TSPoint{ts: 5, val:10.0}).unwrap();
+        summary.add_point(&TSPoint{ts: 10, val:20.0}).unwrap();
+        summary.add_point(&TSPoint{ts: 15, val:20.0}).unwrap();
+        summary.add_point(&TSPoint{ts: 20, val:50.0}).unwrap();
+        summary.add_point(&TSPoint{ts: 25, val:10.0}).unwrap();
+
+        assert_eq!(summary.first, startpt);
+        assert_eq!(summary.second, TSPoint{ts: 5, val:10.0});
+        assert_eq!(summary.penultimate, TSPoint{ts: 20, val:50.0});
+        assert_eq!(summary.last, TSPoint{ts: 25, val:10.0});
+        assert_eq!(summary.num_changes, 4);
+        assert_eq!(summary.regress.count(), 6);
+        // unlike counters, the decrease at the end is not a reset, so sum y is just the sum of the values
+        assert_relative_eq!(summary.regress.sum().unwrap().y, 0.0 + 10.0 + 20.0 + 20.0 + 50.0 + 10.0);
+
+        assert_eq!(CounterError::OrderError, summary.add_point(&TSPoint{ts: 2, val:9.0}).unwrap_err());
+    }
+
+    #[test]
+    fn test_gauge_extraction() {
+        let mut summary = GaugeSummary::new(&TSPoint{ts: 0, val: 10.0}, None);
+        assert_relative_eq!(summary.delta(), 0.0);
+        assert_eq!(summary.rate(), None);
+        assert_eq!(summary.irate_left(), None);
+        assert_eq!(summary.irate_right(), None);
+
+        summary.add_point(&TSPoint{ts: 5, val:5.0}).unwrap();
+        summary.add_point(&TSPoint{ts: 10, val:30.0}).unwrap();
+        summary.add_point(&TSPoint{ts: 15, val: 15.0}).unwrap();
+
+        assert_relative_eq!(summary.delta(), 5.0);
+        assert_relative_eq!(summary.rate().unwrap(), to_micro(1.0 / 3.0));
+        assert_relative_eq!(summary.idelta_left(), -5.0);
+        assert_relative_eq!(summary.idelta_right(), -15.0);
+        assert_relative_eq!(summary.irate_left().unwrap(), to_micro(-1.0));
+        assert_relative_eq!(summary.irate_right().unwrap(), to_micro(-3.0));
+        assert_eq!(summary.num_changes, 3);
+    }
+
+    #[test]
+    fn test_gauge_combine() {
+        let mut summary = GaugeSummary::new(&TSPoint{ts: 0, val:0.0}, None);
+        summary.add_point(&TSPoint{ts: 5, val:10.0}).unwrap();
+        summary.add_point(&TSPoint{ts: 10, val:20.0}).unwrap();
+        summary.add_point(&TSPoint{ts: 15, val:30.0}).unwrap();
+        summary.add_point(&TSPoint{ts: 20, val:50.0}).unwrap();
+        summary.add_point(&TSPoint{ts: 25, val:10.0}).unwrap();
+        summary.add_point(&TSPoint{ts: 30, val:40.0}).unwrap();
+
+        let mut part1 = GaugeSummary::new(&TSPoint{ts: 0, val:0.0}, None);
+        part1.add_point(&TSPoint{ts: 5, val:10.0}).unwrap();
+        part1.add_point(&TSPoint{ts: 10, val:20.0}).unwrap();
+
+        let mut part2 = GaugeSummary::new(&TSPoint{ts: 15, val:30.0}, None);
+        part2.add_point(&TSPoint{ts: 20, val:50.0}).unwrap();
+        part2.add_point(&TSPoint{ts: 25, val:10.0}).unwrap();
+        part2.add_point(&TSPoint{ts: 30, val:40.0}).unwrap();
+
+        let mut combined = part1.clone();
+        combined.combine(&part2).unwrap();
+        assert_close_enough(&summary, &combined);
+
+        // a decrease at the boundary is just a change
+        let part3 = GaugeSummary::new(&TSPoint{ts: 35, val:5.0}, None);
+        combined.combine(&part3).unwrap();
+        assert_eq!(combined.num_changes, 7);
+        assert_relative_eq!(combined.delta(), 5.0);
+
+        // test error in wrong direction
+        assert_eq!(part2.combine(&part1).unwrap_err(), CounterError::OrderError);
+    }
+
+    #[test]
+    fn test_gauge_prometheus_extrapolation() {
+        let summary = GaugeSummary::new(&TSPoint{ts: 5, val:15.0}, None);
+        assert_eq!(summary.prometheus_delta().unwrap_err(), CounterError::BoundsInvalid);
+        assert_eq!(summary.prometheus_rate().unwrap_err(), CounterError::BoundsInvalid);
+
+        let mut summary = GaugeSummary::new(&TSPoint{ts: 20, val:20.0}, Some(I64Range{left:Some(10), right:Some(50)}));
+        assert_eq!(summary.prometheus_delta().unwrap(), None);
+        summary.add_point(&TSPoint{ts: 30, val:40.0}).unwrap();
+        summary.add_point(&TSPoint{ts: 40, val: 60.0}).unwrap();
+        //we go all the way to the edge of the bounds here because it's within 1.1 average steps
+        assert_relative_eq!(summary.prometheus_delta().unwrap().unwrap(), 80.0);
+        assert_relative_eq!(summary.prometheus_rate().unwrap().unwrap(), summary.rate().unwrap());
+
+        // counters would stop at their zero point here, gauges don't have one so they extrapolate
+        // out to half the average distance between samples on both sides
+        summary.bounds = Some(I64Range{left:Some(8), right:Some(52)});
+        assert_relative_eq!(summary.prometheus_delta().unwrap().unwrap(), 60.0);
+        assert_relative_eq!(summary.prometheus_rate().unwrap().unwrap(), to_micro(60.0 / 44.0));
+
+        // decreasing gauges extrapolate to negative deltas
+        let mut summary = GaugeSummary::new(&TSPoint{ts: 20, val:60.0}, Some(I64Range{left:Some(10), right:Some(50)}));
+        summary.add_point(&TSPoint{ts: 30, val:40.0}).unwrap();
+        summary.add_point(&TSPoint{ts: 40, val: 20.0}).unwrap();
+        assert_relative_eq!(summary.prometheus_delta().unwrap().unwrap(), -80.0);
+    }
+}
@@ -4,12 +4,15 @@ use regression::{XYPair, RegressionSummary};
 
 pub mod regression;
 pub mod range;
+pub mod gauge;
 mod tests;
+mod prometheus_tests;
 
 #[derive(Debug, PartialEq)]
 pub enum CounterError{
     OrderError,
     BoundsInvalid,
+    OverlapError,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
@@ -92,6 +95,50 @@ impl CounterSummary {
         Ok(())
     }
 
+    /// Removes the first point from the summary, this is the inverse of adding it and is used to
+    /// slide a window over a series. As we don't keep all the points, the caller has to provide
+    /// the point that will become the new `second`, ie the point following the current `second`,
+    /// or the current `second` itself if that is the last point. A summary with a single point
+    /// can't be emptied, and points that were ignored by `add_point` for having a duplicate
+    /// timestamp must not be removed.
+    pub fn remove_first(&mut self, new_second: &TSPoint) -> Result<(), CounterError> {
+        if self.single_value() || new_second.ts < self.second.ts {
+            return Err(CounterError::OrderError);
+        }
+        let removed = self.first;
+        let new_first = self.second;
+        // the first point is always stored without any reset offset
+        let mut removed_xy = ts_to_xy(removed);
+        removed_xy.y = removed.val;
+        self.regress.remove(removed_xy).unwrap();
+        if new_first.val != removed.val {
+            self.num_changes -= 1;
+        }
+        if new_first.val < removed.val {
+            // the reset at our new first point no longer happened within the summary, so every
+            // remaining point loses the offset it caused.
+            self.num_resets -= 1;
+            self.reset_sum -= removed.val;
+            self.regress.offset(XYPair{x: 0.0, y: -removed.val}).unwrap();
+        }
+        self.first = new_first;
+        if new_first == self.last {
+            self.second = new_first;
+            self.penultimate = new_first;
+        } else {
+            self.second = *new_second;
+        }
+        Ok(())
+    }
+
+    // the distinct points we store, in time order
+    fn known_points(&self) -> Vec<TSPoint> {
+        let mut points = vec![self.first, self.second, self.penultimate, self.last];
+        points.sort_unstable_by_key(|p| p.ts);
+        points.dedup();
+        points
+    }
+
     fn single_value(&self) -> bool {
         self.last == self.first
     }
@@ -132,6 +179,109 @@ impl CounterSummary {
         Ok(())
     }
     
+    /// Merges a summary whose points are interleaved in time with ours, as happens when parallel
+    /// workers each see an arbitrary subset of the rows. Without the points themselves we can't
+    /// tell where a reset happened relative to the other summary's points, so this only works if
+    /// neither summary contains a reset and the points we do know of, the first two and last two
+    /// of each, keep increasing when put in time order. Otherwise the counter reset somewhere in
+    /// the overlap, or the summaries share a timestamp, and we return an `OverlapError`. The
+    /// changes of both summaries are kept, plus one where they meet unless they start at the
+    /// same value, which is exact as long as that is the only value they have in common.
+    pub fn merge_overlapping(&mut self, incoming: &CounterSummary) -> Result<(), CounterError> {
+        if self.num_resets > 0 || incoming.num_resets > 0 {
+            return Err(CounterError::OverlapError);
+        }
+        let mut known = self.known_points();
+        known.extend(incoming.known_points());
+        known.sort_unstable_by_key(|p| p.ts);
+        if known.windows(2).any(|w| w[0].ts == w[1].ts || w[0].val > w[1].val) {
+            return Err(CounterError::OverlapError);
+        }
+
+        if self.first.val != incoming.first.val {
+            self.num_changes += 1;
+        }
+        self.num_changes += incoming.num_changes;
+        self.first = known[0];
+        self.second = known[1];
+        self.penultimate = known[known.len() - 2];
+        self.last = known[known.len() - 1];
+        // without resets there's no offset to apply to either regression
+        self.regress = self.regress.combine(incoming.regress).unwrap();
+        self.bounds_extend(incoming.bounds);
+        Ok(())
+    }
+
+    /// Combines a set of summaries that may arrive in any order, as they will when partials are
+    /// produced by parallel workers. The summaries are re-ordered by their first and last
+    /// timestamps and then combined in order. A single point that duplicates the boundary of
+    /// another summary is only counted once, and summaries that overlap in time are merged with
+    /// `merge_overlapping`, which returns an `OverlapError` if the order of their points can't be
+    /// worked out.
+    pub fn combine_summaries(summaries: &mut [CounterSummary]) -> Result<Option<CounterSummary>, CounterError> {
+        summaries.sort_unstable_by_key(|s| (s.first.ts, s.last.ts));
+        let mut iter = summaries.iter();
+        let mut combined = match iter.next() {
+            None => return Ok(None),
+            Some(s) => s.clone(),
+        };
+        for next in iter {
+            if combined.last.ts < next.first.ts {
+                combined.combine(next)?;
+            } else if next.single_value() && next.first == combined.last {
+                // the same point seen by two partials, we already have it.
+                combined.bounds_extend(next.bounds);
+            } else if combined.single_value() && combined.first == next.first {
+                let bounds = combined.bounds;
+                combined = next.clone();
+                combined.bounds_extend(bounds);
+            } else {
+                combined.merge_overlapping(next)?;
+            }
+        }
+        Ok(Some(combined))
+    }
+
+    /// Produces a summary covering exactly `[interval_start, interval_start + interval_len]` by
+    /// linearly interpolating the counter value at each edge of the interval between the
+    /// neighbouring summaries and this one. `prev` must end before the interval starts and `next`
+    /// must start at or after it ends; if either is missing that edge is left as is. A reset in
+    /// the gap between two summaries is assumed to have reset the counter to zero just after the
+    /// earlier point, so the increase over the gap (the value of the later point) is split
+    /// between the two intervals in proportion to the time on each side of the edge. This way the
+    /// deltas of consecutive interpolated summaries add up to the delta over all of them.
+    pub fn interpolate(
+        &self,
+        interval_start: i64,
+        interval_len: i64,
+        prev: Option<&CounterSummary>,
+        next: Option<&CounterSummary>,
+    ) -> Result<CounterSummary, CounterError> {
+        let interval_end = interval_start + interval_len;
+        if self.first.ts < interval_start || self.last.ts >= interval_end {
+            return Err(CounterError::BoundsInvalid);
+        }
+        let mut summary = self.clone();
+        if let Some(prev) = prev {
+            if prev.last.ts >= interval_start {
+                return Err(CounterError::BoundsInvalid);
+            }
+            if self.first.ts > interval_start {
+                let (start_val, _) = interpolate_across_gap(&prev.last, &self.first, interval_start);
+                summary = CounterSummary::new(&TSPoint{ts: interval_start, val: start_val}, None);
+                summary.combine(self)?;
+            }
+        }
+        if let Some(next) = next {
+            if next.first.ts < interval_end {
+                return Err(CounterError::BoundsInvalid);
+            }
+            let (_, end_val) = interpolate_across_gap(&self.last, &next.first, interval_end);
+            summary.add_point(&TSPoint{ts: interval_end, val: end_val})?;
+        }
+        Ok(summary)
+    }
+
     pub fn time_delta(&self) -> f64{
         to_seconds((self.last.ts - self.first.ts) as f64)
     }
@@ -180,6 +330,18 @@ impl CounterSummary {
             Some(self.idelta_right() / to_seconds((self.last.ts - self.penultimate.ts) as f64))
         }
     }
+
+    /// the skewness of the values in the summary, see `RegressionSummary::skewness_y`. Like the
+    /// regression, this is computed over the reset-adjusted values of the counter.
+    pub fn skewness(&self) -> Option<f64> {
+        self.regress.skewness_y()
+    }
+
+    /// the kurtosis of the values in the summary, see `RegressionSummary::kurtosis_y`. Like the
+    /// regression, this is computed over the reset-adjusted values of the counter.
+    pub fn kurtosis(&self) -> Option<f64> {
+        self.regress.kurtosis_y()
+    }
     
     pub fn bounds_valid(&self) -> bool {
         match self.bounds{
@@ -202,54 +364,59 @@ impl CounterSummary {
     // based on:  https://github.com/timescale/promscale_extension/blob/d51a0958442f66cb78d38b584a10100f0d278298/src/lib.rs#L208, 
     // which is based on:     // https://github.com/prometheus/prometheus/blob/e5ffa8c9a08a5ee4185271c8c26051ddc1388b7a/promql/functions.go#L59
     pub fn prometheus_delta(&self) -> Result<Option<f64>, CounterError>{
-        if self.bounds.is_none() || !self.bounds_valid() ||  self.bounds.unwrap().has_infinite() {
-            return Err(CounterError::BoundsInvalid);
-        }
-        //must have at least 2 values
-        if self.single_value() || self.bounds.unwrap().is_singleton(){ //technically, the is_singleton check is redundant, it's included for clarity (any singleton bound that is valid can only be one point)
-            return Ok(None);
-        }
-
-        let mut result_val = self.delta();
+        prometheus_extrapolated_delta(self.delta(), &self.first, &self.last, self.regress.n64(), self.bounds, true)
+    }
 
-        // all calculated durations in seconds in Prom implementation, so we'll do that here.
-        // we can unwrap all of the bounds accesses as they are guaranteed to be there from the checks above
-        let mut duration_to_start = to_seconds((self.first.ts - self.bounds.unwrap().left.unwrap()) as f64);
-        let duration_to_end = to_seconds((self.bounds.unwrap().right.unwrap() - self.last.ts) as f64);
-        let sampled_interval = self.time_delta();
-        let avg_duration_between_samples = sampled_interval / (self.regress.n64() - 1.0); // don't have to worry about divide by zero because we know we have at least 2 values from the above.
-        
-        // we don't want to extrapolate to negative counter values, so we calculate the duration to the zero point of the counter (based on what we know here) and set that as duration_to_start if it's smaller than duration_to_start
-        if result_val > 0.0 && self.first.val >= 0.0 {
-            let duration_to_zero = sampled_interval * (self.first.val / result_val);
-            if duration_to_zero < duration_to_start {
-                duration_to_start = duration_to_zero;
-            }
-        }
+    // The extrapolation for PromQL's `increase` is the same as for counter deltas, this is just
+    // here so that the mapping to the Prometheus functions is clear.
+    pub fn prometheus_increase(&self) -> Result<Option<f64>, CounterError>{
+        self.prometheus_delta()
+    }
 
-        // If the first/last samples are close to the boundaries of the range,
-        // extrapolate the result. This is as we expect that another sample
-        // will exist given the spacing between samples we've seen thus far,
-        // with an allowance for noise.
-        // Otherwise, we extrapolate to one half the avg distance between samples...
-        // this was empirically shown to be good for certain things and was discussed at length in: https://github.com/prometheus/prometheus/pull/1161
+    // equivalent to PromQL's `resets` over the points of the summary that fall in `range`
+    pub fn num_resets_in(&self, range: range::I64Range) -> Result<u64, CounterError> {
+        self.transitions_in(range, self.num_resets, |prev, next| next.val < prev.val)
+    }
 
-        let extrapolation_threshold = avg_duration_between_samples * 1.1;
-        let mut extrapolate_to_interval = sampled_interval;
+    // equivalent to PromQL's `changes` over the points of the summary that fall in `range`
+    pub fn num_changes_in(&self, range: range::I64Range) -> Result<u64, CounterError> {
+        self.transitions_in(range, self.num_changes, |prev, next| next.val != prev.val)
+    }
 
-        if duration_to_start < extrapolation_threshold {
-            extrapolate_to_interval += duration_to_start
-        } else {
-            extrapolate_to_interval += avg_duration_between_samples / 2.0
+    // We only keep the first two and last two points, so we can exclude at most the first and
+    // last points of the summary from the count, if the range cuts off more than that we don't
+    // know what happened in the excluded part and error.
+    fn transitions_in(
+        &self,
+        range: range::I64Range,
+        total: u64,
+        counts: impl Fn(&TSPoint, &TSPoint) -> bool,
+    ) -> Result<u64, CounterError> {
+        let left = range.left.unwrap_or(i64::MIN);
+        let right = range.right.unwrap_or(i64::MAX);
+        if left >= right || self.last.ts < left || self.first.ts >= right {
+            return Ok(0);
         }
-
-        if duration_to_end < extrapolation_threshold {
-            extrapolate_to_interval += duration_to_end
-        } else {
-            extrapolate_to_interval += avg_duration_between_samples / 2.0
+        let mut count = total;
+        if self.first.ts < left {
+            if self.second.ts < left {
+                return Err(CounterError::BoundsInvalid);
+            }
+            if self.second.ts >= right {
+                return Ok(0);
+            }
+            count -= counts(&self.first, &self.second) as u64;
+        }
+        if self.last.ts >= right {
+            if self.penultimate.ts >= right {
+                return Err(CounterError::BoundsInvalid);
+            }
+            if self.penultimate.ts < left {
+                return Ok(0);
+            }
+            count -= counts(&self.penultimate, &self.last) as u64;
         }
-        result_val = result_val * (extrapolate_to_interval / sampled_interval);
-        Ok(Some(result_val))
+        Ok(count)
     }
 
     pub fn prometheus_rate(&self) -> Result<Option<f64>, CounterError>{
@@ -263,3 +430,80 @@ impl CounterSummary {
         Ok(Some(delta / to_seconds(duration as f64))) // don't have to deal with 0 case because that is checked in delta as well (singleton)
     }
 }
+
+// Interpolates the counter value at `ts` between `before` and `after`, returns the value in the
+// frame of reference of `after` (ie after any reset between the two) as well as in the frame of
+// reference of `before`.
+fn interpolate_across_gap(before: &TSPoint, after: &TSPoint, ts: i64) -> (f64, f64) {
+    let frac = (ts - before.ts) as f64 / (after.ts - before.ts) as f64;
+    if after.val < before.val {
+        // counter reset, we treat it as if the counter went to zero just after `before`
+        let increase = after.val * frac;
+        (increase, before.val + increase)
+    } else {
+        let val = before.val + (after.val - before.val) * frac;
+        (val, val)
+    }
+}
+
+// The extrapolation shared by counters and gauges, the only difference between the two is that counters
+// can't be extrapolated to negative values, so for them we clamp the start of the extrapolation to the
+// inferred zero point of the counter. This mirrors the `isCounter` flag in the Prometheus implementation.
+fn prometheus_extrapolated_delta(
+    delta: f64,
+    first: &TSPoint,
+    last: &TSPoint,
+    n: f64,
+    bounds: Option<range::I64Range>,
+    is_counter: bool,
+) -> Result<Option<f64>, CounterError> {
+    let bounds = match bounds {
+        Some(b) if !b.has_infinite() && b.contains(first.ts) && b.contains(last.ts) => b,
+        _ => return Err(CounterError::BoundsInvalid),
+    };
+    //must have at least 2 values
+    if first == last || bounds.is_singleton(){ //technically, the is_singleton check is redundant, it's included for clarity (any singleton bound that is valid can only be one point)
+        return Ok(None);
+    }
+
+    let mut result_val = delta;
+
+    // all calculated durations in seconds in Prom implementation, so we'll do that here.
+    // we can unwrap all of the bounds accesses as they are guaranteed to be there from the checks above
+    let mut duration_to_start = to_seconds((first.ts - bounds.left.unwrap()) as f64);
+    let duration_to_end = to_seconds((bounds.right.unwrap() - last.ts) as f64);
+    let sampled_interval = to_seconds((last.ts - first.ts) as f64);
+    let avg_duration_between_samples = sampled_interval / (n - 1.0); // don't have to worry about divide by zero because we know we have at least 2 values from the above.
+
+    // we don't want to extrapolate to negative counter values, so we calculate the duration to the zero point of the counter (based on what we know here) and set that as duration_to_start if it's smaller than duration_to_start
+    if is_counter && result_val > 0.0 && first.val >= 0.0 {
+        let duration_to_zero = sampled_interval * (first.val / result_val);
+        if duration_to_zero < duration_to_start {
+            duration_to_start = duration_to_zero;
+        }
+    }
+
+    // If the first/last samples are close to the boundaries of the range,
+    // extrapolate the result. This is as we expect that another sample
+    // will exist given the spacing between samples we've seen thus far,
+    // with an allowance for noise.
+    // Otherwise, we extrapolate to one half the avg distance between samples...
+    // this was empirically shown to be good for certain things and was discussed at length in: https://github.com/prometheus/prometheus/pull/1161
+
+    let extrapolation_threshold = avg_duration_between_samples * 1.1;
+    let mut extrapolate_to_interval = sampled_interval;
+
+    if duration_to_start < extrapolation_threshold {
+        extrapolate_to_interval += duration_to_start
+    } else {
+        extrapolate_to_interval += avg_duration_between_samples / 2.0
+    }
+
+    if duration_to_end < extrapolation_threshold {
+        extrapolate_to_interval += duration_to_end
+    } else {
+        extrapolate_to_interval += avg_duration_between_samples / 2.0
+    }
+    result_val = result_val * (extrapolate_to_interval / sampled_interval);
+    Ok(Some(result_val))
+}
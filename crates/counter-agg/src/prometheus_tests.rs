@@ -0,0 +1,147 @@
+// Golden values taken from the Prometheus test fixtures in
+// https://github.com/prometheus/prometheus/blob/main/promql/testdata/functions.test
+// Prometheus range selectors are closed on both sides, `foo[20m]` evaluated at 50m selects the
+// samples in [30m, 50m], so the bounds used here are [start, end + 1µs).
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+    use crate::range::I64Range;
+    use crate::*;
+
+    const MINUTE: i64 = 60 * 1_000_000;
+
+    // equivalent of a `load 5m` series in the Prometheus test language, the series starts at 0
+    fn load_5m(vals: &[f64]) -> Vec<TSPoint> {
+        vals.iter().enumerate()
+            .map(|(i, &val)| TSPoint{ts: i as i64 * 5 * MINUTE, val})
+            .collect()
+    }
+
+    // `start+stepxcount` in the Prometheus test language
+    fn series(start: f64, step: f64, count: usize) -> Vec<f64> {
+        (0..=count).map(|i| start + step * i as f64).collect()
+    }
+
+    fn window(eval_at: i64, range: i64) -> I64Range {
+        I64Range{left: Some((eval_at - range) * MINUTE), right: Some(eval_at * MINUTE + 1)}
+    }
+
+    // build the summary from the points a query bucketed around the window would see: those
+    // inside the window along with the preceding point, if there is one.
+    fn summary_for(points: &[TSPoint], window: I64Range) -> CounterSummary {
+        let left = window.left.unwrap();
+        let right = window.right.unwrap();
+        let start = points.iter().rposition(|p| p.ts < left).unwrap_or(0);
+        let mut iter = points[start..].iter().filter(|p| p.ts < right);
+        let mut summary = CounterSummary::new(iter.next().unwrap(), Some(window));
+        for p in iter {
+            summary.add_point(p).unwrap();
+        }
+        summary
+    }
+
+    fn summary_in(points: &[TSPoint], window: I64Range) -> CounterSummary {
+        let in_window: Vec<_> = points.iter().filter(|p| window.contains(p.ts)).copied().collect();
+        summary_for(&in_window, window)
+    }
+
+    #[test]
+    fn test_prometheus_resets() {
+        let foo = load_5m(&[1.0, 2.0, 3.0, 0.0, 1.0, 0.0, 0.0, 1.0, 2.0, 0.0]);
+        let bar = load_5m(&[1.0, 2.0, 3.0, 4.0, 5.0, 1.0, 2.0, 3.0, 4.0, 5.0]);
+        let biz = load_5m(&[0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 1.0, 1.0, 1.0, 1.0]);
+
+        // eval instant at 50m resets(http_requests[range])
+        let expected = [
+            (5, [0, 0, 0]),
+            (20, [1, 0, 0]),
+            (30, [2, 1, 0]),
+            (50, [3, 1, 0]),
+        ];
+        for (range, resets) in expected.iter() {
+            let w = window(50, *range);
+            for (points, &expected) in [&foo, &bar, &biz].iter().zip(resets.iter()) {
+                let summary = summary_for(points, w);
+                assert_eq!(summary.num_resets_in(w).unwrap(), expected, "resets in [{}m]", range);
+            }
+        }
+    }
+
+    #[test]
+    fn test_prometheus_changes() {
+        let foo = load_5m(&[1.0, 2.0, 3.0, 0.0, 1.0, 0.0, 0.0, 1.0, 2.0, 0.0]);
+        let bar = load_5m(&[1.0, 2.0, 3.0, 4.0, 5.0, 1.0, 2.0, 3.0, 4.0, 5.0]);
+        let biz = load_5m(&[0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 1.0, 1.0, 1.0, 1.0]);
+
+        // eval instant at 50m changes(http_requests[range])
+        let expected = [
+            (5, [0, 0, 0]),
+            (20, [3, 3, 0]),
+            (30, [4, 5, 1]),
+            (50, [8, 9, 1]),
+        ];
+        for (range, changes) in expected.iter() {
+            let w = window(50, *range);
+            for (points, &expected) in [&foo, &bar, &biz].iter().zip(changes.iter()) {
+                let summary = summary_for(points, w);
+                assert_eq!(summary.num_changes_in(w).unwrap(), expected, "changes in [{}m]", range);
+            }
+        }
+    }
+
+    #[test]
+    fn test_transitions_out_of_range() {
+        let foo = load_5m(&[1.0, 2.0, 3.0, 0.0, 1.0, 0.0, 0.0, 1.0, 2.0, 0.0]);
+        let summary = summary_for(&foo, window(50, 50));
+        // entirely outside the summary
+        assert_eq!(summary.num_resets_in(window(100, 20)).unwrap(), 0);
+        assert_eq!(summary.num_changes_in(I64Range{left: Some(-10), right: Some(-5)}).unwrap(), 0);
+        // between two points
+        assert_eq!(summary.num_changes_in(I64Range{left: Some(MINUTE), right: Some(2 * MINUTE)}).unwrap(), 0);
+        // unbounded
+        assert_eq!(summary.num_resets_in(I64Range{left: None, right: None}).unwrap(), 3);
+        // cuts off more than one point on the left
+        assert_eq!(summary.num_resets_in(window(50, 20)).unwrap_err(), CounterError::BoundsInvalid);
+        // or the right
+        assert_eq!(summary.num_changes_in(window(20, 20)).unwrap_err(), CounterError::BoundsInvalid);
+        // only cuts off the last point
+        assert_eq!(summary.num_changes_in(window(40, 50)).unwrap(), 7);
+        assert_eq!(summary.num_resets_in(window(40, 50)).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_prometheus_increase() {
+        let foo = load_5m(&series(0.0, 10.0, 10));
+        let mut bar = series(0.0, 10.0, 5);
+        bar.extend(series(0.0, 10.0, 5));
+        let bar = load_5m(&bar);
+
+        // eval instant at 50m increase(http_requests[range])
+        for &range in [50, 100].iter() {
+            let w = window(50, range);
+            let increase = summary_in(&foo, w).prometheus_increase().unwrap().unwrap();
+            assert_relative_eq!(increase, 100.0, max_relative = 1e-6);
+            let increase = summary_in(&bar, w).prometheus_increase().unwrap().unwrap();
+            assert_relative_eq!(increase, 90.0, max_relative = 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_prometheus_rate_resets() {
+        // testcounter_reset_middle 0+10x4 0+10x5
+        let mut vals = series(0.0, 10.0, 4);
+        vals.extend(series(0.0, 10.0, 5));
+        let reset_middle = load_5m(&vals);
+        // eval instant at 50m rate(testcounter_reset_middle[50m])
+        let rate = summary_in(&reset_middle, window(50, 50)).prometheus_rate().unwrap().unwrap();
+        assert_relative_eq!(rate, 0.03, max_relative = 1e-6);
+
+        // testcounter_reset_end 0+10x9 0 10
+        let mut vals = series(0.0, 10.0, 9);
+        vals.extend([0.0, 10.0].iter());
+        let reset_end = load_5m(&vals);
+        // eval instant at 50m rate(testcounter_reset_end[5m])
+        let rate = summary_in(&reset_end, window(50, 5)).prometheus_rate().unwrap().unwrap();
+        assert_relative_eq!(rate, 0.0);
+    }
+}
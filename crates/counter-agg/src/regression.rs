@@ -7,6 +7,7 @@ use serde::{Deserialize, Serialize};
 #[derive(Debug, PartialEq)]
 pub enum RegressionError {
     DoubleOverflow,
+    NotInvertible,
 }
 #[derive(Debug, PartialEq)]
 pub struct XYPair {
@@ -15,7 +16,6 @@ pub struct XYPair {
 }
 //regression partials
 #[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
-#[repr(C)]
 pub struct RegressionSummary {
     pub n: u64,   // count
     pub sx: f64,  // sum(x)
@@ -23,7 +23,35 @@ pub struct RegressionSummary {
     pub sy: f64,  // sum(y)
     pub syy: f64, // sum((y-sy/n)^2) (sum of squares)
     pub sxy: f64, // sum((x-sx/n)*(y-sy/n)) (sum of products)
+    // None for summaries that were stored before the higher moments were tracked, they can't be
+    // recovered so anything combined with such a summary doesn't have them either
+    pub higher_moments: Option<HigherMoments>,
+}
+
+// The moments up to the second, laid out as RegressionSummary was before it tracked the higher
+// moments. This is what the summary types store on disk, types that also store the
+// HigherMoments do so after their other fields so that older summaries can still be read.
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
+#[repr(C)]
+pub struct RegressionPartials {
+    pub n: u64,
+    pub sx: f64,
+    pub sxx: f64,
+    pub sy: f64,
+    pub syy: f64,
+    pub sxy: f64,
+}
+
+//third and fourth moment partials
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
+#[repr(C)]
+pub struct HigherMoments {
+    pub sx3: f64, // sum((x-sx/n)^3)
+    pub sx4: f64, // sum((x-sx/n)^4)
+    pub sy3: f64, // sum((y-sy/n)^3)
+    pub sy4: f64, // sum((y-sy/n)^4)
 }
+
 impl RegressionSummary {
     pub fn new() -> Self {
         RegressionSummary {
@@ -33,6 +61,30 @@ impl RegressionSummary {
             sy: 0.0,
             syy: 0.0,
             sxy: 0.0,
+            higher_moments: Some(HigherMoments::new()),
+        }
+    }
+
+    pub fn from_partials(p: RegressionPartials, higher_moments: Option<HigherMoments>) -> Self {
+        RegressionSummary {
+            n: p.n,
+            sx: p.sx,
+            sxx: p.sxx,
+            sy: p.sy,
+            syy: p.syy,
+            sxy: p.sxy,
+            higher_moments,
+        }
+    }
+
+    pub fn partials(&self) -> RegressionPartials {
+        RegressionPartials {
+            n: self.n,
+            sx: self.sx,
+            sxx: self.sxx,
+            sy: self.sy,
+            syy: self.syy,
+            sxy: self.sxy,
         }
     }
 
@@ -52,16 +104,16 @@ impl RegressionSummary {
     ///
     /// assert_eq!(p.accum(XYPair{y:f64::MAX, x:1.0,}), Err(RegressionError::DoubleOverflow)); // we do error if we actually overflow however
     ///
+    /// // the fourth moment overflows long before the values themselves do, in which case we
+    /// // stop tracking the higher moments
+    /// let mut p = RegressionSummary::new();
+    /// p.accum(XYPair{x:0.0, y:0.0,}).unwrap();
+    /// p.accum(XYPair{x:1e80, y:0.0,}).unwrap();
+    /// assert_eq!(p.higher_moments, None);
+    /// assert!(p.sum_squares().unwrap().x.is_finite());
     ///```
     pub fn accum(&mut self, p: XYPair) -> Result<(), RegressionError> {
-        let old = RegressionSummary {
-            n: self.n,
-            sx: self.sx,
-            sxx: self.sxx,
-            sy: self.sy,
-            syy: self.syy,
-            sxy: self.sxy,
-        };
+        let old = *self;
         self.n += 1;
         self.sx += p.x;
         self.sy += p.y;
@@ -69,9 +121,14 @@ impl RegressionSummary {
             let tmpx = p.x * self.n64() - self.sx;
             let tmpy = p.y * self.n64() - self.sy;
             let scale = 1.0 / (self.n64() * old.n64());
+            // the higher moments need the sums of squares from before this point
+            self.higher_moments = old.higher_moments.map(|m| m.with_point(&old, &p));
             self.sxx += tmpx * tmpx * scale;
             self.syy += tmpy * tmpy * scale;
             self.sxy += tmpx * tmpy * scale;
+            if self.moments_overflowed(&old, &p) {
+                self.higher_moments = None;
+            }
             if self.has_infinite() {
                 if self.check_overflow(&old, p) {
                     return Err(RegressionError::DoubleOverflow);
@@ -90,26 +147,115 @@ impl RegressionSummary {
                 if self.sxy.is_infinite() {
                     self.sxy = f64::NAN;
                 }
+                // the same goes for the higher moments
+                if let Some(m) = &mut self.higher_moments {
+                    if m.sx3.is_infinite() || m.sx4.is_infinite() {
+                        m.sx3 = f64::NAN;
+                        m.sx4 = f64::NAN;
+                    }
+                    if m.sy3.is_infinite() || m.sy4.is_infinite() {
+                        m.sy3 = f64::NAN;
+                        m.sy4 = f64::NAN;
+                    }
+                }
             }
         } else {
-            // first input, leave sxx/syy/sxy alone unless we have infinite inputs
+            // first input, leave the moments alone unless we have infinite inputs
             if !p.x.is_finite() {
                 self.sxx = f64::NAN;
                 self.sxy = f64::NAN;
+                if let Some(m) = &mut self.higher_moments {
+                    m.sx3 = f64::NAN;
+                    m.sx4 = f64::NAN;
+                }
             }
             if !p.y.is_finite() {
                 self.syy = f64::NAN;
                 self.sxy = f64::NAN;
+                if let Some(m) = &mut self.higher_moments {
+                    m.sy3 = f64::NAN;
+                    m.sy4 = f64::NAN;
+                }
             }
         }
         Result::Ok(())
     }
+    /// remove an XYPair that was previously accumulated into a RegressionSummary, this is the
+    /// inverse of `accum`, up to floating point error.
+    /// ```
+    /// use counter_agg::regression::*;
+    /// let mut p = RegressionSummary::new_from_vec(vec![XYPair{x:1.0, y:1.0,}, XYPair{x:2.0, y:4.0,}, XYPair{x:3.0, y:3.0,}]).unwrap();
+    /// let q = RegressionSummary::new_from_vec(vec![XYPair{x:2.0, y:4.0,}, XYPair{x:3.0, y:3.0,}]).unwrap();
+    /// p.remove(XYPair{x:1.0, y:1.0,}).unwrap();
+    /// assert_eq!(p.count(), q.count());
+    /// assert_eq!(p.sum(), q.sum());
+    /// // the sums of squares and products are equal up to floating point error
+    /// assert!((p.sum_squares().unwrap().y - q.sum_squares().unwrap().y).abs() < 1e-12);
+    /// assert!((p.sumxy().unwrap() - q.sumxy().unwrap()).abs() < 1e-12);
+    /// // once an infinite value has been added we can't remove anything
+    /// p.accum(XYPair{x:f64::INFINITY, y:1.0}).unwrap();
+    /// assert_eq!(p.remove(XYPair{x:2.0, y:4.0,}), Err(RegressionError::NotInvertible));
+    /// ```
+    // this reverses the update in accum: if the current n, sx and sy include p, then
+    //      tmpx = x * n - sx
+    //      sxx' = sxx - tmpx * tmpx / (n * (n - 1))
+    // and analogously for syy and sxy. The higher moments are recovered by inverting the
+    // single-point case of combine_higher_moments, see HigherMoments::without_point.
+    pub fn remove(&mut self, p: XYPair) -> Result<(), RegressionError> {
+        if !p.x.is_finite() || !p.y.is_finite() || !self.is_finite() {
+            return Err(RegressionError::NotInvertible);
+        }
+        if self.n <= 1 {
+            *self = RegressionSummary::new();
+            return Ok(());
+        }
+        let tmpx = p.x * self.n64() - self.sx;
+        let tmpy = p.y * self.n64() - self.sy;
+        let scale = 1.0 / (self.n64() * (self.n64() - 1.0));
+        let n = self.n64();
+        self.n -= 1;
+        self.sx -= p.x;
+        self.sy -= p.y;
+        if self.n == 1 {
+            // a single value has no spread, don't leave rounding error behind
+            self.sxx = 0.0;
+            self.syy = 0.0;
+            self.sxy = 0.0;
+            self.higher_moments = self.higher_moments.map(|_| HigherMoments::new());
+        } else {
+            self.sxx -= tmpx * tmpx * scale;
+            self.syy -= tmpy * tmpy * scale;
+            self.sxy -= tmpx * tmpy * scale;
+            let remaining = *self;
+            self.higher_moments = self.higher_moments.map(|m| m.without_point(n, &remaining, &p));
+        }
+        Ok(())
+    }
+    fn is_finite(&self) -> bool {
+        self.sx.is_finite()
+            && self.sxx.is_finite()
+            && self.sy.is_finite()
+            && self.syy.is_finite()
+            && self.sxy.is_finite()
+            && self.higher_moments.iter().all(|m| m.is_finite())
+    }
     fn has_infinite(&self) -> bool {
         self.sx.is_infinite()
             || self.sxx.is_infinite()
             || self.sy.is_infinite()
             || self.syy.is_infinite()
             || self.sxy.is_infinite()
+            || self.higher_moments.iter().any(|m| m.has_infinite())
+    }
+    // The higher moments grow with the third and fourth powers of the spread of the values, so
+    // they overflow well before the sums of squares do. As with check_overflow, only finite
+    // inputs that lead to infinite moments are an overflow.
+    fn moments_overflowed(&self, old: &RegressionSummary, p: &XYPair) -> bool {
+        match self.higher_moments {
+            Some(m) => ((m.sx3.is_infinite() || m.sx4.is_infinite()) && old.sx.is_finite() && p.x.is_finite())
+                || ((m.sy3.is_infinite() || m.sy4.is_infinite()) && old.sy.is_finite() && p.y.is_finite()),
+            None => false,
+        }
     }
     fn check_overflow(&self, old: &RegressionSummary, p: XYPair) -> bool {
         //Only report overflow if we have finite inputs that lead to infinite results.
@@ -158,6 +304,7 @@ impl RegressionSummary {
     //      sxx = sxx1 + sxx2 + n1 * n2 * (sx1/n1 - sx2/n)^2 / n
     //      sy / syy analogous
     //      sxy = sxy1 + sxy2 + n1 * n2 * (sx1/n1 - sx2/n2) * (sy1/n1 - sy2/n2) / n
+    // the third and fourth moments are combined as described in combine_higher_moments
     pub fn combine(&self, other: RegressionSummary) -> Result<Self, RegressionError> {
         // TODO: think about whether we want to just modify &self in place here for perf
         // reasons. This is also a set of weird questions around the Rust compiler, so
@@ -174,16 +321,27 @@ impl RegressionSummary {
         let tmpx = self.sx / self.n64() - other.sx / other.n64();
         let tmpy = self.sy / self.n64() - other.sy / other.n64();
         let n = self.n + other.n;
-        let r = RegressionSummary {
+        let higher_moments = match (self.higher_moments, other.higher_moments) {
+            (Some(a), Some(b)) => Some(a.combine(self, &b, &other)),
+            _ => None,
+        };
+        let mut r = RegressionSummary {
             n: n,
             sx: self.sx + other.sx,
             sxx: self.sxx + other.sxx + self.n64() * other.n64() * tmpx * tmpx / n as f64,
             sy: self.sy + other.sy,
             syy: self.syy + other.syy + self.n64() * other.n64() * tmpy * tmpy / n as f64,
             sxy: self.sxy + other.sxy + self.n64() * other.n64() * tmpx * tmpy / n as f64,
+            higher_moments,
         };
-        if r.has_infinite() && !self.has_infinite() && !other.has_infinite() {
-            return Err(RegressionError::DoubleOverflow);
+        if !self.has_infinite() && !other.has_infinite() {
+            // rather than fail when only the higher moments overflow, stop tracking them
+            if r.higher_moments.iter().any(|m| m.has_infinite()) {
+                r.higher_moments = None;
+            }
+            if r.has_infinite() {
+                return Err(RegressionError::DoubleOverflow);
+            }
         }
         Ok(r)
     }
@@ -201,6 +359,7 @@ impl RegressionSummary {
         // Y + C - Sy/N - NC/N 
         // Y + C - Sy/N - C 
         // Y - Sy/N
+    // The same argument applies to the higher moments, which are sums of powers of (Y-Sy/N).
     pub fn offset(&mut self, offset: XYPair) -> Result<(), RegressionError> {
         self.sx = self.sx + self.n64() * offset.x;
         self.sy = self.sy + self.n64() * offset.y;
@@ -327,8 +486,8 @@ impl RegressionSummary {
             return None;
         }
         Some(XYPair {
-            x: (self.sxx / self.n64() - 1.0).sqrt(),
-            y: (self.syy / self.n64() - 1.0).sqrt(),
+            x: (self.sxx / (self.n64() - 1.0)).sqrt(),
+            y: (self.syy / (self.n64() - 1.0)).sqrt(),
         })
     }
 
@@ -425,6 +584,167 @@ impl RegressionSummary {
         }
         Some(self.sxy / self.n64())
     }
+
+    ///returns the population skewness of the independent variable, sqrt(n) * sum((x-sx/n)^3) / sum((x-sx/n)^2)^1.5
+    ///```
+    /// use counter_agg::regression::RegressionSummary;
+    /// use counter_agg::regression::XYPair;
+    /// let p = RegressionSummary::new_from_vec(vec![XYPair{y:1.0, x:1.0,}, XYPair{y:1.0, x:2.0,}, XYPair{y:4.0, x:3.0,}]).unwrap();
+    /// // x is symmetric, y has a long right tail
+    /// assert!(p.skewness_x().unwrap().abs() < 1e-12);
+    /// assert!((p.skewness_y().unwrap() - 2.0_f64.sqrt() / 2.0).abs() < 1e-12);
+    /// //RegressionSummarys with fewer than two distinct values return None
+    /// assert!(RegressionSummary::new().skewness_x().is_none());
+    /// let p = RegressionSummary::new_from_vec(vec![XYPair{y:1.0, x:1.0,}, XYPair{y:2.0, x:1.0,}]).unwrap();
+    /// assert!(p.skewness_x().is_none());
+    /// assert!(p.skewness_y().unwrap().abs() < 1e-12);
+    /// ```
+    pub fn skewness_x(&self) -> Option<f64> {
+        let m = self.higher_moments?;
+        if self.n <= 1 || self.sxx == 0.0 {
+            return None;
+        }
+        Some(self.n64().sqrt() * m.sx3 / self.sxx.powf(1.5))
+    }
+
+    ///returns the population skewness of the dependent variable, see `skewness_x`
+    pub fn skewness_y(&self) -> Option<f64> {
+        let m = self.higher_moments?;
+        if self.n <= 1 || self.syy == 0.0 {
+            return None;
+        }
+        Some(self.n64().sqrt() * m.sy3 / self.syy.powf(1.5))
+    }
+
+    ///returns the population kurtosis of the independent variable, n * sum((x-sx/n)^4) / sum((x-sx/n)^2)^2.
+    ///Note that this is the kurtosis, not the excess kurtosis, a normal distribution has a
+    ///kurtosis of 3.
+    ///```
+    /// use counter_agg::regression::RegressionSummary;
+    /// use counter_agg::regression::XYPair;
+    /// let p = RegressionSummary::new_from_vec(vec![XYPair{y:1.0, x:1.0,}, XYPair{y:1.0, x:2.0,}, XYPair{y:4.0, x:3.0,}]).unwrap();
+    /// assert!((p.kurtosis_x().unwrap() - 1.5).abs() < 1e-12);
+    /// assert!((p.kurtosis_y().unwrap() - 1.5).abs() < 1e-12);
+    /// //RegressionSummarys with fewer than two distinct values return None
+    /// assert!(RegressionSummary::new().kurtosis_x().is_none());
+    /// let p = RegressionSummary::new_from_vec(vec![XYPair{y:1.0, x:1.0,}]).unwrap();
+    /// assert!(p.kurtosis_y().is_none());
+    /// ```
+    pub fn kurtosis_x(&self) -> Option<f64> {
+        let m = self.higher_moments?;
+        if self.n <= 1 || self.sxx == 0.0 {
+            return None;
+        }
+        Some(self.n64() * m.sx4 / (self.sxx * self.sxx))
+    }
+
+    ///returns the population kurtosis of the dependent variable, see `kurtosis_x`
+    pub fn kurtosis_y(&self) -> Option<f64> {
+        let m = self.higher_moments?;
+        if self.n <= 1 || self.syy == 0.0 {
+            return None;
+        }
+        Some(self.n64() * m.sy4 / (self.syy * self.syy))
+    }
+}
+
+impl HigherMoments {
+    fn new() -> Self {
+        HigherMoments {
+            sx3: 0.0,
+            sx4: 0.0,
+            sy3: 0.0,
+            sy4: 0.0,
+        }
+    }
+    fn is_finite(&self) -> bool {
+        self.sx3.is_finite() && self.sx4.is_finite() && self.sy3.is_finite() && self.sy4.is_finite()
+    }
+    fn has_infinite(&self) -> bool {
+        self.sx3.is_infinite() || self.sx4.is_infinite() || self.sy3.is_infinite() || self.sy4.is_infinite()
+    }
+    // the moments after adding p, `old` is the non-empty summary these are the moments of,
+    // before p was added to it
+    fn with_point(&self, old: &RegressionSummary, p: &XYPair) -> Self {
+        let (sx3, sx4) = combine_higher_moments(
+            (old.n64(), old.sxx, self.sx3, self.sx4),
+            (1.0, 0.0, 0.0, 0.0),
+            p.x - old.sx / old.n64(),
+        );
+        let (sy3, sy4) = combine_higher_moments(
+            (old.n64(), old.syy, self.sy3, self.sy4),
+            (1.0, 0.0, 0.0, 0.0),
+            p.y - old.sy / old.n64(),
+        );
+        HigherMoments { sx3, sx4, sy3, sy4 }
+    }
+    // the moments after removing p from a set of n values, `remaining` is the summary of the
+    // values without p, with at least two values
+    fn without_point(&self, n: f64, remaining: &RegressionSummary, p: &XYPair) -> Self {
+        let (sx3, sx4) = remove_higher_moments(
+            n, remaining.sxx, self.sx3, self.sx4, p.x - remaining.sx / remaining.n64(),
+        );
+        let (sy3, sy4) = remove_higher_moments(
+            n, remaining.syy, self.sy3, self.sy4, p.y - remaining.sy / remaining.n64(),
+        );
+        HigherMoments { sx3, sx4, sy3, sy4 }
+    }
+    // the moments of the union of two non-empty summaries, `a` and `b`, whose moments are self
+    // and other
+    fn combine(&self, a: &RegressionSummary, other: &Self, b: &RegressionSummary) -> Self {
+        let (sx3, sx4) = combine_higher_moments(
+            (a.n64(), a.sxx, self.sx3, self.sx4),
+            (b.n64(), b.sxx, other.sx3, other.sx4),
+            b.sx / b.n64() - a.sx / a.n64(),
+        );
+        let (sy3, sy4) = combine_higher_moments(
+            (a.n64(), a.syy, self.sy3, self.sy4),
+            (b.n64(), b.syy, other.sy3, other.sy4),
+            b.sy / b.n64() - a.sy / a.n64(),
+        );
+        HigherMoments { sx3, sx4, sy3, sy4 }
+    }
+}
+
+// The third and fourth central moments of the union of two sets, a and b, each given as
+// (n, sum of squares, sum of cubes, sum of fourth powers) about their own mean. `delta` is
+// mean(b) - mean(a). These are the pairwise update formulas from Pébay, "Formulas for Robust,
+// One-Pass Parallel Computation of Covariances and Arbitrary-Order Statistical Moments" (2008),
+// see also Terriberry's "Computing Higher-Order Moments Online":
+//      m3 = m3a + m3b + delta^3 * na * nb * (na - nb) / n^2 + 3 * delta * (na * m2b - nb * m2a) / n
+//      m4 = m4a + m4b + delta^4 * na * nb * (na^2 - na * nb + nb^2) / n^3
+//              + 6 * delta^2 * (na^2 * m2b + nb^2 * m2a) / n^2 + 4 * delta * (na * m3b - nb * m3a) / n
+// Adding a single point is the case nb = 1, m2b = m3b = m4b = 0.
+fn combine_higher_moments(
+    (na, m2a, m3a, m4a): (f64, f64, f64, f64),
+    (nb, m2b, m3b, m4b): (f64, f64, f64, f64),
+    delta: f64,
+) -> (f64, f64) {
+    let n = na + nb;
+    let m3 = m3a
+        + m3b
+        + delta.powi(3) * na * nb * (na - nb) / (n * n)
+        + 3.0 * delta * (na * m2b - nb * m2a) / n;
+    let m4 = m4a
+        + m4b
+        + delta.powi(4) * na * nb * (na * na - na * nb + nb * nb) / (n * n * n)
+        + 6.0 * delta * delta * (na * na * m2b + nb * nb * m2a) / (n * n)
+        + 4.0 * delta * (na * m3b - nb * m3a) / n;
+    (m3, m4)
+}
+
+// The inverse of combine_higher_moments for a single point: given the moments of a set of n
+// values that includes some x, and the sum of squares `m2a` of the set without it, returns the
+// third and fourth moments of the set without x. `delta` is x - mean(the set without x).
+//      m3a = m3 - delta^3 * na * (na - 1) / n^2 + 3 * delta * m2a / n
+//      m4a = m4 - delta^4 * na * (na^2 - na + 1) / n^3 - 6 * delta^2 * m2a / n^2 + 4 * delta * m3a / n
+fn remove_higher_moments(n: f64, m2a: f64, m3: f64, m4: f64, delta: f64) -> (f64, f64) {
+    let na = n - 1.0;
+    let m3a = m3 - delta.powi(3) * na * (na - 1.0) / (n * n) + 3.0 * delta * m2a / n;
+    let m4a = m4 - delta.powi(4) * na * (na * na - na + 1.0) / (n * n * n)
+        - 6.0 * delta * delta * m2a / (n * n)
+        + 4.0 * delta * m3a / n;
+    (m3a, m4a)
 }
 
 #[cfg(test)]
@@ -463,4 +783,148 @@ mod tests {
         assert_eq!(p.intercept().unwrap(), 2.0);
         assert_eq!(p.x_intercept(), None);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_stddev(){
+        let p = RegressionSummary::new_from_vec(vec![XYPair{y:2.0, x:1.0,}, XYPair{y:4.0, x:2.0,}, XYPair{y:6.0, x:3.0,}]).unwrap();
+        // the sums of squares are 2 and 8
+        let pop = p.stddev_pop().unwrap();
+        assert!((pop.x - (2.0_f64 / 3.0).sqrt()).abs() < 1e-12);
+        assert!((pop.y - (8.0_f64 / 3.0).sqrt()).abs() < 1e-12);
+        let samp = p.stddev_samp().unwrap();
+        assert!((samp.x - 1.0).abs() < 1e-12);
+        assert!((samp.y - 2.0).abs() < 1e-12);
+
+        // singleton
+        let p = RegressionSummary::new_from_vec(vec![XYPair{y:2.0, x:2.0,}, ]).unwrap();
+        assert_eq!(p.stddev_pop().unwrap(), XYPair{x: 0.0, y: 0.0});
+        assert_eq!(p.stddev_samp(), None);
+    }
+
+    // the moments computed directly from the definitions, using a two-pass algorithm
+    fn two_pass_moments(vals: &[f64]) -> (f64, f64, f64) {
+        let mean = vals.iter().sum::<f64>() / vals.len() as f64;
+        let moment = |k| vals.iter().map(|v| (v - mean).powi(k)).sum::<f64>();
+        (moment(2), moment(3), moment(4))
+    }
+
+    fn assert_moments(p: &RegressionSummary, xs: &[f64], ys: &[f64]) {
+        let close = |a: f64, b: f64| (a - b).abs() <= 1e-9 * b.abs().max(1.0);
+        let (xx, x3, x4) = two_pass_moments(xs);
+        let (yy, y3, y4) = two_pass_moments(ys);
+        let m = p.higher_moments.unwrap();
+        assert!(close(p.sxx, xx), "{} != {}", p.sxx, xx);
+        assert!(close(m.sx3, x3), "{} != {}", m.sx3, x3);
+        assert!(close(m.sx4, x4), "{} != {}", m.sx4, x4);
+        assert!(close(p.syy, yy), "{} != {}", p.syy, yy);
+        assert!(close(m.sy3, y3), "{} != {}", m.sy3, y3);
+        assert!(close(m.sy4, y4), "{} != {}", m.sy4, y4);
+    }
+
+    #[test]
+    fn test_higher_moments() {
+        let xs: Vec<f64> = (0..20).map(|i| i as f64 * 0.5).collect();
+        let ys: Vec<f64> = xs.iter().map(|x| (x * 1.3).sin() * 10.0 + x * x).collect();
+        let points = || xs.iter().zip(ys.iter()).map(|(&x, &y)| XYPair{x, y});
+
+        let all = RegressionSummary::new_from_vec(points().collect()).unwrap();
+        assert_moments(&all, &xs, &ys);
+
+        // combining at every split point gives the same moments
+        for split in 0..=xs.len() {
+            let a = RegressionSummary::new_from_vec(points().take(split).collect()).unwrap();
+            let b = RegressionSummary::new_from_vec(points().skip(split).collect()).unwrap();
+            assert_moments(&a.combine(b).unwrap(), &xs, &ys);
+            assert_moments(&b.combine(a).unwrap(), &xs, &ys);
+        }
+
+        // removing points from the front
+        let mut p = all;
+        for (i, point) in points().enumerate().take(xs.len() - 1) {
+            p.remove(point).unwrap();
+            assert_moments(&p, &xs[i + 1..], &ys[i + 1..]);
+        }
+
+        // offsets don't change central moments
+        let mut p = all;
+        p.offset(XYPair{x: 100.0, y: -50.0}).unwrap();
+        assert_moments(&p, &xs, &ys);
+    }
+
+    #[test]
+    fn test_missing_higher_moments() {
+        // summaries stored before the higher moments were tracked
+        let p = RegressionSummary::new_from_vec(vec![XYPair{x: 1.0, y: 2.0}, XYPair{x: 2.0, y: 5.0}, XYPair{x: 4.0, y: 1.0}]).unwrap();
+        let old = RegressionSummary::from_partials(p.partials(), None);
+        assert_eq!(old.skewness_y(), None);
+        assert_eq!(old.kurtosis_x(), None);
+        // the other statistics are unaffected
+        assert_eq!(old.slope(), p.slope());
+
+        let q = RegressionSummary::new_from_vec(vec![XYPair{x: 5.0, y: 3.0}]).unwrap();
+        let combined = old.combine(q).unwrap();
+        assert_eq!(combined.higher_moments, None);
+        assert_eq!(combined.partials(), p.combine(q).unwrap().partials());
+        assert_eq!(q.combine(old).unwrap().higher_moments, None);
+        let mut r = old;
+        r.accum(XYPair{x: 5.0, y: 3.0}).unwrap();
+        assert_eq!(r.skewness_y(), None);
+    }
+
+    #[test]
+    fn test_degenerate_skewness_kurtosis() {
+        // a single value
+        let p = RegressionSummary::new_from_vec(vec![XYPair{x: 1.0, y: 2.0}]).unwrap();
+        assert_eq!(p.skewness_x(), None);
+        assert_eq!(p.skewness_y(), None);
+        assert_eq!(p.kurtosis_x(), None);
+        assert_eq!(p.kurtosis_y(), None);
+
+        // all the values of one variable are equal
+        let p = RegressionSummary::new_from_vec(vec![XYPair{x: 1.0, y: 2.0}, XYPair{x: 1.0, y: 4.0}, XYPair{x: 1.0, y: 9.0}]).unwrap();
+        assert_eq!(p.skewness_x(), None);
+        assert_eq!(p.kurtosis_x(), None);
+        assert!(p.skewness_y().unwrap().is_finite());
+        assert!(p.kurtosis_y().unwrap().is_finite());
+
+        // removing down to a single value
+        let mut p = RegressionSummary::new_from_vec(vec![XYPair{x: 1.0, y: 2.0}, XYPair{x: 3.0, y: 4.0}]).unwrap();
+        p.remove(XYPair{x: 1.0, y: 2.0}).unwrap();
+        assert_eq!(p.skewness_y(), None);
+        assert_eq!(p.kurtosis_y(), None);
+    }
+
+    #[test]
+    fn test_higher_moment_overflow() {
+        // finite values whose fourth moment overflows, the higher moments are no longer tracked
+        // but the rest of the summary is unaffected
+        let p = RegressionSummary::new_from_vec(vec![XYPair{x: 0.0, y: 0.0}, XYPair{x: 1.0, y: 1e76}]).unwrap();
+        assert!(p.higher_moments.unwrap().sy4.is_finite());
+        let q = RegressionSummary::new_from_vec(vec![XYPair{x: 0.0, y: -2e77}]).unwrap();
+        let combined = p.combine(q).unwrap();
+        assert_eq!(combined.higher_moments, None);
+        assert_eq!(combined.skewness_y(), None);
+        assert_eq!(combined.kurtosis_x(), None);
+        assert!(combined.sum_squares().unwrap().y.is_finite());
+        let mut r = p;
+        r.accum(XYPair{x: 2.0, y: -2e77}).unwrap();
+        assert_eq!(r.higher_moments, None);
+        assert_eq!(r.count(), 3);
+        let (a, b) = (r.sum_squares().unwrap().y, combined.sum_squares().unwrap().y);
+        assert!((a - b).abs() <= a * 1e-12);
+        // and stay untracked as more values are added
+        r.accum(XYPair{x: 3.0, y: 0.0}).unwrap();
+        assert_eq!(r.higher_moments, None);
+
+        // infinite inputs give NaN moments instead
+        let mut p = RegressionSummary::new_from_vec(vec![XYPair{x: 0.0, y: 0.0}, XYPair{x: 1.0, y: 1.0}]).unwrap();
+        p.accum(XYPair{x: 2.0, y: f64::INFINITY}).unwrap();
+        let m = p.higher_moments.unwrap();
+        assert!(m.sy3.is_nan());
+        assert!(m.sy4.is_nan());
+        assert!(m.sx3.is_finite());
+        assert!(p.skewness_y().unwrap().is_nan());
+        let q = RegressionSummary::new_from_vec(vec![XYPair{x: 0.0, y: 1.0}]).unwrap();
+        assert!(p.combine(q).unwrap().kurtosis_y().unwrap().is_nan());
+    }
+}
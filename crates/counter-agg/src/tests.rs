@@ -18,8 +18,13 @@ pub mod tests {
         assert_eq!(p1.regress.n, p2.regress.n, "n");
         assert_relative_eq!(p1.regress.sx, p2.regress.sx);
         assert_relative_eq!(p1.regress.sxx, p2.regress.sxx);
+        let (m1, m2) = (p1.regress.higher_moments.unwrap(), p2.regress.higher_moments.unwrap());
+        assert_relative_eq!(m1.sx3, m2.sx3);
+        assert_relative_eq!(m1.sx4, m2.sx4);
         assert_relative_eq!(p1.regress.sy, p2.regress.sy);
         assert_relative_eq!(p1.regress.syy, p2.regress.syy);
+        assert_relative_eq!(m1.sy3, m2.sy3, max_relative = 1e-12);
+        assert_relative_eq!(m1.sy4, m2.sy4, max_relative = 1e-12);
         assert_relative_eq!(p1.regress.sxy, p2.regress.sxy);
     }
     #[test]
@@ -149,6 +154,257 @@ pub mod tests {
         combined = part2.clone();
         assert_eq!(combined.combine(&part1).unwrap_err(), CounterError::OrderError);
     }
+    #[test]
+    fn test_combine_out_of_order(){
+        let mut summary = CounterSummary::new( &TSPoint{ts: 0, val:0.0}, None);
+        summary.add_point(&TSPoint{ts: 5, val:10.0}).unwrap();
+        summary.add_point(&TSPoint{ts: 10, val:20.0}).unwrap();
+        summary.add_point(&TSPoint{ts: 15, val:5.0}).unwrap();
+        summary.add_point(&TSPoint{ts: 20, val:50.0}).unwrap();
+        summary.add_point(&TSPoint{ts: 25, val:10.0}).unwrap();
+
+        let mut part1 = CounterSummary::new(&TSPoint{ts: 0, val:0.0}, None);
+        part1.add_point(&TSPoint{ts: 5, val:10.0}).unwrap();
+        let part2 = CounterSummary::new(&TSPoint{ts: 10, val:20.0}, None);
+        let mut part3 = CounterSummary::new(&TSPoint{ts: 15, val:5.0}, None);
+        part3.add_point(&TSPoint{ts: 20, val:50.0}).unwrap();
+        part3.add_point(&TSPoint{ts: 25, val:10.0}).unwrap();
+
+        let mut parts = vec![part3.clone(), part1.clone(), part2.clone()];
+        let combined = CounterSummary::combine_summaries(&mut parts).unwrap().unwrap();
+        assert_close_enough(&summary, &combined);
+
+        // a point duplicated at the boundary of two partials is only counted once
+        let dup = CounterSummary::new(&TSPoint{ts: 5, val:10.0}, None);
+        let mut parts = vec![part3.clone(), dup.clone(), part2.clone(), part1.clone()];
+        let combined = CounterSummary::combine_summaries(&mut parts).unwrap().unwrap();
+        assert_close_enough(&summary, &combined);
+        let mut parts = vec![dup, part1.clone(), part3.clone(), part2.clone()];
+        let combined = CounterSummary::combine_summaries(&mut parts).unwrap().unwrap();
+        assert_close_enough(&summary, &combined);
+
+        // actually overlapping data can't be combined
+        let mut overlap = CounterSummary::new(&TSPoint{ts: 3, val:5.0}, None);
+        overlap.add_point(&TSPoint{ts: 12, val:5.0}).unwrap();
+        let mut parts = vec![part1.clone(), overlap, part3.clone()];
+        assert_eq!(CounterSummary::combine_summaries(&mut parts).unwrap_err(), CounterError::OverlapError);
+
+        // and neither can two different values at the same time
+        let conflict = CounterSummary::new(&TSPoint{ts: 5, val:11.0}, None);
+        let mut parts = vec![part1, conflict];
+        assert_eq!(CounterSummary::combine_summaries(&mut parts).unwrap_err(), CounterError::OverlapError);
+
+        assert_eq!(CounterSummary::combine_summaries(&mut []).unwrap(), None);
+    }
+
+    #[test]
+    fn test_combine_interleaved(){
+        // parallel workers each see an arbitrary subset of the points
+        let points: Vec<TSPoint> = (0..20).map(|i| TSPoint{ts: i * 5, val: (i * i) as f64}).collect();
+        let mut summary = CounterSummary::new(&points[0], None);
+        for p in &points[1..] {
+            summary.add_point(p).unwrap();
+        }
+        let mut parts: Vec<CounterSummary> = vec![];
+        for worker in 0..3 {
+            let mut pts = points.iter().enumerate().filter(|(i, _)| (i * 7) % 3 == worker).map(|(_, p)| p);
+            let mut part = CounterSummary::new(pts.next().unwrap(), None);
+            for p in pts {
+                part.add_point(p).unwrap();
+            }
+            parts.push(part);
+        }
+        parts.reverse();
+        let combined = CounterSummary::combine_summaries(&mut parts).unwrap().unwrap();
+        assert_close_enough(&summary, &combined);
+
+        // partials starting at the same value only count that change once
+        let mut summary = CounterSummary::new(&TSPoint{ts: 0, val: 10.0}, None);
+        let mut evens = summary.clone();
+        let mut odds = CounterSummary::new(&TSPoint{ts: 5, val: 10.0}, None);
+        for &(ts, val) in &[(5, 10.0), (10, 20.0), (15, 30.0), (20, 40.0)] {
+            summary.add_point(&TSPoint{ts, val}).unwrap();
+        }
+        evens.add_point(&TSPoint{ts: 10, val: 20.0}).unwrap();
+        evens.add_point(&TSPoint{ts: 20, val: 40.0}).unwrap();
+        odds.add_point(&TSPoint{ts: 15, val: 30.0}).unwrap();
+        let combined = CounterSummary::combine_summaries(&mut [odds, evens]).unwrap().unwrap();
+        assert_close_enough(&summary, &combined);
+
+        // a reset in one partial can't be placed among the points of the other
+        let mut reset = CounterSummary::new(&TSPoint{ts: 0, val: 10.0}, None);
+        reset.add_point(&TSPoint{ts: 10, val: 5.0}).unwrap();
+        let mut other = CounterSummary::new(&TSPoint{ts: 5, val: 12.0}, None);
+        other.add_point(&TSPoint{ts: 15, val: 20.0}).unwrap();
+        assert_eq!(CounterSummary::combine_summaries(&mut [reset, other.clone()]).unwrap_err(), CounterError::OverlapError);
+
+        // nor can the counter decrease between the two partials
+        let mut lower = CounterSummary::new(&TSPoint{ts: 2, val: 1.0}, None);
+        lower.add_point(&TSPoint{ts: 12, val: 11.0}).unwrap();
+        assert_eq!(CounterSummary::combine_summaries(&mut [lower, other.clone()]).unwrap_err(), CounterError::OverlapError);
+
+        // and the same points summarized twice aren't counted twice
+        assert_eq!(CounterSummary::combine_summaries(&mut [other.clone(), other]).unwrap_err(), CounterError::OverlapError);
+    }
+
+    #[test]
+    fn test_interpolation(){
+        // buckets of width 10 with resets both within a bucket and in the gaps between them
+        let points = [(1, 10.0), (4, 20.0), (8, 30.0), (13, 5.0), (15, 10.0), (19, 40.0), (22, 20.0), (27, 30.0), (45, 50.0), (48, 55.0)];
+        let mut whole = CounterSummary::new(&TSPoint{ts: 1, val: 10.0}, None);
+        let mut buckets: Vec<CounterSummary> = vec![];
+        for &(ts, val) in points.iter() {
+            let pt = TSPoint{ts, val};
+            whole.add_point(&pt).unwrap();
+            match buckets.last_mut() {
+                Some(b) if b.first.ts / 10 == ts / 10 => b.add_point(&pt).unwrap(),
+                _ => buckets.push(CounterSummary::new(&pt, None)),
+            }
+        }
+        // there's an empty bucket at 30, so buckets are 0, 10, 20 and 40
+        assert_eq!(buckets.len(), 4);
+        assert_eq!(buckets[3].first.ts, 45);
+
+        let mut total = 0.0;
+        for (i, bucket) in buckets.iter().enumerate() {
+            let prev = if i == 0 { None } else { buckets.get(i - 1) };
+            let next = buckets.get(i + 1);
+            let start = bucket.first.ts / 10 * 10;
+            total += bucket.interpolate(start, 10, prev, next).unwrap().delta();
+        }
+        // the increase over the empty bucket, interpolated between 30 at 27 and 50 at 45, is the
+        // only part that isn't in any of the others
+        assert_relative_eq!(total + 20.0 * 10.0 / 18.0, whole.delta());
+
+        // reset in the gap: 30 at 8 -> 5 at 13, the 5 increase is split 2:3 around 10
+        let first = buckets[0].interpolate(0, 10, None, Some(&buckets[1])).unwrap();
+        assert_eq!(first.last, TSPoint{ts: 10, val: 32.0});
+        assert_relative_eq!(first.delta(), 22.0);
+        let second = buckets[1].interpolate(10, 10, Some(&buckets[0]), None).unwrap();
+        assert_eq!(second.first, TSPoint{ts: 10, val: 2.0});
+        assert_relative_eq!(second.delta(), 38.0);
+        // interpolating both sides gives a rate over the full interval
+        let second = buckets[1].interpolate(10, 10, Some(&buckets[0]), Some(&buckets[2])).unwrap();
+        assert_relative_eq!(second.time_delta(), to_seconds(10.0));
+
+        // no interpolation needed when the first point is on the edge
+        let summary = CounterSummary::new(&TSPoint{ts: 10, val: 5.0}, None);
+        assert_eq!(summary.interpolate(10, 10, Some(&buckets[0]), None).unwrap(), summary);
+
+        // neighbours must not overlap the interval
+        assert_eq!(buckets[1].interpolate(10, 10, Some(&buckets[1]), None).unwrap_err(), CounterError::BoundsInvalid);
+        assert_eq!(buckets[1].interpolate(10, 5, None, None).unwrap_err(), CounterError::BoundsInvalid);
+    }
+
+    #[test]
+    fn test_skewness_kurtosis(){
+        let vals = [0.0, 10.0, 20.0, 5.0, 50.0, 60.0, 10.0, 15.0, 95.0, 100.0];
+        let points: Vec<_> = vals.iter().enumerate()
+            .map(|(i, &val)| TSPoint{ts: i as i64 * 5, val})
+            .collect();
+        let mut summary = CounterSummary::new(&points[0], None);
+        for p in &points[1..] {
+            summary.add_point(p).unwrap();
+        }
+
+        // the moments are of the reset-adjusted values
+        let adjusted = [0.0, 10.0, 20.0, 25.0, 70.0, 80.0, 90.0, 95.0, 175.0, 180.0];
+        let n = adjusted.len() as f64;
+        let mean = adjusted.iter().sum::<f64>() / n;
+        let moment = |k| adjusted.iter().map(|v: &f64| (v - mean).powi(k)).sum::<f64>();
+        assert_relative_eq!(summary.skewness().unwrap(), n.sqrt() * moment(3) / moment(2).powf(1.5), max_relative = 1e-12);
+        assert_relative_eq!(summary.kurtosis().unwrap(), n * moment(4) / moment(2).powi(2), max_relative = 1e-12);
+
+        // and they combine exactly, wherever the partials are split, even across resets
+        for &(a, b) in [(1, 5), (3, 4), (2, 9), (6, 7)].iter() {
+            let mut parts: Vec<CounterSummary> = [&points[..a], &points[a..b], &points[b..]].iter()
+                .map(|part| {
+                    let mut s = CounterSummary::new(&part[0], None);
+                    for p in &part[1..] {
+                        s.add_point(p).unwrap();
+                    }
+                    s
+                })
+                .collect();
+            parts.reverse();
+            let combined = CounterSummary::combine_summaries(&mut parts).unwrap().unwrap();
+            assert_close_enough(&summary, &combined);
+            assert_relative_eq!(summary.skewness().unwrap(), combined.skewness().unwrap(), max_relative = 1e-12);
+            assert_relative_eq!(summary.kurtosis().unwrap(), combined.kurtosis().unwrap(), max_relative = 1e-12);
+        }
+
+        // a single point, or a counter that never changes, has no skewness or kurtosis
+        let summary = CounterSummary::new(&points[0], None);
+        assert_eq!(summary.skewness(), None);
+        assert_eq!(summary.kurtosis(), None);
+        let mut summary = CounterSummary::new(&TSPoint{ts: 0, val: 7.0}, None);
+        summary.add_point(&TSPoint{ts: 5, val: 7.0}).unwrap();
+        summary.add_point(&TSPoint{ts: 10, val: 7.0}).unwrap();
+        assert_eq!(summary.skewness(), None);
+        assert_eq!(summary.kurtosis(), None);
+    }
+
+    // removal accumulates a little more floating point error than adding points
+    #[track_caller]
+    fn assert_close_after_removal(p1:&CounterSummary, p2:&CounterSummary) {
+        assert_eq!(p1.first, p2.first, "first");
+        assert_eq!(p1.second, p2.second, "second");
+        assert_eq!(p1.penultimate, p2.penultimate, "penultimate");
+        assert_eq!(p1.last, p2.last, "last");
+        assert_eq!(p1.num_changes, p2.num_changes, "num_changes");
+        assert_eq!(p1.num_resets, p2.num_resets, "num_resets");
+        assert_eq!(p1.regress.n, p2.regress.n, "n");
+        assert_relative_eq!(p1.reset_sum, p2.reset_sum);
+        assert_relative_eq!(p1.regress.sx, p2.regress.sx, max_relative = 1e-12);
+        assert_relative_eq!(p1.regress.sxx, p2.regress.sxx, epsilon = 1e-9, max_relative = 1e-12);
+        assert_relative_eq!(p1.regress.sy, p2.regress.sy, max_relative = 1e-12);
+        assert_relative_eq!(p1.regress.syy, p2.regress.syy, epsilon = 1e-9, max_relative = 1e-12);
+        assert_relative_eq!(p1.regress.sxy, p2.regress.sxy, epsilon = 1e-9, max_relative = 1e-12);
+        let (m1, m2) = (p1.regress.higher_moments.unwrap(), p2.regress.higher_moments.unwrap());
+        assert_relative_eq!(m1.sy3, m2.sy3, epsilon = 1e-6, max_relative = 1e-9);
+        assert_relative_eq!(m1.sy4, m2.sy4, epsilon = 1e-6, max_relative = 1e-9);
+    }
+
+    #[test]
+    fn test_remove_first(){
+        let points = [(0, 10.0), (5, 20.0), (10, 5.0), (15, 5.0), (20, 30.0), (25, 10.0), (30, 15.0)];
+        let points: Vec<_> = points.iter().map(|&(ts, val)| TSPoint{ts, val}).collect();
+        let bounds = Some(I64Range{left:Some(0), right:Some(40)});
+        let mut sliding = CounterSummary::new(&points[0], bounds);
+        for p in &points[1..] {
+            sliding.add_point(p).unwrap();
+        }
+        for i in 1..points.len() {
+            let new_second = points.get(i + 1).unwrap_or(&points[i]);
+            sliding.remove_first(new_second).unwrap();
+
+            let mut expected = CounterSummary::new(&points[i], bounds);
+            for p in &points[i + 1..] {
+                expected.add_point(p).unwrap();
+            }
+            assert_close_after_removal(&sliding, &expected);
+            assert_relative_eq!(sliding.delta(), expected.delta());
+        }
+        // can't remove the last point
+        assert_eq!(sliding.remove_first(&points[6]).unwrap_err(), CounterError::OrderError);
+
+        // adding points after removing some works as usual
+        let mut sliding = CounterSummary::new(&points[0], bounds);
+        let mut expected = CounterSummary::new(&points[2], bounds);
+        for p in &points[1..4] {
+            sliding.add_point(p).unwrap();
+        }
+        sliding.remove_first(&points[2]).unwrap();
+        sliding.remove_first(&points[3]).unwrap();
+        for p in &points[3..] {
+            expected.add_point(p).unwrap();
+        }
+        for p in &points[4..] {
+            sliding.add_point(p).unwrap();
+        }
+        assert_close_after_removal(&sliding, &expected);
+    }
+
     #[test]
     fn test_multiple_resets() {
         let startpt = TSPoint{ts: 0, val:0.0};
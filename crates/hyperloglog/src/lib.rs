@@ -16,6 +16,8 @@ use crate::hyperloglog_data::{
 };
 
 mod hyperloglog_data;
+pub mod sparse;
+pub mod stable_hash;
 
 /// A HyperLogLog is a data structure to count unique elements on a data stream.
 ///
@@ -52,8 +54,10 @@ mod hyperloglog_data;
 /// factors a corrections applied (see paper or source code).
 ///
 /// # Implementation
-/// - The registers always allocate 8 bits and are not compressed.
-/// - No sparse representation is used at any point.
+/// - While few registers are in use only the non-zero ones are stored, as a sorted list (see
+///   [`sparse`]). Once more than `m / 8` are in use the HyperLogLog switches to storing every
+///   register, and never switches back.
+/// - The dense registers always allocate 8 bits and are not compressed.
 /// - A 64 bit hash function is used (like in HyperLogLog++ paper) instead of the 32 bit hash
 ///   function (like in the original HyperLogLog paper).
 /// - Bias correction is applied and the data is currently just taken from the HyperLogLog++ paper
@@ -75,7 +79,7 @@ mod hyperloglog_data;
 /// - [Wikipedia: HyperLogLog](https://en.wikipedia.org/wiki/HyperLogLog)
 #[derive(Clone, Serialize, Deserialize)]
 pub struct HyperLogLogger<T: ?Sized, B = BuildHasherDefault<DefaultHasher>> {
-    registers: Box<[u8]>,
+    registers: Registers<'static>,
     b: usize,
     buildhasher: B,
     #[serde(skip)]
@@ -111,10 +115,8 @@ where
             b
         );
 
-        let m = (1 as usize) << b;
-        let registers = vec![0; m].into_boxed_slice();
         Self {
-            registers,
+            registers: Registers::Sparse(Cow::Owned(vec![])),
             b,
             buildhasher,
             phantom: PhantomData,
@@ -123,7 +125,7 @@ where
 
     pub fn as_hyperloglog(&self) -> HyperLogLog<'_, T, B> {
         HyperLogLog {
-            registers: &self.registers,
+            registers: self.registers.borrow(),
             b: self.b,
             buildhasher: Cow::Borrowed(&self.buildhasher),
             phantom: PhantomData,
@@ -154,8 +156,15 @@ where
     pub fn add(&mut self, obj: &T) {
         let mut hasher = self.buildhasher.build_hasher();
         obj.hash(&mut hasher);
-        let h: u64 = hasher.finish();
+        self.add_hash(hasher.finish())
+    }
 
+    /// Adds an element that was already hashed to the HyperLogLog.
+    ///
+    /// The hash should be uniformly distributed over all 64 bits, and must be computed the same
+    /// way as the hashes of every other element added to this or merged HyperLogLogs, see
+    /// [`stable_hash`].
+    pub fn add_hash(&mut self, h: u64) {
         // split h into:
         //  - w = 64 - b upper bits
         //  - j = b lower bits
@@ -165,8 +174,64 @@ where
         // p = leftmost bit (1-based count)
         let p = w.leading_zeros() + 1 - (self.b as u32);
 
-        let m_old = self.registers[j as usize];
-        self.registers[j as usize] = cmp::max(m_old, p as u8);
+        match &mut self.registers {
+            Registers::Sparse(entries) => {
+                sparse::insert(entries.to_mut(), j as usize, p as u8);
+                self.densify_if_full();
+            }
+            Registers::Dense(registers) => {
+                let registers = registers.to_mut();
+                let m_old = registers[j as usize];
+                registers[j as usize] = cmp::max(m_old, p as u8);
+            }
+        }
+    }
+
+    /// Switch to the dense representation once the sparse one would use more
+    /// than half as much memory.
+    fn densify_if_full(&mut self) {
+        if let Registers::Sparse(entries) = &self.registers {
+            if entries.len() > self.m() / 8 {
+                self.densify()
+            }
+        }
+    }
+
+    fn densify(&mut self) {
+        if let Registers::Sparse(entries) = &self.registers {
+            let mut registers = vec![0; self.m()];
+            for &entry in entries.iter() {
+                registers[sparse::index(entry)] = sparse::value(entry);
+            }
+            self.registers = Registers::Dense(Cow::Owned(registers));
+        }
+    }
+
+    fn merge_registers(&mut self, other: &Registers<'_>) {
+        if let (Registers::Sparse(entries), Registers::Sparse(other)) = (&self.registers, other) {
+            let merged = sparse::merge(entries, other);
+            self.registers = Registers::Sparse(Cow::Owned(merged));
+            self.densify_if_full();
+            return
+        }
+
+        self.densify();
+        let registers = match &mut self.registers {
+            Registers::Dense(registers) => registers.to_mut(),
+            Registers::Sparse(_) => unreachable!(),
+        };
+        match other {
+            Registers::Sparse(entries) => for &entry in entries.iter() {
+                let i = sparse::index(entry);
+                registers[i] = cmp::max(registers[i], sparse::value(entry))
+            },
+            Registers::Dense(other) => {
+                let other_registers = &other[..registers.len()];
+                for i in 0..registers.len() {
+                    registers[i] = cmp::max(registers[i], other_registers[i])
+                }
+            },
+        }
     }
 
     /// Guess the number of unique elements seen by the HyperLogLog.
@@ -192,15 +257,12 @@ where
             "buildhasher must be equal"
         );
 
-        let other_registers = &other.registers[..self.registers.len()];
-        for i in 0..self.registers.len() {
-            self.registers[i] = cmp::max(self.registers[i], other_registers[i])
-        }
+        self.merge_registers(&other.registers);
     }
 
     /// Empties the HyperLogLog.
     pub fn clear(&mut self) {
-        self.registers.iter_mut().for_each(|r| *r = 0);
+        self.registers = Registers::Sparse(Cow::Owned(vec![]));
     }
 
     /// Checks whether the HyperLogLog has never seen an element.
@@ -239,9 +301,38 @@ where
         }
     }
 }
+/// The registers of a HyperLogLog.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Registers<'buffer> {
+    /// Only the non-zero registers, packed and sorted as described in [`sparse`].
+    Sparse(Cow<'buffer, [u32]>),
+    /// All `2^b` registers.
+    Dense(Cow<'buffer, [u8]>),
+}
+
+impl<'buffer> Registers<'buffer> {
+    pub fn borrow(&self) -> Registers<'_> {
+        match self {
+            Registers::Sparse(entries) => Registers::Sparse(Cow::Borrowed(entries)),
+            Registers::Dense(registers) => Registers::Dense(Cow::Borrowed(registers)),
+        }
+    }
+
+    pub fn into_owned(self) -> Registers<'static> {
+        match self {
+            Registers::Sparse(entries) => Registers::Sparse(Cow::Owned(entries.into_owned())),
+            Registers::Dense(registers) => Registers::Dense(Cow::Owned(registers.into_owned())),
+        }
+    }
+
+    pub fn is_sparse(&self) -> bool {
+        matches!(self, Registers::Sparse(_))
+    }
+}
+
 pub struct HyperLogLog<'buffer, T: ?Sized, B = BuildHasherDefault<DefaultHasher>>
 where B: Clone {
-    pub registers: &'buffer [u8],
+    pub registers: Registers<'buffer>,
     pub b: usize,
     pub buildhasher: Cow<'buffer, B>,
     pub phantom: PhantomData<T>,
@@ -254,7 +345,7 @@ where
 
     fn clone(&self) -> Self {
         Self {
-            registers: self.registers,
+            registers: self.registers.clone(),
             b: self.b,
             buildhasher: self.buildhasher.clone(),
             phantom: self.phantom,
@@ -268,7 +359,7 @@ where
     B: Clone {
     /// Get number of registers.
     pub fn m(&self) -> usize {
-        self.registers.len()
+        1 << self.b
     }
 
     /// Get relative error for this HyperLogLog configuration.
@@ -278,7 +369,7 @@ where
 
 
     fn am(&self) -> f64 {
-        let m = self.registers.len();
+        let m = self.m();
 
         if m >= 128 {
             0.7213 / (1. + 1.079 / (m as f64))
@@ -357,7 +448,7 @@ where
     }
 
     fn linear_counting(&self, v: usize) -> f64 {
-        let m = self.registers.len() as f64;
+        let m = self.m() as f64;
 
         m * (m / (v as f64)).ln()
     }
@@ -368,14 +459,27 @@ where
 
     /// Guess the number of unique elements seen by the HyperLogLog.
     pub fn count(&self) -> i64 {
-        let m = self.registers.len() as f64;
-
-        let z = 1f64
-            / self
-                .registers
-                .iter()
-                .map(|&x| 2f64.powi(-(i32::from(x))))
-                .sum::<f64>();
+        let m = self.m() as f64;
+
+        let (sum, v) = match &self.registers {
+            Registers::Dense(registers) => {
+                let sum = registers
+                    .iter()
+                    .map(|&x| 2f64.powi(-(i32::from(x))))
+                    .sum::<f64>();
+                (sum, bytecount::count(registers, 0))
+            }
+            Registers::Sparse(entries) => {
+                // every register not in the list is 0, and contributes 2^0
+                let zeros = self.m() - entries.len();
+                let sum = entries
+                    .iter()
+                    .map(|&e| 2f64.powi(-(i32::from(sparse::value(e)))))
+                    .sum::<f64>();
+                (zeros as f64 + sum, zeros)
+            }
+        };
+        let z = 1f64 / sum;
 
         let e = self.am() * m * m * z;
 
@@ -385,7 +489,6 @@ where
             e
         };
 
-        let v = bytecount::count(&self.registers, 0);
         let h = if v != 0 {
             self.linear_counting(v)
         } else {
@@ -400,7 +503,10 @@ where
     }
 
     pub fn is_empty(&self) -> bool {
-        self.registers.iter().all(|&x| x == 0)
+        match &self.registers {
+            Registers::Sparse(entries) => entries.is_empty(),
+            Registers::Dense(registers) => registers.iter().all(|&x| x == 0),
+        }
     }
 }
 
@@ -427,30 +533,58 @@ where
             "buildhasher must be equal"
         );
 
-        let a_registers = &a.registers[..];
-        let b_registers = &b.registers[..a_registers.len()];
-
-        HyperLogLogger {
-            registers: a_registers
-                .iter()
-                .zip(b_registers.iter())
-                .map(|x| cmp::max(x.0, x.1))
-                .cloned()
-                .collect(),
+        let mut merged = HyperLogLogger {
+            registers: a.registers.clone().into_owned(),
             b: a.b,
             buildhasher: match &a.buildhasher {
                 Cow::Borrowed(hasher) => B::clone(hasher),
                 Cow::Owned(hasher) => hasher.clone(),
             },
             phantom: PhantomData,
+        };
+        merged.merge_registers(&b.registers);
+        merged
+    }
+
+    /// Estimate the number of elements seen by both `a` and `b`.
+    ///
+    /// This uses the inclusion-exclusion principle `|A ∩ B| = |A| + |B| - |A ∪ B|`, so its error
+    /// is relative to the size of the union, not the intersection: the intersection of two large
+    /// sets with little overlap cannot be estimated precisely. The estimate is clamped to
+    /// `[0, min(|A|, |B|)]`.
+    ///
+    /// Panics when `b` or `buildhasher` parameter of `a` and `b` do not match.
+    pub fn intersection_count(a: &Self, b: &Self) -> i64
+    where B: Clone + Eq {
+        let union = Self::merge(a, b).count();
+        Self::estimate_intersection(a.count(), b.count(), union)
+    }
+
+    /// Estimate the Jaccard index `|A ∩ B| / |A ∪ B|` of the elements seen by `a` and `b`.
+    ///
+    /// The intersection is estimated like in `intersection_count`. Two empty HyperLogLogs have an
+    /// index of 0.
+    ///
+    /// Panics when `b` or `buildhasher` parameter of `a` and `b` do not match.
+    pub fn jaccard(a: &Self, b: &Self) -> f64
+    where B: Clone + Eq {
+        let union = Self::merge(a, b).count();
+        if union == 0 {
+            return 0.0
         }
+        let intersection = Self::estimate_intersection(a.count(), b.count(), union);
+        intersection as f64 / union as f64
     }
-}
 
+    fn estimate_intersection(a: i64, b: i64, union: i64) -> i64 {
+        (a + b - union).max(0).min(a.min(b))
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use super::{HyperLogLogger, HyperLogLog};
+    use crate::stable_hash::xxhash64;
     use crate::hyperloglog_data::{RAW_ESTIMATE_DATA_OFFSET, RAW_ESTIMATE_DATA_VEC};
 
     use std::collections::hash_map::DefaultHasher;
@@ -612,6 +746,16 @@ mod tests {
         assert!(!hll.is_empty());
     }
 
+    #[test]
+    fn add_hash_b12_n1k() {
+        let mut hll = HyperLogLogger::<[u8]>::new(12);
+        for i in 0..1000u64 {
+            hll.add_hash(xxhash64(&i.to_le_bytes()));
+        }
+        assert_eq!(hll.count(), 999);
+        assert!(!hll.is_empty());
+    }
+
     #[test]
     fn clear() {
         let mut hll = HyperLogLogger::new(8);
@@ -665,6 +809,110 @@ mod tests {
         assert_eq!(hll.count(), hll1.count());
     }
 
+    #[test]
+    fn sparse_until_full() {
+        let mut hll = HyperLogLogger::new(12);
+        assert!(hll.registers.is_sparse());
+        for i in 0..400 {
+            hll.add(&i);
+        }
+        assert!(hll.registers.is_sparse());
+        for i in 400..1000 {
+            hll.add(&i);
+        }
+        assert!(!hll.registers.is_sparse());
+
+        hll.clear();
+        assert!(hll.registers.is_sparse());
+        assert!(hll.is_empty());
+    }
+
+    #[test]
+    fn sparse_count_matches_dense() {
+        for &n in &[1, 10, 100, 500] {
+            let mut sparse = HyperLogLogger::new(12);
+            sparse.extend(0..n);
+            assert!(sparse.registers.is_sparse());
+
+            let mut dense = sparse.clone();
+            dense.densify();
+            assert!(!dense.registers.is_sparse());
+            assert_eq!(sparse.count(), dense.count());
+        }
+    }
+
+    #[test]
+    fn merge_sparse_and_dense() {
+        let mut small1 = HyperLogLogger::new(12);
+        let mut small2 = HyperLogLogger::new(12);
+        let mut large = HyperLogLogger::new(12);
+        let mut hll = HyperLogLogger::new(12);
+        small1.extend(0..200);
+        small2.extend(150..300);
+        large.extend(1000..5000);
+        hll.extend(0..300);
+        hll.extend(1000..5000);
+        assert!(small1.registers.is_sparse());
+        assert!(!large.registers.is_sparse());
+
+        let mut merged = HyperLogLog::merge(&small1.as_hyperloglog(), &small2.as_hyperloglog());
+        assert!(merged.registers.is_sparse());
+        merged.merge_in(&large);
+        assert_eq!(merged.count(), hll.count());
+
+        let mut merged = HyperLogLog::merge(&large.as_hyperloglog(), &small1.as_hyperloglog());
+        merged.merge_in(&small2);
+        assert_eq!(merged.registers, hll.registers);
+    }
+
+    #[test]
+    fn intersection_and_jaccard() {
+        // two sets of 20k elements with varying overlaps
+        let n = 20_000;
+        for &overlap in &[0.0, 0.1, 0.25, 0.5, 0.75, 0.9, 1.0] {
+            let shared = (n as f64 * overlap) as i64;
+            let mut hll1 = HyperLogLogger::new(14);
+            let mut hll2 = HyperLogLogger::new(14);
+            hll1.extend(0..n);
+            hll2.extend((n - shared)..(2 * n - shared));
+
+            let (a, b) = (hll1.as_hyperloglog(), hll2.as_hyperloglog());
+            let union = 2 * n - shared;
+
+            // the error of both estimates is relative to the union, allow
+            // a little over 3 standard errors
+            let error = 3.5 * a.relative_error() * union as f64;
+            let intersection = HyperLogLog::intersection_count(&a, &b);
+            assert!(
+                (intersection - shared).abs() as f64 <= error,
+                "overlap {}: estimated {} expected {}", overlap, intersection, shared
+            );
+            assert_eq!(intersection, HyperLogLog::intersection_count(&b, &a));
+
+            let jaccard = HyperLogLog::jaccard(&a, &b);
+            let expected = shared as f64 / union as f64;
+            assert!(
+                (jaccard - expected).abs() <= 3.5 * a.relative_error(),
+                "overlap {}: estimated {} expected {}", overlap, jaccard, expected
+            );
+        }
+    }
+
+    #[test]
+    fn intersection_of_identical() {
+        let mut hll = HyperLogLogger::new(12);
+        hll.extend(0..1000);
+        let a = hll.as_hyperloglog();
+        assert_eq!(HyperLogLog::intersection_count(&a, &a), hll.count());
+        assert_eq!(HyperLogLog::jaccard(&a, &a), 1.0);
+
+        let empty = HyperLogLogger::new(12);
+        let e = empty.as_hyperloglog();
+        assert_eq!(HyperLogLog::intersection_count(&a, &e), 0);
+        assert_eq!(HyperLogLog::jaccard(&a, &e), 0.0);
+        assert_eq!(HyperLogLog::jaccard(&e, &e), 0.0);
+    }
+
     #[test]
     #[should_panic(expected = "b must be equal (left=5, right=12)")]
     fn merge_panics_p() {
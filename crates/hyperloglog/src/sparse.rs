@@ -0,0 +1,144 @@
+//! The sparse register representation from the HyperLogLog++ paper.
+//!
+//! A sparse HyperLogLog only stores its non-zero registers, as a list of
+//! entries sorted by register index. Each entry is packed into a `u32` as
+//! `index << 8 | value`, so sorting the packed entries also sorts them by
+//! index. Unlike the paper we use the same precision for the sparse and dense
+//! forms, which lets a sparse log be converted to a dense one without losing
+//! anything, and makes both forms produce the same count.
+//!
+//! For storage the entries are delta-encoded and compressed as prefix varints;
+//! since the indexes are strictly increasing the deltas are always positive,
+//! and small when there are many entries.
+
+use std::cmp::{self, Ordering};
+
+use encodings::{delta, prefix_varint};
+
+#[inline]
+pub fn pack(index: usize, value: u8) -> u32 {
+    (index as u32) << 8 | value as u32
+}
+
+#[inline]
+pub fn index(entry: u32) -> usize {
+    (entry >> 8) as usize
+}
+
+#[inline]
+pub fn value(entry: u32) -> u8 {
+    entry as u8
+}
+
+/// Set the register at `index` to at least `value`, keeping `entries` sorted.
+pub fn insert(entries: &mut Vec<u32>, index: usize, value: u8) {
+    match entries.binary_search_by_key(&index, |&e| self::index(e)) {
+        Ok(i) => {
+            if self::value(entries[i]) < value {
+                entries[i] = pack(index, value)
+            }
+        }
+        Err(i) => entries.insert(i, pack(index, value)),
+    }
+}
+
+/// Union two sorted entry lists, keeping the larger value for registers
+/// present in both.
+pub fn merge(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut merged = Vec::with_capacity(cmp::max(a.len(), b.len()));
+    let (mut a, mut b) = (a.iter().peekable(), b.iter().peekable());
+    loop {
+        let next = match (a.peek(), b.peek()) {
+            (None, None) => break,
+            (Some(_), None) => a.next(),
+            (None, Some(_)) => b.next(),
+            (Some(&&x), Some(&&y)) => match index(x).cmp(&index(y)) {
+                Ordering::Less => a.next(),
+                Ordering::Greater => b.next(),
+                Ordering::Equal => {
+                    // same index so the larger entry has the larger value
+                    let (x, y) = (a.next(), b.next());
+                    cmp::max(x, y)
+                }
+            },
+        };
+        merged.push(*next.unwrap());
+    }
+    merged
+}
+
+pub fn compress(entries: &[u32]) -> Vec<u8> {
+    let mut compressor = prefix_varint::U64Compressor::with(delta::u64_encoder());
+    for &entry in entries {
+        compressor.push(entry as u64)
+    }
+    compressor.finish()
+}
+
+/// Decompress entries written by `compress`, returns `None` if the bytes do
+/// not contain a valid, sorted, list of entries for a log with `2^b` registers.
+pub fn decompress(bytes: &[u8], b: usize) -> Option<Vec<u32>> {
+    let max_value = (64 - b + 1) as u8;
+    let mut entries: Vec<u32> = vec![];
+    for entry in prefix_varint::u64_decompressor(bytes).map(delta::u64_decoder()) {
+        if entry >> b >> 8 != 0 {
+            return None
+        }
+        let entry = entry as u32;
+        if value(entry) == 0 || value(entry) > max_value {
+            return None
+        }
+        if let Some(&last) = entries.last() {
+            if index(last) >= index(entry) {
+                return None
+            }
+        }
+        entries.push(entry)
+    }
+    Some(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_keeps_max() {
+        let mut entries = vec![];
+        insert(&mut entries, 7, 2);
+        insert(&mut entries, 3, 4);
+        insert(&mut entries, 7, 1);
+        insert(&mut entries, 12, 3);
+        insert(&mut entries, 3, 5);
+        assert_eq!(entries, vec![pack(3, 5), pack(7, 2), pack(12, 3)]);
+    }
+
+    #[test]
+    fn merge_entries() {
+        let a = vec![pack(1, 3), pack(4, 1), pack(9, 2)];
+        let b = vec![pack(0, 1), pack(4, 6), pack(9, 1), pack(15, 2)];
+        let expected = vec![pack(0, 1), pack(1, 3), pack(4, 6), pack(9, 2), pack(15, 2)];
+        assert_eq!(merge(&a, &b), expected);
+        assert_eq!(merge(&b, &a), expected);
+        assert_eq!(merge(&a, &[]), a);
+    }
+
+    #[test]
+    fn compression_roundtrip() {
+        let entries = vec![pack(0, 1), pack(1, 47), pack(300, 2), pack(65535, 9)];
+        let bytes = compress(&entries);
+        assert_eq!(decompress(&bytes, 16), Some(entries));
+        assert_eq!(decompress(&compress(&[]), 16), Some(vec![]));
+    }
+
+    #[test]
+    fn decompress_invalid() {
+        // index out of range
+        assert_eq!(decompress(&compress(&[pack(16, 1)]), 4), None);
+        // value out of range
+        assert_eq!(decompress(&compress(&[pack(3, 0)]), 4), None);
+        assert_eq!(decompress(&compress(&[pack(3, 62)]), 4), None);
+        // duplicate index
+        assert_eq!(decompress(&compress(&[pack(3, 1), pack(3, 2)]), 4), None);
+    }
+}
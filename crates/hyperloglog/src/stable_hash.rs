@@ -0,0 +1,39 @@
+//! A hash function with a fixed output, for HyperLogLogs that are persisted.
+//!
+//! Neither the standard library's `Hash` implementations nor `DefaultHasher`
+//! promise to produce the same hashes across Rust versions, so HyperLogLogs
+//! built with them can only be merged with ones built by the same binary. The
+//! hash here does not change: it is
+//! [xxHash64](https://github.com/Cyan4973/xxHash/blob/dev/doc/xxhash_spec.md)
+//! with a seed of 0, applied directly to the bytes it is given, so callers
+//! must take care to produce those bytes in a fixed way as well.
+//! `HyperLogLogger::add_hash` can be used to add the resulting hashes.
+
+use std::hash::Hasher;
+
+use twox_hash::XxHash64;
+
+pub const XXHASH64_SEED: u64 = 0;
+
+/// Hash `bytes` with xxHash64.
+pub fn xxhash64(bytes: &[u8]) -> u64 {
+    let mut hasher = XxHash64::with_seed(XXHASH64_SEED);
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::xxhash64;
+
+    // these must never change, HyperLogLogs built with older versions
+    // depend on them
+    #[test]
+    fn pinned_hashes() {
+        assert_eq!(xxhash64(b""), 0xef46db3751d8e999);
+        assert_eq!(xxhash64(b"a"), 0xd24ec4f1a98c6e5b);
+        assert_eq!(xxhash64(b"abc"), 0x44bc2cf5ad770999);
+        assert_eq!(xxhash64(&42i32.to_le_bytes()), 0xd756d7b62fc50bf1);
+        assert_eq!(xxhash64(&42i64.to_le_bytes()), 0xb556806fb6d14353);
+    }
+}
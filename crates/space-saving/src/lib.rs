@@ -0,0 +1,395 @@
+//! An implementation of the Space-Saving algorithm for finding the most
+//! frequent values in a stream.
+//!
+//! A `SpaceSaving` tracks at most `capacity` values, each with a count. When a
+//! value that is not tracked arrives and there is no room for it, it replaces
+//! the tracked value with the smallest count, and inherits that count (plus
+//! one). The inherited part is recorded as the counter's error, so a tracked
+//! value's true frequency is always in `[count - error, count]`. Any value that
+//! occurs more than `total / capacity` times is guaranteed to be tracked.
+//!
+//! Two sketches are merged as described in "Parallel Space Saving on Multi and
+//! Many-Core Processors", Cafaro et al. 2017: a value missing from one of the
+//! sketches may have occurred up to that sketch's minimum count times, so that
+//! amount is added to both its count and error.
+//!
+//! The counters are kept in a binary min-heap ordered by count, along with a
+//! map from value to heap position, so every update is `O(log capacity)`.
+//!
+//! # References
+//! - ["Efficient Computation of Frequent and Top-k Elements in Data Streams",
+//!   Ahmed Metwally, Divyakant Agrawal, Amr El Abbadi, 2005](https://www.cs.ucsb.edu/sites/default/files/documents/2005-23.pdf)
+
+use std::{
+    borrow::Borrow,
+    collections::HashMap,
+    hash::Hash,
+};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Counter<T> {
+    pub value: T,
+    pub count: u64,
+    pub error: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SpaceSaving<T: Hash + Eq> {
+    capacity: usize,
+    total: u64,
+    // min-heap ordered by count
+    counters: Vec<Counter<T>>,
+    // the position of each value in `counters`, rebuilt lazily after
+    // deserialization
+    #[serde(skip)]
+    positions: HashMap<T, usize>,
+}
+
+impl<T: Hash + Eq + Clone> SpaceSaving<T> {
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "capacity must be at least 1");
+        Self {
+            capacity,
+            total: 0,
+            counters: Vec::with_capacity(capacity),
+            positions: HashMap::with_capacity(capacity),
+        }
+    }
+
+    /// Rebuild a sketch from the counters returned by `counters()`.
+    pub fn from_counters(capacity: usize, total: u64, counters: Vec<Counter<T>>) -> Self {
+        assert!(capacity > 0, "capacity must be at least 1");
+        assert!(counters.len() <= capacity, "more counters than capacity");
+        let mut sketch = Self {
+            capacity,
+            total,
+            counters,
+            positions: HashMap::new(),
+        };
+        sketch.heapify();
+        sketch
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The number of values added to the sketch.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.counters.len() >= self.capacity
+    }
+
+    /// The tracked values, in no particular order.
+    pub fn counters(&self) -> &[Counter<T>] {
+        &self.counters
+    }
+
+    /// The tracked values, from most to least frequent.
+    pub fn top(&self) -> Vec<&Counter<T>> {
+        let mut top: Vec<_> = self.counters.iter().collect();
+        // prefer the counter with the most certain count on ties
+        top.sort_by(|a, b| b.count.cmp(&a.count).then(a.error.cmp(&b.error)));
+        top
+    }
+
+    /// The smallest count any untracked value could have; every untracked
+    /// value occurred at most this many times.
+    pub fn min_count(&self) -> u64 {
+        if self.is_full() {
+            self.counters[0].count
+        } else {
+            0
+        }
+    }
+
+    /// An upper bound on how many times `value` occurred.
+    pub fn estimate<Q>(&mut self, value: &Q) -> u64
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.build_positions();
+        match self.positions.get(value) {
+            Some(&i) => self.counters[i].count,
+            None => self.min_count(),
+        }
+    }
+
+    pub fn add<Q>(&mut self, value: &Q)
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = T> + ?Sized,
+    {
+        self.build_positions();
+        self.total += 1;
+        if let Some(&i) = self.positions.get(value) {
+            self.counters[i].count += 1;
+            self.sift_down(i);
+            return
+        }
+
+        let value = value.to_owned();
+        if !self.is_full() {
+            let i = self.counters.len();
+            self.positions.insert(value.clone(), i);
+            self.counters.push(Counter { value, count: 1, error: 0 });
+            self.sift_up(i);
+            return
+        }
+
+        // replace the least frequent value
+        let min = self.counters[0].count;
+        self.positions.remove::<T>(&self.counters[0].value);
+        self.positions.insert(value.clone(), 0);
+        self.counters[0] = Counter { value, count: min + 1, error: min };
+        self.sift_down(0);
+    }
+
+    /// Merge `other` into this sketch.
+    ///
+    /// Panics when the sketches have different capacities.
+    pub fn merge(&mut self, other: &Self) {
+        assert_eq!(
+            self.capacity, other.capacity,
+            "capacity must be equal (left={}, right={})",
+            self.capacity, other.capacity
+        );
+
+        self.build_positions();
+        let (self_min, other_min) = (self.min_count(), other.min_count());
+
+        let mut merged: Vec<Counter<T>> = self.counters.iter().cloned()
+            .map(|mut c| {
+                c.count += other_min;
+                c.error += other_min;
+                c
+            })
+            .collect();
+        for c in &other.counters {
+            match self.positions.get(&c.value) {
+                Some(&i) => {
+                    // the value is in both sketches, replace the guess at its
+                    // count in `other` with the actual one
+                    let m = &mut merged[i];
+                    m.count = m.count - other_min + c.count;
+                    m.error = m.error - other_min + c.error;
+                }
+                None => merged.push(Counter {
+                    value: c.value.clone(),
+                    count: c.count + self_min,
+                    error: c.error + self_min,
+                }),
+            }
+        }
+
+        merged.sort_by(|a, b| b.count.cmp(&a.count).then(a.error.cmp(&b.error)));
+        merged.truncate(self.capacity);
+
+        self.total += other.total;
+        self.counters = merged;
+        self.heapify();
+    }
+
+    fn build_positions(&mut self) {
+        if self.positions.len() == self.counters.len() {
+            return
+        }
+        self.positions = self.counters.iter()
+            .enumerate()
+            .map(|(i, c)| (c.value.clone(), i))
+            .collect();
+    }
+
+    fn heapify(&mut self) {
+        for i in (0..self.counters.len() / 2).rev() {
+            self.sift_down(i)
+        }
+        self.positions.clear();
+        self.build_positions();
+    }
+
+    fn swap(&mut self, i: usize, j: usize) {
+        self.counters.swap(i, j);
+        if let Some(p) = self.positions.get_mut(&self.counters[i].value) {
+            *p = i
+        }
+        if let Some(p) = self.positions.get_mut(&self.counters[j].value) {
+            *p = j
+        }
+    }
+
+    fn sift_up(&mut self, mut i: usize) {
+        while i > 0 {
+            let parent = (i - 1) / 2;
+            if self.counters[parent].count <= self.counters[i].count {
+                break
+            }
+            self.swap(i, parent);
+            i = parent;
+        }
+    }
+
+    fn sift_down(&mut self, mut i: usize) {
+        loop {
+            let (left, right) = (2 * i + 1, 2 * i + 2);
+            let mut smallest = i;
+            if left < self.counters.len() && self.counters[left].count < self.counters[smallest].count {
+                smallest = left
+            }
+            if right < self.counters.len() && self.counters[right].count < self.counters[smallest].count {
+                smallest = right
+            }
+            if smallest == i {
+                break
+            }
+            self.swap(i, smallest);
+            i = smallest;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    // a zipf-ish stream: value i occurs roughly 1000 / i times
+    fn skewed_stream() -> Vec<u64> {
+        let mut values = vec![];
+        for i in 1..=200u64 {
+            for _ in 0..(1000 / i) {
+                values.push(i)
+            }
+        }
+        // interleave the values so they don't arrive in order
+        let len = values.len();
+        (0..len).map(|i| values[(i * 7919) % len]).collect()
+    }
+
+    fn exact_counts(values: &[u64]) -> HashMap<u64, u64> {
+        let mut counts = HashMap::new();
+        for &v in values {
+            *counts.entry(v).or_insert(0) += 1
+        }
+        counts
+    }
+
+    fn check_bounds(sketch: &SpaceSaving<u64>, counts: &HashMap<u64, u64>) {
+        let total: u64 = counts.values().sum();
+        assert_eq!(sketch.total(), total);
+        for c in sketch.counters() {
+            let actual = counts[&c.value];
+            assert!(c.count - c.error <= actual && actual <= c.count, "{:?} actual {}", c, actual);
+        }
+        // every value that occurs more than total / capacity times is tracked
+        for (v, &count) in counts {
+            if count > total / sketch.capacity() as u64 {
+                assert!(sketch.counters().iter().any(|c| c.value == *v), "{} missing", v);
+            }
+        }
+    }
+
+    #[test]
+    fn exact_below_capacity() {
+        let mut sketch = SpaceSaving::new(10);
+        for &v in &[1, 2, 2, 3, 3, 3] {
+            sketch.add(&v);
+        }
+        let top: Vec<_> = sketch.top().into_iter().map(|c| (c.value, c.count, c.error)).collect();
+        assert_eq!(top, vec![(3, 3, 0), (2, 2, 0), (1, 1, 0)]);
+        assert_eq!(sketch.estimate(&2), 2);
+        assert_eq!(sketch.estimate(&4), 0);
+    }
+
+    #[test]
+    fn replaces_least_frequent() {
+        let mut sketch = SpaceSaving::new(2);
+        for &v in &[1, 1, 1, 2, 3] {
+            sketch.add(&v);
+        }
+        let top: Vec<_> = sketch.top().into_iter().map(|c| (c.value, c.count, c.error)).collect();
+        assert_eq!(top, vec![(1, 3, 0), (3, 2, 1)]);
+        assert_eq!(sketch.estimate(&2), 2);
+    }
+
+    #[test]
+    fn skewed() {
+        let values = skewed_stream();
+        let counts = exact_counts(&values);
+        let mut sketch = SpaceSaving::new(50);
+        for v in &values {
+            sketch.add(v);
+        }
+        check_bounds(&sketch, &counts);
+
+        let top: Vec<_> = sketch.top().into_iter().take(5).map(|c| c.value).collect();
+        assert_eq!(top, vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn merge() {
+        let values = skewed_stream();
+        let counts = exact_counts(&values);
+        let mut sketches: Vec<SpaceSaving<u64>> = values.chunks(values.len() / 4 + 1)
+            .map(|chunk| {
+                let mut sketch = SpaceSaving::new(50);
+                chunk.iter().for_each(|v| sketch.add(v));
+                sketch
+            })
+            .collect();
+        let mut merged = sketches.remove(0);
+        for sketch in &sketches {
+            merged.merge(sketch);
+        }
+        check_bounds(&merged, &counts);
+
+        let top: Vec<_> = merged.top().into_iter().take(5).map(|c| c.value).collect();
+        assert_eq!(top, vec![1, 2, 3, 4, 5]);
+
+        // adding after a merge keeps the heap and positions consistent
+        for _ in 0..2000 {
+            merged.add(&1000);
+        }
+        assert_eq!(merged.top()[0].value, 1000);
+    }
+
+    #[test]
+    fn from_counters() {
+        let mut sketch = SpaceSaving::new(20);
+        for v in &skewed_stream() {
+            sketch.add(v);
+        }
+        let mut rebuilt = SpaceSaving::from_counters(
+            sketch.capacity(),
+            sketch.total(),
+            sketch.counters().to_vec(),
+        );
+        assert_eq!(rebuilt.top(), sketch.top());
+        assert_eq!(rebuilt.estimate(&1), sketch.estimate(&1));
+        assert_eq!(rebuilt.estimate(&1000), sketch.min_count());
+    }
+
+    #[test]
+    fn borrowed_values() {
+        let mut sketch: SpaceSaving<Vec<u8>> = SpaceSaving::new(4);
+        sketch.add(&b"abc"[..]);
+        sketch.add(&b"abc"[..]);
+        sketch.add(&b"de"[..]);
+        assert_eq!(sketch.estimate(&b"abc"[..]), 2);
+        assert_eq!(sketch.top()[0].value, b"abc".to_vec());
+    }
+
+    #[test]
+    #[should_panic(expected = "capacity must be equal (left=2, right=3)")]
+    fn merge_panics_capacity() {
+        let mut a = SpaceSaving::<u64>::new(2);
+        a.merge(&SpaceSaving::new(3));
+    }
+}
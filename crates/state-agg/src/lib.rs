@@ -0,0 +1,326 @@
+//! Tracks how long a series spends in each of a set of discrete states.
+//!
+//! Each state lasts from its point until the next one, as with the LOCF method
+//! of time weighting, and the last state ends at the last point. Consecutive
+//! points in the same state are merged into a single run, so a summary grows
+//! with the number of state changes rather than the number of points.
+//!
+//! As with `TimeWeightSummary`s, summaries can only be combined if they cover
+//! disjoint time ranges. The time between them belongs to the last state of the
+//! earlier one, so the durations of summaries of consecutive buckets add up to
+//! those of a summary of all of their points. The durations within each bucket
+//! need its neighbours as well, see `StateSummary::interpolate`.
+
+use std::{collections::HashMap, hash::Hash};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StateSummary<K> {
+    // the start of each run of the same state, in time order, consecutive runs
+    // are always in different states
+    runs: Vec<(i64, K)>,
+    last: i64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StateAggError {
+    OrderError,
+    EmptyIterator,
+}
+
+impl<K: Clone + Eq + Hash> StateSummary<K> {
+    pub fn new(ts: i64, state: K) -> Self {
+        Self {
+            runs: vec![(ts, state)],
+            last: ts,
+        }
+    }
+
+    /// Rebuild a summary from its runs and the time of its last point, as returned
+    /// by `runs()` and `last()`. Consecutive runs in the same state are merged.
+    pub fn from_runs(
+        runs: impl IntoIterator<Item = (i64, K)>,
+        last: i64,
+    ) -> Result<Self, StateAggError> {
+        let mut runs = runs.into_iter();
+        let (ts, state) = runs.next().ok_or(StateAggError::EmptyIterator)?;
+        let mut summary = Self::new(ts, state);
+        for (ts, state) in runs {
+            if ts <= summary.last {
+                return Err(StateAggError::OrderError);
+            }
+            summary.accum(ts, state)?;
+        }
+        if last < summary.last {
+            return Err(StateAggError::OrderError);
+        }
+        summary.last = last;
+        Ok(summary)
+    }
+
+    pub fn accum(&mut self, ts: i64, state: K) -> Result<(), StateAggError> {
+        if ts < self.last {
+            return Err(StateAggError::OrderError);
+        }
+        if ts == self.last {
+            // as with time_weight, if two points are equal we only use the first we see
+            return Ok(());
+        }
+        self.last = ts;
+        if self.last_state() != &state {
+            self.runs.push((ts, state));
+        }
+        Ok(())
+    }
+
+    pub fn new_from_sorted_iter(
+        iter: impl IntoIterator<Item = (i64, K)>,
+    ) -> Result<Self, StateAggError> {
+        let mut t = iter.into_iter();
+        let mut s = match t.next() {
+            None => return Err(StateAggError::EmptyIterator),
+            Some((ts, state)) => Self::new(ts, state),
+        };
+        for (ts, state) in t {
+            s.accum(ts, state)?;
+        }
+        Ok(s)
+    }
+
+    // Like TimeWeightSummary::combine this requires disjoint time ranges, the
+    // last state of self lasts until the first point of next.
+    pub fn combine(&self, next: &Self) -> Result<Self, StateAggError> {
+        if self.last >= next.first() {
+            return Err(StateAggError::OrderError);
+        }
+        let mut combined = self.clone();
+        let mut next_runs = next.runs.iter();
+        if let Some((_, state)) = next.runs.first() {
+            if state == self.last_state() {
+                next_runs.next();
+            }
+        }
+        combined.runs.extend(next_runs.cloned());
+        combined.last = next.last;
+        Ok(combined)
+    }
+
+    pub fn combine_sorted_iter<'a>(
+        iter: impl IntoIterator<Item = &'a Self>,
+    ) -> Result<Self, StateAggError>
+    where
+        K: 'a,
+    {
+        let mut t = iter.into_iter();
+        let mut s = match t.next() {
+            None => return Err(StateAggError::EmptyIterator),
+            Some(s) => s.clone(),
+        };
+        for next in t {
+            s = s.combine(next)?;
+        }
+        Ok(s)
+    }
+
+    /// Interpolate the summary of the points in the interval
+    /// `[interval_start, interval_start + interval_len)` to the bounds of that interval, using the
+    /// summaries of the intervals before and after it, as with `TimeWeightSummary::interpolate`.
+    /// This makes the durations of consecutive intervals, such as `time_bucket`s, account for the
+    /// time between the last point of one interval and the first point of the next.
+    /// 1. Without a `prev` summary the start is not interpolated, and the summary starts at its first point.
+    /// 2. The last state lasts until the end of the interval whether or not there is a `next`
+    ///    summary, as with locf, so `next` is only checked to start after the interval.
+    pub fn interpolate(
+        &self,
+        interval_start: i64,
+        interval_len: i64,
+        prev: Option<&Self>,
+        next: Option<&Self>,
+    ) -> Result<Self, StateAggError> {
+        let interval_end = interval_start + interval_len;
+        if self.first() < interval_start || self.last >= interval_end {
+            return Err(StateAggError::OrderError);
+        }
+        if prev.iter().any(|prev| prev.last >= interval_start)
+            || next.iter().any(|next| next.first() < interval_end)
+        {
+            return Err(StateAggError::OrderError);
+        }
+
+        let mut interpolated = self.clone();
+        if let Some(prev) = prev {
+            // the last state of prev lasts until our first point
+            if prev.last_state() == &self.runs[0].1 {
+                interpolated.runs[0].0 = interval_start;
+            } else if self.first() > interval_start {
+                interpolated.runs.insert(0, (interval_start, prev.last_state().clone()));
+            }
+        }
+        interpolated.last = interval_end;
+        Ok(interpolated)
+    }
+
+    pub fn first(&self) -> i64 {
+        self.runs[0].0
+    }
+
+    pub fn last(&self) -> i64 {
+        self.last
+    }
+
+    fn last_state(&self) -> &K {
+        &self.runs[self.runs.len() - 1].1
+    }
+
+    /// The start of each run of the same state, in time order.
+    pub fn runs(&self) -> impl Iterator<Item = (i64, &K)> + '_ {
+        self.runs.iter().map(|(ts, state)| (*ts, state))
+    }
+
+    /// Each run of the same state with its start and end, in time order. The
+    /// last run ends at the last point, so it is empty if that is where it starts.
+    pub fn timeline(&self) -> impl Iterator<Item = (&K, i64, i64)> + '_ {
+        let ends = self.runs.iter().skip(1).map(|(ts, _)| *ts).chain(Some(self.last));
+        self.runs.iter().zip(ends).map(|((start, state), end)| (state, *start, end))
+    }
+
+    /// The total time spent in each state, in the order the states first occur.
+    pub fn durations(&self) -> Vec<(&K, i64)> {
+        let mut durations: Vec<(&K, i64)> = vec![];
+        let mut positions = HashMap::new();
+        for (state, start, end) in self.timeline() {
+            let i = *positions.entry(state).or_insert_with(|| {
+                durations.push((state, 0));
+                durations.len() - 1
+            });
+            durations[i].1 += end - start;
+        }
+        durations
+    }
+
+    /// The total time spent in `state`.
+    pub fn duration_in(&self, state: &K) -> i64 {
+        self.timeline()
+            .filter(|(s, _, _)| *s == state)
+            .map(|(_, start, end)| end - start)
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(points: &[(i64, &'static str)]) -> StateSummary<&'static str> {
+        StateSummary::new_from_sorted_iter(points.iter().copied()).unwrap()
+    }
+
+    #[test]
+    fn test_durations() {
+        let s = summary(&[(0, "on"), (10, "on"), (15, "off"), (30, "on"), (35, "broken"), (40, "on")]);
+        assert_eq!(s.durations(), vec![(&"on", 20), (&"off", 15), (&"broken", 5)]);
+        assert_eq!(s.duration_in(&"on"), 20);
+        assert_eq!(s.duration_in(&"off"), 15);
+        assert_eq!(s.duration_in(&"unknown"), 0);
+        assert_eq!(
+            s.timeline().collect::<Vec<_>>(),
+            vec![(&"on", 0, 15), (&"off", 15, 30), (&"on", 30, 35), (&"broken", 35, 40), (&"on", 40, 40)]
+        );
+        assert_eq!((s.first(), s.last()), (0, 40));
+
+        // a single point has no duration
+        let s = summary(&[(5, "on")]);
+        assert_eq!(s.durations(), vec![(&"on", 0)]);
+    }
+
+    #[test]
+    fn test_order() {
+        let mut s = StateSummary::new(10, "on");
+        assert_eq!(s.accum(5, "off"), Err(StateAggError::OrderError));
+        // duplicates are ignored
+        s.accum(20, "off").unwrap();
+        s.accum(20, "on").unwrap();
+        s.accum(30, "off").unwrap();
+        assert_eq!(s.durations(), vec![(&"on", 10), (&"off", 10)]);
+        assert_eq!(
+            StateSummary::<&str>::new_from_sorted_iter(vec![]),
+            Err(StateAggError::EmptyIterator)
+        );
+    }
+
+    #[test]
+    fn test_combine() {
+        let points = [(0, "on"), (10, "off"), (20, "off"), (30, "on"), (40, "on"), (50, "off")];
+        let all = summary(&points);
+        for split in 1..points.len() {
+            let first = summary(&points[..split]);
+            let second = summary(&points[split..]);
+            assert_eq!(first.combine(&second).unwrap(), all);
+            assert_eq!(second.combine(&first), Err(StateAggError::OrderError));
+        }
+        let parts = [summary(&points[..2]), summary(&points[2..3]), summary(&points[3..])];
+        assert_eq!(StateSummary::combine_sorted_iter(&parts).unwrap(), all);
+    }
+
+    #[test]
+    fn test_interpolate() {
+        let buckets = [
+            summary(&[(0, "on"), (5, "off")]),
+            summary(&[(12, "off"), (17, "on")]),
+            summary(&[(25, "on")]),
+        ];
+        let interpolate = |i: usize| {
+            buckets[i]
+                .interpolate(i as i64 * 10, 10, i.checked_sub(1).map(|i| &buckets[i]), buckets.get(i + 1))
+                .unwrap()
+        };
+
+        assert_eq!(interpolate(0).durations(), vec![(&"on", 5), (&"off", 5)]);
+        // off carried from the previous bucket until 17, then on until the end
+        assert_eq!(interpolate(1).durations(), vec![(&"off", 7), (&"on", 3)]);
+        assert_eq!(
+            interpolate(1).timeline().collect::<Vec<_>>(),
+            vec![(&"off", 10, 17), (&"on", 17, 20)]
+        );
+        // the carried state is merged with the first run when they match
+        assert_eq!(interpolate(2).timeline().collect::<Vec<_>>(), vec![(&"on", 20, 30)]);
+
+        // the buckets add up to the whole time they cover
+        let on: i64 = (0..3).map(|i| interpolate(i).duration_in(&"on")).sum();
+        let off: i64 = (0..3).map(|i| interpolate(i).duration_in(&"off")).sum();
+        assert_eq!((on, off), (18, 12));
+
+        // without neighbours the start isn't interpolated but the end still is
+        assert_eq!(
+            buckets[1].interpolate(10, 10, None, None).unwrap().durations(),
+            vec![(&"off", 5), (&"on", 3)]
+        );
+
+        assert_eq!(buckets[1].interpolate(15, 10, None, None), Err(StateAggError::OrderError));
+        assert_eq!(buckets[1].interpolate(10, 5, None, None), Err(StateAggError::OrderError));
+        assert_eq!(
+            buckets[1].interpolate(10, 10, Some(&buckets[1]), None),
+            Err(StateAggError::OrderError)
+        );
+        assert_eq!(
+            buckets[1].interpolate(10, 20, None, Some(&buckets[2])),
+            Err(StateAggError::OrderError)
+        );
+    }
+
+    #[test]
+    fn test_from_runs() {
+        let s = summary(&[(0, "on"), (10, "off"), (20, "on"), (30, "on")]);
+        let runs: Vec<_> = s.runs().map(|(ts, state)| (ts, *state)).collect();
+        assert_eq!(StateSummary::from_runs(runs, s.last()).unwrap(), s);
+        assert_eq!(
+            StateSummary::from_runs(vec![(10, "on"), (0, "off")], 20),
+            Err(StateAggError::OrderError)
+        );
+        assert_eq!(
+            StateSummary::from_runs(vec![(10, "on")], 5),
+            Err(StateAggError::OrderError)
+        );
+    }
+}
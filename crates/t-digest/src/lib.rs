@@ -240,6 +240,22 @@ impl TDigest {
         self.merge_sorted(sorted_values)
     }
 
+    /// Merge (value, weight) pairs into the digest, where a weight of `n` is equivalent to
+    /// merging `n` copies of the value. Values with weight 0 are ignored.
+    pub fn merge_unsorted_weighted(&self, unsorted_values: Vec<(f64, u64)>) -> TDigest {
+        let mut sorted_values: Vec<(OrderedFloat<f64>, u64)> = unsorted_values
+            .into_iter()
+            .map(|(value, weight)| (OrderedFloat::from(value), weight))
+            .collect();
+        sorted_values.sort();
+        let sorted_values = sorted_values
+            .into_iter()
+            .map(|(value, weight)| (value.into_inner(), weight))
+            .collect();
+
+        self.merge_sorted_weighted(sorted_values)
+    }
+
     // Allow f64 overflow to create centroids with infinite mean, but make sure our min/max are updated
     fn update_bounds_on_overflow(
         value: OrderedFloat<f64>,
@@ -255,15 +271,26 @@ impl TDigest {
     }
 
     pub fn merge_sorted(&self, sorted_values: Vec<f64>) -> TDigest {
+        self.merge_sorted_weighted(sorted_values.into_iter().map(|value| (value, 1)).collect())
+    }
+
+    /// Merge (value, weight) pairs, sorted by value, into the digest. See
+    /// `merge_unsorted_weighted`.
+    pub fn merge_sorted_weighted(&self, sorted_values: Vec<(f64, u64)>) -> TDigest {
+        let sorted_values: Vec<Centroid> = sorted_values
+            .into_iter()
+            .filter(|&(_, weight)| weight > 0)
+            .map(|(value, weight)| Centroid::new(value, weight))
+            .collect();
         if sorted_values.is_empty() {
             return self.clone();
         }
 
         let mut result = TDigest::new_with_size(self.max_size());
-        result.count = self.count() + (sorted_values.len() as u64);
+        result.count = self.count() + sorted_values.iter().map(|c| c.weight()).sum::<u64>();
 
-        let maybe_min = OrderedFloat::from(*sorted_values.first().unwrap());
-        let maybe_max = OrderedFloat::from(*sorted_values.last().unwrap());
+        let maybe_min = sorted_values.first().unwrap().mean;
+        let maybe_max = sorted_values.last().unwrap().mean;
 
         if self.count() > 0 {
             result.min = std::cmp::min(self.min, maybe_min);
@@ -284,14 +311,14 @@ impl TDigest {
         let mut iter_sorted_values = sorted_values.iter().peekable();
 
         let mut curr: Centroid = if let Some(c) = iter_centroids.peek() {
-            let curr = **iter_sorted_values.peek().unwrap();
+            let curr = iter_sorted_values.peek().unwrap().mean();
             if c.mean() < curr {
                 iter_centroids.next().unwrap().clone()
             } else {
-                Centroid::new(*iter_sorted_values.next().unwrap(), 1)
+                iter_sorted_values.next().unwrap().clone()
             }
         } else {
-            Centroid::new(*iter_sorted_values.next().unwrap(), 1)
+            iter_sorted_values.next().unwrap().clone()
         };
 
         let mut weight_so_far: u64 = curr.weight();
@@ -302,14 +329,14 @@ impl TDigest {
         while iter_centroids.peek().is_some() || iter_sorted_values.peek().is_some() {
             let next: Centroid = if let Some(c) = iter_centroids.peek() {
                 if iter_sorted_values.peek().is_none()
-                    || c.mean() < **iter_sorted_values.peek().unwrap()
+                    || c.mean() < iter_sorted_values.peek().unwrap().mean()
                 {
                     iter_centroids.next().unwrap().clone()
                 } else {
-                    Centroid::new(*iter_sorted_values.next().unwrap(), 1)
+                    iter_sorted_values.next().unwrap().clone()
                 }
             } else {
-                Centroid::new(*iter_sorted_values.next().unwrap(), 1)
+                iter_sorted_values.next().unwrap().clone()
             };
 
             let next_sum: f64 = next.mean() * next.weight() as f64;
@@ -385,6 +412,17 @@ impl TDigest {
         }
     }
 
+    /// Re-cluster the digest into at most `max_size` centroids, as if it had been built with that size.
+    /// Growing a digest only changes its `max_size`, the centroids are left as they are.
+    pub fn resize(&self, max_size: usize) -> TDigest {
+        if self.centroids.is_empty() {
+            return TDigest::new_with_size(max_size);
+        }
+        let mut digest = self.clone();
+        digest.max_size = max_size;
+        TDigest::merge_digests(vec![digest])
+    }
+
     // Merge multiple T-Digests
     pub fn merge_digests(digests: Vec<TDigest>) -> TDigest {
         let n_centroids: usize = digests.iter().map(|d| d.centroids.len()).sum();
@@ -528,6 +566,51 @@ impl TDigest {
         }
     }
 
+    /// Estimate the number of values falling in each of the bins delimited by `edges`, which must be sorted
+    /// in increasing order.  The first bin holds the values less than `edges[0]`, bin `i` the values in
+    /// `[edges[i-1], edges[i])`, and the last bin the values greater than or equal to the last edge, so
+    /// `edges.len() + 1` counts are returned.  The counts always sum to the number of values in the digest.
+    pub fn histogram(&self, edges: &[f64]) -> Vec<u64> {
+        let mut counts = Vec::with_capacity(edges.len() + 1);
+        let mut below = 0;
+        for &edge in edges {
+            let rank = (self.estimate_quantile_at_value(edge) * self.count as f64).round() as u64;
+            // guard against rounding making the ranks decrease or exceed the count
+            let rank = rank.max(below).min(self.count);
+            counts.push(rank - below);
+            below = rank;
+        }
+        counts.push(self.count - below);
+        counts
+    }
+
+    /// Estimate the mean of the values ranked between the `low` and `high` quantiles, for instance
+    /// `(0.01, 0.99)` for the mean without the top and bottom 1%, or `(0.95, 1.0)` for the mean of the
+    /// top 5%.  Every centroid contributes its mean weighted by how much of its weight falls in the rank
+    /// range, with the boundary centroids contributing fractionally.
+    pub fn trimmed_mean(&self, low: f64, high: f64) -> f64 {
+        assert!(0.0 <= low && low < high && high <= 1.0);
+        if self.centroids.is_empty() {
+            return 0.0;
+        }
+
+        let (low_rank, high_rank) = (low * self.count as f64, high * self.count as f64);
+        let mut sum = 0.0;
+        let mut seen = 0.0;
+        for centroid in &self.centroids {
+            let (start, end) = (seen, seen + centroid.weight() as f64);
+            seen = end;
+            let weight = end.min(high_rank) - start.max(low_rank);
+            if weight > 0.0 {
+                sum += centroid.mean() * weight;
+            }
+            if seen >= high_rank {
+                break;
+            }
+        }
+        sum / (high_rank - low_rank)
+    }
+
     /// To estimate the value located at `q` quantile
     pub fn estimate_quantile(&self, q: f64) -> f64 {
         if self.centroids.is_empty() {
@@ -905,6 +988,108 @@ mod tests {
         assert_eq!(estimate, 99.5);
     }
 
+    #[test]
+    fn test_weighted_values_match_repeated_values() {
+        let t = TDigest::new_with_size(100);
+        let weighted: Vec<(f64, u64)> = (1..=1000).map(|v| (f64::from(v), (v % 7) as u64)).collect();
+        let repeated: Vec<f64> = weighted
+            .iter()
+            .flat_map(|&(v, w)| std::iter::repeat(v).take(w as usize))
+            .collect();
+
+        let mut shuffled = weighted.clone();
+        shuffled.reverse();
+        let w = t.merge_unsorted_weighted(shuffled);
+        let r = t.merge_sorted(repeated);
+
+        assert_eq!(w.count(), r.count());
+        assert_eq!(w.min(), r.min());
+        assert_eq!(w.max(), r.max());
+        assert!((w.sum() - r.sum()).abs() <= 1e-9 * r.sum());
+        for &q in [0.01, 0.1, 0.5, 0.9, 0.99].iter() {
+            let (a, b) = (w.estimate_quantile(q), r.estimate_quantile(q));
+            assert!((a - b).abs() / b < 0.01, "quantile {}: {} != {}", q, a, b);
+        }
+    }
+
+    #[test]
+    fn test_weighted_quantiles() {
+        // values 1..=1000, each with weight equal to its value, so the exact weighted
+        // quantile q is the smallest v with v(v+1)/2 >= q * total
+        let values: Vec<(f64, u64)> = (1..=1000).rev().map(|v| (f64::from(v), v as u64)).collect();
+        let total: u64 = values.iter().map(|&(_, w)| w).sum();
+        let t = TDigest::new_with_size(100).merge_unsorted_weighted(values);
+        assert_eq!(t.count(), total);
+
+        for &q in [0.01, 0.1, 0.25, 0.5, 0.75, 0.9, 0.99].iter() {
+            let rank = q * total as f64;
+            let expected = (1..=1000).find(|&v: &u64| (v * (v + 1) / 2) as f64 >= rank).unwrap() as f64;
+            let ans = t.estimate_quantile(q);
+            let percentage = (expected - ans).abs() / expected;
+            assert!(percentage < 0.01, "quantile {}: expected {}, received {}", q, expected, ans);
+        }
+
+        // zero weights are ignored entirely
+        let t = TDigest::new_with_size(100).merge_unsorted_weighted(vec![(1.0, 1), (100.0, 0)]);
+        assert_eq!(t.count(), 1);
+        assert_eq!(t.max(), 1.0);
+    }
+
+    #[test]
+    fn test_histogram() {
+        let values: Vec<f64> = (1..=1000).map(f64::from).collect();
+        let t = TDigest::new_with_size(100).merge_sorted(values);
+
+        let counts = t.histogram(&[100.5, 500.5, 900.5]);
+        assert_eq!(counts.len(), 4);
+        assert_eq!(counts.iter().sum::<u64>(), 1000);
+        for (&count, &expected) in counts.iter().zip([100, 400, 400, 100].iter()) {
+            assert!((count as f64 - expected as f64).abs() <= 5.0, "counts {:?}", counts);
+        }
+
+        assert_eq!(t.histogram(&[]), vec![1000]);
+        assert_eq!(t.histogram(&[0.0, 2000.0]), vec![0, 1000, 0]);
+        assert_eq!(TDigest::new_with_size(100).histogram(&[1.0]), vec![0, 0]);
+    }
+
+    #[test]
+    fn test_resize() {
+        let values: Vec<f64> = (1..=1000).map(f64::from).collect();
+        let t = TDigest::new_with_size(200).merge_sorted(values);
+
+        let small = t.resize(50);
+        assert_eq!(small.max_size(), 50);
+        assert!(small.num_buckets() <= 51, "{} buckets", small.num_buckets());
+        assert_eq!(small.count(), t.count());
+        assert_eq!(small.sum(), t.sum());
+        assert_eq!(small.min(), 1.0);
+        assert_eq!(small.max(), 1000.0);
+        for &q in &[0.01, 0.25, 0.5, 0.75, 0.99] {
+            let ans = small.estimate_quantile(q);
+            assert!((ans - q * 1000.0).abs() < 10.0, "quantile {} was {}", q, ans);
+        }
+
+        let large = small.resize(500);
+        assert_eq!(large.max_size(), 500);
+        assert_eq!(large.raw_centroids(), small.raw_centroids());
+
+        assert_eq!(TDigest::new_with_size(100).resize(10).max_size(), 10);
+    }
+
+    #[test]
+    fn test_trimmed_mean() {
+        let values: Vec<f64> = (1..=1000).map(f64::from).collect();
+        let t = TDigest::new_with_size(100).merge_sorted(values);
+
+        assert!((t.trimmed_mean(0.0, 1.0) - t.mean()).abs() < 1e-9);
+        for &(low, high, expected) in &[(0.01, 0.99, 500.5), (0.95, 1.0, 975.5), (0.0, 0.1, 50.5)] {
+            let mean = t.trimmed_mean(low, high);
+            assert!((mean - expected).abs() / expected < 0.01, "mean of ({}, {}) was {}, expected {}", low, high, mean, expected);
+        }
+
+        assert_eq!(TDigest::new_with_size(100).trimmed_mean(0.1, 0.9), 0.0);
+    }
+
     use quickcheck::*;
 
     #[derive(Hash, PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Debug)]
@@ -0,0 +1,182 @@
+use serde::{Deserialize, Serialize};
+use time_series::TSPoint;
+
+use crate::{TimeWeightError, TimeWeightMethod};
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum ThresholdOp {
+    LessThan,
+    LessOrEqual,
+    GreaterThan,
+    GreaterOrEqual,
+}
+
+impl ThresholdOp {
+    pub fn matches(&self, val: f64, threshold: f64) -> bool {
+        match self {
+            ThresholdOp::LessThan => val < threshold,
+            ThresholdOp::LessOrEqual => val <= threshold,
+            ThresholdOp::GreaterThan => val > threshold,
+            ThresholdOp::GreaterOrEqual => val >= threshold,
+        }
+    }
+}
+
+/// The time during which the values of a series satisfy `op threshold`, where the values
+/// between the points are determined by the weighting method.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct DurationIn {
+    pub method: TimeWeightMethod,
+    pub op: ThresholdOp,
+    pub threshold: f64,
+    pub first: TSPoint,
+    pub last: TSPoint,
+    /// In microseconds, with linear interpolation the threshold may be crossed between them.
+    pub duration: f64,
+}
+
+impl DurationIn {
+    pub fn new(pt: TSPoint, method: TimeWeightMethod, op: ThresholdOp, threshold: f64) -> Self {
+        DurationIn {
+            method,
+            op,
+            threshold,
+            first: pt,
+            last: pt,
+            duration: 0.0,
+        }
+    }
+
+    pub fn accum(&mut self, pt: TSPoint) -> Result<(), TimeWeightError> {
+        if pt.ts < self.last.ts {
+            return Err(TimeWeightError::OrderError);
+        }
+        if pt.ts == self.last.ts {
+            // as with TimeWeightSummary, only the first of several equal points is used
+            return Ok(());
+        }
+        self.duration += self.duration_between(self.last, pt);
+        self.last = pt;
+        Ok(())
+    }
+
+    pub fn new_from_sorted_iter<'a>(
+        iter: impl IntoIterator<Item = &'a TSPoint>,
+        method: TimeWeightMethod,
+        op: ThresholdOp,
+        threshold: f64,
+    ) -> Result<DurationIn, TimeWeightError> {
+        let mut t = iter.into_iter();
+        let mut s = match t.next() {
+            None => {
+                return Err(TimeWeightError::EmptyIterator);
+            }
+            Some(val) => DurationIn::new(*val, method, op, threshold),
+        };
+        for p in t {
+            s.accum(*p)?;
+        }
+        Ok(s)
+    }
+
+    /// The fraction of the time between the first and last points during which the
+    /// condition holds, `None` if there is no time between them.
+    pub fn fraction(&self) -> Option<f64> {
+        if self.last.ts == self.first.ts {
+            return None;
+        }
+        Some(self.duration / (self.last.ts - self.first.ts) as f64)
+    }
+
+    fn duration_between(&self, first: TSPoint, second: TSPoint) -> f64 {
+        debug_assert!(second.ts > first.ts);
+        let duration = (second.ts - first.ts) as f64;
+        let held = |val| if self.op.matches(val, self.threshold) { 1.0 } else { 0.0 };
+        match self.method {
+            TimeWeightMethod::LOCF => held(first.val) * duration,
+            TimeWeightMethod::NOCB => held(second.val) * duration,
+            TimeWeightMethod::Midpoint => (held(first.val) + held(second.val)) / 2.0 * duration,
+            TimeWeightMethod::Linear => {
+                if first.val == second.val {
+                    return held(first.val) * duration;
+                }
+                // the line crosses the threshold at this fraction of the way to the second
+                // point, the crossing itself takes no time so strict and non-strict
+                // comparisons only differ when the line stays at the threshold
+                let crossing =
+                    ((self.threshold - first.val) / (second.val - first.val)).clamp(0.0, 1.0);
+                let above = if second.val > first.val {
+                    1.0 - crossing
+                } else {
+                    crossing
+                };
+                match self.op {
+                    ThresholdOp::GreaterThan | ThresholdOp::GreaterOrEqual => above * duration,
+                    ThresholdOp::LessThan | ThresholdOp::LessOrEqual => (1.0 - above) * duration,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use TimeWeightMethod::*;
+    use ThresholdOp::*;
+
+    fn duration_in(points: &[(i64, f64)], method: TimeWeightMethod, op: ThresholdOp, threshold: f64) -> DurationIn {
+        let points: Vec<_> = points.iter().map(|&(ts, val)| TSPoint { ts, val }).collect();
+        DurationIn::new_from_sorted_iter(&points, method, op, threshold).unwrap()
+    }
+
+    #[test]
+    fn test_steps() {
+        let points = [(0, 1.0), (10, 5.0), (30, 2.0), (40, 5.0), (50, 0.0)];
+        assert_eq!(duration_in(&points, LOCF, GreaterThan, 3.0).duration, 30.0);
+        assert_eq!(duration_in(&points, LOCF, LessThan, 3.0).duration, 20.0);
+        assert_eq!(duration_in(&points, LOCF, GreaterOrEqual, 5.0).duration, 30.0);
+        assert_eq!(duration_in(&points, LOCF, GreaterThan, 5.0).duration, 0.0);
+        assert_eq!(duration_in(&points, NOCB, GreaterThan, 3.0).duration, 20.0);
+        assert_eq!(duration_in(&points, NOCB, LessOrEqual, 2.0).duration, 30.0);
+        // half of each segment belongs to each of its points
+        assert_eq!(duration_in(&points, Midpoint, GreaterThan, 3.0).duration, 5.0 + 10.0 + 5.0 + 5.0);
+
+        let s = duration_in(&points, LOCF, GreaterThan, 3.0);
+        assert_eq!(s.fraction(), Some(0.6));
+        assert_eq!(duration_in(&points[..1], LOCF, GreaterThan, 3.0).fraction(), None);
+    }
+
+    #[test]
+    fn test_linear_crossings() {
+        // crosses 3 at 5 going up, and at 20 going down
+        let points = [(0, 1.0), (10, 5.0), (20, 3.0), (30, 1.0)];
+        assert_eq!(duration_in(&points, Linear, GreaterThan, 3.0).duration, 15.0);
+        assert_eq!(duration_in(&points, Linear, LessOrEqual, 3.0).duration, 15.0);
+        // entirely on one side of the threshold
+        assert_eq!(duration_in(&points, Linear, GreaterThan, 10.0).duration, 0.0);
+        assert_eq!(duration_in(&points, Linear, LessThan, 10.0).duration, 30.0);
+        assert_eq!(duration_in(&points, Linear, GreaterThan, 0.0).duration, 30.0);
+
+        // a flat line at the threshold only counts for non-strict comparisons
+        let flat = [(0, 3.0), (10, 3.0)];
+        assert_eq!(duration_in(&flat, Linear, GreaterThan, 3.0).duration, 0.0);
+        assert_eq!(duration_in(&flat, Linear, GreaterOrEqual, 3.0).duration, 10.0);
+        assert_eq!(duration_in(&flat, Linear, LessOrEqual, 3.0).duration, 10.0);
+    }
+
+    #[test]
+    fn test_order() {
+        let mut s = DurationIn::new(TSPoint { ts: 10, val: 1.0 }, LOCF, GreaterThan, 0.0);
+        assert_eq!(s.accum(TSPoint { ts: 5, val: 1.0 }), Err(TimeWeightError::OrderError));
+        // duplicates are ignored
+        s.accum(TSPoint { ts: 20, val: 1.0 }).unwrap();
+        s.accum(TSPoint { ts: 20, val: -1.0 }).unwrap();
+        assert_eq!(s.last.val, 1.0);
+        assert_eq!(s.duration, 10.0);
+        assert_eq!(
+            DurationIn::new_from_sorted_iter(&[] as &[TSPoint], LOCF, GreaterThan, 0.0),
+            Err(TimeWeightError::EmptyIterator)
+        );
+    }
+}
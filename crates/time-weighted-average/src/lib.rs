@@ -1,10 +1,19 @@
 use serde::{Deserialize, Serialize};
 use time_series::TSPoint;
+
+pub mod duration_in;
+
 #[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
 #[repr(u8)]
 pub enum TimeWeightMethod {
+    /// Last observation carried forward, each value holds until the next point.
     LOCF,
+    /// The value changes linearly between points.
     Linear,
+    /// Next observation carried backward, each value holds from the previous point.
+    NOCB,
+    /// Each value holds from halfway since the previous point until halfway to the next one.
+    Midpoint,
 }
 
 #[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
@@ -13,6 +22,15 @@ pub struct TimeWeightSummary {
     pub first: TSPoint,
     pub last: TSPoint,
     pub w_sum: f64,
+    /// The weighted sum of the squares of the values, for the variance. `None` for summaries
+    /// stored before it was tracked, and anything they are combined with.
+    pub w_sum_sq: Option<f64>,
+    /// Segments between consecutive points further apart than this are treated as missing data,
+    /// and count towards neither `w_sum` nor `covered`. `None` includes every segment.
+    pub max_gap: Option<i64>,
+    /// The total duration of the segments included in `w_sum`, without a `max_gap` this is always
+    /// `last.ts - first.ts`.
+    pub covered: i64,
 }
 
 #[derive(PartialEq, Debug)]
@@ -20,21 +38,35 @@ pub enum TimeWeightError {
     OrderError,
     DoubleOverflow, // do we need to do this?
     MethodMismatch,
+    MaxGapMismatch,
     InterpolateMissingPoint,
     ZeroDuration,
     EmptyIterator,
+    MissingSumOfSquares,
 }
 
 impl TimeWeightSummary {
     pub fn new(pt: TSPoint, method: TimeWeightMethod) -> Self {
+        Self::new_with_max_gap(pt, method, None)
+    }
+
+    pub fn new_with_max_gap(pt: TSPoint, method: TimeWeightMethod, max_gap: Option<i64>) -> Self {
         TimeWeightSummary {
             method: method,
             first: pt,
             last: pt,
             w_sum: 0.0,
+            w_sum_sq: Some(0.0),
+            max_gap,
+            covered: 0,
         }
     }
 
+    // whether the segment between two points is short enough to count as data
+    fn covers(&self, from: i64, to: i64) -> bool {
+        !matches!(self.max_gap, Some(max_gap) if to - from > max_gap)
+    }
+
     pub fn accum(&mut self, pt: TSPoint) -> Result<(), TimeWeightError> {
         if pt.ts < self.last.ts {
             return Err(TimeWeightError::OrderError);
@@ -44,7 +76,11 @@ impl TimeWeightSummary {
             // see discussion at https://github.com/timescale/timescale-analytics/discussions/65
             return Ok(());
         }
-        self.w_sum += self.method.weighted_sum(self.last, pt);
+        if self.covers(self.last.ts, pt.ts) {
+            self.w_sum += self.method.weighted_sum(self.last, pt);
+            self.w_sum_sq = self.w_sum_sq.map(|w_sum_sq| w_sum_sq + self.method.weighted_sum_sq(self.last, pt));
+            self.covered += pt.ts - self.last.ts;
+        }
         self.last = pt;
         Ok(())
     }
@@ -58,17 +94,32 @@ impl TimeWeightSummary {
         if self.method != next.method {
             return Err(TimeWeightError::MethodMismatch);
         }
+        if self.max_gap != next.max_gap {
+            return Err(TimeWeightError::MaxGapMismatch);
+        }
         if self.last.ts >= next.first.ts {
             // this combine function should always be pulling from disjoint sets, so duplicate values do not need to be handled
             // as we do in accum() (where duplicates are ignored) here we throw an error, because duplicate values should
             // always have been sorted into one or another bucket, and it means that the bounds of our buckets were wrong.
             return Err(TimeWeightError::OrderError);
         }
+        let (gap_sum, gap_sum_sq, gap_covered) = if self.covers(self.last.ts, next.first.ts) {
+            (
+                self.method.weighted_sum(self.last, next.first),
+                self.method.weighted_sum_sq(self.last, next.first),
+                next.first.ts - self.last.ts,
+            )
+        } else {
+            (0.0, 0.0, 0)
+        };
         let new = TimeWeightSummary {
             method: self.method,
             first: self.first,
             last: next.last,
-            w_sum: self.w_sum + next.w_sum + self.method.weighted_sum(self.last, next.first),
+            w_sum: self.w_sum + next.w_sum + gap_sum,
+            w_sum_sq: self.w_sum_sq.zip(next.w_sum_sq).map(|(a, b)| a + b + gap_sum_sq),
+            max_gap: self.max_gap,
+            covered: self.covered + next.covered + gap_covered,
         };
         Ok(new)
     }
@@ -76,13 +127,21 @@ impl TimeWeightSummary {
     pub fn new_from_sorted_iter<'a>(
         iter: impl IntoIterator<Item = &'a TSPoint>,
         method: TimeWeightMethod,
+    ) -> Result<TimeWeightSummary, TimeWeightError> {
+        Self::new_from_sorted_iter_with_max_gap(iter, method, None)
+    }
+
+    pub fn new_from_sorted_iter_with_max_gap<'a>(
+        iter: impl IntoIterator<Item = &'a TSPoint>,
+        method: TimeWeightMethod,
+        max_gap: Option<i64>,
     ) -> Result<TimeWeightSummary, TimeWeightError> {
         let mut t = iter.into_iter();
         let mut s = match t.next() {
             None => {
                 return Err(TimeWeightError::EmptyIterator);
             }
-            Some(val) => TimeWeightSummary::new(*val, method),
+            Some(val) => TimeWeightSummary::new_with_max_gap(*val, method, max_gap),
         };
         for p in t {
             s.accum(*p)?;
@@ -106,15 +165,29 @@ impl TimeWeightSummary {
         Ok(s)
     }
 
+    /// Set the `max_gap` of a summary that was built without one, so it can be combined with
+    /// summaries that do have it. The segments already in the summary are kept as they are,
+    /// which is only exact if none of them are longer than `max_gap`, as is the case when the
+    /// summary covers a period no longer than `max_gap`.
+    pub fn with_max_gap(&self, max_gap: i64) -> Result<Self, TimeWeightError> {
+        match self.max_gap {
+            Some(current) if current != max_gap => Err(TimeWeightError::MaxGapMismatch),
+            _ => Ok(TimeWeightSummary {
+                max_gap: Some(max_gap),
+                ..*self
+            }),
+        }
+    }
+
     /// Extrapolate a TimeWeightSummary to bounds using the method and provided points outside the bounds of the original summary.
     /// This is especially useful for cases where you want to get an average for, say, a time_bucket, using points outside of that time_bucket.
     /// The initial aggregate will only have points within the time bucket, but outside of it, you will either have a point that you select
     /// or a TimeWeightSummary where the first or last point can be used depending on which bound you are extrapolating to.
     /// 1. The start_prev parameter is optional, but if a start is provided a previous point must be
-    /// provided (for both linear and locf weighting methods).
+    ///    provided (for all weighting methods).
     /// 2. The end_next parameter is also optional, if an end is provided and the locf weighting
-    /// method is specified, a next parameter isn't needed, with the linear method, the next
-    /// point is needed and we will error if it is not provided.
+    ///    method is specified, a next parameter isn't needed, with the other methods, the next
+    ///    point is needed and we will error if it is not provided.
     pub fn with_bounds(
         &self,
         start_prev: Option<(i64, TSPoint)>,
@@ -126,11 +199,50 @@ impl TimeWeightSummary {
         }
 
         if let Some((end, next)) = end_next {
-            calc = self.with_next(end, next)?
+            calc = calc.with_next(end, next)?
         }
         Ok(calc)
     }
 
+    /// Interpolate the summary of the points in the interval
+    /// `[interval_start, interval_start + interval_len)` to the bounds of that interval, using the
+    /// summaries of the intervals before and after it. This makes the averages of consecutive
+    /// intervals, such as `time_bucket`s, account for the time between the last point of one
+    /// interval and the first point of the next.
+    /// 1. Without a `prev` summary the start is not interpolated, and the summary starts at its first point.
+    /// 2. Without a `next` summary the end is only interpolated with the locf method, which carries the
+    ///    last value forward to the end of the interval.
+    /// 3. With a `max_gap`, the summary is not interpolated to points further than `max_gap` away,
+    ///    and locf carries the last value forward for at most `max_gap`.
+    pub fn interpolate(
+        &self,
+        interval_start: i64,
+        interval_len: i64,
+        prev: Option<&TimeWeightSummary>,
+        next: Option<&TimeWeightSummary>,
+    ) -> Result<Self, TimeWeightError> {
+        let interval_end = interval_start + interval_len;
+        if self.first.ts < interval_start || self.last.ts >= interval_end {
+            return Err(TimeWeightError::OrderError);
+        }
+        if prev.iter().chain(next.iter()).any(|s| s.method != self.method) {
+            return Err(TimeWeightError::MethodMismatch);
+        }
+
+        let start_prev = match prev {
+            Some(prev) if prev.last.ts >= interval_start => return Err(TimeWeightError::OrderError),
+            Some(prev) => Some((interval_start, prev.last)),
+            None => None,
+        };
+        let end_next = match (next, self.method) {
+            (Some(next), _) if next.first.ts < interval_end => return Err(TimeWeightError::OrderError),
+            (Some(next), _) => Some((interval_end, Some(next.first))),
+            (None, TimeWeightMethod::LOCF) => Some((interval_end, None)),
+            (None, _) => None,
+        };
+        self.with_bounds(start_prev, end_next)
+    }
+
     fn with_prev(&self, target_start: i64, prev: TSPoint) -> Result<Self, TimeWeightError> {
         // target_start must always be between [prev.ts, self.first.ts]
         if prev.ts >= self.first.ts || target_start > self.first.ts || prev.ts > target_start {
@@ -139,15 +251,42 @@ impl TimeWeightSummary {
         if target_start == self.first.ts {
             return Ok(*self);
         }
+        if let Some(max_gap) = self.max_gap.filter(|_| !self.covers(prev.ts, self.first.ts)) {
+            // prev is too far away to interpolate from, but locf still carries its value
+            // forward for max_gap, which may reach into our bounds
+            let carried = prev.ts + max_gap - target_start;
+            if self.method != TimeWeightMethod::LOCF || carried <= 0 {
+                return Ok(*self);
+            }
+            return Ok(TimeWeightSummary {
+                first: TSPoint { ts: target_start, val: prev.val },
+                w_sum: self.w_sum + prev.val * carried as f64,
+                w_sum_sq: self.w_sum_sq.map(|w_sum_sq| w_sum_sq + prev.val * prev.val * carried as f64),
+                covered: self.covered + carried,
+                ..*self
+            });
+        }
 
         let new_first = self
             .method
             .interpolate(prev, Some(self.first), target_start)?;
-        let w_sum = self.w_sum + self.method.weighted_sum(new_first, self.first);
+        let (w_sum, w_sum_sq) = match self.method {
+            // the value may step from prev's to first's after target_start, so we need prev itself
+            TimeWeightMethod::Midpoint => (
+                midpoint_weighted_sum(prev, self.first, target_start, self.first.ts, |v| v),
+                midpoint_weighted_sum(prev, self.first, target_start, self.first.ts, |v| v * v),
+            ),
+            _ => (
+                self.method.weighted_sum(new_first, self.first),
+                self.method.weighted_sum_sq(new_first, self.first),
+            ),
+        };
 
         Ok(TimeWeightSummary {
             first: new_first,
-            w_sum,
+            w_sum: self.w_sum + w_sum,
+            w_sum_sq: self.w_sum_sq.map(|s| s + w_sum_sq),
+            covered: self.covered + (self.first.ts - target_start),
             ..*self
         })
     }
@@ -168,23 +307,81 @@ impl TimeWeightSummary {
             }
         }
 
+        // a next point further than max_gap away is missing data, so it cannot be interpolated
+        // to, and without a next point the last value is only carried forward for max_gap
+        let (target_end, next) = match (next, self.max_gap) {
+            (Some(next), Some(max_gap)) if next.ts - self.last.ts > max_gap => match self.method {
+                TimeWeightMethod::LOCF => (target_end.min(self.last.ts + max_gap), None),
+                _ => return Ok(*self),
+            },
+            (None, Some(max_gap)) => (target_end.min(self.last.ts + max_gap), None),
+            (next, _) => (target_end, next),
+        };
+        if target_end == self.last.ts {
+            return Ok(*self);
+        }
+
         let new_last = self.method.interpolate(self.last, next, target_end)?;
-        let w_sum = self.w_sum + self.method.weighted_sum(self.last, new_last);
+        let (w_sum, w_sum_sq) = match (self.method, next) {
+            // the value may step from last's to next's before target_end, so we need next itself
+            (TimeWeightMethod::Midpoint, Some(next)) => (
+                midpoint_weighted_sum(self.last, next, self.last.ts, target_end, |v| v),
+                midpoint_weighted_sum(self.last, next, self.last.ts, target_end, |v| v * v),
+            ),
+            _ => (
+                self.method.weighted_sum(self.last, new_last),
+                self.method.weighted_sum_sq(self.last, new_last),
+            ),
+        };
 
         Ok(TimeWeightSummary {
             last: new_last,
-            w_sum,
+            w_sum: self.w_sum + w_sum,
+            w_sum_sq: self.w_sum_sq.map(|s| s + w_sum_sq),
+            covered: self.covered + (target_end - self.last.ts),
             ..*self
         })
     }
 
-    ///Evaluate the time_weighted_average from the summary.
+    ///Evaluate the time_weighted_average from the summary, this is over the covered duration
+    ///only, so gaps longer than `max_gap` do not count.
     pub fn time_weighted_average(&self) -> Result<f64, TimeWeightError> {
-        if self.last.ts == self.first.ts {
+        if self.covered == 0 {
             return Err(TimeWeightError::ZeroDuration);
         }
-        let duration = (self.last.ts - self.first.ts) as f64;
-        Ok(self.w_sum / duration)
+        Ok(self.w_sum / self.covered as f64)
+    }
+
+    /// The time weighted variance of the values, over the covered duration like the average.
+    pub fn time_weighted_variance(&self) -> Result<f64, TimeWeightError> {
+        let average = self.time_weighted_average()?;
+        let w_sum_sq = self.w_sum_sq.ok_or(TimeWeightError::MissingSumOfSquares)?;
+        let variance = w_sum_sq / self.covered as f64 - average * average;
+        // rounding can make the variance of (nearly) constant values slightly negative
+        if variance < 0.0 {
+            return Ok(0.0);
+        }
+        Ok(variance)
+    }
+
+    /// The time weighted standard deviation of the values.
+    pub fn time_weighted_stddev(&self) -> Result<f64, TimeWeightError> {
+        Ok(self.time_weighted_variance()?.sqrt())
+    }
+
+    /// The fraction of the interval `[interval_start, interval_start + interval_len)` that is
+    /// covered by data, after interpolating the summary to the bounds of the interval as in
+    /// `interpolate`. Time in the interval before the first point or after the last one that can't
+    /// be interpolated to, and gaps longer than `max_gap`, are not covered.
+    pub fn coverage(
+        &self,
+        interval_start: i64,
+        interval_len: i64,
+        prev: Option<&TimeWeightSummary>,
+        next: Option<&TimeWeightSummary>,
+    ) -> Result<f64, TimeWeightError> {
+        let interpolated = self.interpolate(interval_start, interval_len, prev, next)?;
+        Ok(interpolated.covered as f64 / interval_len as f64)
     }
 }
 
@@ -208,7 +405,23 @@ impl TimeWeightMethod {
                 (TimeWeightMethod::Linear, Some(second)) => {
                     first.interpolate_linear(&second, target).unwrap()
                 }
-                (TimeWeightMethod::Linear, None) => {
+                (TimeWeightMethod::NOCB, Some(second)) => {
+                    if target == first.ts {
+                        first.val
+                    } else {
+                        second.val
+                    }
+                }
+                (TimeWeightMethod::Midpoint, Some(second)) => {
+                    if target - first.ts < second.ts - target {
+                        first.val
+                    } else {
+                        second.val
+                    }
+                }
+                (TimeWeightMethod::Linear, None)
+                | (TimeWeightMethod::NOCB, None)
+                | (TimeWeightMethod::Midpoint, None) => {
                     return Err(TimeWeightError::InterpolateMissingPoint)
                 }
             },
@@ -228,9 +441,40 @@ impl TimeWeightMethod {
             //two / 2 * duration) this is equivalent to the rectangle formed by the
             //midpoint of the two.
             //TODO: Stable midpoint calc? http://www.open-std.org/jtc1/sc22/wg21/docs/papers/2018/p0811r2.html
-            TimeWeightMethod::Linear => (first.val + second.val) / 2.0 * duration,
+            //a step at the midpoint covers the same area as the line between the two
+            //points, each value holds for half the duration.
+            TimeWeightMethod::Linear | TimeWeightMethod::Midpoint => {
+                (first.val + second.val) / 2.0 * duration
+            }
+            TimeWeightMethod::NOCB => second.val * duration,
         }
     }
+
+    // the same as weighted_sum, but of the squares of the values
+    fn weighted_sum_sq(&self, first: TSPoint, second: TSPoint) -> f64 {
+        debug_assert!(second.ts > first.ts);
+        let duration = (second.ts - first.ts) as f64;
+        let (a, b) = (first.val, second.val);
+        match self {
+            TimeWeightMethod::LOCF => a * a * duration,
+            //the integral of the square of the line between the points
+            TimeWeightMethod::Linear => (a * a + a * b + b * b) / 3.0 * duration,
+            //unlike the plain sum, the squares of the two steps are not the same as linear's
+            TimeWeightMethod::Midpoint => (a * a + b * b) / 2.0 * duration,
+            TimeWeightMethod::NOCB => b * b * duration,
+        }
+    }
+}
+
+// The weighted sum of `f` of the values in the part of the step between `first` and `second`
+// that lies in `[from, to]`, the value steps from `first.val` to `second.val` halfway between them.
+fn midpoint_weighted_sum(first: TSPoint, second: TSPoint, from: i64, to: i64, f: impl Fn(f64) -> f64) -> f64 {
+    debug_assert!(first.ts <= from && from <= to && to <= second.ts);
+    let midpoint = (first.ts as f64 + second.ts as f64) / 2.0;
+    let (from, to) = (from as f64, to as f64);
+    let before = (midpoint.min(to) - from).max(0.0);
+    let after = (to - midpoint.max(from)).max(0.0);
+    f(first.val) * before + f(second.val) * after
 }
 
 #[cfg(test)]
@@ -311,6 +555,8 @@ mod tests {
     fn test_new_from_sorted_iter() {
         new_from_sorted_iter_test(TimeWeightMethod::LOCF);
         new_from_sorted_iter_test(TimeWeightMethod::Linear);
+        new_from_sorted_iter_test(TimeWeightMethod::NOCB);
+        new_from_sorted_iter_test(TimeWeightMethod::Midpoint);
     }
 
     fn combine_test(t: TimeWeightMethod) {
@@ -345,6 +591,8 @@ mod tests {
     fn test_combine() {
         combine_test(TimeWeightMethod::LOCF);
         combine_test(TimeWeightMethod::Linear);
+        combine_test(TimeWeightMethod::NOCB);
+        combine_test(TimeWeightMethod::Midpoint);
     }
 
     fn order_accum_test(t: TimeWeightMethod) {
@@ -405,6 +653,8 @@ mod tests {
     fn test_order_accum() {
         order_accum_test(TimeWeightMethod::LOCF);
         order_accum_test(TimeWeightMethod::Linear);
+        order_accum_test(TimeWeightMethod::NOCB);
+        order_accum_test(TimeWeightMethod::Midpoint);
     }
 
     fn order_combine_test(t: TimeWeightMethod) {
@@ -432,6 +682,8 @@ mod tests {
     fn test_order_combine() {
         order_combine_test(TimeWeightMethod::LOCF);
         order_combine_test(TimeWeightMethod::Linear);
+        order_combine_test(TimeWeightMethod::NOCB);
+        order_combine_test(TimeWeightMethod::Midpoint);
     }
 
     fn combine_sorted_iter_test(t: TimeWeightMethod) {
@@ -492,6 +744,8 @@ mod tests {
     fn test_combine_sorted_iter() {
         combine_sorted_iter_test(TimeWeightMethod::LOCF);
         combine_sorted_iter_test(TimeWeightMethod::Linear);
+        combine_sorted_iter_test(TimeWeightMethod::NOCB);
+        combine_sorted_iter_test(TimeWeightMethod::Midpoint);
     }
 
     #[test]
@@ -521,6 +775,67 @@ mod tests {
         assert_eq!(s1.combine(&s2), Err(TimeWeightError::MethodMismatch));
     }
 
+    #[test]
+    fn test_simple_accum_nocb() {
+        let mut s = TimeWeightSummary::new(TSPoint { ts: 0, val: 1.0 }, TimeWeightMethod::NOCB);
+        assert_eq!(s.w_sum, 0.0);
+        s.accum(TSPoint { ts: 10, val: 0.0 }).unwrap();
+        assert_eq!(s.w_sum, 0.0);
+        s.accum(TSPoint { ts: 20, val: 2.0 }).unwrap();
+        assert_eq!(s.w_sum, 20.0);
+        s.accum(TSPoint { ts: 30, val: 1.0 }).unwrap();
+        assert_eq!(s.w_sum, 30.0);
+        s.accum(TSPoint { ts: 40, val: -3.0 }).unwrap();
+        assert_eq!(s.w_sum, 0.0);
+    }
+
+    #[test]
+    fn test_simple_accum_midpoint() {
+        // a step at each midpoint covers the same area as the linear interpolation
+        let mut s = TimeWeightSummary::new(TSPoint { ts: 0, val: 1.0 }, TimeWeightMethod::Midpoint);
+        s.accum(TSPoint { ts: 10, val: 0.0 }).unwrap();
+        assert_eq!(s.w_sum, 5.0);
+        s.accum(TSPoint { ts: 20, val: 2.0 }).unwrap();
+        assert_eq!(s.w_sum, 15.0);
+        s.accum(TSPoint { ts: 30, val: 1.0 }).unwrap();
+        assert_eq!(s.w_sum, 30.0);
+    }
+
+    #[test]
+    fn test_bounds_nocb_midpoint() {
+        let points = [TSPoint { ts: 10, val: 1.0 }, TSPoint { ts: 20, val: 3.0 }];
+        let prev = TSPoint { ts: 0, val: 5.0 };
+        let next = TSPoint { ts: 30, val: 7.0 };
+
+        // nocb takes the value of the point after the target
+        let test = TimeWeightSummary::new_from_sorted_iter(&points, TimeWeightMethod::NOCB).unwrap();
+        let bounded = test.with_bounds(Some((5, prev)), Some((25, Some(next)))).unwrap();
+        assert_eq!(bounded.first, TSPoint { ts: 5, val: 1.0 });
+        assert_eq!(bounded.last, TSPoint { ts: 25, val: 7.0 });
+        assert_eq!(bounded.w_sum, 1.0 * 5.0 + 3.0 * 10.0 + 7.0 * 5.0);
+        // and needs a next point to extend the end
+        assert_eq!(
+            test.with_bounds(None, Some((25, None))),
+            Err(TimeWeightError::InterpolateMissingPoint)
+        );
+
+        // midpoint steps halfway between the points, so 2 is before the step from prev and 8
+        // is after it, while 22 is before the step to next and 28 after it
+        let test = TimeWeightSummary::new_from_sorted_iter(&points, TimeWeightMethod::Midpoint).unwrap();
+        let bounded = test.with_bounds(Some((2, prev)), Some((22, Some(next)))).unwrap();
+        assert_eq!(bounded.first, TSPoint { ts: 2, val: 5.0 });
+        assert_eq!(bounded.last, TSPoint { ts: 22, val: 3.0 });
+        assert_eq!(bounded.w_sum, 5.0 * 3.0 + 1.0 * 5.0 + 2.0 * 10.0 + 3.0 * 2.0);
+        let bounded = test.with_bounds(Some((8, prev)), Some((28, Some(next)))).unwrap();
+        assert_eq!(bounded.first, TSPoint { ts: 8, val: 1.0 });
+        assert_eq!(bounded.last, TSPoint { ts: 28, val: 7.0 });
+        assert_eq!(bounded.w_sum, 1.0 * 2.0 + 2.0 * 10.0 + 3.0 * 5.0 + 7.0 * 3.0);
+        assert_eq!(
+            test.with_bounds(None, Some((25, None))),
+            Err(TimeWeightError::InterpolateMissingPoint)
+        );
+    }
+
     #[test]
     fn test_weighted_sum() {
         let pt1 = TSPoint { ts: 10, val: 20.0 };
@@ -636,6 +951,8 @@ mod tests {
         // now some common tests:
         with_prev_common_test(TimeWeightMethod::Linear);
         with_prev_common_test(TimeWeightMethod::LOCF);
+        with_prev_common_test(TimeWeightMethod::NOCB);
+        with_prev_common_test(TimeWeightMethod::Midpoint);
     }
 
     fn with_next_common_test(t: TimeWeightMethod) {
@@ -743,6 +1060,8 @@ mod tests {
         // now some common tests:
         with_next_common_test(TimeWeightMethod::Linear);
         with_next_common_test(TimeWeightMethod::LOCF);
+        with_next_common_test(TimeWeightMethod::NOCB);
+        with_next_common_test(TimeWeightMethod::Midpoint);
     }
 
     // add average tests
@@ -757,6 +1076,8 @@ mod tests {
     fn test_average() {
         average_common_tests(TimeWeightMethod::Linear);
         average_common_tests(TimeWeightMethod::LOCF);
+        average_common_tests(TimeWeightMethod::NOCB);
+        average_common_tests(TimeWeightMethod::Midpoint);
 
         let test = TimeWeightSummary::new_from_sorted_iter(
             vec![
@@ -781,4 +1102,228 @@ mod tests {
         let expected = (10.0 * 1.5 + 10.0 * 2.5) / (30.0 - 10.0);
         assert_eq!(test.time_weighted_average().unwrap(), expected);
     }
+
+    #[test]
+    fn test_with_bounds() {
+        let test = TimeWeightSummary::new_from_sorted_iter(
+            vec![&TSPoint { ts: 10, val: 1.0 }, &TSPoint { ts: 20, val: 3.0 }],
+            TimeWeightMethod::Linear,
+        )
+        .unwrap();
+        // both bounds are applied
+        let bounded = test
+            .with_bounds(
+                Some((5, TSPoint { ts: 0, val: 0.0 })),
+                Some((25, Some(TSPoint { ts: 30, val: 5.0 }))),
+            )
+            .unwrap();
+        let expected = TimeWeightSummary::new_from_sorted_iter(
+            vec![
+                &TSPoint { ts: 5, val: 0.5 },
+                &TSPoint { ts: 10, val: 1.0 },
+                &TSPoint { ts: 20, val: 3.0 },
+                &TSPoint { ts: 25, val: 4.0 },
+            ],
+            TimeWeightMethod::Linear,
+        )
+        .unwrap();
+        assert_eq!(bounded, expected);
+    }
+
+    fn summary(points: &[(i64, f64)], t: TimeWeightMethod) -> TimeWeightSummary {
+        let points: Vec<_> = points.iter().map(|&(ts, val)| TSPoint { ts, val }).collect();
+        TimeWeightSummary::new_from_sorted_iter(&points, t).unwrap()
+    }
+
+    #[test]
+    fn test_interpolate() {
+        use TimeWeightMethod::*;
+        for &t in &[LOCF, Linear, NOCB, Midpoint] {
+            let prev = summary(&[(0, 2.0), (5, 4.0)], t);
+            let test = summary(&[(12, 1.0), (15, 3.0)], t);
+            let next = summary(&[(24, 1.0), (30, 0.0)], t);
+            let all = summary(&[(0, 2.0), (5, 4.0), (12, 1.0), (15, 3.0), (24, 1.0), (30, 0.0)], t);
+
+            // interpolating each interval and combining them is the same as summarizing all the points
+            let first = summary(&[(0, 2.0), (5, 4.0)], t).interpolate(0, 10, None, Some(&test)).unwrap();
+            let second = test.interpolate(10, 10, Some(&prev), Some(&next)).unwrap();
+            let third = next.interpolate(20, 20, Some(&test), None).unwrap();
+            assert_eq!(first.first.ts, 0);
+            assert_eq!(second.first.ts, 10);
+            assert_eq!(second.last.ts, 20);
+            assert_eq!(third.first.ts, 20);
+            assert!((first.w_sum + second.w_sum + third.w_sum - all.w_sum).abs() < 1e-9);
+
+            // the interval must contain the summary, and the neighbours must be outside of it
+            assert_eq!(test.interpolate(13, 10, None, None), Err(TimeWeightError::OrderError));
+            assert_eq!(test.interpolate(0, 15, None, None), Err(TimeWeightError::OrderError));
+            assert_eq!(test.interpolate(5, 10, Some(&prev), None), Err(TimeWeightError::OrderError));
+            assert_eq!(test.interpolate(10, 15, None, Some(&next)), Err(TimeWeightError::OrderError));
+        }
+
+        // without a next summary only locf extends to the end of the interval
+        let test = summary(&[(12, 1.0), (15, 3.0)], LOCF);
+        assert_eq!(test.interpolate(10, 10, None, None).unwrap().last, TSPoint { ts: 20, val: 3.0 });
+        let test = summary(&[(12, 1.0), (15, 3.0)], Linear);
+        assert_eq!(test.interpolate(10, 10, None, None).unwrap(), test);
+
+        let other = summary(&[(0, 2.0), (5, 4.0)], Linear);
+        assert_eq!(
+            summary(&[(12, 1.0), (15, 3.0)], LOCF).interpolate(10, 10, Some(&other), None),
+            Err(TimeWeightError::MethodMismatch)
+        );
+    }
+
+    fn gapped_summary(points: &[(i64, f64)], t: TimeWeightMethod, max_gap: i64) -> TimeWeightSummary {
+        let points: Vec<_> = points.iter().map(|&(ts, val)| TSPoint { ts, val }).collect();
+        TimeWeightSummary::new_from_sorted_iter_with_max_gap(&points, t, Some(max_gap)).unwrap()
+    }
+
+    #[test]
+    fn test_max_gap() {
+        use TimeWeightMethod::*;
+        // the segment between 20 and 50 is longer than max_gap so it is ignored
+        let points = [(0, 1.0), (10, 3.0), (20, 5.0), (50, 100.0), (60, 7.0)];
+        let s = gapped_summary(&points, LOCF, 10);
+        assert_eq!(s.covered, 30);
+        assert_eq!(s.w_sum, 1.0 * 10.0 + 3.0 * 10.0 + 100.0 * 10.0);
+        assert_eq!(s.time_weighted_average().unwrap(), 1040.0 / 30.0);
+        // locf carries the last value forward for max_gap, to 70 of the 100 long interval
+        assert_eq!(s.coverage(0, 100, None, None).unwrap(), 0.4);
+        // while the other methods end at the last point
+        assert_eq!(gapped_summary(&points, Linear, 10).coverage(0, 100, None, None).unwrap(), 0.3);
+
+        let s = gapped_summary(&points, Linear, 10);
+        assert_eq!(s.covered, 30);
+        assert_eq!(s.w_sum, 2.0 * 10.0 + 4.0 * 10.0 + 53.5 * 10.0);
+
+        // a segment exactly max_gap long is still covered
+        let s = gapped_summary(&points, LOCF, 30);
+        assert_eq!(s.covered, 60);
+        assert_eq!(s.coverage(0, 80, None, None).unwrap(), 1.0);
+        // unless the next point is too far away
+        let next = gapped_summary(&[(100, 1.0)], LOCF, 30);
+        assert_eq!(s.coverage(0, 100, None, Some(&next)).unwrap(), 0.9);
+        let next = gapped_summary(&[(80, 1.0)], LOCF, 30);
+        assert_eq!(s.coverage(0, 80, None, Some(&next)).unwrap(), 1.0);
+        // or the interval starts before the first point, and there's no previous one
+        assert_eq!(s.coverage(-20, 100, None, None).unwrap(), 0.8);
+        let prev = gapped_summary(&[(-40, 1.0)], LOCF, 30);
+        assert_eq!(s.coverage(-20, 100, Some(&prev), None).unwrap(), 0.9);
+
+        // without any covered segment there is no average
+        let s = gapped_summary(&[(0, 1.0), (20, 2.0)], LOCF, 10);
+        assert_eq!(s.time_weighted_average(), Err(TimeWeightError::ZeroDuration));
+        assert_eq!(s.coverage(0, 40, None, None).unwrap(), 0.25);
+        assert_eq!(gapped_summary(&[(0, 1.0), (20, 2.0)], Linear, 10).coverage(0, 40, None, None).unwrap(), 0.0);
+        // a single point is covered for as long as it is carried forward
+        assert_eq!(gapped_summary(&[(0, 1.0)], LOCF, 10).coverage(0, 40, None, None).unwrap(), 0.25);
+        assert_eq!(summary(&[(0, 1.0)], LOCF).coverage(0, 40, None, None).unwrap(), 1.0);
+        assert_eq!(s.coverage(5, 40, None, None), Err(TimeWeightError::OrderError));
+
+        // combining skips the gap between the summaries in the same way
+        for &t in &[LOCF, Linear, NOCB, Midpoint] {
+            let first = gapped_summary(&points[..3], t, 10);
+            let second = gapped_summary(&points[3..], t, 10);
+            assert_eq!(first.combine(&second).unwrap(), gapped_summary(&points, t, 10));
+            assert_eq!(
+                first.combine(&summary(&points[3..], t)),
+                Err(TimeWeightError::MaxGapMismatch)
+            );
+        }
+
+        // summaries without a max_gap can be given one
+        let s = summary(&points[3..], LOCF);
+        assert_eq!(s.with_max_gap(10).unwrap(), gapped_summary(&points[3..], LOCF, 10));
+        assert_eq!(
+            gapped_summary(&points[3..], LOCF, 5).with_max_gap(10),
+            Err(TimeWeightError::MaxGapMismatch)
+        );
+    }
+
+    #[test]
+    fn test_max_gap_bounds() {
+        use TimeWeightMethod::*;
+        let prev = TSPoint { ts: 0, val: 2.0 };
+        let next = TSPoint { ts: 100, val: 4.0 };
+        for &t in &[LOCF, Linear, NOCB, Midpoint] {
+            // points within max_gap are interpolated to as usual
+            let s = gapped_summary(&[(10, 1.0), (20, 3.0)], t, 10);
+            let bounded = s.with_bounds(Some((5, prev)), None).unwrap();
+            let expected = summary(&[(10, 1.0), (20, 3.0)], t)
+                .with_bounds(Some((5, prev)), None)
+                .unwrap();
+            assert_eq!(bounded.w_sum, expected.w_sum);
+            assert_eq!(bounded.covered, 15);
+
+            // points further away are not
+            let s = gapped_summary(&[(20, 1.0), (30, 3.0)], t, 10);
+            let bounded = s.with_bounds(Some((15, prev)), Some((35, Some(next)))).unwrap();
+            match t {
+                // the last value is still carried forward for max_gap
+                LOCF => {
+                    assert_eq!(bounded.w_sum, 1.0 * 10.0 + 3.0 * 5.0);
+                    assert_eq!(bounded.covered, 15);
+                }
+                _ => assert_eq!(bounded, s),
+            }
+        }
+
+        // locf carries the value forward for at most max_gap
+        let s = gapped_summary(&[(20, 1.0), (30, 3.0)], LOCF, 10);
+        let bounded = s.with_bounds(Some((5, prev)), Some((100, None))).unwrap();
+        assert_eq!(bounded.w_sum, 2.0 * 5.0 + 1.0 * 10.0 + 3.0 * 10.0);
+        assert_eq!(bounded.covered, 25);
+        assert_eq!(bounded.time_weighted_average().unwrap(), 50.0 / 25.0);
+    }
+
+    #[test]
+    fn test_variance() {
+        use TimeWeightMethod::*;
+        // 1 for 10, 3 for 30, the average is 2.5 and the variance 0.75
+        let s = summary(&[(0, 1.0), (10, 3.0), (40, 3.0)], LOCF);
+        assert_eq!(s.w_sum_sq, Some(1.0 * 10.0 + 9.0 * 30.0));
+        assert_eq!(s.time_weighted_variance().unwrap(), 0.75);
+        assert_eq!(s.time_weighted_stddev().unwrap(), 0.75f64.sqrt());
+
+        // a line from 0 to 6 has mean 3 and variance 6^2 / 12
+        let s = summary(&[(0, 0.0), (10, 6.0)], Linear);
+        assert_eq!(s.w_sum_sq, Some(120.0));
+        assert!((s.time_weighted_variance().unwrap() - 3.0).abs() < 1e-12);
+
+        // half the time at each value
+        let s = summary(&[(0, 0.0), (10, 6.0)], Midpoint);
+        assert_eq!(s.time_weighted_variance().unwrap(), 9.0);
+        let s = summary(&[(0, 5.0), (10, 5.0), (20, 5.0)], NOCB);
+        assert_eq!(s.time_weighted_variance().unwrap(), 0.0);
+        assert_eq!(summary(&[(0, 5.0)], LOCF).time_weighted_stddev(), Err(TimeWeightError::ZeroDuration));
+
+        // the sum of squares combines exactly, including the bounds
+        let points = [(0, 2.0), (10, 4.0), (20, 1.0), (30, 5.0), (40, 2.0)];
+        for &t in &[LOCF, Linear, NOCB, Midpoint] {
+            let all = summary(&points, t);
+            let combined = summary(&points[..2], t).combine(&summary(&points[2..], t)).unwrap();
+            assert_eq!(combined.w_sum_sq, all.w_sum_sq);
+
+            let bounded = summary(&points[1..3], t)
+                .with_bounds(
+                    Some((5, TSPoint { ts: 0, val: 2.0 })),
+                    Some((25, Some(TSPoint { ts: 30, val: 5.0 }))),
+                )
+                .unwrap();
+            let head = summary(&points[..1], t).with_bounds(None, Some((5, Some(TSPoint { ts: 10, val: 4.0 })))).unwrap();
+            let tail = summary(&points[3..], t).with_bounds(Some((25, TSPoint { ts: 20, val: 1.0 })), None).unwrap();
+            let parts = head.w_sum_sq.unwrap() + bounded.w_sum_sq.unwrap() + tail.w_sum_sq.unwrap();
+            assert!((parts - all.w_sum_sq.unwrap()).abs() < 1e-9);
+        }
+
+        // summaries stored without the sum of squares have no variance, nor does anything
+        // they are combined with
+        let old = TimeWeightSummary { w_sum_sq: None, ..summary(&points[..2], LOCF) };
+        assert_eq!(old.time_weighted_variance(), Err(TimeWeightError::MissingSumOfSquares));
+        let combined = old.combine(&summary(&points[2..], LOCF)).unwrap();
+        assert_eq!(combined.w_sum_sq, None);
+        assert_eq!(combined.time_weighted_stddev(), Err(TimeWeightError::MissingSumOfSquares));
+        assert_eq!(combined.time_weighted_average(), summary(&points, LOCF).time_weighted_average());
+    }
 }
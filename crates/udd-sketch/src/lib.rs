@@ -17,7 +17,7 @@ extern crate quickcheck_macros;
 // This is used to index the buckets of the UddSketch.  In particular, because UddSketch stores values
 // based on a logarithmic scale, we need to track negative values separately from positive values, and
 // zero also needs special casing.
-#[derive(Serialize, Deserialize, Hash, PartialEq, Eq, Copy, Clone, Ord, Debug)]
+#[derive(Serialize, Deserialize, Hash, PartialEq, Eq, Copy, Clone, Debug)]
 pub enum SketchHashKey {
     Negative(i64),
     Zero,
@@ -25,9 +25,10 @@ pub enum SketchHashKey {
     Invalid,
 }
 
+// Keys are ordered by the values they contain, so a larger negative index comes first.
 // Invalid is treated as greater than valid values (making it a nice boundary value for list end)
-impl std::cmp::PartialOrd for SketchHashKey {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+impl std::cmp::Ord for SketchHashKey {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         use self::SketchHashKey::*;
         use std::cmp::Ordering::*;
         match (self, other) {
@@ -42,7 +43,12 @@ impl std::cmp::PartialOrd for SketchHashKey {
             (_, Negative(_)) => Greater,
             (Negative(_), _) => Less,
         }
-        .into()
+    }
+}
+
+impl std::cmp::PartialOrd for SketchHashKey {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
     }
 }
 
@@ -113,9 +119,52 @@ impl SketchHashMap {
         }
     }
 
-    // Increment the count at a key, creating the entry if needed.
-    fn increment(&mut self, key: SketchHashKey) {
-        self.entry(key).count += 1;
+    // Build a map from (key, count) pairs already sorted by increasing key.
+    fn from_sorted(buckets: impl Iterator<Item=(SketchHashKey, u64)>) -> SketchHashMap {
+        let mut map = SketchHashMap::new();
+        let mut prev = SketchHashKey::Invalid;
+        for (key, count) in buckets {
+            debug_assert!(prev == SketchHashKey::Invalid || prev < key);
+            map.map.insert(key, SketchHashEntry { count, next: SketchHashKey::Invalid });
+            match prev {
+                SketchHashKey::Invalid => map.head = key,
+                prev => map.map.get_mut(&prev).unwrap().next = key,
+            }
+            prev = key;
+        }
+        map
+    }
+
+    // Increment the count at a key by `count`, creating the entry if needed.
+    fn increment(&mut self, key: SketchHashKey, count: u64) {
+        self.entry(key).count += count;
+    }
+
+    // Decrement the count at a key by `count`, removing the entry once it's empty.
+    // The key must be present with a count of at least `count`.
+    fn decrement(&mut self, key: SketchHashKey, count: u64) {
+        let entry = self.map.get_mut(&key).expect("decrementing a missing key");
+        entry.count = entry.count.checked_sub(count).expect("decrementing below zero");
+        if entry.count > 0 {
+            return;
+        }
+
+        let next = entry.next;
+        self.map.remove(&key);
+        if self.head == key {
+            self.head = next;
+            return;
+        }
+        let mut prev = self.head;
+        while self.map[&prev].next != key {
+            prev = self.map[&prev].next;
+        }
+        self.map.get_mut(&prev).unwrap().next = next;
+    }
+
+    // The count at a key, 0 if there is no entry for it.
+    fn count(&self, key: SketchHashKey) -> u64 {
+        self.map.get(&key).map_or(0, |entry| entry.count)
     }
 
     fn iter(&self) -> SketchHashIterator {
@@ -184,72 +233,116 @@ impl SketchHashMap {
     }
 }
 
+/// The reasons values can't be removed from a sketch, see `UDDSketch::remove_weighted_value`
+/// and `UDDSketch::subtract_sketch`.  In all cases the sketch is left unchanged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemovalError {
+    /// Some of the values to remove aren't in the sketch.
+    NotPresent,
+    /// The values to remove come from a sketch that was compacted more than this one, so
+    /// their buckets cover several of this sketch's buckets.
+    MoreCompacted,
+    /// Infinite and NaN values can't be removed from the sum of the sketch.
+    NotFinite,
+}
+
+/// The smallest bucket budget a sketch can always be compacted to, see `UDDSketch::resize`.
+/// Compacting eventually takes every bucket of a sign to the one at or below 1, the one
+/// above 1, or the infinite one, none of which are ever combined.
+pub const MIN_BUCKETS: u64 = 6;
+
+// As in DDSketch, negative and positive values are kept in separate stores, indexed by
+// magnitude, so the relative error guarantee holds on both sides of zero.  Zeros are
+// counted exactly, and don't count towards `max_buckets` or take part in compaction.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct UDDSketch {
-    buckets: SketchHashMap,
+    negative: SketchHashMap,
+    zero_count: u64,
+    positive: SketchHashMap,
     alpha: f64,
     gamma: f64,
     compactions: u32, // should always be smaller than 64
     max_buckets: u64,
     num_values: u64,
     values_sum: f64,
+    // exact bounds of the values added, NaN while the sketch is empty
+    min: f64,
+    max: f64,
 }
 
 impl UDDSketch {
     pub fn new(max_buckets: u64, initial_error: f64) -> Self {
         assert!(initial_error >= 1e-12 && initial_error < 1.0);
         UDDSketch {
-            buckets: SketchHashMap::new(),
+            negative: SketchHashMap::new(),
+            zero_count: 0,
+            positive: SketchHashMap::new(),
             alpha: initial_error,
             gamma: (1.0 + initial_error) / (1.0 - initial_error),
             compactions: 0,
             max_buckets: max_buckets,
             num_values: 0,
             values_sum: 0.0,
+            min: f64::NAN,
+            max: f64::NAN,
         }
     }
 
-    // This constructor is used to recreate a UddSketch from it's component data
+    // This constructor is used to recreate a UddSketch from it's component data.
+    // If `min` or `max` is NaN, for instance because the data was stored before
+    // they were tracked, it will be estimated from the outermost bucket instead.
+    #[allow(clippy::too_many_arguments)]
     pub fn new_from_data(
         max_buckets: u64,
         current_error: f64,
         compactions: u64,
         values: u64,
         sum: f64,
+        min: f64,
+        max: f64,
         keys: impl Iterator<Item=SketchHashKey>,
         counts: impl Iterator<Item=u64>
     ) -> Self {
-        let mut sketch =UDDSketch {
-            buckets: SketchHashMap::new(),
+        // the buckets are sorted by increasing value, so all the negative buckets come
+        // first, followed by the zero bucket, if any, and then the positive buckets
+        let mut buckets = keys.zip(counts).peekable();
+        let mut negative = vec![];
+        while let Some(&(key @ SketchHashKey::Negative(_), count)) = buckets.peek() {
+            negative.push((key, count));
+            buckets.next();
+        }
+        let mut zero_count = 0;
+        if let Some(&(SketchHashKey::Zero, count)) = buckets.peek() {
+            zero_count = count;
+            buckets.next();
+        }
+        let positive = SketchHashMap::from_sorted(buckets.inspect(|(key, _)|
+            assert!(matches!(key, SketchHashKey::Positive(_)), "unsorted bucket {:?}", key)
+        ));
+
+        let mut sketch = UDDSketch {
+            negative: SketchHashMap::from_sorted(negative.into_iter()),
+            zero_count,
+            positive,
             alpha: current_error,
             gamma: gamma(current_error),
             compactions: compactions as u32,
-            max_buckets: max_buckets,
+            max_buckets,
             num_values: values,
             values_sum: sum,
+            min,
+            max,
         };
-        // TODO
-        let keys: Vec<_> = keys.collect();
-        let counts: Vec<_> = counts.collect();
-        assert_eq!(keys.len(), counts.len());
-        // assert!(keys.is_sorted());
-        for i in 0..keys.len() {
-            sketch
-                .buckets
-                .map
-                .entry(keys[i])
-                .or_insert(SketchHashEntry {
-                    count: 0,
-                    next: if i == keys.len() - 1 {
-                        SketchHashKey::Invalid
-                    } else {
-                        keys[i + 1]
-                    },
-                })
-                .count = counts[i];
+        let first = sketch.bucket_iter().next().map(|(key, _)| key);
+        let last = sketch.bucket_iter().last().map(|(key, _)| key);
+        if let (Some(first), Some(last)) = (first, last) {
+            if sketch.min.is_nan() {
+                sketch.min = bucket_to_value(sketch.alpha, sketch.gamma, first);
+            }
+            if sketch.max.is_nan() {
+                sketch.max = bucket_to_value(sketch.alpha, sketch.gamma, last);
+            }
         }
-        sketch.buckets.head = keys[0];
-
         sketch
     }
 }
@@ -260,29 +353,202 @@ impl UDDSketch {
         key(value, self.gamma)
     }
 
+    // Add `count` to the bucket at `key`, in whichever store it belongs to.
+    fn increment(&mut self, key: SketchHashKey, count: u64) {
+        match key {
+            SketchHashKey::Negative(_) => self.negative.increment(key, count),
+            SketchHashKey::Zero => self.zero_count += count,
+            SketchHashKey::Positive(_) => self.positive.increment(key, count),
+            SketchHashKey::Invalid => panic!("Unable to add values to the invalid bucket"),
+        }
+    }
+
+    // The count of the bucket at `key`, in whichever store it belongs to.
+    fn count_at(&self, key: SketchHashKey) -> u64 {
+        match key {
+            SketchHashKey::Negative(_) => self.negative.count(key),
+            SketchHashKey::Zero => self.zero_count,
+            SketchHashKey::Positive(_) => self.positive.count(key),
+            SketchHashKey::Invalid => 0,
+        }
+    }
+
+    // Subtract `count` from the bucket at `key`, which must hold at least that many values.
+    fn decrement(&mut self, key: SketchHashKey, count: u64) {
+        match key {
+            SketchHashKey::Negative(_) => self.negative.decrement(key, count),
+            SketchHashKey::Zero => self.zero_count -= count,
+            SketchHashKey::Positive(_) => self.positive.decrement(key, count),
+            SketchHashKey::Invalid => panic!("Unable to remove values from the invalid bucket"),
+        }
+    }
+
+    // Zeros don't need a bucket in either store, so they don't count towards the limit.
+    fn signed_buckets_count(&self) -> usize {
+        self.negative.len() + self.positive.len()
+    }
+
+    // Compact until the buckets fit in `max_buckets`, or compacting can't combine them any
+    // further, which can leave up to `MIN_BUCKETS` buckets when the budget is smaller than that.
+    fn compact_to_fit(&mut self) {
+        while self.signed_buckets_count() > self.max_buckets as usize && !self.fully_compacted() {
+            self.compact_buckets();
+        }
+    }
+
+    // Compacting eventually takes every key to one that compacts to itself, after which the
+    // buckets stay as they are.
+    fn fully_compacted(&self) -> bool {
+        self.bucket_iter().all(|(key, _)| key.compact_key() == key)
+    }
+
     pub fn compact_buckets(&mut self) {
-        self.buckets.compact();
+        self.negative.compact();
+        self.positive.compact();
 
         self.compactions += 1;
         self.gamma *= self.gamma; // See https://arxiv.org/pdf/2004.08604.pdf Equation 3
         self.alpha = 2.0 * self.alpha / (1.0 + self.alpha.powi(2)); // See https://arxiv.org/pdf/2004.08604.pdf Equation 4
     }
 
-    pub fn bucket_iter(&self) -> SketchHashIterator {
-        self.buckets.iter()
+    // Iterate over all the buckets in order of increasing value.
+    pub fn bucket_iter(&self) -> impl Iterator<Item=(SketchHashKey, u64)> + Clone + '_ {
+        let zero = if self.zero_count != 0 { Some((SketchHashKey::Zero, self.zero_count)) } else { None };
+        self.negative.iter().chain(zero).chain(self.positive.iter())
+    }
+
+    // The (index, count) pairs of the negative store in order of increasing value,
+    // i.e. decreasing index.
+    pub fn negative_buckets(&self) -> impl Iterator<Item=(i64, u64)> + '_ {
+        self.negative.iter().map(|(key, count)| match key {
+            SketchHashKey::Negative(i) => (i, count),
+            _ => unreachable!(),
+        })
+    }
+
+    pub fn zero_count(&self) -> u64 {
+        self.zero_count
+    }
+
+    // The (index, count) pairs of the positive store in order of increasing value.
+    pub fn positive_buckets(&self) -> impl Iterator<Item=(i64, u64)> + '_ {
+        self.positive.iter().map(|(key, count)| match key {
+            SketchHashKey::Positive(i) => (i, count),
+            _ => unreachable!(),
+        })
     }
 }
 
 impl UDDSketch {
     pub fn add_value(&mut self, value: f64) {
-        self.buckets.increment(self.key(value));
+        self.add_weighted_value(value, 1);
+    }
 
-        while self.buckets.len() > self.max_buckets as usize {
-            self.compact_buckets();
+    // Add `count` copies of a value to the sketch. A count of 0 leaves the sketch unchanged.
+    pub fn add_weighted_value(&mut self, value: f64, count: u64) {
+        if count == 0 {
+            return;
+        }
+
+        self.increment(self.key(value), count);
+        self.compact_to_fit();
+
+        self.num_values += count;
+        self.values_sum += value * count as f64;
+        // f64::min and max ignore the NaN of an empty sketch
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    /// Remove `count` copies of a value previously added to the sketch.  Since a value's bucket
+    /// only depends on the number of times the sketch has been compacted, the value is removed
+    /// from the bucket it was counted in even if the sketch was compacted after it was added.
+    /// Buckets are never split back up, so the error of the sketch is unchanged.
+    pub fn remove_weighted_value(&mut self, value: f64, count: u64) -> Result<(), RemovalError> {
+        if count == 0 {
+            return Ok(());
+        }
+        if !value.is_finite() {
+            return Err(RemovalError::NotFinite);
         }
+        let key = self.key(value);
+        if self.count_at(key) < count {
+            return Err(RemovalError::NotPresent);
+        }
+
+        self.decrement(key, count);
+        self.num_values -= count;
+        self.values_sum -= value * count as f64;
+        self.update_bounds_after_removal(value, value);
+        Ok(())
+    }
 
-        self.num_values += 1;
-        self.values_sum += value;
+    pub fn remove_value(&mut self, value: f64) -> Result<(), RemovalError> {
+        self.remove_weighted_value(value, 1)
+    }
+
+    /// Remove all the values of `other`, which must have been merged into this sketch, or
+    /// built from a subset of its values.  If this sketch has been compacted more often than
+    /// `other`, `other` is compacted to match first, the other way around the buckets can't
+    /// be matched up and `RemovalError::MoreCompacted` is returned.
+    pub fn subtract_sketch(&mut self, other: &UDDSketch) -> Result<(), RemovalError> {
+        // Require matching initial parameters
+        assert!(
+            self.gamma
+                .powf(1.0 / f64::powi(2.0, self.compactions as i32))
+                == other
+                    .gamma
+                    .powf(1.0 / f64::powi(2.0, other.compactions as i32))
+        );
+
+        if other.num_values == 0 {
+            return Ok(());
+        }
+        if other.compactions > self.compactions {
+            return Err(RemovalError::MoreCompacted);
+        }
+        if !other.values_sum.is_finite() {
+            return Err(RemovalError::NotFinite);
+        }
+
+        let mut other = other.clone();
+        while other.compactions < self.compactions {
+            other.compact_buckets();
+        }
+        if other.num_values > self.num_values
+            || other.bucket_iter().any(|(key, count)| self.count_at(key) < count) {
+            return Err(RemovalError::NotPresent);
+        }
+
+        for (key, count) in other.bucket_iter() {
+            self.decrement(key, count);
+        }
+        self.num_values -= other.num_values;
+        self.values_sum -= other.values_sum;
+        self.update_bounds_after_removal(other.min, other.max);
+        Ok(())
+    }
+
+    // If the minimum or maximum was removed all we know about the new one is
+    // which bucket it's in, and that it's no further out than the old one.
+    fn update_bounds_after_removal(&mut self, removed_min: f64, removed_max: f64) {
+        if self.num_values == 0 {
+            // start over from an empty sketch, including any rounding error in the sum
+            self.values_sum = 0.0;
+            self.min = f64::NAN;
+            self.max = f64::NAN;
+            return;
+        }
+        // once estimated, a bound may no longer be one of the values, so
+        // also re-estimate it whenever its bucket has been emptied
+        let (first, _) = self.bucket_iter().next().unwrap();
+        if removed_min <= self.min || self.key(self.min) != first {
+            self.min = bucket_to_value(self.alpha, self.gamma, first).max(self.min);
+        }
+        let (last, _) = self.bucket_iter().last().unwrap();
+        if removed_max >= self.max || self.key(self.max) != last {
+            self.max = bucket_to_value(self.alpha, self.gamma, last).min(self.max);
+        }
     }
 
     pub fn merge_sketch(&mut self, other: &UDDSketch) {
@@ -313,17 +579,25 @@ impl UDDSketch {
             self.compact_buckets();
         }
 
-        for entry in other.buckets.iter() {
-            let (key, value) = entry;
-            self.buckets.entry(key).count += value;
-        }
-
-        while self.buckets.len() > self.max_buckets as usize {
-            self.compact_buckets();
+        for (key, count) in other.bucket_iter() {
+            self.increment(key, count);
         }
+        self.compact_to_fit();
 
         self.num_values += other.num_values;
         self.values_sum += other.values_sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    /// Change the bucket budget of the sketch to `max_buckets`, compacting it until it fits.  Shrinking a
+    /// sketch increases its error in the same way as if it had been built with the smaller budget, growing
+    /// it leaves the buckets, and the error, as they are.  Since three buckets of each sign can never be
+    /// combined, a sketch always needs room for at least `MIN_BUCKETS` buckets.
+    pub fn resize(&mut self, max_buckets: u64) {
+        assert!(max_buckets >= MIN_BUCKETS);
+        self.max_buckets = max_buckets;
+        self.compact_to_fit();
     }
 
     pub fn max_allowed_buckets(&self) -> u64 {
@@ -334,8 +608,9 @@ impl UDDSketch {
         self.compactions
     }
 
+    // The number of buckets in use, including the zero bucket if there are any zeros.
     pub fn current_buckets_count(&self) -> usize {
-        self.buckets.map.len()
+        self.signed_buckets_count() + (self.zero_count != 0) as usize
     }
 }
 
@@ -364,12 +639,38 @@ impl UDDSketch {
         self.alpha
     }
 
+    #[inline]
+    pub fn min(&self) -> f64 {
+        self.min
+    }
+
+    #[inline]
+    pub fn max(&self) -> f64 {
+        self.max
+    }
+
     pub fn estimate_quantile(&self, quantile: f64) -> f64 {
-        estimate_quantile(quantile, self.alpha, self.gamma, self.num_values, self.buckets.iter())
+        estimate_quantile_with_bounds(
+            quantile,
+            self.alpha,
+            self.gamma,
+            self.num_values,
+            self.min,
+            self.max,
+            self.bucket_iter(),
+        )
     }
 
     pub fn estimate_quantile_at_value(&self, value: f64) -> f64 {
-        estimate_quantile_at_value(value, self.gamma, self.num_values, self.buckets.iter())
+        estimate_quantile_at_value(value, self.gamma, self.num_values, self.bucket_iter())
+    }
+
+    pub fn histogram(&self, edges: &[f64]) -> Vec<u64> {
+        histogram(edges, self.alpha, self.gamma, self.bucket_iter())
+    }
+
+    pub fn trimmed_mean(&self, low: f64, high: f64) -> (f64, f64) {
+        trimmed_mean(low, high, self.alpha, self.gamma, self.num_values, self.bucket_iter())
     }
 }
 
@@ -398,6 +699,26 @@ pub fn estimate_quantile(
     unreachable!();
 }
 
+/// `estimate_quantile` for a sketch whose exact `min` and `max` are known: quantiles 0 and 1
+/// are exact, and no estimate falls outside the range of the values in the sketch.
+pub fn estimate_quantile_with_bounds(
+    quantile: f64,
+    alpha: f64,
+    gamma: f64,
+    num_values: u64,
+    min: f64,
+    max: f64,
+    buckets: impl Iterator<Item=(SketchHashKey, u64)>,
+) -> f64 {
+    if quantile == 0.0 {
+        return min;
+    }
+    if quantile == 1.0 {
+        return max;
+    }
+    estimate_quantile(quantile, alpha, gamma, num_values, buckets).max(min).min(max)
+}
+
 // Look up the value of the last bucket
 // This is not an efficient operation
 fn last_bucket_value(
@@ -445,6 +766,72 @@ pub fn estimate_quantile_at_value(
     1.0 // Greater than anything in the sketch
 }
 
+/// Count the values falling in each of the bins delimited by `edges`, which must be sorted in increasing order.
+/// The first bin holds the values less than `edges[0]`, bin `i` the values in `[edges[i-1], edges[i])`,
+/// and the last bin the values greater than or equal to the last edge, so `edges.len() + 1` counts are returned.
+/// Every value in a bucket is counted in the bin containing the bucket's estimated value, so values within
+/// `alpha` of an edge may be counted in the neighboring bin.
+pub fn histogram(
+    edges: &[f64],
+    alpha: f64,
+    gamma: f64,
+    buckets: impl Iterator<Item=(SketchHashKey, u64)>,
+) -> Vec<u64> {
+    let mut counts = vec![0; edges.len() + 1];
+    for (key, count) in buckets {
+        let value = bucket_to_value(alpha, gamma, key);
+        // the number of edges <= value, the comparison never reports equal so
+        // the search always ends where value would be inserted after them
+        let bin = edges
+            .binary_search_by(|&edge| if edge <= value { std::cmp::Ordering::Less } else { std::cmp::Ordering::Greater })
+            .unwrap_err();
+        counts[bin] += count;
+    }
+    counts
+}
+
+/// Estimate the mean of the values ranked between the `low` and `high` quantiles, for instance `(0.01, 0.99)`
+/// for the mean without the top and bottom 1%, or `(0.95, 1.0)` for the mean of the top 5%.  Every bucket
+/// contributes its estimated value weighted by how much of its count falls in the rank range, with the
+/// boundary buckets contributing fractionally.  Returns the estimate along with a bound on its absolute error:
+/// every value is within `alpha` of its bucket's value, relative to the value, so the estimate is off by at
+/// most `alpha` times the mean absolute value of the range.  When all the values in the range have the same
+/// sign this makes the estimate accurate to within `alpha` relative error.
+pub fn trimmed_mean(
+    low: f64,
+    high: f64,
+    alpha: f64,
+    gamma: f64,
+    num_values: u64,
+    buckets: impl Iterator<Item=(SketchHashKey, u64)>,
+) -> (f64, f64) {
+    assert!(0.0 <= low && low < high && high <= 1.0);
+    if num_values == 0 {
+        return (0.0, 0.0);
+    }
+
+    let (low_rank, high_rank) = (low * num_values as f64, high * num_values as f64);
+    let mut sum = 0.0;
+    let mut abs_sum = 0.0;
+    let mut seen = 0.0;
+    for (key, count) in buckets {
+        let (start, end) = (seen, seen + count as f64);
+        seen = end;
+        let weight = end.min(high_rank) - start.max(low_rank);
+        if weight > 0.0 {
+            let value = bucket_to_value(alpha, gamma, key);
+            sum += value * weight;
+            abs_sum += value.abs() * weight;
+        }
+        if seen >= high_rank {
+            break;
+        }
+    }
+    let weight = high_rank - low_rank;
+    // |value - estimate| <= alpha * |value|, and |value| <= |estimate| / (1 - alpha)
+    (sum / weight, alpha / (1.0 - alpha) * abs_sum / weight)
+}
+
 fn key(value: f64, gamma: f64) -> SketchHashKey {
     let negative = value < 0.0;
     let value = value.abs();
@@ -561,8 +948,14 @@ mod tests {
 
         assert_eq!(sketch4.max_error(), a1);
 
+        // the zero bucket doesn't count towards the limit, so this leaves exactly 20 buckets
         sketch1.merge_sketch(&sketch4);
         assert_eq!(sketch1.count(), 24);
+        assert_eq!(sketch1.max_error(), a1);
+        assert_eq!(sketch1.current_buckets_count(), 21);
+
+        sketch1.add_value(-1.0); // Neg. Bucket #0
+        assert_eq!(sketch1.count(), 25);
         assert_eq!(sketch1.max_error(), a2);
 
         let mut sketch5 = UDDSketch::new(20, 0.1);
@@ -573,7 +966,7 @@ mod tests {
         assert_eq!(sketch5.max_error(), a4);
 
         sketch1.merge_sketch(&sketch5);
-        assert_eq!(sketch1.count(), 144);
+        assert_eq!(sketch1.count(), 145);
         assert_eq!(sketch1.max_error(), a5); // Note that each compaction doesn't always result in half the numbers of buckets, hence a5 here instead of a4
     }
 
@@ -665,6 +1058,418 @@ mod tests {
         }
     }
 
+    #[test]
+    fn weighted_values_match_repeated_values() {
+        let mut weighted = UDDSketch::new(20, 0.1);
+        let mut repeated = UDDSketch::new(20, 0.1);
+        for i in 1..=50 {
+            let value = 1.5_f64.powi(i);
+            weighted.add_weighted_value(value, i as u64);
+            for _ in 0..i {
+                repeated.add_value(value);
+            }
+        }
+        // zero weights are ignored
+        weighted.add_weighted_value(1e10, 0);
+
+        assert_eq!(weighted.count(), repeated.count());
+        assert_eq!(weighted.max_error(), repeated.max_error());
+        assert!((weighted.sum() - repeated.sum()).abs() <= 1e-9 * repeated.sum());
+        assert_eq!(
+            weighted.bucket_iter().collect::<Vec<_>>(),
+            repeated.bucket_iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn weighted_quantiles() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let mut sketch = UDDSketch::new(200, 0.001);
+        let mut values: Vec<(f64, u64)> = (0..1000)
+            .map(|_| (rng.gen_range(-1000.0..1000.0), rng.gen_range(0..100)))
+            .collect();
+        for &(value, weight) in &values {
+            sketch.add_weighted_value(value, weight);
+        }
+        let total: u64 = values.iter().map(|(_, w)| w).sum();
+        assert_eq!(sketch.count(), total);
+
+        // the exact weighted quantile is the smallest value whose cumulative weight is
+        // greater than quantile * total, matching how the sketch picks its bucket
+        values.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        for &quantile in [0.01, 0.1, 0.25, 0.5, 0.75, 0.9, 0.99].iter() {
+            let rank = (total as f64 * quantile) as u64 + 1;
+            let mut seen = 0;
+            let target = values.iter()
+                .find(|(_, w)| {
+                    seen += w;
+                    seen >= rank
+                })
+                .unwrap()
+                .0;
+            let estimate = sketch.estimate_quantile(quantile);
+            let error = (estimate - target).abs() / target.abs();
+            assert!(
+                error <= sketch.max_error(),
+                "quantile {} estimated as {}, expected {}",
+                quantile, estimate, target
+            );
+        }
+    }
+
+    #[test]
+    fn signed_values() {
+        let mut sketch = UDDSketch::new(1000, 0.01);
+        for i in -1000..=1000 {
+            sketch.add_value(i as f64);
+        }
+        assert_eq!(sketch.max_error(), 0.01);
+        assert_eq!(sketch.zero_count(), 1);
+
+        // the relative error bound holds on both sides of zero
+        for i in 0..=2000 {
+            let quantile = i as f64 / 2000.0;
+            let expected = (-1000 + i) as f64;
+            let estimate = sketch.estimate_quantile(quantile);
+            assert!(
+                (estimate - expected).abs() <= expected.abs() * sketch.max_error(),
+                "quantile {} estimated as {}, expected {}", quantile, estimate, expected
+            );
+            assert_eq!(estimate.signum() == expected.signum(), expected != 0.0 || estimate == 0.0);
+        }
+
+        assert_eq!(sketch.estimate_quantile_at_value(-2000.0), 0.0);
+        assert_eq!(sketch.estimate_quantile_at_value(0.0), 0.5);
+        assert_eq!(sketch.estimate_quantile_at_value(2000.0), 1.0);
+        for &(value, rank) in [(-500.0, 0.25), (-1.0, 0.5), (1.0, 0.5), (500.0, 0.75)].iter() {
+            let estimate = sketch.estimate_quantile_at_value(value);
+            assert!((estimate - rank).abs() < 0.01, "rank of {} estimated as {}", value, estimate);
+        }
+    }
+
+    #[test]
+    fn zeros_are_counted_exactly() {
+        let mut sketch = UDDSketch::new(2, 0.01);
+        for _ in 0..100 {
+            sketch.add_value(0.0);
+            sketch.add_value(-0.0);
+        }
+        sketch.add_value(-5.0);
+        sketch.add_value(5.0);
+
+        // zeros don't use up any of the buckets, so nothing is compacted
+        assert_eq!(sketch.zero_count(), 200);
+        assert_eq!(sketch.times_compacted(), 0);
+        assert_eq!(sketch.current_buckets_count(), 3);
+        assert_eq!(sketch.estimate_quantile(0.5), 0.0);
+        assert!((sketch.estimate_quantile(0.0) + 5.0).abs() <= 5.0 * sketch.max_error());
+
+        // compacting the signed buckets leaves the zero count unchanged
+        sketch.add_value(6.0);
+        assert!(sketch.times_compacted() > 0);
+        assert_eq!(sketch.current_buckets_count(), 3);
+        assert_eq!(sketch.zero_count(), 200);
+        assert_eq!(sketch.estimate_quantile(0.5), 0.0);
+    }
+
+    #[test]
+    fn rebuild_from_data() {
+        let mut sketch = UDDSketch::new(20, 0.01);
+        for i in -100..=100 {
+            sketch.add_value(i as f64 / 3.0);
+        }
+        let rebuilt = UDDSketch::new_from_data(
+            sketch.max_allowed_buckets(),
+            sketch.max_error(),
+            sketch.times_compacted() as u64,
+            sketch.count(),
+            sketch.sum(),
+            sketch.min(),
+            sketch.max(),
+            sketch.bucket_iter().map(|(key, _)| key),
+            sketch.bucket_iter().map(|(_, count)| count),
+        );
+        assert_eq!(
+            rebuilt.bucket_iter().collect::<Vec<_>>(),
+            sketch.bucket_iter().collect::<Vec<_>>()
+        );
+        assert_eq!(rebuilt.zero_count(), 1);
+        assert_eq!(
+            rebuilt.negative_buckets().collect::<Vec<_>>(),
+            sketch.negative_buckets().collect::<Vec<_>>()
+        );
+        assert_eq!(rebuilt.positive_buckets().count(), sketch.positive_buckets().count());
+
+        let empty = UDDSketch::new_from_data(20, 0.01, 0, 0, 0.0, f64::NAN, f64::NAN, std::iter::empty(), std::iter::empty());
+        assert_eq!(empty.bucket_iter().count(), 0);
+    }
+
+    #[test]
+    fn exact_bounds() {
+        let mut sketch = UDDSketch::new(20, 0.01);
+        assert!(sketch.min().is_nan() && sketch.max().is_nan());
+        for i in 1..=1000 {
+            sketch.add_value(i as f64 / 7.0);
+        }
+        assert_eq!(sketch.min(), 1.0 / 7.0);
+        assert_eq!(sketch.max(), 1000.0 / 7.0);
+        assert_eq!(sketch.estimate_quantile(0.0), sketch.min());
+        assert_eq!(sketch.estimate_quantile(1.0), sketch.max());
+        for i in 0..=100 {
+            let estimate = sketch.estimate_quantile(i as f64 / 100.0);
+            assert!(sketch.min() <= estimate && estimate <= sketch.max());
+        }
+
+        let mut other = UDDSketch::new(20, 0.01);
+        other.add_weighted_value(-3.5, 4);
+        other.add_value(0.0);
+        sketch.merge_sketch(&other);
+        assert_eq!(sketch.min(), -3.5);
+        assert_eq!(sketch.max(), 1000.0 / 7.0);
+        assert_eq!(sketch.estimate_quantile(0.0), -3.5);
+
+        // merging into an empty sketch keeps the other's bounds
+        let mut empty = UDDSketch::new(20, 0.01);
+        empty.merge_sketch(&other);
+        assert_eq!((empty.min(), empty.max()), (-3.5, 0.0));
+
+        // estimates for a single repeated value are exact
+        let mut constant = UDDSketch::new(20, 0.01);
+        constant.add_weighted_value(42.0, 10);
+        assert_eq!(constant.estimate_quantile(0.5), 42.0);
+
+        // without stored bounds they're estimated from the outermost buckets
+        let rebuilt = UDDSketch::new_from_data(
+            sketch.max_allowed_buckets(),
+            sketch.max_error(),
+            sketch.times_compacted() as u64,
+            sketch.count(),
+            sketch.sum(),
+            f64::NAN,
+            f64::NAN,
+            sketch.bucket_iter().map(|(key, _)| key),
+            sketch.bucket_iter().map(|(_, count)| count),
+        );
+        assert!((rebuilt.min() + 3.5).abs() <= 3.5 * rebuilt.max_error());
+        assert!((rebuilt.max() - sketch.max()).abs() <= sketch.max() * rebuilt.max_error());
+    }
+
+    #[test]
+    fn key_order_matches_value_order() {
+        use SketchHashKey::*;
+        let mut keys = vec![Positive(2), Invalid, Negative(-1), Zero, Positive(-3), Negative(4)];
+        keys.sort();
+        assert_eq!(keys, vec![Negative(4), Negative(-1), Zero, Positive(-3), Positive(2), Invalid]);
+    }
+
+    #[test]
+    fn histogram() {
+        let mut sketch = UDDSketch::new(200, 0.001);
+        for i in -100..=100 {
+            sketch.add_value(i as f64);
+        }
+
+        // edges sitting between the values aren't affected by the sketch's error
+        let counts = sketch.histogram(&[-50.5, -0.5, 0.5, 50.5]);
+        assert_eq!(counts, vec![50, 50, 1, 50, 50]);
+        assert_eq!(counts.iter().sum::<u64>(), sketch.count());
+
+        assert_eq!(sketch.histogram(&[]), vec![201]);
+        assert_eq!(sketch.histogram(&[1000.0]), vec![201, 0]);
+        assert_eq!(sketch.histogram(&[-1000.0]), vec![0, 201]);
+    }
+
+    fn assert_same_buckets(a: &UDDSketch, b: &UDDSketch) {
+        assert_eq!(a.times_compacted(), b.times_compacted());
+        assert_eq!(a.count(), b.count());
+        assert!(a.bucket_iter().eq(b.bucket_iter()), "{:?} != {:?}",
+            a.bucket_iter().collect::<Vec<_>>(), b.bucket_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn remove_values() {
+        let mut sketch = UDDSketch::new(200, 0.01);
+        let mut expected = UDDSketch::new(200, 0.01);
+        for i in -100..=100 {
+            sketch.add_value(i as f64);
+            if i > 0 {
+                expected.add_value(i as f64);
+            }
+        }
+        for i in -100..=0 {
+            sketch.remove_value(i as f64).unwrap();
+        }
+        assert_same_buckets(&sketch, &expected);
+        assert!((sketch.sum() - expected.sum()).abs() < 1e-9);
+        // the bounds are re-estimated from the buckets when removed
+        assert_eq!(sketch.max(), 100.0);
+        assert!((sketch.min() - 1.0).abs() <= sketch.max_error() + 1e-9);
+        assert!(sketch.min() >= -100.0);
+
+        // missing values are refused without changing anything
+        assert_eq!(sketch.remove_value(-5.0), Err(RemovalError::NotPresent));
+        assert_eq!(sketch.remove_weighted_value(5.0, 2), Err(RemovalError::NotPresent));
+        assert_eq!(sketch.remove_value(f64::INFINITY), Err(RemovalError::NotFinite));
+        assert_same_buckets(&sketch, &expected);
+
+        for i in 1..=100 {
+            sketch.remove_value(i as f64).unwrap();
+        }
+        assert_eq!(sketch.count(), 0);
+        assert_eq!(sketch.current_buckets_count(), 0);
+        assert_eq!(sketch.sum(), 0.0);
+        assert!(sketch.min().is_nan() && sketch.max().is_nan());
+    }
+
+    #[test]
+    fn remove_values_after_compaction() {
+        let mut sketch = UDDSketch::new(20, 0.01);
+        for i in 1..=1000 {
+            sketch.add_weighted_value(i as f64, 2);
+        }
+        assert!(sketch.times_compacted() > 0);
+        for i in 1..=1000 {
+            sketch.remove_value(i as f64).unwrap();
+        }
+        for i in 1..=500 {
+            sketch.remove_value(i as f64).unwrap();
+        }
+
+        let mut expected = UDDSketch::new(20, 0.01);
+        for i in 501..=1000 {
+            expected.add_value(i as f64);
+        }
+        while expected.times_compacted() < sketch.times_compacted() {
+            expected.compact_buckets();
+        }
+        assert_same_buckets(&sketch, &expected);
+        // one of the copies of the maximum was removed, so it's only estimated now
+        assert!((sketch.max() - 1000.0).abs() <= 1000.0 * sketch.max_error());
+    }
+
+    #[test]
+    fn subtract_sketch() {
+        let mut a = UDDSketch::new(20, 0.01);
+        let mut b = UDDSketch::new(20, 0.01);
+        for i in 1..=10 {
+            a.add_value(i as f64);
+        }
+        for i in -1000..=1000 {
+            b.add_value(i as f64);
+        }
+        assert!(b.times_compacted() > a.times_compacted());
+
+        let mut merged = b.clone();
+        merged.merge_sketch(&a);
+        // the less compacted sketch is compacted to match
+        merged.subtract_sketch(&a).unwrap();
+        assert_same_buckets(&merged, &b);
+        assert!((merged.sum() - b.sum()).abs() < 1e-9);
+
+        // but the buckets of a more compacted sketch can't be split up to match
+        assert_eq!(a.clone().subtract_sketch(&b), Err(RemovalError::MoreCompacted));
+
+        // subtracting values that were never added is refused without changing anything
+        let before = b.clone();
+        let mut missing = UDDSketch::new(20, 0.01);
+        missing.add_value(1e9);
+        assert_eq!(b.subtract_sketch(&missing), Err(RemovalError::NotPresent));
+        assert_same_buckets(&b, &before);
+
+        b.subtract_sketch(&before).unwrap();
+        assert_eq!(b.count(), 0);
+        assert_eq!(b.current_buckets_count(), 0);
+    }
+
+    #[test]
+    fn resize() {
+        let mut sketch = UDDSketch::new(200, 0.001);
+        for i in -1000..=1000 {
+            sketch.add_value(i as f64);
+        }
+        let mut expected = sketch.clone();
+
+        sketch.resize(50);
+        assert!(sketch.current_buckets_count() <= 51);
+        assert_eq!(sketch.max_allowed_buckets(), 50);
+        assert_eq!(sketch.count(), 2001);
+        assert_eq!(sketch.min(), -1000.0);
+        assert_eq!(sketch.max(), 1000.0);
+
+        // same as if it had been compacted as part of a merge
+        while expected.current_buckets_count() > 51 {
+            expected.compact_buckets();
+        }
+        assert_eq!(sketch.times_compacted(), expected.times_compacted());
+        assert_eq!(sketch.max_error(), expected.max_error());
+        assert!(sketch.bucket_iter().eq(expected.bucket_iter()));
+        for &q in &[0.01, 0.25, 0.5, 0.75, 0.99] {
+            let exact = q * 2000.0 - 1000.0;
+            assert!((sketch.estimate_quantile(q) - exact).abs() <= exact.abs() * sketch.max_error() + 1.0);
+        }
+
+        // growing doesn't undo the compaction
+        sketch.resize(1000);
+        assert_eq!(sketch.times_compacted(), expected.times_compacted());
+        assert_eq!(sketch.max_allowed_buckets(), 1000);
+
+        // even spread-out values can be compacted down to the minimum
+        let mut sketch = UDDSketch::new(200, 0.001);
+        for &value in &[-1e10, -5.0, -1e-10, 1e-10, 5.0, 1e10] {
+            sketch.add_value(value);
+        }
+        sketch.resize(MIN_BUCKETS);
+        assert!(sketch.current_buckets_count() <= MIN_BUCKETS as usize);
+
+        // as can infinities, which are never combined with finite values
+        let mut sketch = UDDSketch::new(200, 0.001);
+        for &value in &[f64::NEG_INFINITY, -2.0, -0.5, 0.5, 2.0, f64::INFINITY] {
+            sketch.add_value(value);
+        }
+        sketch.resize(MIN_BUCKETS);
+        assert_eq!(sketch.current_buckets_count(), MIN_BUCKETS as usize);
+        assert_eq!(sketch.count(), 6);
+
+        // sketches built with a smaller budget stop compacting once it can't help
+        let mut small = UDDSketch::new(2, 0.001);
+        for &value in &[f64::NEG_INFINITY, -2.0, -0.5, 0.5, 2.0, f64::INFINITY] {
+            small.add_value(value);
+        }
+        assert_eq!(small.current_buckets_count(), MIN_BUCKETS as usize);
+        small.merge_sketch(&small.clone());
+        assert_eq!(small.current_buckets_count(), MIN_BUCKETS as usize);
+        assert_eq!(small.count(), 12);
+    }
+
+    #[test]
+    fn trimmed_mean() {
+        let mut sketch = UDDSketch::new(200, 0.01);
+        for i in 1..=100 {
+            sketch.add_value(i as f64);
+        }
+
+        for &(low, high, expected) in &[(0.0, 1.0, 50.5), (0.1, 0.9, 50.5), (0.95, 1.0, 98.0), (0.0, 0.05, 3.0)] {
+            let (mean, bound) = sketch.trimmed_mean(low, high);
+            assert!((mean - expected).abs() <= bound, "mean of ({}, {}) was {} ± {}, expected {}", low, high, mean, bound, expected);
+            assert!(bound <= 1.02 * sketch.max_error() * expected);
+        }
+
+        // boundary buckets count fractionally
+        let (mean, _) = sketch.trimmed_mean(0.005, 0.015);
+        assert!((mean - 1.5).abs() < 0.1, "{}", mean);
+
+        // with mixed signs the bound is relative to the magnitudes, not the mean
+        let mut sketch = UDDSketch::new(200, 0.01);
+        for i in -50..=50 {
+            sketch.add_value(i as f64);
+        }
+        let (mean, bound) = sketch.trimmed_mean(0.0, 1.0);
+        assert!(mean.abs() <= bound);
+        assert!(bound > 0.2);
+
+        assert_eq!(UDDSketch::new(200, 0.01).trimmed_mean(0.1, 0.9), (0.0, 0.0));
+    }
+
     use quickcheck::*;
 
     #[derive(Hash, PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Debug)]
@@ -1,6 +1,7 @@
 use serde::{Serialize, Deserialize};
 
 use std::{
+    collections::VecDeque,
     slice,
 };
 
@@ -11,6 +12,7 @@ use flat_serialize::*;
 
 use crate::{
     aggregate_utils::in_aggregate_context,
+    datum_utils::interval_to_micros,
     json_inout_funcs,
     flatten,
     palloc::Internal,
@@ -24,7 +26,7 @@ use time_series::{
 
 use counter_agg::{
     CounterSummary as InternalCounterSummary,
-    regression::RegressionSummary,
+    regression::{HigherMoments, RegressionPartials, RegressionSummary},
     range::I64Range,
 };
 
@@ -34,10 +36,13 @@ type tstzrange = Datum;
 #[allow(non_camel_case_types)]
 type bytea = pg_sys::Datum;
 
+#[allow(non_camel_case_types)]
+type interval = pg_sys::Datum;
+
 pg_type! {
     #[derive(Debug, PartialEq)]
     struct CounterSummary {
-        regress: RegressionSummary,
+        regress: RegressionPartials,
         first: TSPoint,
         second: TSPoint,
         penultimate:TSPoint,
@@ -46,11 +51,15 @@ pg_type! {
         num_resets: u64,
         num_changes: u64,
         bounds: I64RangeWrapper,
+        #[serde(default)]
+        higher_moments: [HigherMoments; (self.version >= 2) as u8],
     }
 }
 
 json_inout_funcs!(CounterSummary);
 
+const COUNTER_SUMMARY_VERSION: u8 = 2;
+
 // hack to allow us to qualify names with "timescale_analytics_experimental"
 // so that pgx generates the correct SQL
 mod timescale_analytics_experimental {
@@ -60,7 +69,10 @@ mod timescale_analytics_experimental {
 }
 
 impl<'input> CounterSummary<'input> {
-    fn to_internal_counter_summary(&self) -> InternalCounterSummary {
+    pub(crate) fn to_internal_counter_summary(&self) -> InternalCounterSummary {
+        if *self.version > COUNTER_SUMMARY_VERSION {
+            error!("unsupported CounterSummary version {}, the newest supported version is {}", self.version, COUNTER_SUMMARY_VERSION)
+        }
         InternalCounterSummary{
             first: *self.first,
             second: *self.second,
@@ -69,15 +81,22 @@ impl<'input> CounterSummary<'input> {
             reset_sum: *self.reset_sum,
             num_resets: *self.num_resets,
             num_changes: *self.num_changes,
-            regress: *self.regress,
+            // summaries from before version 2 don't have the higher moments
+            regress: RegressionSummary::from_partials(*self.regress, self.higher_moments.first().copied()),
             bounds: self.bounds.to_i64range(),
         }
     }
-    fn from_internal_counter_summary(st: InternalCounterSummary) -> Self {
+    pub(crate) fn from_internal_counter_summary(st: InternalCounterSummary) -> Self {
+        // use the oldest version that can represent the summary, so summaries without
+        // the higher moments stay readable by older versions
+        let (version, higher_moments) = match st.regress.higher_moments {
+            Some(m) => (COUNTER_SUMMARY_VERSION, vec![m]),
+            None => (1, vec![]),
+        };
         unsafe{
             flatten!(
-            CounterSummary {
-                regress: &st.regress,
+            CounterSummary version: version, {
+                regress: &st.regress.partials(),
                 first: &st.first,
                 second: &st.second,
                 penultimate: &st.penultimate,
@@ -85,7 +104,8 @@ impl<'input> CounterSummary<'input> {
                 reset_sum: &st.reset_sum,
                 num_resets: &st.num_resets,
                 num_changes: &st.num_changes,
-                bounds: &I64RangeWrapper::from_i64range(st.bounds)
+                bounds: &I64RangeWrapper::from_i64range(st.bounds),
+                higher_moments: &higher_moments,
             })
         }
     }
@@ -107,7 +127,11 @@ pub struct CounterSummaryTransState {
 }
 
 impl CounterSummaryTransState {
-    fn push_point(&mut self, value: TSPoint) {
+    pub(crate) fn new(bounds: Option<I64Range>) -> Self {
+        CounterSummaryTransState{point_buffer: vec![], bounds, summary_buffer: vec![]}
+    }
+
+    pub(crate) fn push_point(&mut self, value: TSPoint) {
         self.point_buffer.push(value);
     }
 
@@ -133,11 +157,13 @@ impl CounterSummaryTransState {
         self.summary_buffer.push(summary);
     }
 
-    fn push_summary(&mut self, other: &CounterSummaryTransState) {
-        let sum_iter = other.summary_buffer.iter();
-        for sum in sum_iter {
-            self.summary_buffer.push(sum.clone());
-        }
+    pub(crate) fn push_state(&mut self, other: &CounterSummaryTransState) {
+        self.point_buffer.extend_from_slice(&other.point_buffer);
+        self.bounds = match (self.bounds, other.bounds) {
+            (None, b) | (b, None) => b,
+            (Some(mut a), Some(b)) => {a.extend(&b); Some(a)},
+        };
+        self.summary_buffer.extend_from_slice(&other.summary_buffer);
     }
 
     fn combine_summaries(&mut self) {
@@ -146,20 +172,33 @@ impl CounterSummaryTransState {
         if self.summary_buffer.len() <= 1 {
             return
         }
-        self.summary_buffer.sort_unstable_by_key(|s| s.first.ts);
-        let mut sum_iter = self.summary_buffer.iter();
-        let mut new_summary = sum_iter.next().unwrap().clone();
-        for sum in sum_iter {
-            new_summary.combine(sum).unwrap();
-        }
+        let new_summary = match InternalCounterSummary::combine_summaries(&mut self.summary_buffer) {
+            Ok(summary) => summary.unwrap(),
+            Err(counter_agg::CounterError::OverlapError) =>
+                error!("cannot combine overlapping CounterSummaries"),
+            Err(e) => error!("cannot combine CounterSummaries: {:?}", e),
+        };
         self.summary_buffer = vec![new_summary];
     }
+
+    pub(crate) fn push_summary(&mut self, summary: InternalCounterSummary) {
+        self.summary_buffer.push(summary);
+    }
+
+    // combines everything we've seen into a single summary
+    pub(crate) fn to_summary(&mut self) -> Option<InternalCounterSummary> {
+        self.combine_summaries();
+        debug_assert!(self.summary_buffer.len() <= 1);
+        self.summary_buffer.last().cloned()
+    }
 }
 
 #[pg_extern(schema = "timescale_analytics_experimental")]
 pub fn counter_summary_trans_serialize(
     mut state: Internal<CounterSummaryTransState>,
 ) -> bytea {
+    // partial states are sent as a summary, when running in parallel they
+    // will generally overlap and are merged by `combine_summaries`
     state.combine_summaries();
     crate::do_serialize!(state)
 }
@@ -189,7 +228,7 @@ pub fn counter_agg_trans(
             };
             match state {
                 None => {
-                    let mut s = CounterSummaryTransState{point_buffer: vec![], bounds: None, summary_buffer: vec![]};
+                    let mut s = CounterSummaryTransState::new(None);
                     if let Some(r) = bounds {
                         s.bounds = get_range(r as *mut pg_sys::varlena);
                     }
@@ -223,10 +262,13 @@ pub fn counter_agg_summary_trans(
         in_aggregate_context(fcinfo, || {
             match (state, value) {
                 (state, None) => state,
-                (None, Some(value)) => Some(
-                    CounterSummaryTransState{point_buffer: vec![], bounds: None, summary_buffer: vec![value.to_internal_counter_summary()]}.into()),
+                (None, Some(value)) => {
+                    let mut state = CounterSummaryTransState::new(None);
+                    state.push_summary(value.to_internal_counter_summary());
+                    Some(state.into())
+                },
                 (Some(mut state), Some(value)) => {
-                    state.summary_buffer.push(value.to_internal_counter_summary());
+                    state.push_summary(value.to_internal_counter_summary());
                     Some(state)
                 }
             }
@@ -244,15 +286,12 @@ pub fn counter_agg_combine(
         in_aggregate_context(fcinfo, || {
             match (state1, state2) {
                 (None, None) => None,
-                (None, Some(state2)) => {let mut s = state2.clone(); s.combine_points(); Some(s.into())},
-                (Some(state1), None) => {let mut s = state1.clone(); s.combine_points(); Some(s.into())}, //should I make these return themselves?
+                (None, Some(state2)) => Some(state2.clone().into()),
+                (Some(state1), None) => Some(state1.clone().into()),
                 (Some(state1), Some(state2)) => {
                     let mut s1 = state1.clone(); // is there a way to avoid if it doesn't need it?
-                    s1.combine_points();
-                    let mut s2 = state2.clone();
-                    s2.combine_points();
-                    s2.push_summary(&s1);
-                    Some(s2.into())
+                    s1.push_state(&state2);
+                    Some(s1.into())
                 }
             }
         })
@@ -270,9 +309,7 @@ fn counter_agg_final(
                 None => return None,
                 Some(state) => state.clone(),
             };
-            state.combine_summaries();
-            debug_assert!(state.summary_buffer.len() <= 1);
-            match state.summary_buffer.pop() {
+            match state.to_summary() {
                 None => None,
                 Some(st) => {
                     // there are some edge cases that this should prevent, but I'm not sure it's necessary, we do check the bounds in the functions that use them.
@@ -287,6 +324,159 @@ fn counter_agg_final(
 }
 
 
+// State for the moving-aggregate (window function) form of counter_agg. Postgres removes rows
+// from the front of the window in the order they were added, so as long as the rows arrive in
+// time order we can keep a running summary and slide it along by removing the first point,
+// making each step O(1). We keep all the points in the window because removing a point requires
+// knowing the one that will follow it. If the rows aren't in time order (or contain duplicate
+// times) we can't maintain the summary incrementally, so we fall back to building it from the
+// points in the final function and tell Postgres to restart the window on inverse transitions.
+#[derive(Debug, Clone)]
+pub struct CounterSummaryMovingState {
+    points: VecDeque<TSPoint>,
+    summary: Option<InternalCounterSummary>,
+    in_order: bool,
+    bounds: Option<I64Range>,
+}
+
+impl CounterSummaryMovingState {
+    fn push_point(&mut self, p: TSPoint) {
+        self.points.push_back(p);
+        if !self.in_order {
+            return
+        }
+        match &mut self.summary {
+            None => self.summary = Some(InternalCounterSummary::new(&p, self.bounds)),
+            Some(summary) if p.ts > summary.last.ts => summary.add_point(&p).unwrap(),
+            Some(_) => {
+                self.in_order = false;
+                self.summary = None;
+            },
+        }
+    }
+
+    // returns false if the point can't be removed incrementally and the window must be restarted
+    fn remove_point(&mut self, p: TSPoint) -> bool {
+        if !self.in_order || self.points.front() != Some(&p) {
+            return false
+        }
+        self.points.pop_front();
+        let summary = match &mut self.summary {
+            None => return false,
+            Some(summary) => summary,
+        };
+        match self.points.len() {
+            0 => self.summary = None,
+            1 => summary.remove_first(&self.points[0]).unwrap(),
+            _ => summary.remove_first(&self.points[1]).unwrap(),
+        }
+        true
+    }
+
+    fn to_summary(&self) -> Option<InternalCounterSummary> {
+        if self.in_order {
+            return self.summary.clone();
+        }
+        let mut points: Vec<_> = self.points.iter().copied().collect();
+        points.sort_by_key(|p| p.ts);
+        let mut iter = points.iter();
+        let mut summary = InternalCounterSummary::new(iter.next()?, self.bounds);
+        for p in iter {
+            summary.add_point(p).unwrap();
+        }
+        Some(summary)
+    }
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn counter_agg_moving_trans(
+    state: Option<Internal<CounterSummaryMovingState>>,
+    ts: Option<pg_sys::TimestampTz>,
+    val: Option<f64>,
+    bounds: Option<tstzrange>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<CounterSummaryMovingState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let p = match (ts, val) {
+                (_, None) => return state,
+                (None, _) => return state,
+                (Some(ts), Some(val)) => TSPoint{ts, val},
+            };
+            let mut state = match state {
+                Some(state) => state,
+                None => {
+                    let bounds = bounds.and_then(|r| get_range(r as *mut pg_sys::varlena));
+                    CounterSummaryMovingState{points: VecDeque::new(), summary: None, in_order: true, bounds}.into()
+                },
+            };
+            state.push_point(p);
+            Some(state)
+        })
+    }
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn counter_agg_moving_trans_no_bounds(
+    state: Option<Internal<CounterSummaryMovingState>>,
+    ts: Option<pg_sys::TimestampTz>,
+    val: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<CounterSummaryMovingState>> {
+    counter_agg_moving_trans(state, ts, val, None, fcinfo)
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn counter_agg_moving_inv(
+    state: Option<Internal<CounterSummaryMovingState>>,
+    ts: Option<pg_sys::TimestampTz>,
+    val: Option<f64>,
+    _bounds: Option<tstzrange>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<CounterSummaryMovingState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let p = match (ts, val) {
+                (_, None) => return state,
+                (None, _) => return state,
+                (Some(ts), Some(val)) => TSPoint{ts, val},
+            };
+            let mut state = state?;
+            // returning NULL makes Postgres recompute the aggregate over the current window
+            if !state.remove_point(p) {
+                return None
+            }
+            Some(state)
+        })
+    }
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn counter_agg_moving_inv_no_bounds(
+    state: Option<Internal<CounterSummaryMovingState>>,
+    ts: Option<pg_sys::TimestampTz>,
+    val: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<CounterSummaryMovingState>> {
+    counter_agg_moving_inv(state, ts, val, None, fcinfo)
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+fn counter_agg_moving_final(
+    state: Option<Internal<CounterSummaryMovingState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<timescale_analytics_experimental::CounterSummary<'static>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let st = state?.to_summary()?;
+            if !st.bounds_valid() {
+                panic!("counter bounds invalid")
+            }
+            Some(CounterSummary::from_internal_counter_summary(st).into())
+        })
+    }
+}
+
 extension_sql!(r#"
 CREATE AGGREGATE timescale_analytics_experimental.counter_agg( ts timestamptz, value DOUBLE PRECISION, bounds tstzrange )
 (
@@ -296,7 +486,11 @@ CREATE AGGREGATE timescale_analytics_experimental.counter_agg( ts timestamptz, v
     combinefunc = timescale_analytics_experimental.counter_agg_combine,
     serialfunc = timescale_analytics_experimental.counter_summary_trans_serialize,
     deserialfunc = timescale_analytics_experimental.counter_summary_trans_deserialize,
-    parallel = restricted
+    msfunc = timescale_analytics_experimental.counter_agg_moving_trans,
+    minvfunc = timescale_analytics_experimental.counter_agg_moving_inv,
+    mstype = internal,
+    mfinalfunc = timescale_analytics_experimental.counter_agg_moving_final,
+    parallel = safe
 );
 "#);
 
@@ -310,7 +504,11 @@ CREATE AGGREGATE timescale_analytics_experimental.counter_agg( ts timestamptz, v
     combinefunc = timescale_analytics_experimental.counter_agg_combine,
     serialfunc = timescale_analytics_experimental.counter_summary_trans_serialize,
     deserialfunc = timescale_analytics_experimental.counter_summary_trans_deserialize,
-    parallel = restricted
+    msfunc = timescale_analytics_experimental.counter_agg_moving_trans_no_bounds,
+    minvfunc = timescale_analytics_experimental.counter_agg_moving_inv_no_bounds,
+    mstype = internal,
+    mfinalfunc = timescale_analytics_experimental.counter_agg_moving_final,
+    parallel = safe
 );
 "#);
 
@@ -323,7 +521,7 @@ CREATE AGGREGATE timescale_analytics_experimental.rollup(cs timescale_analytics_
     combinefunc = timescale_analytics_experimental.counter_agg_combine,
     serialfunc = timescale_analytics_experimental.counter_summary_trans_serialize,
     deserialfunc = timescale_analytics_experimental.counter_summary_trans_deserialize,
-    parallel = restricted
+    parallel = safe
 );
 "#);
 
@@ -425,6 +623,62 @@ fn counter_agg_extrapolated_rate(
     }
 }
 
+#[pg_extern(name="extrapolated_increase", schema = "timescale_analytics_experimental", strict, immutable)]
+fn counter_agg_extrapolated_increase(
+    summary: timescale_analytics_experimental::CounterSummary,
+    method: String,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> Option<f64> {
+    match method.to_lowercase().as_str() {
+        "prometheus" => {
+            summary.to_internal_counter_summary().prometheus_increase().unwrap()
+        },
+        _ => panic!("unknown method"),
+    }
+}
+
+fn interpolated_summary(
+    summary: timescale_analytics_experimental::CounterSummary,
+    start: pg_sys::TimestampTz,
+    interval: interval,
+    prev: Option<timescale_analytics_experimental::CounterSummary>,
+    next: Option<timescale_analytics_experimental::CounterSummary>,
+) -> InternalCounterSummary {
+    let interval = unsafe { interval_to_micros(interval) };
+    let prev = prev.map(|p| p.to_internal_counter_summary());
+    let next = next.map(|n| n.to_internal_counter_summary());
+    let interpolated = summary.to_internal_counter_summary()
+        .interpolate(start, interval, prev.as_ref(), next.as_ref());
+    match interpolated {
+        Ok(summary) => summary,
+        Err(_) => error!("CounterSummaries must lie within their interval, with the previous one ending before it and the next one starting after it"),
+    }
+}
+
+#[pg_extern(name="interpolated_delta", schema = "timescale_analytics_experimental", immutable)]
+fn counter_agg_interpolated_delta(
+    summary: Option<timescale_analytics_experimental::CounterSummary>,
+    start: Option<pg_sys::TimestampTz>,
+    interval: Option<interval>,
+    prev: Option<timescale_analytics_experimental::CounterSummary>,
+    next: Option<timescale_analytics_experimental::CounterSummary>,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> Option<f64> {
+    Some(interpolated_summary(summary?, start?, interval?, prev, next).delta())
+}
+
+#[pg_extern(name="interpolated_rate", schema = "timescale_analytics_experimental", immutable)]
+fn counter_agg_interpolated_rate(
+    summary: Option<timescale_analytics_experimental::CounterSummary>,
+    start: Option<pg_sys::TimestampTz>,
+    interval: Option<interval>,
+    prev: Option<timescale_analytics_experimental::CounterSummary>,
+    next: Option<timescale_analytics_experimental::CounterSummary>,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> Option<f64> {
+    interpolated_summary(summary?, start?, interval?, prev, next).rate()
+}
+
 #[pg_extern(name="num_elements", schema = "timescale_analytics_experimental", strict, immutable)]
 fn counter_agg_num_elements(
     summary: timescale_analytics_experimental::CounterSummary,
@@ -449,6 +703,38 @@ fn counter_agg_num_resets(
     summary.to_internal_counter_summary().num_resets as i64
 }
 
+#[pg_extern(name="changes_in", schema = "timescale_analytics_experimental", strict, immutable)]
+fn counter_agg_changes_in(
+    summary: timescale_analytics_experimental::CounterSummary,
+    range: tstzrange,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> i64 {
+    let range = match unsafe { get_range(range as *mut pg_sys::varlena) } {
+        None => return 0, // empty range
+        Some(range) => range,
+    };
+    match summary.to_internal_counter_summary().num_changes_in(range) {
+        Ok(changes) => changes as i64,
+        Err(_) => error!("range must include all but the first and last points of the CounterSummary"),
+    }
+}
+
+#[pg_extern(name="resets_in", schema = "timescale_analytics_experimental", strict, immutable)]
+fn counter_agg_resets_in(
+    summary: timescale_analytics_experimental::CounterSummary,
+    range: tstzrange,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> i64 {
+    let range = match unsafe { get_range(range as *mut pg_sys::varlena) } {
+        None => return 0, // empty range
+        Some(range) => range,
+    };
+    match summary.to_internal_counter_summary().num_resets_in(range) {
+        Ok(resets) => resets as i64,
+        Err(_) => error!("range must include all but the first and last points of the CounterSummary"),
+    }
+}
+
 #[pg_extern(name="slope", schema = "timescale_analytics_experimental", strict, immutable)]
 fn counter_agg_slope(
     summary: timescale_analytics_experimental::CounterSummary,
@@ -473,6 +759,22 @@ fn counter_agg_corr(
     summary.to_internal_counter_summary().regress.corr()
 }
 
+#[pg_extern(name="skewness", schema = "timescale_analytics_experimental", strict, immutable)]
+fn counter_agg_skewness(
+    summary: timescale_analytics_experimental::CounterSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> Option<f64> {
+    summary.to_internal_counter_summary().skewness()
+}
+
+#[pg_extern(name="kurtosis", schema = "timescale_analytics_experimental", strict, immutable)]
+fn counter_agg_kurtosis(
+    summary: timescale_analytics_experimental::CounterSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> Option<f64> {
+    summary.to_internal_counter_summary().kurtosis()
+}
+
 #[pg_extern(name="counter_zero_time", schema = "timescale_analytics_experimental", strict, immutable)]
 fn counter_agg_counter_zero_time(
     summary: timescale_analytics_experimental::CounterSummary,
@@ -564,6 +866,13 @@ mod tests {
             let stmt = "SELECT corr(counter_agg(ts, val)) FROM test";
             assert_relative_eq!(select_one!(client, stmt, f64), 1.0);
 
+            // the reset-adjusted values are 10, 20, 30, 40, 50
+            let stmt = "SELECT skewness(counter_agg(ts, val)) FROM test";
+            assert_relative_eq!(select_one!(client, stmt, f64), 0.0, epsilon = 1e-10);
+
+            let stmt = "SELECT kurtosis(counter_agg(ts, val)) FROM test";
+            assert_relative_eq!(select_one!(client, stmt, f64), 1.7, max_relative = 1e-10);
+
             let stmt = "SELECT counter_zero_time(counter_agg(ts, val)) FROM test";
             let zp = select_one!(client, stmt, i64);
             let real_zp = select_one!(client, "SELECT '2019-12-31 23:59:00+00'::timestamptz", i64);
@@ -590,6 +899,168 @@ mod tests {
         });
     }
 
+    #[pg_test]
+    fn test_counter_aggregate_parallel() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test(ts timestamptz, val DOUBLE PRECISION)", None, None);
+            // set search_path after defining our table so we don't pollute the wrong schema
+            let stmt = "SELECT format('timescale_analytics_experimental, %s',current_setting('search_path'))";
+            let search_path = select_one!(client, stmt, String);
+            client.select(&format!("SET LOCAL search_path TO {}", search_path), None, None);
+            // a counter that never resets, inserted out of order so the rows each worker sees are
+            // interleaved in time
+            let stmt = "INSERT INTO test SELECT '2020-01-01 00:00:00+00'::timestamptz + (i || ' seconds')::interval, i FROM generate_series(1, 10000) i ORDER BY random()";
+            client.select(stmt, None, None);
+            client.select("ANALYZE test", None, None);
+
+            let stmt = "SELECT counter_agg(ts, val) FROM test";
+            let serial = select_one!(client, stmt, timescale_analytics_experimental::CounterSummary).to_internal_counter_summary();
+
+            client.select("SET LOCAL parallel_setup_cost = 0", None, None);
+            client.select("SET LOCAL parallel_tuple_cost = 0", None, None);
+            client.select("SET LOCAL min_parallel_table_scan_size = 0", None, None);
+            client.select("SET LOCAL max_parallel_workers_per_gather = 4", None, None);
+            client.select("SET LOCAL force_parallel_mode = on", None, None);
+            let parallel = select_one!(client, stmt, timescale_analytics_experimental::CounterSummary).to_internal_counter_summary();
+            assert_close_enough(&serial, &parallel);
+            assert_eq!(parallel.num_changes, 9999);
+
+            // rollup combines disjoint summaries no matter what order they arrive in
+            let stmt = "WITH t as (SELECT date_trunc('minute', ts), counter_agg(ts, val) as agg FROM test group by 1 ORDER BY random()) SELECT rollup(agg) FROM t";
+            let rolled = select_one!(client, stmt, timescale_analytics_experimental::CounterSummary).to_internal_counter_summary();
+            assert_close_enough(&serial, &rolled);
+
+            // and merges overlapping ones as long as the counter doesn't reset
+            let stmt = "WITH t as (SELECT val::int % 3, counter_agg(ts, val) as agg FROM test group by 1) SELECT rollup(agg) FROM t";
+            let interleaved = select_one!(client, stmt, timescale_analytics_experimental::CounterSummary).to_internal_counter_summary();
+            assert_close_enough(&serial, &interleaved);
+        });
+    }
+
+    #[pg_test(error = "cannot combine overlapping CounterSummaries")]
+    fn test_counter_rollup_overlap_reset() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test(ts timestamptz, val DOUBLE PRECISION)", None, None);
+            // set search_path after defining our table so we don't pollute the wrong schema
+            let stmt = "SELECT format('timescale_analytics_experimental, %s',current_setting('search_path'))";
+            let search_path = select_one!(client, stmt, String);
+            client.select(&format!("SET LOCAL search_path TO {}", search_path), None, None);
+            let stmt = "INSERT INTO test SELECT '2020-01-01 00:00:00+00'::timestamptz + (i || ' seconds')::interval, i % 50 FROM generate_series(1, 100) i";
+            client.select(stmt, None, None);
+
+            // we can't tell where the reset in each summary happened relative to the other's points
+            let stmt = "WITH t as (SELECT extract(epoch from ts)::int % 2, counter_agg(ts, val) as agg FROM test group by 1) SELECT rollup(agg) FROM t";
+            client.select(stmt, None, None);
+        });
+    }
+
+    #[pg_test(error = "cannot combine overlapping CounterSummaries")]
+    fn test_counter_rollup_overlap() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test(ts timestamptz, val DOUBLE PRECISION)", None, None);
+            // set search_path after defining our table so we don't pollute the wrong schema
+            let stmt = "SELECT format('timescale_analytics_experimental, %s',current_setting('search_path'))";
+            let search_path = select_one!(client, stmt, String);
+            client.select(&format!("SET LOCAL search_path TO {}", search_path), None, None);
+            let stmt = "INSERT INTO test SELECT '2020-01-01 00:00:00+00'::timestamptz + (i || ' seconds')::interval, i FROM generate_series(1, 100) i";
+            client.select(stmt, None, None);
+
+            let stmt = "WITH t as (SELECT i % 2, counter_agg(ts, val) as agg FROM test, generate_series(0, 1) i group by 1) SELECT rollup(agg) FROM t";
+            client.select(stmt, None, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_counter_prometheus_functions() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test(ts timestamptz, val DOUBLE PRECISION)", None, None);
+            // set search_path after defining our table so we don't pollute the wrong schema
+            let stmt = "SELECT format('timescale_analytics_experimental, %s',current_setting('search_path'))";
+            let search_path = select_one!(client, stmt, String);
+            client.select(&format!("SET LOCAL search_path TO {}", search_path), None, None);
+            // http_requests{path="/foo"} 1 2 3 0 1 0 0 1 2 0 from Prometheus' functions.test
+            let stmt = "INSERT INTO test SELECT '2020-01-01 00:00:00+00'::timestamptz + ((i - 1) * 5 || ' minutes')::interval, v FROM unnest(ARRAY[1, 2, 3, 0, 1, 0, 0, 1, 2, 0]) WITH ORDINALITY AS t(v, i)";
+            client.select(stmt, None, None);
+
+            let stmt = "SELECT resets_in(counter_agg(ts, val), '[2020-01-01 00:30:00+00, 2020-01-01 00:50:00+00]') FROM test WHERE ts >= '2020-01-01 00:25:00+00'";
+            assert_eq!(select_one!(client, stmt, i64), 1);
+            let stmt = "SELECT changes_in(counter_agg(ts, val), '[2020-01-01 00:30:00+00, 2020-01-01 00:50:00+00]') FROM test WHERE ts >= '2020-01-01 00:25:00+00'";
+            assert_eq!(select_one!(client, stmt, i64), 3);
+            let stmt = "SELECT changes_in(counter_agg(ts, val), 'empty') FROM test";
+            assert_eq!(select_one!(client, stmt, i64), 0);
+
+            // http_requests{path="/bar"} 0+10x5 0+10x5
+            client.select("TRUNCATE test", None, None);
+            let stmt = "INSERT INTO test SELECT '2020-01-01 00:00:00+00'::timestamptz + (i * 5 || ' minutes')::interval, (i % 6) * 10 FROM generate_series(0, 11) i";
+            client.select(stmt, None, None);
+            let stmt = "SELECT extrapolated_increase(counter_agg(ts, val, '[2020-01-01 00:00:00+00, 2020-01-01 00:50:00+00]'), 'prometheus') FROM test WHERE ts <= '2020-01-01 00:50:00+00'";
+            assert_relative_eq!(select_one!(client, stmt, f64), 90.0, max_relative = 1e-6);
+        });
+    }
+
+    #[pg_test]
+    fn test_counter_interpolation() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test(ts timestamptz, val DOUBLE PRECISION)", None, None);
+            // set search_path after defining our table so we don't pollute the wrong schema
+            let stmt = "SELECT format('timescale_analytics_experimental, %s',current_setting('search_path'))";
+            let search_path = select_one!(client, stmt, String);
+            client.select(&format!("SET LOCAL search_path TO {}", search_path), None, None);
+            // resets both within the buckets and between them
+            let stmt = "INSERT INTO test VALUES('2020-01-01 00:00:06+00', 10.0), ('2020-01-01 00:00:24+00', 20.0), ('2020-01-01 00:00:48+00', 30.0), ('2020-01-01 00:01:18+00', 5.0), ('2020-01-01 00:01:30+00', 10.0), ('2020-01-01 00:01:54+00', 40.0), ('2020-01-01 00:02:12+00', 20.0), ('2020-01-01 00:02:42+00', 30.0)";
+            client.select(stmt, None, None);
+
+            let stmt = "WITH t AS (SELECT date_trunc('minute', ts) AS bucket, counter_agg(ts, val) AS agg FROM test GROUP BY 1) \
+                SELECT sum(interpolated_delta(agg, bucket, '1 min', LAG(agg) OVER (ORDER BY bucket), LEAD(agg) OVER (ORDER BY bucket))) FROM t";
+            let interpolated = select_one!(client, stmt, f64);
+            let stmt = "SELECT delta(counter_agg(ts, val)) FROM test";
+            assert_relative_eq!(interpolated, select_one!(client, stmt, f64));
+
+            // 30 at 00:00:48 -> 5 at 00:01:18 is a reset, 2 of the 5 increase falls in the first bucket
+            let stmt = "WITH t AS (SELECT date_trunc('minute', ts) AS bucket, counter_agg(ts, val) AS agg FROM test GROUP BY 1) \
+                SELECT interpolated_delta(agg, bucket, '1 min', NULL, LEAD(agg) OVER (ORDER BY bucket)) FROM t ORDER BY bucket LIMIT 1";
+            assert_relative_eq!(select_one!(client, stmt, f64), 22.0);
+            let stmt = "WITH t AS (SELECT date_trunc('minute', ts) AS bucket, counter_agg(ts, val) AS agg FROM test GROUP BY 1) \
+                SELECT interpolated_rate(agg, bucket, '1 min', NULL, LEAD(agg) OVER (ORDER BY bucket)) FROM t ORDER BY bucket LIMIT 1";
+            assert_relative_eq!(select_one!(client, stmt, f64), 22.0 / 54.0);
+        });
+    }
+
+    #[pg_test]
+    fn test_counter_moving_aggregate() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test(ts timestamptz, val DOUBLE PRECISION)", None, None);
+            // set search_path after defining our table so we don't pollute the wrong schema
+            let stmt = "SELECT format('timescale_analytics_experimental, %s',current_setting('search_path'))";
+            let search_path = select_one!(client, stmt, String);
+            client.select(&format!("SET LOCAL search_path TO {}", search_path), None, None);
+            // a counter that resets every 7 points
+            let stmt = "INSERT INTO test SELECT '2020-01-01 00:00:00+00'::timestamptz + (i || ' minutes')::interval, (i % 7) * 10.0 FROM generate_series(0, 99) i";
+            client.select(stmt, None, None);
+
+            // the sliding window gives the same results as aggregating each window from scratch
+            let stmt = "WITH windowed AS (\
+                    SELECT ts, counter_agg(ts, val) OVER (ORDER BY ts ROWS BETWEEN 5 PRECEDING AND CURRENT ROW) AS agg FROM test\
+                ), recomputed AS (\
+                    SELECT t1.ts, counter_agg(t2.ts, t2.val) AS agg FROM test t1 JOIN test t2 ON t2.ts BETWEEN t1.ts - '5 minutes'::interval AND t1.ts GROUP BY t1.ts\
+                ) \
+                SELECT count(*) FROM windowed JOIN recomputed USING (ts) \
+                WHERE num_resets(windowed.agg) != num_resets(recomputed.agg) \
+                   OR num_changes(windowed.agg) != num_changes(recomputed.agg) \
+                   OR abs(delta(windowed.agg) - delta(recomputed.agg)) > 1e-9 \
+                   OR abs(slope(windowed.agg) - slope(recomputed.agg)) > 1e-9";
+            assert_eq!(select_one!(client, stmt, i64), 0);
+
+            // windows that aren't ordered by time still work, they're just slower
+            let stmt = "WITH windowed AS (\
+                    SELECT ts, delta(counter_agg(ts, val) OVER (ORDER BY val, ts ROWS BETWEEN 2 PRECEDING AND 2 FOLLOWING)) AS d FROM test\
+                ) SELECT d FROM windowed WHERE ts = '2020-01-01 00:03:00+00'";
+            // the window contains the rows with value 30 at minutes 3, 10 and 17 and 20 at minutes 86 and 93,
+            // in time order that's a single reset from 30 down to 20
+            assert_relative_eq!(select_one!(client, stmt, f64), 20.0);
+        });
+    }
+
     // #[pg_test]
     // fn test_combine_aggregate(){
     //     Spi::execute(|client| {
@@ -0,0 +1,445 @@
+use serde::{Serialize, Deserialize};
+
+use std::{
+    collections::BTreeMap,
+    slice,
+};
+
+use pgx::*;
+use pg_sys::Datum;
+
+use flat_serialize::*;
+
+use crate::{
+    aggregate_utils::in_aggregate_context,
+    counter_agg::{CounterSummary, CounterSummaryTransState},
+    json_inout_funcs,
+    flatten,
+    palloc::Internal,
+    pg_type,
+    range::*,
+};
+
+use time_series::{
+    TSPoint,
+};
+
+use counter_agg::{
+    CounterSummary as InternalCounterSummary,
+    regression::{HigherMoments, RegressionPartials, RegressionSummary},
+};
+
+#[allow(non_camel_case_types)]
+type tstzrange = Datum;
+
+#[allow(non_camel_case_types)]
+type bytea = pg_sys::Datum;
+
+// The per-series part of a CounterSummary, the bounds are shared by all the series in a
+// MultiCounterSummary so they're stored once for the whole thing.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct SeriesCounterSummary {
+    regress: RegressionPartials,
+    first: TSPoint,
+    second: TSPoint,
+    penultimate: TSPoint,
+    last: TSPoint,
+    reset_sum: f64,
+    num_resets: u64,
+    num_changes: u64,
+}
+
+pg_type! {
+    #[derive(Debug)]
+    struct MultiCounterSummary {
+        bounds: I64RangeWrapper,
+        num_series: u64,
+        keys_bytes: u64,
+        summaries: [SeriesCounterSummary; self.num_series],
+        // the keys are stored as the concatenation of their text representations, key_ends[i] is
+        // where the i-th key ends.
+        key_ends: [u64; self.num_series],
+        keys: [u8; self.keys_bytes],
+        // the higher moments of each series, see CounterSummary
+        #[serde(default)]
+        higher_moments: [HigherMoments; (self.version >= 2) as u64 * self.num_series],
+    }
+}
+
+json_inout_funcs!(MultiCounterSummary);
+
+const MULTI_COUNTER_SUMMARY_VERSION: u8 = 2;
+
+// hack to allow us to qualify names with "timescale_analytics_experimental"
+// so that pgx generates the correct SQL
+mod timescale_analytics_experimental {
+    pub(crate) use super::*;
+
+    varlena_type!(MultiCounterSummary);
+}
+
+impl<'input> MultiCounterSummary<'input> {
+    fn series(&self) -> impl Iterator<Item=(&str, InternalCounterSummary)> + '_ {
+        if *self.version > MULTI_COUNTER_SUMMARY_VERSION {
+            error!("unsupported MultiCounterSummary version {}, the newest supported version is {}", self.version, MULTI_COUNTER_SUMMARY_VERSION)
+        }
+        let bounds = self.bounds.to_i64range();
+        let starts = std::iter::once(0).chain(self.key_ends.iter().copied());
+        // summaries from before version 2 don't have the higher moments
+        let higher_moments = self.higher_moments.iter().copied().map(Some).chain(std::iter::repeat(None));
+        self.key_ends.iter().zip(starts).zip(self.summaries.iter()).zip(higher_moments).map(move |(((&end, start), s), higher_moments)| {
+            let key = std::str::from_utf8(&self.keys[start as usize..end as usize]).unwrap();
+            let summary = InternalCounterSummary{
+                first: s.first,
+                second: s.second,
+                penultimate: s.penultimate,
+                last: s.last,
+                reset_sum: s.reset_sum,
+                num_resets: s.num_resets,
+                num_changes: s.num_changes,
+                regress: RegressionSummary::from_partials(s.regress, higher_moments),
+                bounds,
+            };
+            (key, summary)
+        })
+    }
+
+    fn from_series(series: &BTreeMap<String, InternalCounterSummary>) -> Self {
+        let mut bounds: Option<counter_agg::range::I64Range> = None;
+        let mut summaries = Vec::with_capacity(series.len());
+        let mut key_ends = Vec::with_capacity(series.len());
+        let mut keys = vec![];
+        let mut higher_moments = Vec::with_capacity(series.len());
+        for (key, s) in series {
+            bounds = match (bounds, s.bounds) {
+                (None, b) | (b, None) => b,
+                (Some(mut a), Some(b)) => {a.extend(&b); Some(a)},
+            };
+            higher_moments.extend(s.regress.higher_moments);
+            summaries.push(SeriesCounterSummary{
+                regress: s.regress.partials(),
+                first: s.first,
+                second: s.second,
+                penultimate: s.penultimate,
+                last: s.last,
+                reset_sum: s.reset_sum,
+                num_resets: s.num_resets,
+                num_changes: s.num_changes,
+            });
+            keys.extend_from_slice(key.as_bytes());
+            key_ends.push(keys.len() as u64);
+        }
+        // as with CounterSummary, use the oldest version that can represent the summary, the
+        // higher moments are only stored if every series has them
+        let version = if higher_moments.len() == summaries.len() {
+            MULTI_COUNTER_SUMMARY_VERSION
+        } else {
+            higher_moments.clear();
+            1
+        };
+        unsafe{
+            flatten!(
+            MultiCounterSummary version: version, {
+                bounds: &I64RangeWrapper::from_i64range(bounds),
+                num_series: &(summaries.len() as u64),
+                keys_bytes: &(keys.len() as u64),
+                summaries: &summaries,
+                key_ends: &key_ends,
+                keys: &keys,
+                higher_moments: &higher_moments,
+            })
+        }
+    }
+}
+
+// One counter_agg transition state per series, keyed by the canonical text of the jsonb key.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MultiCounterSummaryTransState {
+    series: BTreeMap<String, CounterSummaryTransState>,
+}
+
+impl MultiCounterSummaryTransState {
+    fn push_state(&mut self, other: &MultiCounterSummaryTransState) {
+        for (key, state) in &other.series {
+            match self.series.get_mut(key) {
+                Some(s) => s.push_state(state),
+                None => {self.series.insert(key.clone(), state.clone());},
+            }
+        }
+    }
+
+    fn to_summaries(&mut self) -> BTreeMap<String, InternalCounterSummary> {
+        self.series.iter_mut()
+            .filter_map(|(key, state)| Some((key.clone(), state.to_summary()?)))
+            .collect()
+    }
+}
+
+fn key_to_string(key: &JsonB) -> String {
+    serde_json::to_string(&key.0).unwrap()
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn multi_counter_summary_trans_serialize(
+    state: Internal<MultiCounterSummaryTransState>,
+) -> bytea {
+    crate::do_serialize!(state)
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental", strict)]
+pub fn multi_counter_summary_trans_deserialize(
+    bytes: bytea,
+    _internal: Option<Internal<()>>,
+) -> Internal<MultiCounterSummaryTransState> {
+    crate::do_deserialize!(bytes, MultiCounterSummaryTransState)
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn counter_agg_by_trans(
+    state: Option<Internal<MultiCounterSummaryTransState>>,
+    ts: Option<pg_sys::TimestampTz>,
+    val: Option<f64>,
+    key: Option<JsonB>,
+    bounds: Option<tstzrange>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<MultiCounterSummaryTransState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let (p, key) = match (ts, val, key) {
+                (Some(ts), Some(val), Some(key)) => (TSPoint{ts, val}, key_to_string(&key)),
+                _ => return state,
+            };
+            let mut state = match state {
+                Some(state) => state,
+                None => MultiCounterSummaryTransState{series: BTreeMap::new()}.into(),
+            };
+            state.series.entry(key)
+                .or_insert_with(|| {
+                    let bounds = bounds.and_then(|r| get_range(r as *mut pg_sys::varlena));
+                    CounterSummaryTransState::new(bounds)
+                })
+                .push_point(p);
+            Some(state)
+        })
+    }
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn counter_agg_by_trans_no_bounds(
+    state: Option<Internal<MultiCounterSummaryTransState>>,
+    ts: Option<pg_sys::TimestampTz>,
+    val: Option<f64>,
+    key: Option<JsonB>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<MultiCounterSummaryTransState>> {
+    counter_agg_by_trans(state, ts, val, key, None, fcinfo)
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn counter_agg_by_summary_trans(
+    state: Option<Internal<MultiCounterSummaryTransState>>,
+    value: Option<timescale_analytics_experimental::MultiCounterSummary>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<MultiCounterSummaryTransState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let value = match value {
+                None => return state,
+                Some(value) => value,
+            };
+            let mut state = match state {
+                Some(state) => state,
+                None => MultiCounterSummaryTransState{series: BTreeMap::new()}.into(),
+            };
+            for (key, summary) in value.series() {
+                match state.series.get_mut(key) {
+                    Some(s) => s.push_summary(summary),
+                    None => {
+                        let mut s = CounterSummaryTransState::new(None);
+                        s.push_summary(summary);
+                        state.series.insert(key.to_string(), s);
+                    },
+                }
+            }
+            Some(state)
+        })
+    }
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn counter_agg_by_combine(
+    state1: Option<Internal<MultiCounterSummaryTransState>>,
+    state2: Option<Internal<MultiCounterSummaryTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+)  -> Option<Internal<MultiCounterSummaryTransState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            match (state1, state2) {
+                (None, None) => None,
+                (None, Some(state2)) => Some(state2.clone().into()),
+                (Some(state1), None) => Some(state1.clone().into()),
+                (Some(state1), Some(state2)) => {
+                    let mut s1 = state1.clone();
+                    s1.push_state(&state2);
+                    Some(s1.into())
+                }
+            }
+        })
+    }
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+fn counter_agg_by_final(
+    state: Option<Internal<MultiCounterSummaryTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<timescale_analytics_experimental::MultiCounterSummary<'static>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let mut state = match state {
+                None => return None,
+                Some(state) => state.clone(),
+            };
+            let summaries = state.to_summaries();
+            if summaries.values().any(|s| !s.bounds_valid()) {
+                panic!("counter bounds invalid")
+            }
+            Some(MultiCounterSummary::from_series(&summaries).into())
+        })
+    }
+}
+
+extension_sql!(r#"
+CREATE AGGREGATE timescale_analytics_experimental.counter_agg_by( ts timestamptz, value DOUBLE PRECISION, key jsonb, bounds tstzrange )
+(
+    sfunc = timescale_analytics_experimental.counter_agg_by_trans,
+    stype = internal,
+    finalfunc = timescale_analytics_experimental.counter_agg_by_final,
+    combinefunc = timescale_analytics_experimental.counter_agg_by_combine,
+    serialfunc = timescale_analytics_experimental.multi_counter_summary_trans_serialize,
+    deserialfunc = timescale_analytics_experimental.multi_counter_summary_trans_deserialize,
+    parallel = safe
+);
+"#);
+
+// allow calling counter_agg_by without bounds provided.
+extension_sql!(r#"
+CREATE AGGREGATE timescale_analytics_experimental.counter_agg_by( ts timestamptz, value DOUBLE PRECISION, key jsonb )
+(
+    sfunc = timescale_analytics_experimental.counter_agg_by_trans_no_bounds,
+    stype = internal,
+    finalfunc = timescale_analytics_experimental.counter_agg_by_final,
+    combinefunc = timescale_analytics_experimental.counter_agg_by_combine,
+    serialfunc = timescale_analytics_experimental.multi_counter_summary_trans_serialize,
+    deserialfunc = timescale_analytics_experimental.multi_counter_summary_trans_deserialize,
+    parallel = safe
+);
+"#);
+
+extension_sql!(r#"
+CREATE AGGREGATE timescale_analytics_experimental.rollup(mcs timescale_analytics_experimental.MultiCounterSummary)
+(
+    sfunc = timescale_analytics_experimental.counter_agg_by_summary_trans,
+    stype = internal,
+    finalfunc = timescale_analytics_experimental.counter_agg_by_final,
+    combinefunc = timescale_analytics_experimental.counter_agg_by_combine,
+    serialfunc = timescale_analytics_experimental.multi_counter_summary_trans_serialize,
+    deserialfunc = timescale_analytics_experimental.multi_counter_summary_trans_deserialize,
+    parallel = safe
+);
+"#);
+
+// Equivalent to PromQL's `sum(rate(...))`: the sum of the extrapolated rates of each series,
+// series with fewer than two points have no rate and are left out.
+#[pg_extern(name="sum_rate", schema = "timescale_analytics_experimental", strict, immutable)]
+fn multi_counter_summary_sum_rate(
+    summary: timescale_analytics_experimental::MultiCounterSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> Option<f64> {
+    let mut sum = None;
+    for (_, s) in summary.series() {
+        let rate = match s.prometheus_rate() {
+            Ok(rate) => rate,
+            Err(_) => error!("sum_rate requires bounds that contain every point, use counter_agg_by with bounds"),
+        };
+        if let Some(rate) = rate {
+            sum = Some(sum.unwrap_or(0.0) + rate);
+        }
+    }
+    sum
+}
+
+#[pg_extern(name="unnest", schema = "timescale_analytics_experimental", strict, immutable)]
+pub fn multi_counter_summary_unnest(
+    summary: timescale_analytics_experimental::MultiCounterSummary,
+) -> impl std::iter::Iterator<Item = (name!(key,JsonB),name!(summary,timescale_analytics_experimental::CounterSummary))> + '_ {
+    summary.series().map(|(key, s)| {
+        let key = JsonB(serde_json::from_str(key).unwrap());
+        (key, CounterSummary::from_internal_counter_summary(s))
+    })
+}
+
+
+#[cfg(any(test, feature = "pg_test"))]
+mod tests {
+
+    use approx::assert_relative_eq;
+    use pgx::*;
+
+    macro_rules! select_one {
+        ($client:expr, $stmt:expr, $type:ty) => {
+            $client
+                .select($stmt, None, None)
+                .first()
+                .get_one::<$type>()
+                .unwrap()
+        };
+    }
+
+    #[pg_test]
+    fn test_counter_agg_by() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test(ts timestamptz, val DOUBLE PRECISION, labels jsonb)", None, None);
+            // set search_path after defining our table so we don't pollute the wrong schema
+            let stmt = "SELECT format('timescale_analytics_experimental, %s',current_setting('search_path'))";
+            let search_path = select_one!(client, stmt, String);
+            client.select(&format!("SET LOCAL search_path TO {}", search_path), None, None);
+            // three series, one of which resets
+            let stmt = "INSERT INTO test SELECT '2020-01-01 00:00:00+00'::timestamptz + (i || ' minutes')::interval, (i % 7) * s, jsonb_build_object('job', 'api', 'instance', s) \
+                FROM generate_series(0, 59) i, generate_series(1, 3) s";
+            client.select(stmt, None, None);
+
+            // the per-series summaries are the same as grouping by the key
+            let stmt = "WITH by_key AS (SELECT u.* FROM (SELECT counter_agg_by(ts, val, labels) AS agg FROM test) t, unnest(t.agg) u), \
+                grouped AS (SELECT labels AS key, counter_agg(ts, val) AS summary FROM test GROUP BY labels) \
+                SELECT count(*) FROM by_key FULL OUTER JOIN grouped USING (key) \
+                WHERE by_key.summary IS NULL OR grouped.summary IS NULL \
+                   OR delta(by_key.summary) != delta(grouped.summary) \
+                   OR num_resets(by_key.summary) != num_resets(grouped.summary)";
+            assert_eq!(select_one!(client, stmt, i64), 0);
+            // key order within the jsonb doesn't matter
+            let stmt = "SELECT count(*) FROM (SELECT counter_agg_by(ts, val, jsonb_build_object('instance', labels->'instance', 'job', labels->'job')) AS agg FROM test) t, unnest(t.agg)";
+            assert_eq!(select_one!(client, stmt, i64), 3);
+
+            // sum_rate is the sum of the extrapolated rates
+            let bounds = "'[2020-01-01 00:00:00+00, 2020-01-01 01:00:00+00)'";
+            let stmt = format!("SELECT sum_rate(counter_agg_by(ts, val, labels, {})) FROM test", bounds);
+            let sum_rate = select_one!(client, &stmt, f64);
+            let stmt = format!("SELECT sum(r) FROM (SELECT extrapolated_rate(counter_agg(ts, val, {}), 'prometheus') FROM test GROUP BY labels) t(r)", bounds);
+            assert_relative_eq!(sum_rate, select_one!(client, &stmt, f64));
+
+            // rolling up works per-series
+            let stmt = format!("WITH t AS (SELECT date_trunc('minute', ts), counter_agg_by(ts, val, labels, {}) AS agg FROM test GROUP BY 1) \
+                SELECT sum_rate(rollup(agg)) FROM t", bounds);
+            assert_relative_eq!(sum_rate, select_one!(client, &stmt, f64));
+        });
+    }
+
+    #[pg_test(error = "sum_rate requires bounds that contain every point, use counter_agg_by with bounds")]
+    fn test_sum_rate_without_bounds() {
+        Spi::execute(|client| {
+            client.select("SELECT timescale_analytics_experimental.sum_rate(timescale_analytics_experimental.counter_agg_by(ts, val, '{}'::jsonb)) \
+                FROM (VALUES ('2020-01-01 00:00:00+00'::timestamptz, 1.0::float8), ('2020-01-01 00:01:00+00', 2.0)) t(ts, val)", None, None);
+        });
+    }
+}
@@ -0,0 +1,329 @@
+use std::{
+    ffi::CStr,
+    hash::{BuildHasher, Hasher},
+    mem::size_of,
+    os::raw::c_char,
+    slice,
+};
+
+use serde::{Deserialize, Serialize};
+
+use pg_sys::{Datum, Oid};
+use pgx::*;
+
+use crate::serialization::{PgCollationId, ShortTypeId};
+
+use hyperloglog::stable_hash;
+
+/// How a hyperloglog hashes its values. This is stored in each hyperloglog,
+/// and only hyperloglogs that use the same hash function can be combined.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[repr(u32)]
+pub enum HashFunction {
+    /// The extended hash function of the type's default hash opclass.
+    /// PostgreSQL does not promise these stay the same across versions.
+    Postgres = 0,
+    /// xxHash64 of the value's binary representation, see `stable_hash_datum`.
+    /// This will never change, so these hyperloglogs can safely be persisted.
+    XxHash64 = 1,
+    /// The values are `bigint`s that are already hashes, and are used as-is.
+    Prehashed = 2,
+}
+
+impl HashFunction {
+    pub(crate) fn from_name(name: &str) -> Self {
+        // TODO technically not portable to ASCII-compatible charsets
+        match name.to_lowercase().as_str() {
+            "postgres" => HashFunction::Postgres,
+            "xxhash64" => HashFunction::XxHash64,
+            _ => error!("unknown hash function \"{}\", expected \"postgres\" or \"xxhash64\"", name),
+        }
+    }
+
+    pub(crate) fn from_id(id: u32) -> Self {
+        match id {
+            0 => HashFunction::Postgres,
+            1 => HashFunction::XxHash64,
+            2 => HashFunction::Prehashed,
+            _ => error!("invalid hyperloglog, unknown hash function {}", id),
+        }
+    }
+}
+
+/// Hashes `Datum`s of a single type. It also records the type's collation and
+/// storage, so it is used to keep track of what values an aggregate is over.
+pub(crate) struct DatumHashBuilder {
+    info: pg_sys::FunctionCallInfo,
+    pub(crate) type_id: pg_sys::Oid,
+    pub(crate) collation: pg_sys::Oid,
+    pub(crate) hash: HashFunction,
+    typlen: i16,
+    typbyval: bool,
+    value: Datum,
+}
+
+impl DatumHashBuilder {
+    pub(crate) unsafe fn from_type_id(
+        type_id: pg_sys::Oid,
+        collation: Option<Oid>,
+        hash: HashFunction,
+    ) -> Self {
+        let entry =
+            pg_sys::lookup_type_cache(type_id, pg_sys::TYPECACHE_HASH_EXTENDED_PROC_FINFO as _);
+        Self::from_type_cache_entry(entry, collation, hash)
+    }
+
+    pub(crate) unsafe fn from_type_cache_entry(
+        tentry: *const pg_sys::TypeCacheEntry,
+        collation: Option<Oid>,
+        hash: HashFunction,
+    ) -> Self {
+        let collation = match collation {
+            Some(collation) => collation,
+            None => (*tentry).typcollation,
+        };
+
+        let info = match hash {
+            HashFunction::Postgres => Self::hash_proc_call_info(tentry),
+            HashFunction::XxHash64 => {
+                check_deterministic(collation);
+                std::ptr::null_mut()
+            }
+            HashFunction::Prehashed => std::ptr::null_mut(),
+        };
+
+        Self {
+            info,
+            type_id: (*tentry).type_id,
+            collation,
+            hash,
+            typlen: (*tentry).typlen,
+            typbyval: (*tentry).typbyval,
+            value: 0,
+        }
+    }
+
+    unsafe fn hash_proc_call_info(tentry: *const pg_sys::TypeCacheEntry) -> pg_sys::FunctionCallInfo {
+        let flinfo = if (*tentry).hash_extended_proc_finfo.fn_addr.is_some() {
+            &(*tentry).hash_extended_proc_finfo
+        } else {
+            pgx::error!("no hash function");
+        };
+
+        // 1 argument for the key, 1 argument for the seed
+        let size =
+            size_of::<pg_sys::FunctionCallInfoBaseData>() + size_of::<pg_sys::NullableDatum>() * 2;
+        let info = pg_sys::palloc0(size) as pg_sys::FunctionCallInfo;
+
+        (*info).flinfo = flinfo as *const pg_sys::FmgrInfo as *mut pg_sys::FmgrInfo;
+        (*info).context = std::ptr::null_mut();
+        (*info).resultinfo = std::ptr::null_mut();
+        (*info).fncollation = (*tentry).typcollation;
+        (*info).isnull = false;
+        (*info).nargs = 1;
+        info
+    }
+
+    // xxHash64 of the value as PostgreSQL stores it on disk, which pg_upgrade
+    // requires to stay the same across versions. By-value types are hashed as
+    // their little-endian bytes, so they hash the same on any platform.
+    // NOTE values that compare equal but are stored differently, such as
+    //      `0.0` and `-0.0`, or `1.0` and `1.00` as `numeric`s, hash differently.
+    unsafe fn stable_hash_datum(&self) -> u64 {
+        self.with_value_bytes(self.value, stable_hash::xxhash64)
+    }
+
+    /// Call `f` with the binary representation of `value`, without any varlena
+    /// header. By-value types are passed as their little-endian bytes.
+    pub(crate) unsafe fn with_value_bytes<T>(&self, value: Datum, f: impl FnOnce(&[u8]) -> T) -> T {
+        if self.typbyval {
+            let bytes = (value as u64).to_le_bytes();
+            return f(&bytes[..self.typlen as usize])
+        }
+
+        match self.typlen {
+            -1 => {
+                let detoasted = pg_sys::pg_detoast_datum_packed(value as *mut pg_sys::varlena);
+                let len = pgx::varsize_any_exhdr(detoasted);
+                let data = pgx::vardata_any(detoasted);
+                let res = f(slice::from_raw_parts(data as *const u8, len));
+                if detoasted as Datum != value {
+                    pg_sys::pfree(detoasted as *mut _);
+                }
+                res
+            }
+            -2 => f(CStr::from_ptr(value as *const c_char).to_bytes()),
+            len => f(slice::from_raw_parts(value as *const u8, len as usize)),
+        }
+    }
+
+    /// The inverse of `with_value_bytes`, the datum is palloc'd in the current
+    /// memory context.
+    pub(crate) unsafe fn datum_from_bytes(&self, bytes: &[u8]) -> Datum {
+        if self.typbyval {
+            let mut value = [0; size_of::<u64>()];
+            value[..bytes.len()].copy_from_slice(bytes);
+            //FIXME 32bit vs 64 bit get value from datum on 32b arch
+            return u64::from_le_bytes(value) as Datum
+        }
+
+        let (header, trailer) = match self.typlen {
+            -1 => (pg_sys::VARHDRSZ, 0),
+            // cstrings need their nul terminator back
+            -2 => (0, 1),
+            _ => (0, 0),
+        };
+        let len = header + bytes.len() + trailer;
+        let ptr = pg_sys::palloc0(len) as *mut u8;
+        std::ptr::copy_nonoverlapping(bytes.as_ptr(), ptr.add(header), bytes.len());
+        if self.typlen == -1 {
+            ::pgx::set_varsize(ptr as *mut pg_sys::varlena, len as i32);
+        }
+        ptr as Datum
+    }
+
+    /// The text representation of `value`, as produced by the type's output
+    /// function.
+    pub(crate) unsafe fn output(&self, value: Datum) -> String {
+        let mut output_fn = 0;
+        let mut is_varlena = false;
+        pg_sys::getTypeOutputInfo(self.type_id, &mut output_fn, &mut is_varlena);
+        let text = pg_sys::OidOutputFunctionCall(output_fn, value);
+        let output = crate::serialization::str_from_db_encoding(CStr::from_ptr(text)).to_string();
+        pg_sys::pfree(text as *mut _);
+        output
+    }
+}
+
+// Values that are equal under a nondeterministic collation can have different
+// bytes, so we cannot hash the bytes directly.
+#[cfg(any(feature = "pg12", feature = "pg13"))]
+unsafe fn check_deterministic(collation: Oid) {
+    if collation != 0 && !pg_sys::get_collation_isdeterministic(collation) {
+        error!("the xxhash64 hash function does not support nondeterministic collations")
+    }
+}
+
+// nondeterministic collations were added in PostgreSQL 12
+#[cfg(not(any(feature = "pg12", feature = "pg13")))]
+unsafe fn check_deterministic(_collation: Oid) {}
+
+impl Clone for DatumHashBuilder {
+    fn clone(&self) -> Self {
+        Self {
+            info: self.info,
+            type_id: self.type_id,
+            collation: self.collation,
+            hash: self.hash,
+            typlen: self.typlen,
+            typbyval: self.typbyval,
+            value: self.value,
+        }
+    }
+}
+
+impl BuildHasher for DatumHashBuilder {
+    type Hasher = DatumHashBuilder;
+
+    fn build_hasher(&self) -> Self::Hasher {
+        self.clone()
+    }
+}
+
+impl Hasher for DatumHashBuilder {
+    fn finish(&self) -> u64 {
+        match self.hash {
+            HashFunction::Postgres => {
+                //FIXME ehhh, this is wildly unsafe, should at least have a separate hash
+                //      buffer for each, probably should have separate args
+                let value = unsafe {
+                    (*self.info).args.as_mut_slice(1)[0] = pg_sys::NullableDatum {
+                        value: self.value,
+                        isnull: false,
+                    };
+                    (*self.info).isnull = false;
+                    let value = (*(*self.info).flinfo).fn_addr.unwrap()(self.info);
+                    (*self.info).args.as_mut_slice(1)[0] = pg_sys::NullableDatum {
+                        value: 0,
+                        isnull: true,
+                    };
+                    (*self.info).isnull = false;
+                    //FIXME 32bit vs 64 bit get value from datum on 32b arch
+                    value
+                };
+                value as u64
+            }
+            HashFunction::XxHash64 => unsafe { self.stable_hash_datum() },
+            //FIXME 32bit vs 64 bit get value from datum on 32b arch
+            HashFunction::Prehashed => self.value as u64,
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        if bytes.len() != size_of::<usize>() {
+            panic!("invalid datum hash")
+        }
+
+        let mut b = [0; size_of::<usize>()];
+        for i in 0..size_of::<usize>() {
+            b[i] = bytes[i]
+        }
+        self.write_usize(usize::from_ne_bytes(b))
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.value = i
+    }
+}
+
+impl PartialEq for DatumHashBuilder {
+    fn eq(&self, other: &Self) -> bool {
+        self.type_id.eq(&other.type_id) && self.hash == other.hash
+    }
+}
+
+impl Eq for DatumHashBuilder {}
+
+impl Serialize for DatumHashBuilder {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let collation = if self.collation == 0 {
+            None
+        } else {
+            Some(PgCollationId(self.collation))
+        };
+        (ShortTypeId(self.type_id), collation, self.hash).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for DatumHashBuilder {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let (type_id, collation, hash) =
+            <(ShortTypeId, Option<PgCollationId>, HashFunction)>::deserialize(deserializer)?;
+        //FIXME no collation?
+        let deserialized = unsafe { Self::from_type_id(type_id.0, collation.map(|c| c.0), hash) };
+        Ok(deserialized)
+    }
+}
+
+/// The length of an `interval` in microseconds. Months have no fixed length so
+/// intervals containing them are rejected.
+pub(crate) unsafe fn interval_to_micros(interval: Datum) -> i64 {
+    let interval = &*(interval as *const pg_sys::Interval);
+    if interval.month != 0 {
+        error!("intervals cannot contain months")
+    }
+    interval.time + interval.day as i64 * 24 * 60 * 60 * 1_000_000
+}
+
+/// An `interval` of `micros` microseconds, palloc'd in the current memory context.
+pub(crate) unsafe fn micros_to_interval(micros: i64) -> Datum {
+    let interval = pg_sys::palloc0(size_of::<pg_sys::Interval>()) as *mut pg_sys::Interval;
+    (*interval).time = micros;
+    interval as Datum
+}
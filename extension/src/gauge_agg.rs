@@ -0,0 +1,570 @@
+use serde::{Serialize, Deserialize};
+
+use std::{
+    slice,
+};
+
+use pgx::*;
+use pg_sys::Datum;
+
+use flat_serialize::*;
+
+use crate::{
+    aggregate_utils::in_aggregate_context,
+    json_inout_funcs,
+    flatten,
+    palloc::Internal,
+    pg_type,
+    range::*,
+};
+
+use time_series::{
+    TSPoint,
+};
+
+use counter_agg::{
+    gauge::GaugeSummary as InternalGaugeSummary,
+    regression::{HigherMoments, RegressionPartials, RegressionSummary},
+    range::I64Range,
+};
+
+#[allow(non_camel_case_types)]
+type tstzrange = Datum;
+
+#[allow(non_camel_case_types)]
+type bytea = pg_sys::Datum;
+
+pg_type! {
+    #[derive(Debug, PartialEq)]
+    struct GaugeSummary {
+        regress: RegressionPartials,
+        first: TSPoint,
+        second: TSPoint,
+        penultimate:TSPoint,
+        last: TSPoint,
+        num_changes: u64,
+        bounds: I64RangeWrapper,
+        #[serde(default)]
+        higher_moments: [HigherMoments; (self.version >= 2) as u8],
+    }
+}
+
+json_inout_funcs!(GaugeSummary);
+
+const GAUGE_SUMMARY_VERSION: u8 = 2;
+
+// hack to allow us to qualify names with "timescale_analytics_experimental"
+// so that pgx generates the correct SQL
+mod timescale_analytics_experimental {
+    pub(crate) use super::*;
+
+    varlena_type!(GaugeSummary);
+}
+
+impl<'input> GaugeSummary<'input> {
+    fn to_internal_gauge_summary(&self) -> InternalGaugeSummary {
+        if *self.version > GAUGE_SUMMARY_VERSION {
+            error!("unsupported GaugeSummary version {}, the newest supported version is {}", self.version, GAUGE_SUMMARY_VERSION)
+        }
+        InternalGaugeSummary{
+            first: *self.first,
+            second: *self.second,
+            penultimate: *self.penultimate,
+            last: *self.last,
+            num_changes: *self.num_changes,
+            // summaries from before version 2 don't have the higher moments
+            regress: RegressionSummary::from_partials(*self.regress, self.higher_moments.first().copied()),
+            bounds: self.bounds.to_i64range(),
+        }
+    }
+    fn from_internal_gauge_summary(st: InternalGaugeSummary) -> Self {
+        // as with CounterSummary, use the oldest version that can represent the summary
+        let (version, higher_moments) = match st.regress.higher_moments {
+            Some(m) => (GAUGE_SUMMARY_VERSION, vec![m]),
+            None => (1, vec![]),
+        };
+        unsafe{
+            flatten!(
+            GaugeSummary version: version, {
+                regress: &st.regress.partials(),
+                first: &st.first,
+                second: &st.second,
+                penultimate: &st.penultimate,
+                last: &st.last,
+                num_changes: &st.num_changes,
+                bounds: &I64RangeWrapper::from_i64range(st.bounds),
+                higher_moments: &higher_moments,
+            })
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GaugeSummaryTransState {
+    #[serde(skip)]
+    point_buffer: Vec<TSPoint>,
+    #[serde(skip)]
+    bounds: Option<I64Range>, // stores bounds until we combine points, after which, the bounds are stored in each summary
+    // same as for counters, the combine function must build up a buffer of
+    // summaries, then sort them, and only then combine them in order.
+    summary_buffer: Vec<InternalGaugeSummary>,
+}
+
+impl GaugeSummaryTransState {
+    fn push_point(&mut self, value: TSPoint) {
+        self.point_buffer.push(value);
+    }
+
+    fn combine_points(&mut self) {
+        if self.point_buffer.is_empty() {
+            return
+        }
+        self.point_buffer.sort_unstable_by_key(|p| p.ts);
+        let mut iter = self.point_buffer.iter();
+        let mut summary = InternalGaugeSummary::new( iter.next().unwrap(), self.bounds);
+        for p in iter {
+            summary.add_point(p).unwrap();
+        }
+        self.point_buffer.clear();
+        // check bounds only after we've combined all the points, so we aren't doing it all the time.
+        if !summary.bounds_valid() {
+            panic!("gauge bounds invalid")
+        }
+        self.summary_buffer.push(summary);
+    }
+
+    fn push_summary(&mut self, other: &GaugeSummaryTransState) {
+        let sum_iter = other.summary_buffer.iter();
+        for sum in sum_iter {
+            self.summary_buffer.push(sum.clone());
+        }
+    }
+
+    fn combine_summaries(&mut self) {
+        self.combine_points();
+
+        if self.summary_buffer.len() <= 1 {
+            return
+        }
+        self.summary_buffer.sort_unstable_by_key(|s| s.first.ts);
+        let mut sum_iter = self.summary_buffer.iter();
+        let mut new_summary = sum_iter.next().unwrap().clone();
+        for sum in sum_iter {
+            new_summary.combine(sum).unwrap();
+        }
+        self.summary_buffer = vec![new_summary];
+    }
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn gauge_summary_trans_serialize(
+    mut state: Internal<GaugeSummaryTransState>,
+) -> bytea {
+    state.combine_summaries();
+    crate::do_serialize!(state)
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental", strict)]
+pub fn gauge_summary_trans_deserialize(
+    bytes: bytea,
+    _internal: Option<Internal<()>>,
+) -> Internal<GaugeSummaryTransState> {
+    crate::do_deserialize!(bytes, GaugeSummaryTransState)
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn gauge_agg_trans(
+    state: Option<Internal<GaugeSummaryTransState>>,
+    ts: Option<pg_sys::TimestampTz>,
+    val: Option<f64>,
+    bounds: Option<tstzrange>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<GaugeSummaryTransState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let p = match (ts, val) {
+                (_, None) => return state,
+                (None, _) => return state,
+                (Some(ts), Some(val)) => TSPoint{ts, val},
+            };
+            match state {
+                None => {
+                    let mut s = GaugeSummaryTransState{point_buffer: vec![], bounds: None, summary_buffer: vec![]};
+                    if let Some(r) = bounds {
+                        s.bounds = get_range(r as *mut pg_sys::varlena);
+                    }
+                    s.push_point(p);
+                    Some(s.into())
+                },
+                Some(mut s) => {s.push_point(p); Some(s)},
+            }
+        })
+    }
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn gauge_agg_trans_no_bounds(
+    state: Option<Internal<GaugeSummaryTransState>>,
+    ts: Option<pg_sys::TimestampTz>,
+    val: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<GaugeSummaryTransState>> {
+    gauge_agg_trans(state, ts, val, None, fcinfo)
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn gauge_agg_summary_trans(
+    state: Option<Internal<GaugeSummaryTransState>>,
+    value: Option<timescale_analytics_experimental::GaugeSummary>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<GaugeSummaryTransState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            match (state, value) {
+                (state, None) => state,
+                (None, Some(value)) => Some(
+                    GaugeSummaryTransState{point_buffer: vec![], bounds: None, summary_buffer: vec![value.to_internal_gauge_summary()]}.into()),
+                (Some(mut state), Some(value)) => {
+                    state.summary_buffer.push(value.to_internal_gauge_summary());
+                    Some(state)
+                }
+            }
+        })
+    }
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn gauge_agg_combine(
+    state1: Option<Internal<GaugeSummaryTransState>>,
+    state2: Option<Internal<GaugeSummaryTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+)  -> Option<Internal<GaugeSummaryTransState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            match (state1, state2) {
+                (None, None) => None,
+                (None, Some(state2)) => {let mut s = state2.clone(); s.combine_points(); Some(s.into())},
+                (Some(state1), None) => {let mut s = state1.clone(); s.combine_points(); Some(s.into())},
+                (Some(state1), Some(state2)) => {
+                    let mut s1 = state1.clone();
+                    s1.combine_points();
+                    let mut s2 = state2.clone();
+                    s2.combine_points();
+                    s2.push_summary(&s1);
+                    Some(s2.into())
+                }
+            }
+        })
+    }
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+fn gauge_agg_final(
+    state: Option<Internal<GaugeSummaryTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<timescale_analytics_experimental::GaugeSummary<'static>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let mut state = match state {
+                None => return None,
+                Some(state) => state.clone(),
+            };
+            state.combine_summaries();
+            debug_assert!(state.summary_buffer.len() <= 1);
+            match state.summary_buffer.pop() {
+                None => None,
+                Some(st) => {
+                    if !st.bounds_valid() {
+                        panic!("gauge bounds invalid")
+                    }
+                    Some(GaugeSummary::from_internal_gauge_summary(st).into())
+                }
+            }
+        })
+    }
+}
+
+
+extension_sql!(r#"
+CREATE AGGREGATE timescale_analytics_experimental.gauge_agg( ts timestamptz, value DOUBLE PRECISION, bounds tstzrange )
+(
+    sfunc = timescale_analytics_experimental.gauge_agg_trans,
+    stype = internal,
+    finalfunc = timescale_analytics_experimental.gauge_agg_final,
+    combinefunc = timescale_analytics_experimental.gauge_agg_combine,
+    serialfunc = timescale_analytics_experimental.gauge_summary_trans_serialize,
+    deserialfunc = timescale_analytics_experimental.gauge_summary_trans_deserialize,
+    parallel = restricted
+);
+"#);
+
+// allow calling gauge agg without bounds provided.
+extension_sql!(r#"
+CREATE AGGREGATE timescale_analytics_experimental.gauge_agg( ts timestamptz, value DOUBLE PRECISION )
+(
+    sfunc = timescale_analytics_experimental.gauge_agg_trans_no_bounds,
+    stype = internal,
+    finalfunc = timescale_analytics_experimental.gauge_agg_final,
+    combinefunc = timescale_analytics_experimental.gauge_agg_combine,
+    serialfunc = timescale_analytics_experimental.gauge_summary_trans_serialize,
+    deserialfunc = timescale_analytics_experimental.gauge_summary_trans_deserialize,
+    parallel = restricted
+);
+"#);
+
+extension_sql!(r#"
+CREATE AGGREGATE timescale_analytics_experimental.rollup(gs timescale_analytics_experimental.GaugeSummary)
+(
+    sfunc = timescale_analytics_experimental.gauge_agg_summary_trans,
+    stype = internal,
+    finalfunc = timescale_analytics_experimental.gauge_agg_final,
+    combinefunc = timescale_analytics_experimental.gauge_agg_combine,
+    serialfunc = timescale_analytics_experimental.gauge_summary_trans_serialize,
+    deserialfunc = timescale_analytics_experimental.gauge_summary_trans_deserialize,
+    parallel = restricted
+);
+"#);
+
+#[pg_extern(name="delta", schema = "timescale_analytics_experimental", strict, immutable)]
+fn gauge_agg_delta(
+    summary: timescale_analytics_experimental::GaugeSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> f64 {
+    summary.to_internal_gauge_summary().delta()
+}
+
+#[pg_extern(name="rate", schema = "timescale_analytics_experimental", strict, immutable )]
+fn gauge_agg_rate(
+    summary: timescale_analytics_experimental::GaugeSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> Option<f64> {
+    summary.to_internal_gauge_summary().rate()
+}
+
+#[pg_extern(name="time_delta", schema = "timescale_analytics_experimental", strict, immutable)]
+fn gauge_agg_time_delta(
+    summary: timescale_analytics_experimental::GaugeSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> f64 {
+    summary.to_internal_gauge_summary().time_delta()
+}
+
+#[pg_extern(name="irate_left", schema = "timescale_analytics_experimental", strict, immutable)]
+fn gauge_agg_irate_left(
+    summary: timescale_analytics_experimental::GaugeSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> Option<f64> {
+    summary.to_internal_gauge_summary().irate_left()
+}
+
+#[pg_extern(name="irate_right", schema = "timescale_analytics_experimental", strict, immutable)]
+fn gauge_agg_irate_right(
+    summary: timescale_analytics_experimental::GaugeSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> Option<f64> {
+    summary.to_internal_gauge_summary().irate_right()
+}
+
+#[pg_extern(name="idelta_left", schema = "timescale_analytics_experimental", strict, immutable)]
+fn gauge_agg_idelta_left(
+    summary: timescale_analytics_experimental::GaugeSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> f64 {
+    summary.to_internal_gauge_summary().idelta_left()
+}
+
+#[pg_extern(name="idelta_right", schema = "timescale_analytics_experimental", strict, immutable)]
+fn gauge_agg_idelta_right(
+    summary: timescale_analytics_experimental::GaugeSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> f64 {
+    summary.to_internal_gauge_summary().idelta_right()
+}
+
+#[pg_extern(name="with_bounds", schema = "timescale_analytics_experimental", strict, immutable)]
+fn gauge_agg_with_bounds(
+    summary: timescale_analytics_experimental::GaugeSummary,
+    bounds: tstzrange,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> timescale_analytics_experimental::GaugeSummary{
+    unsafe{
+        let ptr = bounds as *mut pg_sys::varlena;
+        let mut summary = summary.to_internal_gauge_summary();
+        summary.bounds = get_range(ptr);
+        GaugeSummary::from_internal_gauge_summary(summary)
+    }
+}
+
+#[pg_extern(name="extrapolated_delta", schema = "timescale_analytics_experimental", strict, immutable)]
+fn gauge_agg_extrapolated_delta(
+    summary: timescale_analytics_experimental::GaugeSummary,
+    method: String,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> Option<f64> {
+    match method.to_lowercase().as_str() {
+        "prometheus" => {
+            summary.to_internal_gauge_summary().prometheus_delta().unwrap()
+        },
+        _ => panic!("unknown method"),
+    }
+}
+
+#[pg_extern(name="extrapolated_rate", schema = "timescale_analytics_experimental", strict, immutable)]
+fn gauge_agg_extrapolated_rate(
+    summary: timescale_analytics_experimental::GaugeSummary,
+    method: String,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> Option<f64> {
+    match method.to_lowercase().as_str() {
+        "prometheus" => {
+            summary.to_internal_gauge_summary().prometheus_rate().unwrap()
+        },
+        _ => panic!("unknown method"),
+    }
+}
+
+#[pg_extern(name="num_elements", schema = "timescale_analytics_experimental", strict, immutable)]
+fn gauge_agg_num_elements(
+    summary: timescale_analytics_experimental::GaugeSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> i64 {
+    summary.to_internal_gauge_summary().regress.n as i64
+}
+
+#[pg_extern(name="num_changes", schema = "timescale_analytics_experimental", strict, immutable)]
+fn gauge_agg_num_changes(
+    summary: timescale_analytics_experimental::GaugeSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> i64 {
+    summary.to_internal_gauge_summary().num_changes as i64
+}
+
+#[pg_extern(name="slope", schema = "timescale_analytics_experimental", strict, immutable)]
+fn gauge_agg_slope(
+    summary: timescale_analytics_experimental::GaugeSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> Option<f64> {
+    summary.to_internal_gauge_summary().regress.slope()
+}
+
+#[pg_extern(name="intercept", schema = "timescale_analytics_experimental", strict, immutable)]
+fn gauge_agg_intercept(
+    summary: timescale_analytics_experimental::GaugeSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> Option<f64> {
+    summary.to_internal_gauge_summary().regress.intercept()
+}
+
+#[pg_extern(name="corr", schema = "timescale_analytics_experimental", strict, immutable)]
+fn gauge_agg_corr(
+    summary: timescale_analytics_experimental::GaugeSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> Option<f64> {
+    summary.to_internal_gauge_summary().regress.corr()
+}
+
+#[pg_extern(name="skewness", schema = "timescale_analytics_experimental", strict, immutable)]
+fn gauge_agg_skewness(
+    summary: timescale_analytics_experimental::GaugeSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> Option<f64> {
+    summary.to_internal_gauge_summary().skewness()
+}
+
+#[pg_extern(name="kurtosis", schema = "timescale_analytics_experimental", strict, immutable)]
+fn gauge_agg_kurtosis(
+    summary: timescale_analytics_experimental::GaugeSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> Option<f64> {
+    summary.to_internal_gauge_summary().kurtosis()
+}
+
+
+#[cfg(any(test, feature = "pg_test"))]
+mod tests {
+
+    use approx::assert_relative_eq;
+    use pgx::*;
+    use super::*;
+
+    macro_rules! select_one {
+        ($client:expr, $stmt:expr, $type:ty) => {
+            $client
+                .select($stmt, None, None)
+                .first()
+                .get_one::<$type>()
+                .unwrap()
+        };
+    }
+
+    #[pg_test]
+    fn test_gauge_aggregate() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test(ts timestamptz, val DOUBLE PRECISION)", None, None);
+            // set search_path after defining our table so we don't pollute the wrong schema
+            let stmt = "SELECT format('timescale_analytics_experimental, %s',current_setting('search_path'))";
+            let search_path = select_one!(client, stmt, String);
+            client.select(&format!("SET LOCAL search_path TO {}", search_path), None, None);
+            let stmt = "INSERT INTO test VALUES('2020-01-01 00:00:00+00', 10.0), ('2020-01-01 00:01:00+00', 20.0)";
+            client.select(stmt, None, None);
+
+            // NULL bounds are equivalent to none provided
+            let stmt = "SELECT gauge_agg(ts, val) FROM test";
+            let a = select_one!(client,stmt, timescale_analytics_experimental::GaugeSummary);
+            let stmt = "SELECT gauge_agg(ts, val, NULL::tstzrange) FROM test";
+            let b = select_one!(client,stmt, timescale_analytics_experimental::GaugeSummary);
+            assert_eq!(a, b);
+
+            let stmt = "SELECT delta(gauge_agg(ts, val)) FROM test";
+            assert_relative_eq!(select_one!(client, stmt, f64), 10.0);
+
+            let stmt = "SELECT time_delta(gauge_agg(ts, val)) FROM test";
+            assert_relative_eq!(select_one!(client, stmt, f64), 60.0);
+
+            let stmt = "SELECT extrapolated_delta(gauge_agg(ts, val, '[2020-01-01 00:00:00+00, 2020-01-01 00:02:00+00)'), 'prometheus') FROM test";
+            assert_relative_eq!(select_one!(client, stmt, f64), 20.0);
+
+            let stmt = "SELECT extrapolated_rate(gauge_agg(ts, val, '[2020-01-01 00:00:00+00, 2020-01-01 00:02:00+00)'), 'prometheus') FROM test";
+            assert_relative_eq!(select_one!(client, stmt, f64), 20.0 / 120.0);
+
+            // decreases are not resets for gauges
+            let stmt = "INSERT INTO test VALUES('2020-01-01 00:02:00+00', 10.0), ('2020-01-01 00:03:00+00', 20.0), ('2020-01-01 00:04:00+00', 5.0)";
+            client.select(stmt, None, None);
+
+            let stmt = "SELECT delta(gauge_agg(ts, val)) FROM test";
+            assert_relative_eq!(select_one!(client, stmt, f64), -5.0);
+
+            let stmt = "SELECT idelta_right(gauge_agg(ts, val)) FROM test";
+            assert_relative_eq!(select_one!(client, stmt, f64), -15.0);
+
+            let stmt = "SELECT num_changes(gauge_agg(ts, val)) FROM test";
+            assert_eq!(select_one!(client, stmt, i64), 4);
+
+            let stmt = "SELECT num_elements(gauge_agg(ts, val)) FROM test";
+            assert_eq!(select_one!(client, stmt, i64), 5);
+
+            // the values are 10, 20, 10, 20, 5, whose central moments are 180, 120 and 9060
+            let stmt = "SELECT skewness(gauge_agg(ts, val)) FROM test";
+            assert_relative_eq!(select_one!(client, stmt, f64), 5.0_f64.sqrt() * 120.0 / 180.0_f64.powf(1.5), max_relative = 1e-10);
+
+            let stmt = "SELECT kurtosis(gauge_agg(ts, val)) FROM test";
+            assert_relative_eq!(select_one!(client, stmt, f64), 5.0 * 9060.0 / (180.0 * 180.0), max_relative = 1e-10);
+
+            //combine function works as expected
+            let stmt = "SELECT gauge_agg(ts, val) FROM test";
+            let a = select_one!(client,stmt, timescale_analytics_experimental::GaugeSummary);
+            let stmt = "WITH t as (SELECT date_trunc('minute', ts), gauge_agg(ts, val) as agg FROM test group by 1 ) SELECT rollup(agg) FROM t";
+            let b = select_one!(client,stmt, timescale_analytics_experimental::GaugeSummary);
+            let (a, b) = (a.to_internal_gauge_summary(), b.to_internal_gauge_summary());
+            assert_eq!(a.first, b.first);
+            assert_eq!(a.last, b.last);
+            assert_eq!(a.num_changes, b.num_changes);
+            assert_relative_eq!(a.regress.sy, b.regress.sy);
+            assert_relative_eq!(a.regress.syy, b.regress.syy);
+            assert_relative_eq!(a.regress.sxy, b.regress.sxy);
+            let (a, b) = (a.regress.higher_moments.unwrap(), b.regress.higher_moments.unwrap());
+            assert_relative_eq!(a.sy3, b.sy3, max_relative = 1e-12);
+            assert_relative_eq!(a.sy4, b.sy4, max_relative = 1e-12);
+        });
+    }
+}
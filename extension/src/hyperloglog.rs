@@ -1,27 +1,25 @@
 use std::{
     borrow::Cow,
     convert::TryInto,
-    hash::{BuildHasher, Hasher},
-    mem::size_of,
-    slice,
 };
 
 use serde::{Deserialize, Serialize};
 
-use pg_sys::{Datum, Oid};
+use pg_sys::Datum;
 use pgx::*;
 
 use flat_serialize::*;
 
 use crate::{
     aggregate_utils::{get_collation, in_aggregate_context},
+    datum_utils::{DatumHashBuilder, HashFunction},
     flatten, json_inout_funcs,
     palloc::Internal,
     pg_type,
     serialization::{PgCollationId, ShortTypeId},
 };
 
-use hyperloglog::{HyperLogLog as HLL, HyperLogLogger};
+use hyperloglog::{sparse, HyperLogLog as HLL, HyperLogLogger, Registers};
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct HyperLogLogTrans {
@@ -38,6 +36,38 @@ pub fn hyperloglog_trans(
     size: int,
     value: Option<AnyElement>,
     fc: pg_sys::FunctionCallInfo,
+) -> Option<Internal<HyperLogLogTrans>> {
+    hyperloglog_trans_inner(state, size, value, || HashFunction::Postgres, fc)
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn hyperloglog_hash_trans(
+    state: Option<Internal<HyperLogLogTrans>>,
+    size: int,
+    value: Option<AnyElement>,
+    hash_function: String,
+    fc: pg_sys::FunctionCallInfo,
+) -> Option<Internal<HyperLogLogTrans>> {
+    hyperloglog_trans_inner(state, size, value, || HashFunction::from_name(&hash_function), fc)
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn hyperloglog_hashed_trans(
+    state: Option<Internal<HyperLogLogTrans>>,
+    size: int,
+    hash: Option<i64>,
+    fc: pg_sys::FunctionCallInfo,
+) -> Option<Internal<HyperLogLogTrans>> {
+    let value = hash.map(|hash| hash as Datum);
+    hyperloglog_trans_inner(state, size, value, || HashFunction::Prehashed, fc)
+}
+
+fn hyperloglog_trans_inner(
+    state: Option<Internal<HyperLogLogTrans>>,
+    size: int,
+    value: Option<AnyElement>,
+    hash_function: impl FnOnce() -> HashFunction,
+    fc: pg_sys::FunctionCallInfo,
 ) -> Option<Internal<HyperLogLogTrans>> {
     unsafe {
         in_aggregate_context(fc, || {
@@ -54,7 +84,7 @@ pub fn hyperloglog_trans(
                     let b = size.checked_next_power_of_two().unwrap().trailing_zeros();
                     let typ = pgx::get_getarg_type(fc, 2);
                     let collation = get_collation(fc);
-                    let hasher = DatumHashBuilder::from_type_id(typ, collation);
+                    let hasher = DatumHashBuilder::from_type_id(typ, collation, hash_function());
                     let trans = HyperLogLogTrans {
                         logger: HyperLogLogger::with_hash(b as usize, hasher),
                     };
@@ -106,6 +136,12 @@ pub fn hyperloglog_deserialize(
     crate::do_deserialize!(bytes, HyperLogLogTrans)
 }
 
+// version history
+//  - version 1: the original layout, every log is dense
+//  - version 2: adds logs that only store their non-zero registers
+//  - version 3: adds the hash function, earlier versions always use the
+//    Postgres hash
+// each log is written with the oldest version that can represent it
 pg_type! {
     #[derive(Debug)]
     struct HyperLogLog {
@@ -115,7 +151,54 @@ pg_type! {
         element_type: ShortTypeId,
         collation: PgCollationId,
         b: u32,
-        registers: [u8; (1 as usize) << self.b],
+        // version 1 logs store all 2^b of their registers here, later versions
+        // store them in the fields below
+        registers: [u8; ((self.version == 1) as usize) << self.b],
+        // a later log either stores all 2^b of its registers in
+        // `dense_registers`, or, while it is still sparse, only the non-zero
+        // ones, compressed into `sparse_registers`; see `hyperloglog::sparse`
+        // for the encoding
+        #[serde(default, skip_serializing_if = "crate::serialization::serde_reference_adaptor::is_empty")]
+        num_registers: [u32; (self.version >= 2) as u8],
+        #[serde(default, skip_serializing_if = "crate::serialization::serde_reference_adaptor::is_empty")]
+        sparse_bytes: [u32; (self.version >= 2) as u8],
+        #[serde(default, skip_serializing_if = "crate::serialization::serde_reference_adaptor::is_empty")]
+        dense_registers: [u8; self.num_registers.first().copied().unwrap_or(0)],
+        #[serde(default, skip_serializing_if = "crate::serialization::serde_reference_adaptor::is_empty")]
+        sparse_registers: [u8; self.sparse_bytes.first().copied().unwrap_or(0)],
+        // the `HashFunction` the values were hashed with
+        #[serde(default, skip_serializing_if = "crate::serialization::serde_reference_adaptor::is_empty")]
+        hash_function: [u32; (self.version >= 3) as u8],
+    }
+}
+
+const HYPERLOGLOG_VERSION: u8 = 3;
+
+impl<'input> HyperLogLog<'input> {
+    fn decode_registers(&self) -> Registers<'input> {
+        let b = *self.b as usize;
+        if !(4..=18).contains(&b) {
+            error!("invalid hyperloglog, b must be between 4 and 18, not {}", b)
+        }
+        if *self.version > HYPERLOGLOG_VERSION {
+            error!("unsupported HyperLogLog version {}, the newest supported version is {}", self.version, HYPERLOGLOG_VERSION)
+        }
+        let registers = if *self.version == 1 {
+            self.registers
+        } else if self.dense_registers.is_empty() {
+            match sparse::decompress(self.sparse_registers, b) {
+                Some(entries) => return Registers::Sparse(Cow::Owned(entries)),
+                None => error!("invalid hyperloglog, malformed sparse registers"),
+            }
+        } else if self.sparse_registers.is_empty() {
+            self.dense_registers
+        } else {
+            error!("invalid hyperloglog, both dense and sparse registers")
+        };
+        if registers.len() != 1 << b {
+            error!("invalid hyperloglog, {} registers for b = {}", registers.len(), b)
+        }
+        Registers::Dense(Cow::Borrowed(registers))
     }
 }
 
@@ -157,6 +240,26 @@ CREATE AGGREGATE timescale_analytics_experimental.hyperloglog(size int, value An
     serialfunc = timescale_analytics_experimental.hyperloglog_serialize,
     deserialfunc = timescale_analytics_experimental.hyperloglog_deserialize
 );
+
+CREATE AGGREGATE timescale_analytics_experimental.hyperloglog(size int, value AnyElement, hash_function text)
+(
+    stype = internal,
+    sfunc = timescale_analytics_experimental.hyperloglog_hash_trans,
+    finalfunc = timescale_analytics_experimental.hyperloglog_final,
+    combinefunc = timescale_analytics_experimental.hyperloglog_combine,
+    serialfunc = timescale_analytics_experimental.hyperloglog_serialize,
+    deserialfunc = timescale_analytics_experimental.hyperloglog_deserialize
+);
+
+CREATE AGGREGATE timescale_analytics_experimental.hyperloglog_hashed(size int, hash bigint)
+(
+    stype = internal,
+    sfunc = timescale_analytics_experimental.hyperloglog_hashed_trans,
+    finalfunc = timescale_analytics_experimental.hyperloglog_final,
+    combinefunc = timescale_analytics_experimental.hyperloglog_combine,
+    serialfunc = timescale_analytics_experimental.hyperloglog_serialize,
+    deserialfunc = timescale_analytics_experimental.hyperloglog_deserialize
+);
 "#
 );
 
@@ -166,7 +269,7 @@ pub fn hyperloglog_count<'input>(
 ) -> i64 {
     // count does not depend on the type parameters
     HLL::<()> {
-        registers: hyperloglog.registers,
+        registers: hyperloglog.decode_registers(),
         b: *hyperloglog.b as _,
         buildhasher: Default::default(),
         phantom: Default::default(),
@@ -179,203 +282,109 @@ pub fn hyperloglog_union<'input>(
     a: timescale_analytics_experimental::HyperLogLog<'input>,
     b: timescale_analytics_experimental::HyperLogLog<'input>,
 ) -> timescale_analytics_experimental::HyperLogLog<'input> {
-    let a = HLL::<'_, Datum, DatumHashBuilder> {
-        registers: a.registers,
-        b: *a.b as _,
-        buildhasher: unsafe {
-            Cow::Owned(DatumHashBuilder::from_type_id(
-                a.element_type.0,
-                a.collation.to_option_oid(),
-            ))
-        },
-        phantom: Default::default(),
-    };
-    let b = HLL::<'_, Datum, DatumHashBuilder> {
-        registers: b.registers,
-        b: *b.b as _,
+    let (a, b) = comparable_logs(&a, &b);
+    let merged = HLL::merge(&a, &b);
+    flatten_log(merged.as_hyperloglog())
+}
+
+#[pg_extern(name="approx_intersection_count", schema = "timescale_analytics_experimental")]
+pub fn hyperloglog_intersection_count<'input>(
+    a: timescale_analytics_experimental::HyperLogLog<'input>,
+    b: timescale_analytics_experimental::HyperLogLog<'input>,
+) -> i64 {
+    let (a, b) = comparable_logs(&a, &b);
+    HLL::intersection_count(&a, &b)
+}
+
+#[pg_extern(name="approx_jaccard", schema = "timescale_analytics_experimental")]
+pub fn hyperloglog_jaccard<'input>(
+    a: timescale_analytics_experimental::HyperLogLog<'input>,
+    b: timescale_analytics_experimental::HyperLogLog<'input>,
+) -> f64 {
+    let (a, b) = comparable_logs(&a, &b);
+    HLL::jaccard(&a, &b)
+}
+
+fn to_hll<'input>(log: &HyperLogLog<'input>) -> HLL<'input, Datum, DatumHashBuilder> {
+    HLL {
+        registers: log.decode_registers(),
+        b: *log.b as _,
         buildhasher: unsafe {
             Cow::Owned(DatumHashBuilder::from_type_id(
-                b.element_type.0,
-                b.collation.to_option_oid(),
+                log.element_type.0,
+                log.collation.to_option_oid(),
+                log.hash_function.first().copied().map_or(HashFunction::Postgres, HashFunction::from_id),
             ))
         },
         phantom: Default::default(),
-    };
-    if a.buildhasher().type_id != b.buildhasher().type_id {
-        // TODO
-        error!("missmatched types")
     }
+}
 
-    let merged = HLL::merge(&a, &b);
-    flatten_log(merged.as_hyperloglog())
+// Logs can only be combined if they hash their values the same way and use the
+// same number of registers.
+fn comparable_logs<'input>(a: &HyperLogLog<'input>, b: &HyperLogLog<'input>)
+-> (HLL<'input, Datum, DatumHashBuilder>, HLL<'input, Datum, DatumHashBuilder>) {
+    let (a, b) = (to_hll(a), to_hll(b));
+    if a.b != b.b {
+        error!("hyperloglogs must have the same number of buckets, not {} and {}", a.m(), b.m())
+    }
+    if a.buildhasher().type_id != b.buildhasher().type_id {
+        error!("hyperloglogs must be over values of the same type")
+    }
+    if a.buildhasher().collation != b.buildhasher().collation {
+        error!("hyperloglogs must use the same collation")
+    }
+    if a.buildhasher().hash != b.buildhasher().hash {
+        error!("hyperloglogs must use the same hash function")
+    }
+    (a, b)
 }
 
 fn flatten_log(hyperloglog: HLL<Datum, DatumHashBuilder>)
 -> timescale_analytics_experimental::HyperLogLog<'static> {
-    let (element_type, collation) = {
+    let (element_type, collation, hash) = {
         let hasher = hyperloglog.buildhasher();
-        (ShortTypeId(hasher.type_id), PgCollationId(hasher.collation))
+        (ShortTypeId(hasher.type_id), PgCollationId(hasher.collation), hasher.hash)
+    };
+
+    // write the oldest version that can represent the log
+    let version = match (&hyperloglog.registers, hash) {
+        (_, hash) if hash != HashFunction::Postgres => 3,
+        (Registers::Sparse(_), _) => 2,
+        (Registers::Dense(_), _) => 1,
+    };
+    let (registers, dense_registers, sparse_registers) = match (&hyperloglog.registers, version) {
+        (Registers::Dense(registers), 1) => (&registers[..], &[][..], vec![]),
+        (Registers::Dense(registers), _) => (&[][..], &registers[..], vec![]),
+        (Registers::Sparse(entries), _) => (&[][..], &[][..], sparse::compress(entries)),
+    };
+    let (num_registers, sparse_bytes) = match version {
+        1 => (vec![], vec![]),
+        _ => (vec![dense_registers.len() as u32], vec![sparse_registers.len() as u32]),
+    };
+    let hash_function = match version {
+        3 => vec![hash as u32],
+        _ => vec![],
     };
 
     // we need to flatten the vector to a single buffer that contains
     // both the size, the data, and the varlen header
     unsafe {
-        flatten!(HyperLogLog {
+        flatten!(HyperLogLog version: version, {
             element_type: &element_type,
             collation: &collation,
             b: &(hyperloglog.b as u32),
-            registers: hyperloglog.registers,
+            registers,
+            num_registers: &num_registers,
+            sparse_bytes: &sparse_bytes,
+            dense_registers,
+            sparse_registers: &sparse_registers,
+            hash_function: &hash_function,
         })
         .into()
     }
 }
 
-// TODO move to it's own mod if we reuse it
-struct DatumHashBuilder {
-    info: pg_sys::FunctionCallInfo,
-    type_id: pg_sys::Oid,
-    collation: pg_sys::Oid,
-}
-
-impl DatumHashBuilder {
-    unsafe fn from_type_id(type_id: pg_sys::Oid, collation: Option<Oid>) -> Self {
-        let entry =
-            pg_sys::lookup_type_cache(type_id, pg_sys::TYPECACHE_HASH_EXTENDED_PROC_FINFO as _);
-        Self::from_type_cache_entry(entry, collation)
-    }
-
-    unsafe fn from_type_cache_entry(
-        tentry: *const pg_sys::TypeCacheEntry,
-        collation: Option<Oid>,
-    ) -> Self {
-        let flinfo = if (*tentry).hash_extended_proc_finfo.fn_addr.is_some() {
-            &(*tentry).hash_extended_proc_finfo
-        } else {
-            pgx::error!("no hash function");
-        };
-
-        // 1 argument for the key, 1 argument for the seed
-        let size =
-            size_of::<pg_sys::FunctionCallInfoBaseData>() + size_of::<pg_sys::NullableDatum>() * 2;
-        let mut info = pg_sys::palloc0(size) as pg_sys::FunctionCallInfo;
-
-        (*info).flinfo = flinfo as *const pg_sys::FmgrInfo as *mut pg_sys::FmgrInfo;
-        (*info).context = std::ptr::null_mut();
-        (*info).resultinfo = std::ptr::null_mut();
-        (*info).fncollation = (*tentry).typcollation;
-        (*info).isnull = false;
-        (*info).nargs = 1;
-
-        let collation = match collation {
-            Some(collation) => collation,
-            None => (*tentry).typcollation,
-        };
-
-        Self {
-            info,
-            type_id: (*tentry).type_id,
-            collation,
-        }
-    }
-}
-
-impl Clone for DatumHashBuilder {
-    fn clone(&self) -> Self {
-        Self {
-            info: self.info,
-            type_id: self.type_id,
-            collation: self.collation,
-        }
-    }
-}
-
-impl BuildHasher for DatumHashBuilder {
-    type Hasher = DatumHashBuilder;
-
-    fn build_hasher(&self) -> Self::Hasher {
-        Self {
-            info: self.info,
-            type_id: self.type_id,
-            collation: self.collation,
-        }
-    }
-}
-
-impl Hasher for DatumHashBuilder {
-    fn finish(&self) -> u64 {
-        //FIXME ehhh, this is wildly unsafe, should at least have a separate hash
-        //      buffer for each, probably should have separate args
-        let value = unsafe {
-            let value = (*(*self.info).flinfo).fn_addr.unwrap()(self.info);
-            (*self.info).args.as_mut_slice(1)[0] = pg_sys::NullableDatum {
-                value: 0,
-                isnull: true,
-            };
-            (*self.info).isnull = false;
-            //FIXME 32bit vs 64 bit get value from datum on 32b arch
-            value
-        };
-        value as u64
-    }
-
-    fn write(&mut self, bytes: &[u8]) {
-        if bytes.len() != size_of::<usize>() {
-            panic!("invalid datum hash")
-        }
-
-        let mut b = [0; size_of::<usize>()];
-        for i in 0..size_of::<usize>() {
-            b[i] = bytes[i]
-        }
-        self.write_usize(usize::from_ne_bytes(b))
-    }
-
-    fn write_usize(&mut self, i: usize) {
-        unsafe {
-            (*self.info).args.as_mut_slice(1)[0] = pg_sys::NullableDatum {
-                value: i,
-                isnull: false,
-            };
-            (*self.info).isnull = false;
-        }
-    }
-}
-
-impl PartialEq for DatumHashBuilder {
-    fn eq(&self, other: &Self) -> bool {
-        self.type_id.eq(&other.type_id)
-    }
-}
-
-impl Eq for DatumHashBuilder {}
-
-impl Serialize for DatumHashBuilder {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        let collation = if self.collation == 0 {
-            None
-        } else {
-            Some(PgCollationId(self.collation))
-        };
-        (ShortTypeId(self.type_id), collation).serialize(serializer)
-    }
-}
-
-impl<'de> Deserialize<'de> for DatumHashBuilder {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        let (type_id, collation) =
-            <(ShortTypeId, Option<PgCollationId>)>::deserialize(deserializer)?;
-        //FIXME no collation?
-        let deserialized = unsafe { Self::from_type_id(type_id.0, collation.map(|c| c.0)) };
-        Ok(deserialized)
-    }
-}
-
 #[cfg(any(test, feature = "pg_test"))]
 mod tests {
     use pgx::*;
@@ -552,5 +561,349 @@ mod tests {
         });
     }
 
+    #[pg_test]
+    fn test_hll_sparse() {
+        Spi::execute(|client| {
+            // with only a few distinct values only the used registers are stored
+            let size = client
+                .select(
+                    "SELECT pg_column_size(timescale_analytics_experimental.hyperloglog(65536, v::int)) \
+                    FROM generate_series(1, 3) v",
+                    None,
+                    None,
+                )
+                .first()
+                .get_one::<i32>();
+            assert!(size.unwrap() < 64, "{:?}", size);
+
+            let (count, num_registers) = client
+                .select(
+                    "SELECT \
+                        timescale_analytics_experimental.hyperloglog_count(log), \
+                        (log::text::jsonb->'num_registers'->>0)::int \
+                    FROM (\
+                        SELECT timescale_analytics_experimental.hyperloglog(65536, v::int) AS log \
+                        FROM generate_series(1, 3) v\
+                    ) logs",
+                    None,
+                    None,
+                )
+                .first()
+                .get_two::<i64, i32>();
+            assert_eq!(count, Some(3));
+            assert_eq!(num_registers, Some(0));
+
+            // the text form of a sparse log round-trips
+            let text = client
+                .select(
+                    "SELECT timescale_analytics_experimental.hyperloglog(4096, v::int)::TEXT \
+                    FROM generate_series(1, 100) v",
+                    None,
+                    None,
+                )
+                .first()
+                .get_one::<String>()
+                .unwrap();
+            let count = client
+                .select(
+                    &format!("SELECT timescale_analytics_experimental.hyperloglog_count('{}')", text),
+                    None,
+                    None,
+                )
+                .first()
+                .get_one::<i64>();
+            let expected = client
+                .select(
+                    "SELECT timescale_analytics_experimental.hyperloglog_count(\
+                        timescale_analytics_experimental.hyperloglog(4096, v::int)\
+                    ) FROM generate_series(1, 100) v",
+                    None,
+                    None,
+                )
+                .first()
+                .get_one::<i64>();
+            assert_eq!(count, expected);
+
+            // past m/8 distinct values the log becomes dense, and is written
+            // in the original layout
+            let (version, num_registers) = client
+                .select(
+                    "SELECT (log->>'version')::int, jsonb_array_length(log->'registers') \
+                    FROM (\
+                        SELECT timescale_analytics_experimental.hyperloglog(4096, v::int)::text::jsonb AS log \
+                        FROM generate_series(1, 10000) v\
+                    ) logs",
+                    None,
+                    None,
+                )
+                .first()
+                .get_two::<i32, i32>();
+            assert_eq!(version, Some(1));
+            assert_eq!(num_registers, Some(4096));
+        });
+    }
+
+    #[pg_test]
+    fn test_hll_union_sparse_and_dense() {
+        Spi::execute(|client| {
+            let query = |a: &str, b: &str| format!(
+                "SELECT timescale_analytics_experimental.hyperloglog_count(\
+                    timescale_analytics_experimental.hyperloglog_union(\
+                        (SELECT timescale_analytics_experimental.hyperloglog(4096, v::int) FROM generate_series({}) v),\
+                        (SELECT timescale_analytics_experimental.hyperloglog(4096, v::int) FROM generate_series({}) v)\
+                    )\
+                )", a, b);
+            let expected = client
+                .select(
+                    "SELECT timescale_analytics_experimental.hyperloglog_count(\
+                        timescale_analytics_experimental.hyperloglog(4096, v::int)\
+                    ) FROM (\
+                        SELECT generate_series(1, 100) UNION ALL SELECT generate_series(1000, 10000)\
+                    ) s(v)",
+                    None,
+                    None,
+                )
+                .first()
+                .get_one::<i64>();
+
+            // sparse with dense, in both orders
+            for &(a, b) in &[("1, 100", "1000, 10000"), ("1000, 10000", "1, 100")] {
+                let count = client
+                    .select(&query(a, b), None, None)
+                    .first()
+                    .get_one::<i64>();
+                assert_eq!(count, expected);
+            }
+
+            // sparse with sparse
+            let count = client
+                .select(&query("1, 100", "50, 150"), None, None)
+                .first()
+                .get_one::<i64>();
+            let expected = client
+                .select(
+                    "SELECT timescale_analytics_experimental.hyperloglog_count(\
+                        timescale_analytics_experimental.hyperloglog(4096, v::int)\
+                    ) FROM generate_series(1, 150) v",
+                    None,
+                    None,
+                )
+                .first()
+                .get_one::<i64>();
+            assert_eq!(count, expected);
+        });
+    }
+
+    #[pg_test]
+    fn test_hll_read_version_1() {
+        Spi::execute(|client| {
+            client.select("SET search_path TO timescale_analytics_experimental, public", None, None);
+            // a log written before sparse logs were added
+            let v1 = "{\"version\":1,\"element_type\":\"INT4\",\"collation\":null,\"b\":5,\"registers\":[6,2,6,3,7,2,5,3,2,3,4,4,0,2,2,3,1,4,2,2,2,2,3,2,2,5,1,3,3,3,3,3]}";
+            let count = client
+                .select(&format!("SELECT hyperloglog_count('{}')", v1), None, None)
+                .first()
+                .get_one::<i64>();
+            assert_eq!(count, Some(113));
+
+            // and it can be combined with the sparse logs written now
+            let (union, expected) = client
+                .select(
+                    &format!(
+                        "SELECT \
+                            hyperloglog_count(hyperloglog_union('{}', (SELECT hyperloglog(32, v::int) FROM generate_series(1, 3) v))), \
+                            hyperloglog_count(hyperloglog(32, v::int)) \
+                        FROM generate_series(1, 100) v",
+                        v1
+                    ),
+                    None,
+                    None,
+                )
+                .first()
+                .get_two::<i64, i64>();
+            assert_eq!(union, expected);
+        });
+    }
+
+    #[pg_test(error = "invalid hyperloglog, 16 registers for b = 5")]
+    fn test_hll_invalid_registers() {
+        Spi::execute(|client| {
+            client.select(
+                "SELECT timescale_analytics_experimental.hyperloglog_count('{\"version\":2,\"element_type\":\"INT4\",\"collation\":null,\"b\":5,\"registers\":[],\"num_registers\":[16],\"sparse_bytes\":[0],\"dense_registers\":[1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1]}')",
+                None,
+                None,
+            );
+        });
+    }
+
+    #[pg_test]
+    fn test_hll_intersection_and_jaccard() {
+        Spi::execute(|client| {
+            client.select("SET search_path TO timescale_analytics_experimental, public", None, None);
+            // the standard error of a hyperloglog with 8192 buckets
+            let relative_error = (3.0 * 2f64.ln() - 1.0).sqrt() / 8192f64.sqrt();
+            let n = 10_000;
+            for &shared in &[0, 1000, 2500, 5000, 7500, 9000, 10_000] {
+                let (intersection, jaccard) = client
+                    .select(
+                        &format!(
+                            "SELECT approx_intersection_count(a.log, b.log), approx_jaccard(a.log, b.log) \
+                            FROM (SELECT hyperloglog(8192, v) AS log FROM generate_series(1, {n}) v) a, \
+                                (SELECT hyperloglog(8192, v) AS log FROM generate_series({start}, {end}) v) b",
+                            n = n,
+                            start = n - shared + 1,
+                            end = 2 * n - shared,
+                        ),
+                        None,
+                        None,
+                    )
+                    .first()
+                    .get_two::<i64, f64>();
+                let (intersection, jaccard) = (intersection.unwrap(), jaccard.unwrap());
+
+                // both estimates have an error relative to the size of the union
+                let union = 2 * n - shared;
+                let expected_jaccard = shared as f64 / union as f64;
+                assert!(
+                    ((intersection - shared) as f64).abs() <= 3.5 * relative_error * union as f64,
+                    "shared {}: estimated {}", shared, intersection
+                );
+                assert!(
+                    (jaccard - expected_jaccard).abs() <= 3.5 * relative_error,
+                    "shared {}: estimated {} expected {}", shared, jaccard, expected_jaccard
+                );
+            }
+        });
+    }
+
+    #[pg_test(error = "hyperloglogs must have the same number of buckets, not 32 and 64")]
+    fn test_hll_intersection_mismatched_size() {
+        Spi::execute(|client| {
+            client.select(
+                "SELECT timescale_analytics_experimental.approx_intersection_count(\
+                    (SELECT timescale_analytics_experimental.hyperloglog(32, v) FROM generate_series(1, 100) v),\
+                    (SELECT timescale_analytics_experimental.hyperloglog(64, v) FROM generate_series(1, 100) v)\
+                )",
+                None,
+                None,
+            );
+        });
+    }
+
+    #[pg_test(error = "hyperloglogs must be over values of the same type")]
+    fn test_hll_jaccard_mismatched_type() {
+        Spi::execute(|client| {
+            client.select(
+                "SELECT timescale_analytics_experimental.approx_jaccard(\
+                    (SELECT timescale_analytics_experimental.hyperloglog(32, v::int) FROM generate_series(1, 100) v),\
+                    (SELECT timescale_analytics_experimental.hyperloglog(32, v::bigint) FROM generate_series(1, 100) v)\
+                )",
+                None,
+                None,
+            );
+        });
+    }
+
+    #[pg_test(error = "hyperloglogs must use the same collation")]
+    fn test_hll_jaccard_mismatched_collation() {
+        Spi::execute(|client| {
+            client.select(
+                "SELECT timescale_analytics_experimental.approx_jaccard(\
+                    (SELECT timescale_analytics_experimental.hyperloglog(32, v::text) FROM generate_series(1, 100) v),\
+                    (SELECT timescale_analytics_experimental.hyperloglog(32, v::text COLLATE \"C\") FROM generate_series(1, 100) v)\
+                )",
+                None,
+                None,
+            );
+        });
+    }
+
+    // The xxhash64 and prehashed hyperloglogs must never change, these
+    // outputs are pinned so that anything that would change them is caught.
+    #[pg_test]
+    fn test_hll_stable_hash_pinned() {
+        Spi::execute(|client| {
+            use crate::serialization::PgCollationId;
+
+            client.select("SET search_path TO timescale_analytics_experimental, public", None, None);
+            let text = |query: &str| client
+                .select(query, None, None)
+                .first()
+                .get_one::<String>()
+                .unwrap();
+
+            assert_eq!(
+                text("SELECT hyperloglog(64, v::int, 'xxhash64')::TEXT FROM generate_series(1, 3) v"),
+                "{\"version\":3,\"element_type\":\"INT4\",\"collation\":null,\"b\":6,\"registers\":[],\"num_registers\":[0],\"sparse_bytes\":[6],\"sparse_registers\":[6,68,10,52,250,131],\"hash_function\":[1]}",
+            );
+            assert_eq!(
+                text("SELECT hyperloglog(64, v::float, 'xxhash64')::TEXT FROM generate_series(1, 3) v"),
+                "{\"version\":3,\"element_type\":\"FLOAT8\",\"collation\":null,\"b\":6,\"registers\":[],\"num_registers\":[0],\"sparse_bytes\":[6],\"sparse_registers\":[6,44,2,28,2,12],\"hash_function\":[1]}",
+            );
+            let default_collation = serde_json::to_string(&PgCollationId(100)).unwrap();
+            assert_eq!(
+                text("SELECT hyperloglog(64, v, 'xxhash64')::TEXT FROM (VALUES ('one'), ('two'), ('three')) vals(v)"),
+                format!("{{\"version\":3,\"element_type\":\"TEXT\",\"collation\":{},\"b\":6,\"registers\":[],\"num_registers\":[0],\"sparse_bytes\":[6],\"sparse_registers\":[6,44,14,36,254,135],\"hash_function\":[1]}}", default_collation),
+            );
+            assert_eq!(
+                text("SELECT hyperloglog(32, v::int, 'xxhash64')::TEXT FROM generate_series(1, 100) v"),
+                "{\"version\":3,\"element_type\":\"INT4\",\"collation\":null,\"b\":5,\"registers\":[],\"num_registers\":[32],\"sparse_bytes\":[0],\"dense_registers\":[1,1,4,2,6,0,2,2,2,6,1,3,2,2,4,3,3,4,5,9,2,6,2,3,2,7,10,1,1,4,3,7],\"hash_function\":[1]}",
+            );
+            assert_eq!(
+                text("SELECT hyperloglog_hashed(64, h)::TEXT FROM (VALUES (1), (2), (3), (-1), (1099511627776)) hashes(h)"),
+                "{\"version\":3,\"element_type\":\"INT8\",\"collation\":null,\"b\":6,\"registers\":[],\"num_registers\":[0],\"sparse_bytes\":[9],\"sparse_registers\":[49,142,4,2,4,2,4,26,239],\"hash_function\":[2]}",
+            );
+
+            let count = client
+                .select("SELECT hyperloglog_count(hyperloglog(32, v::int, 'xxhash64')) FROM generate_series(1, 100) v", None, None)
+                .first()
+                .get_one::<i64>();
+            assert_eq!(count, Some(106));
+        });
+    }
+
+    #[pg_test]
+    fn test_hll_postgres_hash_is_default() {
+        Spi::execute(|client| {
+            let (default, postgres) = client
+                .select(
+                    "SELECT \
+                        timescale_analytics_experimental.hyperloglog(32, v::int)::TEXT, \
+                        timescale_analytics_experimental.hyperloglog(32, v::int, 'Postgres')::TEXT \
+                    FROM generate_series(1, 100) v",
+                    None,
+                    None,
+                )
+                .first()
+                .get_two::<String, String>();
+            assert_eq!(default, postgres);
+        });
+    }
+
+    #[pg_test(error = "unknown hash function \"md5\", expected \"postgres\" or \"xxhash64\"")]
+    fn test_hll_unknown_hash_function() {
+        Spi::execute(|client| {
+            client.select(
+                "SELECT timescale_analytics_experimental.hyperloglog(32, v, 'md5') FROM generate_series(1, 100) v",
+                None,
+                None,
+            );
+        });
+    }
+
+    #[pg_test(error = "hyperloglogs must use the same hash function")]
+    fn test_hll_union_mismatched_hash_function() {
+        Spi::execute(|client| {
+            client.select(
+                "SELECT timescale_analytics_experimental.hyperloglog_union(\
+                    (SELECT timescale_analytics_experimental.hyperloglog(32, v, 'postgres') FROM generate_series(1, 100) v),\
+                    (SELECT timescale_analytics_experimental.hyperloglog(32, v, 'xxhash64') FROM generate_series(1, 100) v)\
+                )",
+                None,
+                None,
+            );
+        });
+    }
+
     //TODO test continuous aggregates
 }
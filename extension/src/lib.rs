@@ -3,17 +3,23 @@ pub mod triggers;
 
 pub mod tdigest;
 pub mod hyperloglog;
+pub mod topn;
 pub mod uddsketch;
 pub mod time_weighted_average;
+pub mod state_agg;
 pub mod asap;
 pub mod lttb;
 pub mod counter_agg;
+pub mod counter_agg_by;
+pub mod gauge_agg;
+pub mod stats_agg;
 pub mod range;
 pub mod utilities;
 pub mod time_series;
 
 mod palloc;
 mod aggregate_utils;
+mod datum_utils;
 mod type_builder;
 mod serialization;
 mod schema_test;
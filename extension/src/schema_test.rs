@@ -95,10 +95,13 @@ mod tests {
         "function approx_percentile(double precision,uddsketch)",
         "function approx_percentile_rank(double precision,uddsketch)",
         "function error(uddsketch)",
+        "function max_val(uddsketch)",
+        "function min_val(uddsketch)",
         "function mean(uddsketch)",
         "function num_vals(uddsketch)",
         "function percentile_agg(double precision)",
         "function percentile_agg(uddsketch)",
+        "function percentile_agg_inv(internal,double precision)",
         "function percentile_agg_trans(internal,double precision)",
         "function uddsketch(integer,double precision,double precision)",
         "function rollup(uddsketch)",
@@ -106,6 +109,7 @@ mod tests {
         "function uddsketch_compound_trans(internal,uddsketch)",
         "function uddsketch_deserialize(bytea,internal)",
         "function uddsketch_final(internal)",
+        "function uddsketch_inv(internal,integer,double precision,double precision)",
         "function uddsketch_in(cstring)",
         "function uddsketch_out(uddsketch)",
         "function uddsketch_serialize(internal)",
@@ -80,4 +80,10 @@ pub(crate) mod serde_reference_adaptor {
     pub(crate) fn default_header() -> &'static u32 {
         &0
     }
+
+    // fields that are only present in some versions are empty in the others,
+    // leave them out of the text form of those versions
+    pub(crate) fn is_empty<T>(slice: &&[T]) -> bool {
+        slice.is_empty()
+    }
 }
@@ -0,0 +1,542 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use pgx::*;
+
+use flat_serialize::*;
+
+use crate::{
+    aggregate_utils::{get_collation, in_aggregate_context},
+    datum_utils::{interval_to_micros, micros_to_interval, DatumHashBuilder, HashFunction},
+    flatten, json_inout_funcs,
+    palloc::Internal,
+    pg_type,
+    serialization::PgCollationId,
+};
+
+use state_agg::{StateAggError, StateSummary};
+
+#[allow(non_camel_case_types)]
+type text = pg_sys::Datum;
+
+#[allow(non_camel_case_types)]
+type interval = pg_sys::Datum;
+
+#[allow(non_camel_case_types)]
+type bytea = pg_sys::Datum;
+
+// As in topn, the states are tracked by their binary representation.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct StateAggTrans {
+    // the collation of the states, this uses `HashFunction::XxHash64` so that
+    // nondeterministic collations, under which states with different bytes may
+    // be equal, are rejected
+    datum_info: DatumHashBuilder,
+    #[serde(skip)]
+    point_buffer: Vec<(i64, Vec<u8>)>,
+    summary_buffer: Vec<StateSummary<Vec<u8>>>,
+}
+
+impl StateAggTrans {
+    fn combine_points(&mut self) {
+        if self.point_buffer.is_empty() {
+            return;
+        }
+        self.point_buffer.sort_unstable_by_key(|(ts, _)| *ts);
+        self.summary_buffer.push(
+            StateSummary::new_from_sorted_iter(self.point_buffer.drain(..)).unwrap(),
+        );
+    }
+
+    fn push_summaries(&mut self, other: &StateAggTrans) {
+        if self.datum_info.collation != other.datum_info.collation {
+            error!("state_aggs must use the same collation")
+        }
+        self.summary_buffer.extend(other.summary_buffer.iter().cloned());
+    }
+
+    fn combine_summaries(&mut self) {
+        self.combine_points();
+        if self.summary_buffer.len() <= 1 {
+            return;
+        }
+        self.summary_buffer.sort_unstable_by_key(|s| s.first());
+        self.summary_buffer = match StateSummary::combine_sorted_iter(&self.summary_buffer) {
+            Ok(summary) => vec![summary],
+            Err(StateAggError::OrderError) => error!("state_aggs must cover disjoint time ranges"),
+            Err(e) => Err(e).unwrap(),
+        };
+    }
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn state_agg_trans(
+    state: Option<Internal<StateAggTrans>>,
+    ts: Option<pg_sys::TimestampTz>,
+    value: Option<text>,
+    fc: pg_sys::FunctionCallInfo,
+) -> Option<Internal<StateAggTrans>> {
+    unsafe {
+        in_aggregate_context(fc, || {
+            let (ts, value) = match (ts, value) {
+                (Some(ts), Some(value)) => (ts, value),
+                _ => return state,
+            };
+            let mut state = match state {
+                None => StateAggTrans {
+                    datum_info: DatumHashBuilder::from_type_id(
+                        pg_sys::TEXTOID,
+                        get_collation(fc),
+                        HashFunction::XxHash64,
+                    ),
+                    point_buffer: vec![],
+                    summary_buffer: vec![],
+                }
+                .into(),
+                Some(state) => state,
+            };
+            let value = state.datum_info.with_value_bytes(value, |bytes| bytes.to_vec());
+            state.point_buffer.push((ts, value));
+            Some(state)
+        })
+    }
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn state_agg_rollup_trans(
+    state: Option<Internal<StateAggTrans>>,
+    value: Option<timescale_analytics_experimental::StateAgg>,
+    fc: pg_sys::FunctionCallInfo,
+) -> Option<Internal<StateAggTrans>> {
+    unsafe {
+        in_aggregate_context(fc, || {
+            let value = match value {
+                None => return state,
+                Some(value) => value.to_trans(),
+            };
+            match state {
+                None => Some(value.into()),
+                Some(mut state) => {
+                    state.push_summaries(&value);
+                    Some(state)
+                }
+            }
+        })
+    }
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn state_agg_combine(
+    state1: Option<Internal<StateAggTrans>>,
+    state2: Option<Internal<StateAggTrans>>,
+    fc: pg_sys::FunctionCallInfo,
+) -> Option<Internal<StateAggTrans>> {
+    unsafe {
+        in_aggregate_context(fc, || match (state1, state2) {
+            (None, None) => None,
+            (None, Some(state2)) => {
+                let mut s = state2.clone();
+                s.combine_points();
+                Some(s.into())
+            }
+            (Some(state1), None) => {
+                let mut s = state1.clone();
+                s.combine_points();
+                Some(s.into())
+            }
+            (Some(state1), Some(state2)) => {
+                let mut s1 = state1.clone();
+                s1.combine_points();
+                let mut s2 = state2.clone();
+                s2.combine_points();
+                s2.push_summaries(&s1);
+                Some(s2.into())
+            }
+        })
+    }
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn state_agg_serialize(mut state: Internal<StateAggTrans>) -> bytea {
+    state.combine_summaries();
+    crate::do_serialize!(state)
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental", strict)]
+pub fn state_agg_deserialize(
+    bytes: bytea,
+    _internal: Option<Internal<()>>,
+) -> Internal<StateAggTrans> {
+    crate::do_deserialize!(bytes, StateAggTrans)
+}
+
+pg_type! {
+    #[derive(Debug)]
+    struct StateAgg {
+        collation: PgCollationId,
+        num_states: u32,
+        num_runs: u64,
+        // the time of the last point, where the last run ends
+        last: i64,
+        states_bytes: u64,
+        // the start of each run of the same state
+        run_starts: [i64; self.num_runs],
+        // the distinct states are stored as the concatenation of their binary
+        // representations, state_ends[i] is where the i-th state ends
+        state_ends: [u64; self.num_states],
+        // the index of the state of each run
+        run_states: [u32; self.num_runs],
+        states: [u8; self.states_bytes],
+    }
+}
+
+// hack to allow us to qualify names with "timescale_analytics_experimental"
+// so that pgx generates the correct SQL
+mod timescale_analytics_experimental {
+    pub(crate) use super::*;
+
+    varlena_type!(StateAgg);
+}
+
+json_inout_funcs!(StateAgg);
+
+impl<'input> StateAgg<'input> {
+    fn datum_info(&self) -> DatumHashBuilder {
+        unsafe {
+            DatumHashBuilder::from_type_id(
+                pg_sys::TEXTOID,
+                self.collation.to_option_oid(),
+                HashFunction::XxHash64,
+            )
+        }
+    }
+
+    fn summary(&self) -> StateSummary<Vec<u8>> {
+        let starts = std::iter::once(0).chain(self.state_ends.iter().copied());
+        let states: Vec<_> = self.state_ends.iter().zip(starts)
+            .map(|(&end, start)| match self.states.get(start as usize..end as usize) {
+                Some(state) => state,
+                None => error!("invalid state_agg, malformed states"),
+            })
+            .collect();
+        let runs = self.run_starts.iter().zip(self.run_states.iter())
+            .map(|(&start, &state)| match states.get(state as usize) {
+                Some(state) => (start, state.to_vec()),
+                None => error!("invalid state_agg, unknown state {}", state),
+            });
+        match StateSummary::from_runs(runs, *self.last) {
+            Ok(summary) => summary,
+            Err(_) => error!("invalid state_agg, runs out of order"),
+        }
+    }
+
+    // The summary of a bucket starting at `start`, interpolated to its bounds using the
+    // neighbouring buckets, see `StateSummary::interpolate`.
+    fn interpolated_summary(
+        &self,
+        start: i64,
+        interval: i64,
+        prev: Option<&StateAgg>,
+        next: Option<&StateAgg>,
+    ) -> StateSummary<Vec<u8>> {
+        if prev.iter().chain(next.iter()).any(|agg| agg.collation.0 != self.collation.0) {
+            error!("state_aggs must use the same collation")
+        }
+        let prev = prev.map(|p| p.summary());
+        let next = next.map(|n| n.summary());
+        match self.summary().interpolate(start, interval, prev.as_ref(), next.as_ref()) {
+            Ok(summary) => summary,
+            Err(_) => error!("state_aggs must lie within their interval, with the previous one ending before it and the next one starting after it"),
+        }
+    }
+
+    fn to_trans(&self) -> StateAggTrans {
+        StateAggTrans {
+            datum_info: self.datum_info(),
+            point_buffer: vec![],
+            summary_buffer: vec![self.summary()],
+        }
+    }
+
+    fn from_summary(summary: &StateSummary<Vec<u8>>, collation: pg_sys::Oid) -> StateAgg<'static> {
+        let mut indexes = HashMap::new();
+        let mut state_ends = vec![];
+        let mut states = vec![];
+        let mut run_starts = vec![];
+        let mut run_states = vec![];
+        for (start, state) in summary.runs() {
+            let index = *indexes.entry(state).or_insert_with(|| {
+                states.extend_from_slice(state);
+                state_ends.push(states.len() as u64);
+                state_ends.len() as u32 - 1
+            });
+            run_starts.push(start);
+            run_states.push(index);
+        }
+
+        unsafe {
+            flatten!(StateAgg {
+                collation: &PgCollationId(collation),
+                num_states: &(state_ends.len() as u32),
+                num_runs: &(run_starts.len() as u64),
+                last: &summary.last(),
+                states_bytes: &(states.len() as u64),
+                run_starts: &run_starts,
+                state_ends: &state_ends,
+                run_states: &run_states,
+                states: &states,
+            })
+            .into()
+        }
+    }
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+fn state_agg_final(
+    state: Option<Internal<StateAggTrans>>,
+    fc: pg_sys::FunctionCallInfo,
+) -> Option<timescale_analytics_experimental::StateAgg<'static>> {
+    unsafe {
+        in_aggregate_context(fc, || {
+            let mut state = match state {
+                None => return None,
+                Some(state) => state.clone(),
+            };
+            state.combine_summaries();
+            debug_assert!(state.summary_buffer.len() <= 1);
+            state.summary_buffer.pop()
+                .map(|summary| StateAgg::from_summary(&summary, state.datum_info.collation))
+        })
+    }
+}
+
+extension_sql!(r#"
+CREATE AGGREGATE timescale_analytics_experimental.state_agg(ts timestamptz, state text)
+(
+    sfunc = timescale_analytics_experimental.state_agg_trans,
+    stype = internal,
+    finalfunc = timescale_analytics_experimental.state_agg_final,
+    combinefunc = timescale_analytics_experimental.state_agg_combine,
+    serialfunc = timescale_analytics_experimental.state_agg_serialize,
+    deserialfunc = timescale_analytics_experimental.state_agg_deserialize,
+    parallel = restricted
+);
+
+CREATE AGGREGATE timescale_analytics_experimental.rollup(agg timescale_analytics_experimental.StateAgg)
+(
+    sfunc = timescale_analytics_experimental.state_agg_rollup_trans,
+    stype = internal,
+    finalfunc = timescale_analytics_experimental.state_agg_final,
+    combinefunc = timescale_analytics_experimental.state_agg_combine,
+    serialfunc = timescale_analytics_experimental.state_agg_serialize,
+    deserialfunc = timescale_analytics_experimental.state_agg_deserialize,
+    parallel = restricted
+);
+"#);
+
+#[pg_extern(name="duration_in", schema = "timescale_analytics_experimental", strict, immutable)]
+pub fn state_agg_duration_in(
+    state: text,
+    agg: timescale_analytics_experimental::StateAgg,
+) -> interval {
+    let summary = agg.summary();
+    unsafe {
+        let duration = agg.datum_info().with_value_bytes(state, |bytes| summary.duration_in(&bytes.to_vec()));
+        micros_to_interval(duration)
+    }
+}
+
+// As with interpolated_average, the time from the start of the bucket to the first point
+// belongs to the last state of the previous bucket, and the last state lasts until the end
+// of the bucket.
+#[pg_extern(name="interpolated_duration_in", schema = "timescale_analytics_experimental", immutable)]
+pub fn state_agg_interpolated_duration_in(
+    state: Option<text>,
+    agg: Option<timescale_analytics_experimental::StateAgg>,
+    start: Option<pg_sys::TimestampTz>,
+    interval: Option<interval>,
+    prev: Option<timescale_analytics_experimental::StateAgg>,
+    next: Option<timescale_analytics_experimental::StateAgg>,
+) -> Option<interval> {
+    let (state, agg) = (state?, agg?);
+    let interval = unsafe { interval_to_micros(interval?) };
+    let summary = agg.interpolated_summary(start?, interval, prev.as_ref(), next.as_ref());
+    unsafe {
+        let duration = agg.datum_info().with_value_bytes(state, |bytes| summary.duration_in(&bytes.to_vec()));
+        Some(micros_to_interval(duration))
+    }
+}
+
+#[pg_extern(name="interpolated_states", schema = "timescale_analytics_experimental", immutable)]
+pub fn state_agg_interpolated_states(
+    agg: Option<timescale_analytics_experimental::StateAgg>,
+    start: Option<pg_sys::TimestampTz>,
+    interval: Option<interval>,
+    prev: Option<timescale_analytics_experimental::StateAgg>,
+    next: Option<timescale_analytics_experimental::StateAgg>,
+) -> impl std::iter::Iterator<Item = (name!(state,String),name!(duration,interval))> {
+    let rows: Vec<_> = match (agg, start, interval) {
+        (Some(agg), Some(start), Some(interval)) => {
+            let datum_info = agg.datum_info();
+            let interval = unsafe { interval_to_micros(interval) };
+            let summary = agg.interpolated_summary(start, interval, prev.as_ref(), next.as_ref());
+            summary.durations().into_iter()
+                .map(|(state, duration)| unsafe {
+                    (datum_info.output(datum_info.datum_from_bytes(state)), micros_to_interval(duration))
+                })
+                .collect()
+        }
+        _ => vec![],
+    };
+    rows.into_iter()
+}
+
+#[pg_extern(name="states", schema = "timescale_analytics_experimental", strict, immutable)]
+pub fn state_agg_states(
+    agg: timescale_analytics_experimental::StateAgg,
+) -> impl std::iter::Iterator<Item = (name!(state,String),name!(duration,interval))> + '_ {
+    let datum_info = agg.datum_info();
+    let summary = agg.summary();
+    let rows: Vec<_> = summary.durations().into_iter()
+        .map(|(state, duration)| unsafe {
+            (datum_info.output(datum_info.datum_from_bytes(state)), micros_to_interval(duration))
+        })
+        .collect();
+    rows.into_iter()
+}
+
+#[pg_extern(name="state_timeline", schema = "timescale_analytics_experimental", strict, immutable)]
+pub fn state_agg_state_timeline(
+    agg: timescale_analytics_experimental::StateAgg,
+) -> impl std::iter::Iterator<Item = (name!(state,String),name!(start_time,pg_sys::TimestampTz),name!(end_time,pg_sys::TimestampTz))> + '_ {
+    let datum_info = agg.datum_info();
+    let summary = agg.summary();
+    let rows: Vec<_> = summary.timeline()
+        .map(|(state, start, end)| unsafe {
+            (datum_info.output(datum_info.datum_from_bytes(state)), start, end)
+        })
+        .collect();
+    rows.into_iter()
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+mod tests {
+    use pgx::*;
+
+    #[pg_test]
+    fn test_state_agg() {
+        Spi::execute(|client| {
+            client.select("SET search_path TO timescale_analytics_experimental, public", None, None);
+            client.select("SET TIME ZONE 'UTC'", None, None);
+            client.select("CREATE TABLE test(ts timestamptz, state text)", None, None);
+            client.select("INSERT INTO test VALUES \
+                ('2020-01-01 00:00:00+00', 'online'), ('2020-01-01 00:01:00+00', 'online'), \
+                ('2020-01-01 00:02:00+00', 'degraded'), ('2020-01-01 00:05:00+00', 'offline'), \
+                ('2020-01-01 00:06:00+00', NULL), ('2020-01-01 00:15:00+00', 'online'), \
+                ('2020-01-01 00:20:00+00', 'online')", None, None);
+
+            let stmt = "SELECT duration_in('online', state_agg(ts, state ORDER BY random())) = '7 min' FROM test";
+            assert!(client.select(stmt, None, None).first().get_one::<bool>().unwrap());
+            let stmt = "SELECT duration_in('unknown', state_agg(ts, state)) = '0' FROM test";
+            assert!(client.select(stmt, None, None).first().get_one::<bool>().unwrap());
+
+            let mut rows = client.select(
+                "SELECT state, duration::text FROM states((SELECT state_agg(ts, state) FROM test))", None, None);
+            for &(state, duration) in &[("online", "00:07:00"), ("degraded", "00:03:00"), ("offline", "00:10:00")] {
+                let row = rows.next().unwrap();
+                assert_eq!(row.by_ordinal(1).unwrap().value::<String>().unwrap(), state);
+                assert_eq!(row.by_ordinal(2).unwrap().value::<String>().unwrap(), duration);
+            }
+            assert!(rows.next().is_none());
+
+            // the time between buckets belongs to the last state of the earlier one
+            let stmt = "WITH t AS (SELECT date_trunc('minute', ts), state_agg(ts, state) AS agg FROM test GROUP BY 1) \
+                SELECT array_agg(duration_in(s, (SELECT rollup(agg) FROM t))::text ORDER BY s)::text \
+                FROM unnest(ARRAY['degraded', 'offline', 'online']) s";
+            let durations = client.select(stmt, None, None).first().get_one::<String>().unwrap();
+            assert_eq!(durations, "{00:03:00,00:10:00,00:07:00}");
+
+            let mut rows = client.select(
+                "SELECT state, start_time::text, end_time::text FROM state_timeline((SELECT state_agg(ts, state) FROM test))", None, None);
+            let expected = [
+                ("online", "2020-01-01 00:00:00+00", "2020-01-01 00:02:00+00"),
+                ("degraded", "2020-01-01 00:02:00+00", "2020-01-01 00:05:00+00"),
+                ("offline", "2020-01-01 00:05:00+00", "2020-01-01 00:15:00+00"),
+                ("online", "2020-01-01 00:15:00+00", "2020-01-01 00:20:00+00"),
+            ];
+            for &(state, start, end) in &expected {
+                let row = rows.next().unwrap();
+                assert_eq!(row.by_ordinal(1).unwrap().value::<String>().unwrap(), state);
+                assert_eq!(row.by_ordinal(2).unwrap().value::<String>().unwrap(), start);
+                assert_eq!(row.by_ordinal(3).unwrap().value::<String>().unwrap(), end);
+            }
+            assert!(rows.next().is_none());
+
+            // the text format round-trips
+            let stmt = "SELECT duration_in('offline', state_agg(ts, state)::text::StateAgg) = '10 min' FROM test";
+            assert!(client.select(stmt, None, None).first().get_one::<bool>().unwrap());
+        });
+    }
+
+    #[pg_test]
+    fn test_state_agg_interpolated() {
+        Spi::execute(|client| {
+            client.select("SET search_path TO timescale_analytics_experimental, public", None, None);
+            client.select("CREATE TABLE test(bucket timestamptz, ts timestamptz, state text)", None, None);
+            client.select("INSERT INTO test VALUES \
+                ('2020-01-01 00:00:00+00', '2020-01-01 00:00:00+00', 'online'), \
+                ('2020-01-01 00:00:00+00', '2020-01-01 00:03:00+00', 'offline'), \
+                ('2020-01-01 00:05:00+00', '2020-01-01 00:07:00+00', 'offline'), \
+                ('2020-01-01 00:05:00+00', '2020-01-01 00:08:00+00', 'online'), \
+                ('2020-01-01 00:10:00+00', '2020-01-01 00:12:00+00', 'online')", None, None);
+
+            let interpolated = |state: &str| format!("WITH s AS ( \
+                    SELECT bucket, state_agg(ts, state) AS agg FROM test GROUP BY bucket \
+                ), i AS ( \
+                    SELECT bucket, interpolated_duration_in('{}', agg, bucket, '5 min', \
+                        LAG(agg) OVER (ORDER BY bucket), LEAD(agg) OVER (ORDER BY bucket)) AS duration \
+                    FROM s \
+                ) SELECT array_agg(duration::text ORDER BY bucket)::text FROM i", state);
+
+            // offline from 00:03 is carried to 00:07 in the next bucket, and online from 00:08
+            // lasts until the end of each bucket
+            let durations = client.select(&interpolated("online"), None, None).first().get_one::<String>().unwrap();
+            assert_eq!(durations, "{00:03:00,00:02:00,00:05:00}");
+            let durations = client.select(&interpolated("offline"), None, None).first().get_one::<String>().unwrap();
+            assert_eq!(durations, "{00:02:00,00:03:00,00:00:00}");
+
+            let mut rows = client.select(
+                "SELECT state, duration::text FROM interpolated_states( \
+                    (SELECT state_agg(ts, state) FROM test WHERE bucket = '2020-01-01 00:05:00+00'), \
+                    '2020-01-01 00:05:00+00', '5 min', \
+                    (SELECT state_agg(ts, state) FROM test WHERE bucket = '2020-01-01 00:00:00+00'), NULL)", None, None);
+            for &(state, duration) in &[("offline", "00:03:00"), ("online", "00:02:00")] {
+                let row = rows.next().unwrap();
+                assert_eq!(row.by_ordinal(1).unwrap().value::<String>().unwrap(), state);
+                assert_eq!(row.by_ordinal(2).unwrap().value::<String>().unwrap(), duration);
+            }
+            assert!(rows.next().is_none());
+        });
+    }
+
+    #[pg_test(error = "state_aggs must lie within their interval, with the previous one ending before it and the next one starting after it")]
+    fn test_state_agg_interpolated_out_of_interval() {
+        Spi::execute(|client| {
+            client.select("SET search_path TO timescale_analytics_experimental, public", None, None);
+            client.select("SELECT interpolated_duration_in('a', state_agg(ts, 'a'), '2020-01-01 00:05:00+00', '5 min', NULL, NULL) \
+                FROM generate_series('2020-01-01 00:00:00+00'::timestamptz, '2020-01-01 00:10:00+00', '1 min') ts", None, None);
+        });
+    }
+
+    #[pg_test(error = "state_aggs must cover disjoint time ranges")]
+    fn test_state_agg_overlapping_rollup() {
+        Spi::execute(|client| {
+            client.select("SET search_path TO timescale_analytics_experimental, public", None, None);
+            client.select("WITH t AS (SELECT s, state_agg(ts, s) AS agg \
+                FROM generate_series('2020-01-01 00:00:00+00'::timestamptz, '2020-01-01 00:10:00+00', '1 min') ts, \
+                    unnest(ARRAY['a', 'b']) s GROUP BY s) \
+                SELECT rollup(agg) FROM t", None, None);
+        });
+    }
+}
@@ -0,0 +1,469 @@
+use std::slice;
+
+use pgx::*;
+
+use flat_serialize::*;
+
+use crate::{
+    aggregate_utils::in_aggregate_context,
+    json_inout_funcs,
+    flatten,
+    palloc::Internal,
+    pg_type,
+};
+
+use counter_agg::regression::{
+    HigherMoments,
+    RegressionError,
+    RegressionPartials,
+    RegressionSummary,
+    XYPair,
+};
+
+#[allow(non_camel_case_types)]
+type bytea = pg_sys::Datum;
+
+pg_type! {
+    #[derive(Debug, PartialEq)]
+    struct StatsSummary {
+        regress: RegressionPartials,
+        #[serde(default)]
+        higher_moments: [HigherMoments; (self.version >= 2) as u8],
+    }
+}
+
+json_inout_funcs!(StatsSummary);
+
+const STATS_SUMMARY_VERSION: u8 = 2;
+
+// hack to allow us to qualify names with "timescale_analytics_experimental"
+// so that pgx generates the correct SQL
+mod timescale_analytics_experimental {
+    pub(crate) use super::*;
+
+    varlena_type!(StatsSummary);
+}
+
+impl<'input> StatsSummary<'input> {
+    pub(crate) fn to_internal_regression_summary(&self) -> RegressionSummary {
+        if *self.version > STATS_SUMMARY_VERSION {
+            error!("unsupported StatsSummary version {}, the newest supported version is {}", self.version, STATS_SUMMARY_VERSION)
+        }
+        // summaries from before version 2 don't have the higher moments
+        RegressionSummary::from_partials(*self.regress, self.higher_moments.first().copied())
+    }
+    pub(crate) fn from_internal_regression_summary(st: RegressionSummary) -> Self {
+        // as with CounterSummary, use the oldest version that can represent the summary
+        let (version, higher_moments) = match st.higher_moments {
+            Some(m) => (STATS_SUMMARY_VERSION, vec![m]),
+            None => (1, vec![]),
+        };
+        unsafe{
+            flatten!(
+            StatsSummary version: version, {
+                regress: &st.partials(),
+                higher_moments: &higher_moments,
+            })
+        }
+    }
+}
+
+fn overflow_error(err: RegressionError) -> ! {
+    match err {
+        RegressionError::DoubleOverflow => error!("value out of range: overflow in stats_agg"),
+        RegressionError::NotInvertible => error!("cannot remove value from stats_agg"),
+    }
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn stats_summary_trans_serialize(
+    state: Internal<RegressionSummary>,
+) -> bytea {
+    crate::do_serialize!(state)
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental", strict)]
+pub fn stats_summary_trans_deserialize(
+    bytes: bytea,
+    _internal: Option<Internal<()>>,
+) -> Internal<RegressionSummary> {
+    crate::do_deserialize!(bytes, RegressionSummary)
+}
+
+// as with the regr_* aggregates, rows where either value is NULL are ignored
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn stats_agg_trans(
+    state: Option<Internal<RegressionSummary>>,
+    y: Option<f64>,
+    x: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<RegressionSummary>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let p = match (y, x) {
+                (None, _) => return state,
+                (_, None) => return state,
+                (Some(y), Some(x)) => XYPair{x, y},
+            };
+            let mut state = match state {
+                None => RegressionSummary::new().into(),
+                Some(state) => state,
+            };
+            state.accum(p).unwrap_or_else(|e| overflow_error(e));
+            Some(state)
+        })
+    }
+}
+
+// the inverse transition function for the moving-aggregate form, returning NULL makes Postgres
+// recompute the aggregate over the current window, which we need to do once a non-finite value
+// has been seen as those cannot be removed.
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn stats_agg_inv(
+    state: Option<Internal<RegressionSummary>>,
+    y: Option<f64>,
+    x: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<RegressionSummary>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let p = match (y, x) {
+                (None, _) => return state,
+                (_, None) => return state,
+                (Some(y), Some(x)) => XYPair{x, y},
+            };
+            let mut state = state?;
+            match state.remove(p) {
+                Ok(()) => Some(state),
+                Err(RegressionError::NotInvertible) => None,
+                Err(e) => overflow_error(e),
+            }
+        })
+    }
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn stats_agg_summary_trans(
+    state: Option<Internal<RegressionSummary>>,
+    value: Option<timescale_analytics_experimental::StatsSummary>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<RegressionSummary>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            match (state, value) {
+                (state, None) => state,
+                (None, Some(value)) => Some(value.to_internal_regression_summary().into()),
+                (Some(state), Some(value)) => {
+                    let combined = state.combine(value.to_internal_regression_summary())
+                        .unwrap_or_else(|e| overflow_error(e));
+                    Some(combined.into())
+                }
+            }
+        })
+    }
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn stats_agg_combine(
+    state1: Option<Internal<RegressionSummary>>,
+    state2: Option<Internal<RegressionSummary>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<RegressionSummary>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            match (state1, state2) {
+                (None, None) => None,
+                (None, Some(state2)) => Some((*state2).into()),
+                (Some(state1), None) => Some((*state1).into()),
+                (Some(state1), Some(state2)) => {
+                    let combined = state1.combine(*state2)
+                        .unwrap_or_else(|e| overflow_error(e));
+                    Some(combined.into())
+                }
+            }
+        })
+    }
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+fn stats_agg_final(
+    state: Option<Internal<RegressionSummary>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<timescale_analytics_experimental::StatsSummary<'static>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let state = state?;
+            Some(StatsSummary::from_internal_regression_summary(*state).into())
+        })
+    }
+}
+
+extension_sql!(r#"
+CREATE AGGREGATE timescale_analytics_experimental.stats_agg( y DOUBLE PRECISION, x DOUBLE PRECISION )
+(
+    sfunc = timescale_analytics_experimental.stats_agg_trans,
+    stype = internal,
+    finalfunc = timescale_analytics_experimental.stats_agg_final,
+    combinefunc = timescale_analytics_experimental.stats_agg_combine,
+    serialfunc = timescale_analytics_experimental.stats_summary_trans_serialize,
+    deserialfunc = timescale_analytics_experimental.stats_summary_trans_deserialize,
+    msfunc = timescale_analytics_experimental.stats_agg_trans,
+    minvfunc = timescale_analytics_experimental.stats_agg_inv,
+    mstype = internal,
+    mfinalfunc = timescale_analytics_experimental.stats_agg_final,
+    parallel = safe
+);
+"#);
+
+extension_sql!(r#"
+CREATE AGGREGATE timescale_analytics_experimental.rollup(ss timescale_analytics_experimental.StatsSummary)
+(
+    sfunc = timescale_analytics_experimental.stats_agg_summary_trans,
+    stype = internal,
+    finalfunc = timescale_analytics_experimental.stats_agg_final,
+    combinefunc = timescale_analytics_experimental.stats_agg_combine,
+    serialfunc = timescale_analytics_experimental.stats_summary_trans_serialize,
+    deserialfunc = timescale_analytics_experimental.stats_summary_trans_deserialize,
+    parallel = safe
+);
+"#);
+
+#[pg_extern(name="num_vals", schema = "timescale_analytics_experimental", strict, immutable)]
+fn stats_agg_num_vals(
+    summary: timescale_analytics_experimental::StatsSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> i64 {
+    summary.to_internal_regression_summary().count()
+}
+
+#[pg_extern(name="average_y", schema = "timescale_analytics_experimental", strict, immutable)]
+fn stats_agg_average_y(
+    summary: timescale_analytics_experimental::StatsSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> Option<f64> {
+    Some(summary.to_internal_regression_summary().avg()?.y)
+}
+
+#[pg_extern(name="average_x", schema = "timescale_analytics_experimental", strict, immutable)]
+fn stats_agg_average_x(
+    summary: timescale_analytics_experimental::StatsSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> Option<f64> {
+    Some(summary.to_internal_regression_summary().avg()?.x)
+}
+
+#[pg_extern(name="slope", schema = "timescale_analytics_experimental", strict, immutable)]
+fn stats_agg_slope(
+    summary: timescale_analytics_experimental::StatsSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> Option<f64> {
+    summary.to_internal_regression_summary().slope()
+}
+
+#[pg_extern(name="intercept", schema = "timescale_analytics_experimental", strict, immutable)]
+fn stats_agg_intercept(
+    summary: timescale_analytics_experimental::StatsSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> Option<f64> {
+    summary.to_internal_regression_summary().intercept()
+}
+
+#[pg_extern(name="x_intercept", schema = "timescale_analytics_experimental", strict, immutable)]
+fn stats_agg_x_intercept(
+    summary: timescale_analytics_experimental::StatsSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> Option<f64> {
+    summary.to_internal_regression_summary().x_intercept()
+}
+
+#[pg_extern(name="corr", schema = "timescale_analytics_experimental", strict, immutable)]
+fn stats_agg_corr(
+    summary: timescale_analytics_experimental::StatsSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> Option<f64> {
+    summary.to_internal_regression_summary().corr()
+}
+
+#[pg_extern(name="determination_coeff", schema = "timescale_analytics_experimental", strict, immutable)]
+fn stats_agg_determination_coeff(
+    summary: timescale_analytics_experimental::StatsSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> Option<f64> {
+    summary.to_internal_regression_summary().determination_coeff()
+}
+
+#[pg_extern(name="covar_pop", schema = "timescale_analytics_experimental", strict, immutable)]
+fn stats_agg_covar_pop(
+    summary: timescale_analytics_experimental::StatsSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> Option<f64> {
+    summary.to_internal_regression_summary().covar_pop()
+}
+
+#[pg_extern(name="covar_samp", schema = "timescale_analytics_experimental", strict, immutable)]
+fn stats_agg_covar_samp(
+    summary: timescale_analytics_experimental::StatsSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> Option<f64> {
+    summary.to_internal_regression_summary().covar_samp()
+}
+
+#[pg_extern(name="skewness", schema = "timescale_analytics_experimental", strict, immutable)]
+fn stats_agg_skewness(
+    summary: timescale_analytics_experimental::StatsSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> Option<f64> {
+    summary.to_internal_regression_summary().skewness_y()
+}
+
+#[pg_extern(name="skewness_x", schema = "timescale_analytics_experimental", strict, immutable)]
+fn stats_agg_skewness_x(
+    summary: timescale_analytics_experimental::StatsSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> Option<f64> {
+    summary.to_internal_regression_summary().skewness_x()
+}
+
+#[pg_extern(name="kurtosis", schema = "timescale_analytics_experimental", strict, immutable)]
+fn stats_agg_kurtosis(
+    summary: timescale_analytics_experimental::StatsSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> Option<f64> {
+    summary.to_internal_regression_summary().kurtosis_y()
+}
+
+#[pg_extern(name="kurtosis_x", schema = "timescale_analytics_experimental", strict, immutable)]
+fn stats_agg_kurtosis_x(
+    summary: timescale_analytics_experimental::StatsSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+)-> Option<f64> {
+    summary.to_internal_regression_summary().kurtosis_x()
+}
+
+
+#[cfg(any(test, feature = "pg_test"))]
+mod tests {
+
+    use approx::assert_relative_eq;
+    use pgx::*;
+
+    macro_rules! select_one {
+        ($client:expr, $stmt:expr, $type:ty) => {
+            $client
+                .select($stmt, None, None)
+                .first()
+                .get_one::<$type>()
+                .unwrap()
+        };
+    }
+
+    #[pg_test]
+    fn test_stats_agg_matches_regr() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test(ts timestamptz, y DOUBLE PRECISION, x DOUBLE PRECISION)", None, None);
+            // set search_path after defining our table so we don't pollute the wrong schema
+            let stmt = "SELECT format('timescale_analytics_experimental, %s',current_setting('search_path'))";
+            let search_path = select_one!(client, stmt, String);
+            client.select(&format!("SET LOCAL search_path TO {}", search_path), None, None);
+            let stmt = "INSERT INTO test \
+                SELECT '2020-01-01 00:00:00+00'::timestamptz + i * '1 minute'::interval, \
+                    sin(i / 10.0) * 20 + i * 0.5, i * 1.5 + (i % 7) \
+                FROM generate_series(1, 1000) i";
+            client.select(stmt, None, None);
+            // NULLs in either column are ignored, just like the regr_* functions
+            client.select("INSERT INTO test VALUES ('2020-01-02 00:00:00+00', NULL, 1.0), ('2020-01-02 00:01:00+00', 1.0, NULL)", None, None);
+
+            let pairs = [
+                ("num_vals", "regr_count"),
+                ("average_y", "regr_avgy"),
+                ("average_x", "regr_avgx"),
+                ("slope", "regr_slope"),
+                ("intercept", "regr_intercept"),
+                ("corr", "corr"),
+                ("determination_coeff", "regr_r2"),
+                ("covar_pop", "covar_pop"),
+                ("covar_samp", "covar_samp"),
+            ];
+            for (ours, theirs) in pairs.iter() {
+                let stmt = format!("SELECT {}(stats_agg(y, x))::float FROM test", ours);
+                let a = select_one!(client, &stmt, f64);
+                let stmt = format!("SELECT {}(y, x)::float FROM test", theirs);
+                let b = select_one!(client, &stmt, f64);
+                assert_relative_eq!(a, b, max_relative = 1e-10);
+            }
+
+            let stmt = "SELECT x_intercept(stats_agg(y, x)) FROM test";
+            let a = select_one!(client, stmt, f64);
+            let stmt = "SELECT -regr_intercept(y, x) / regr_slope(y, x) FROM test";
+            let b = select_one!(client, stmt, f64);
+            assert_relative_eq!(a, b, max_relative = 1e-10);
+
+            // rollup gives the same result as aggregating everything at once
+            for accessor in ["slope", "corr", "covar_samp", "skewness", "kurtosis", "skewness_x", "kurtosis_x"].iter() {
+                let stmt = format!("WITH t AS (SELECT date_trunc('hour', ts), stats_agg(y, x) AS agg FROM test GROUP BY 1) \
+                    SELECT {}(rollup(agg)) FROM t", accessor);
+                let a = select_one!(client, &stmt, f64);
+                let stmt = format!("SELECT {}(stats_agg(y, x)) FROM test", accessor);
+                let b = select_one!(client, &stmt, f64);
+                assert_relative_eq!(a, b, max_relative = 1e-10);
+            }
+
+            // skewness and kurtosis match the definitions
+            let stmt = "SELECT skewness(stats_agg(y, x)) FROM test";
+            let skew = select_one!(client, stmt, f64);
+            let stmt = "WITH m AS (SELECT avg(y) AS mean, count(y) AS n FROM test WHERE x IS NOT NULL) \
+                SELECT sqrt(n) * sum((y - mean)^3) / sum((y - mean)^2)^1.5 \
+                FROM test, m WHERE x IS NOT NULL AND y IS NOT NULL GROUP BY n";
+            assert_relative_eq!(skew, select_one!(client, stmt, f64), max_relative = 1e-9);
+            let stmt = "SELECT kurtosis(stats_agg(y, x)) FROM test";
+            let kurt = select_one!(client, stmt, f64);
+            let stmt = "WITH m AS (SELECT avg(y) AS mean, count(y) AS n FROM test WHERE x IS NOT NULL) \
+                SELECT n * sum((y - mean)^4) / sum((y - mean)^2)^2 \
+                FROM test, m WHERE x IS NOT NULL AND y IS NOT NULL GROUP BY n";
+            assert_relative_eq!(kurt, select_one!(client, stmt, f64), max_relative = 1e-9);
+
+            // they're NULL for a single value, or values that are all the same
+            let stmt = "SELECT skewness(stats_agg(y, x)) IS NULL AND kurtosis_x(stats_agg(y, x)) IS NULL \
+                FROM (SELECT * FROM test WHERE x IS NOT NULL AND y IS NOT NULL LIMIT 1) t";
+            assert!(select_one!(client, stmt, bool));
+            let stmt = "SELECT skewness_x(stats_agg(y, 1.0)) IS NULL AND kurtosis_x(stats_agg(y, 1.0)) IS NULL \
+                AND skewness(stats_agg(y, 1.0)) IS NOT NULL FROM test";
+            assert!(select_one!(client, stmt, bool));
+
+            // empty input
+            let stmt = "SELECT stats_agg(y, x) IS NULL FROM test WHERE false";
+            assert!(select_one!(client, stmt, bool));
+        });
+    }
+
+    #[pg_test]
+    fn test_stats_agg_moving_aggregate() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test(ts timestamptz, y DOUBLE PRECISION, x DOUBLE PRECISION)", None, None);
+            // set search_path after defining our table so we don't pollute the wrong schema
+            let stmt = "SELECT format('timescale_analytics_experimental, %s',current_setting('search_path'))";
+            let search_path = select_one!(client, stmt, String);
+            client.select(&format!("SET LOCAL search_path TO {}", search_path), None, None);
+            let stmt = "INSERT INTO test \
+                SELECT '2020-01-01 00:00:00+00'::timestamptz + i * '1 minute'::interval, i * i % 17, i \
+                FROM generate_series(1, 100) i";
+            client.select(stmt, None, None);
+
+            // the sliding window must agree with the regr_* functions over the same frame
+            let stmt = "SELECT count(*) FROM ( \
+                SELECT slope(stats_agg(y, x) OVER w) AS a, regr_slope(y, x) OVER w AS b, \
+                    covar_samp(stats_agg(y, x) OVER w) AS c, covar_samp(y, x) OVER w AS d \
+                FROM test WINDOW w AS (ORDER BY ts ROWS BETWEEN 5 PRECEDING AND CURRENT ROW) \
+            ) s WHERE abs(a - b) > 1e-9 * greatest(abs(b), 1) OR abs(c - d) > 1e-9 * greatest(abs(d), 1)";
+            assert_eq!(select_one!(client, stmt, i64), 0);
+
+            // non-finite values can't be removed, the window gets recomputed instead
+            client.select("UPDATE test SET y = 'Infinity' WHERE x = 10", None, None);
+            let stmt = "SELECT count(*) FROM ( \
+                SELECT x, num_vals(stats_agg(y, x) OVER w) AS n, average_y(stats_agg(y, x) OVER w) AS a \
+                FROM test WINDOW w AS (ORDER BY ts ROWS BETWEEN 2 PRECEDING AND CURRENT ROW) \
+            ) s WHERE (x BETWEEN 10 AND 12) <> (a = 'Infinity') OR (x >= 3 AND n <> 3)";
+            assert_eq!(select_one!(client, stmt, i64), 0);
+        });
+    }
+}
@@ -20,19 +20,19 @@ use tdigest::{
 };
 
 // Intermediate state kept in postgres.  This is a tdigest object paired
-// with a vector of values that still need to be inserted.
+// with a vector of (value, weight) pairs that still need to be inserted.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TDigestTransState {
     #[serde(skip)]
-    buffer: Vec<f64>,
+    buffer: Vec<(f64, u64)>,
     digested: InternalTDigest,
 }
 
 impl TDigestTransState {
     // Add a new value, recalculate the digest if we've crossed a threshold.
     // TODO threshold is currently set to number of digest buckets, should this be adjusted
-    fn push(&mut self, value: f64) {
-        self.buffer.push(value);
+    fn push(&mut self, value: f64, weight: u64) {
+        self.buffer.push((value, weight));
         if self.buffer.len() >= self.digested.max_size() {
             self.digest()
         }
@@ -44,7 +44,7 @@ impl TDigestTransState {
             return
         }
         let new = replace(&mut self.buffer, vec![]);
-        self.digested = self.digested.merge_unsorted(new)
+        self.digested = self.digested.merge_unsorted_weighted(new)
     }
 }
 
@@ -59,6 +59,20 @@ pub fn tdigest_trans(
     size: int,
     value: Option<f64>,
     fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<TDigestTransState>> {
+    tdigest_weighted_trans(state, size, value, Some(1), fcinfo)
+}
+
+// PG function for adding weighted values to a digest, a value with weight n
+// is treated as n copies of that value.
+// Null values and weights are ignored, as are values with weight 0.
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn tdigest_weighted_trans(
+    state: Option<Internal<TDigestTransState>>,
+    size: int,
+    value: Option<f64>,
+    weight: Option<i64>,
+    fcinfo: pg_sys::FunctionCallInfo,
 ) -> Option<Internal<TDigestTransState>> {
     unsafe {
         in_aggregate_context(fcinfo, || {
@@ -67,6 +81,11 @@ pub fn tdigest_trans(
                 // NaNs are nonsensical in the context of a percentile, so exclude them
                 Some(value) => if value.is_nan() {return state} else {value},
             };
+            let weight = match weight {
+                None | Some(0) => return state,
+                Some(weight) if weight < 0 => error!("tdigest weights must not be negative"),
+                Some(weight) => weight as u64,
+            };
             let mut state = match state {
                 None => TDigestTransState{
                     buffer: vec![],
@@ -74,7 +93,7 @@ pub fn tdigest_trans(
                 }.into(),
                 Some(state) => state,
             };
-            state.push(value);
+            state.push(value, weight);
             Some(state)
         })
     }
@@ -95,12 +114,12 @@ pub fn tdigest_combine(
                 (Some(state1), None) => Some(state1.clone().into()),
                 (Some(state1), Some(state2)) => {
                     assert_eq!(state1.digested.max_size(), state2.digested.max_size());
-                    let digvec = vec![state1.digested.clone(), state2.digested.clone()];
+                    let mut digvec = vec![state1.digested.clone(), state2.digested.clone()];
                     if !state1.buffer.is_empty() {
-                        digvec[0].merge_unsorted(state1.buffer.clone());  // merge_unsorted should take a reference
+                        digvec[0] = digvec[0].merge_unsorted_weighted(state1.buffer.clone());  // merge_unsorted should take a reference
                     }
                     if !state2.buffer.is_empty() {
-                        digvec[1].merge_unsorted(state2.buffer.clone());
+                        digvec[1] = digvec[1].merge_unsorted_weighted(state2.buffer.clone());
                     }
 
                     Some(TDigestTransState {
@@ -218,6 +237,19 @@ CREATE AGGREGATE tdigest(size int, value DOUBLE PRECISION)
 );
 "#);
 
+extension_sql!(r#"
+CREATE AGGREGATE timescale_analytics_experimental.tdigest(size int, value DOUBLE PRECISION, weight BIGINT)
+(
+    sfunc = timescale_analytics_experimental.tdigest_weighted_trans,
+    stype = internal,
+    finalfunc = tdigest_final,
+    combinefunc = tdigest_combine,
+    serialfunc = tdigest_serialize,
+    deserialfunc = tdigest_deserialize,
+    parallel = safe
+);
+"#);
+
 #[pg_extern]
 pub fn tdigest_compound_trans(
     state: Option<Internal<InternalTDigest>>,
@@ -304,6 +336,46 @@ CREATE AGGREGATE rollup(
 );
 "#);
 
+// PG function for rolling up digests into one with at most `size` buckets,
+// digests of any size can be combined, they're all re-clustered to `size`.
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn tdigest_compound_resize_trans(
+    state: Option<Internal<InternalTDigest>>,
+    value: Option<TDigest<'static>>,
+    size: int,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<InternalTDigest>> {
+    validate_size(size);
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            match (state, value) {
+                (a, None) => a,
+                (None, Some(a)) => Some(a.to_internal_tdigest().resize(size as usize).into()),
+                (Some(a), Some(b)) => {
+                    // the merged digest takes the size of the first one
+                    Some(InternalTDigest::merge_digests(
+                            vec![a.deref().clone(), b.to_internal_tdigest()]
+                        ).into())
+                }
+            }
+        })
+    }
+}
+
+extension_sql!(r#"
+CREATE AGGREGATE timescale_analytics_experimental.rollup(
+    digest tdigest, size int
+) (
+    sfunc = timescale_analytics_experimental.tdigest_compound_resize_trans,
+    stype = internal,
+    finalfunc = tdigest_compound_final,
+    combinefunc = tdigest_compound_combine,
+    serialfunc = tdigest_compound_serialize,
+    deserialfunc = tdigest_compound_deserialize,
+    parallel = safe
+);
+"#);
+
 //---- Available PG operations on the digest
 
 // Approximate the value at the given quantile (0.0-1.0)
@@ -326,6 +398,76 @@ pub fn tdigest_quantile_at_value(
     digest.to_internal_tdigest().estimate_quantile_at_value(value)
 }
 
+// Approximate the values at each of the given quantiles (0.0-1.0), decoding the digest only once.
+#[pg_extern(immutable, parallel_safe, schema = "timescale_analytics_experimental", name="approx_percentile_array")]
+pub fn tdigest_quantile_array(
+    quantiles: Vec<f64>,
+    digest: TDigest,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Vec<f64> {
+    let digest = digest.to_internal_tdigest();
+    quantiles.into_iter()
+        .map(|quantile| digest.estimate_quantile(quantile))
+        .collect()
+}
+
+// Estimate the number of values falling in each of the bins delimited by the given edges.
+// Returns one more count than there are edges, see `TDigest::histogram` for the bin boundaries.
+#[pg_extern(immutable, parallel_safe, schema = "timescale_analytics_experimental", name="histogram")]
+pub fn tdigest_histogram(
+    digest: TDigest,
+    edges: Vec<f64>,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Vec<i64> {
+    crate::uddsketch::validate_histogram_edges(&edges);
+    digest.to_internal_tdigest()
+        .histogram(&edges)
+        .into_iter()
+        .map(|count| count as i64)
+        .collect()
+}
+
+// Re-cluster the digest into at most `size` buckets, see `TDigest::resize`.
+#[pg_extern(immutable, parallel_safe, schema = "timescale_analytics_experimental", name="resize")]
+pub fn tdigest_resize(
+    digest: TDigest,
+    size: int,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> TDigest<'static> {
+    validate_size(size);
+    TDigest::from_internal_tdigest(&digest.to_internal_tdigest().resize(size as usize))
+}
+
+fn validate_size(size: int) {
+    if size == 0 {
+        error!("tdigest size must be at least 1")
+    }
+}
+
+// Approximate the mean of the values between the `low` and `high` quantiles (0.0-1.0),
+// computed from the centroids, see `TDigest::trimmed_mean`.
+#[pg_extern(immutable, parallel_safe, schema = "timescale_analytics_experimental", name="approx_trimmed_mean")]
+pub fn tdigest_trimmed_mean(
+    low: f64,
+    high: f64,
+    digest: TDigest,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> f64 {
+    crate::uddsketch::validate_trimmed_mean_range(low, high);
+    digest.to_internal_tdigest().trimmed_mean(low, high)
+}
+
+// Approximate the mean of the values above the given quantile (0.0-1.0).
+#[pg_extern(immutable, parallel_safe, schema = "timescale_analytics_experimental", name="approx_tail_mean")]
+pub fn tdigest_tail_mean(
+    quantile: f64,
+    digest: TDigest,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> f64 {
+    crate::uddsketch::validate_trimmed_mean_range(quantile, 1.0);
+    digest.to_internal_tdigest().trimmed_mean(quantile, 1.0)
+}
+
 // Number of elements from which the digest was built.
 #[pg_extern(immutable, parallel_safe, name="num_vals")]
 pub fn tdigest_count(
@@ -455,6 +597,54 @@ mod tests {
         });
     }
 
+    #[pg_test]
+    fn test_tdigest_weighted() {
+        Spi::execute(|client| {
+            // each value v has weight v
+            client.select("CREATE TABLE weighted (value DOUBLE PRECISION, weight BIGINT)", None, None);
+            client.select("INSERT INTO weighted SELECT v, v FROM generate_series(1, 1000) v", None, None);
+            // NULL and zero weights are ignored
+            client.select("INSERT INTO weighted VALUES (5000, NULL), (6000, 0), (NULL, 10)", None, None);
+
+            let count = client
+                .select("SELECT num_vals(timescale_analytics_experimental.tdigest(100, value, weight)) FROM weighted", None, None)
+                .first()
+                .get_one::<f64>();
+            apx_eql(count.unwrap(), 500500.0, 0.000001);
+
+            let max = client
+                .select("SELECT max_val(timescale_analytics_experimental.tdigest(100, value, weight)) FROM weighted", None, None)
+                .first()
+                .get_one::<f64>();
+            apx_eql(max.unwrap(), 1000.0, 0.000001);
+
+            for i in 1..100 {
+                let quantile = i as f64 / 100.0;
+                // the exact weighted quantile is the smallest value whose cumulative weight reaches the rank
+                let (estimate, expected) = client
+                    .select(&format!("SELECT \
+                        approx_percentile({q}, (SELECT timescale_analytics_experimental.tdigest(100, value, weight) FROM weighted)), \
+                        min(value) \
+                        FROM ( \
+                            SELECT value, sum(weight) OVER (ORDER BY value) AS cumulative FROM weighted WHERE weight > 0 \
+                        ) w WHERE cumulative >= {q} * 500500", q = quantile), None, None)
+                    .first()
+                    .get_two::<f64, f64>();
+                pct_eql(estimate.unwrap(), expected.unwrap(), 0.01);
+            }
+
+            // the same as inserting each value `weight` times
+            let (weighted, repeated) = client
+                .select("SELECT \
+                    approx_percentile(0.5, (SELECT timescale_analytics_experimental.tdigest(100, value, weight) FROM weighted)), \
+                    approx_percentile(0.5, tdigest(100, value)) \
+                    FROM weighted, generate_series(1, weight)", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            pct_eql(weighted.unwrap(), repeated.unwrap(), 0.01);
+        });
+    }
+
     #[pg_test]
     fn test_tdigest_small_count() {
         Spi::execute(|client| {
@@ -498,6 +688,100 @@ mod tests {
         });
     }
 
+    #[pg_test]
+    fn test_tdigest_percentile_array_and_histogram() {
+        Spi::execute(|client| {
+            client.select("SET search_path TO timescale_analytics_experimental, public", None, None);
+            client.select("CREATE TABLE array_test (value DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO array_test SELECT generate_series(1, 1000)", None, None);
+            client.select("CREATE VIEW array_digest AS \
+                SELECT tdigest(100, value) FROM array_test", None, None);
+
+            let matches = client
+                .select("SELECT \
+                    approx_percentile_array(ARRAY[0.01, 0.5, 0.9, 0.99], tdigest) = ARRAY[\
+                        approx_percentile(0.01, tdigest), \
+                        approx_percentile(0.5, tdigest), \
+                        approx_percentile(0.9, tdigest), \
+                        approx_percentile(0.99, tdigest)] \
+                    FROM array_digest", None, None)
+                .first()
+                .get_one::<bool>();
+            assert_eq!(matches, Some(true));
+
+            // the counts are estimates, but always account for every value
+            let (low, mid, high) = client
+                .select("SELECT h[1], h[2], h[3] \
+                    FROM (SELECT histogram(tdigest, ARRAY[100.5, 900.5]) AS h FROM array_digest) hist", None, None)
+                .first()
+                .get_three::<i64, i64, i64>();
+            assert_eq!(low.unwrap() + mid.unwrap() + high.unwrap(), 1000);
+            apx_eql(low.unwrap() as f64, 100.0, 5.0);
+            apx_eql(mid.unwrap() as f64, 800.0, 10.0);
+            apx_eql(high.unwrap() as f64, 100.0, 5.0);
+        });
+    }
+
+    #[pg_test]
+    fn test_tdigest_resize() {
+        Spi::execute(|client| {
+            client.select("SET search_path TO timescale_analytics_experimental, public", None, None);
+            client.select("CREATE TABLE resize_test (device INTEGER, value DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO resize_test SELECT d, v FROM generate_series(1, 2) d, generate_series(1, 1000) v", None, None);
+            // digests of different sizes can only be combined by resizing them
+            client.select("CREATE VIEW resize_digests AS \
+                SELECT device, tdigest(100 * device, value) FROM resize_test GROUP BY device", None, None);
+
+            let (buckets, resized_buckets) = client
+                .select("SELECT (tdigest::text::jsonb->>'buckets')::int, \
+                        (resize(tdigest, 20)::text::jsonb->>'buckets')::int \
+                    FROM resize_digests WHERE device = 2", None, None)
+                .first()
+                .get_two::<i32, i32>();
+            assert!(buckets.unwrap() > 21);
+            assert!(resized_buckets.unwrap() <= 21);
+
+            let (count, min, max) = client
+                .select("SELECT num_vals(digest), min_val(digest), max_val(digest) \
+                    FROM (SELECT resize(tdigest, 20) AS digest FROM resize_digests WHERE device = 2) resized", None, None)
+                .first()
+                .get_three::<f64, f64, f64>();
+            assert_eq!(count, Some(1000.0));
+            assert_eq!(min, Some(1.0));
+            assert_eq!(max, Some(1000.0));
+
+            let (count, median, buckets) = client
+                .select("SELECT num_vals(digest), approx_percentile(0.5, digest), \
+                        (digest::text::jsonb->>'buckets')::int \
+                    FROM (SELECT rollup(tdigest, 20) AS digest FROM resize_digests) rolled", None, None)
+                .first()
+                .get_three::<f64, f64, i32>();
+            assert_eq!(count, Some(2000.0));
+            apx_eql(median.unwrap(), 500.0, 10.0);
+            assert!(buckets.unwrap() <= 21);
+        });
+    }
+
+    #[pg_test]
+    fn test_tdigest_trimmed_and_tail_mean() {
+        Spi::execute(|client| {
+            client.select("SET search_path TO timescale_analytics_experimental, public", None, None);
+            client.select("CREATE TABLE trim_test (value DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO trim_test SELECT generate_series(1, 1000)", None, None);
+
+            let (untrimmed, trimmed, tail) = client
+                .select("SELECT approx_trimmed_mean(0.0, 1.0, tdigest), \
+                        approx_trimmed_mean(0.01, 0.99, tdigest), \
+                        approx_tail_mean(0.95, tdigest) \
+                    FROM (SELECT tdigest(100, value) FROM trim_test) digest", None, None)
+                .first()
+                .get_three::<f64, f64, f64>();
+            apx_eql(untrimmed.unwrap(), 500.5, 0.000001);
+            apx_eql(trimmed.unwrap(), 500.5, 5.0);
+            apx_eql(tail.unwrap(), 975.5, 5.0);
+        });
+    }
+
     #[pg_test]
     fn test_tdigest_compound_agg() {
         Spi::execute(|client| {
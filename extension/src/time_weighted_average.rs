@@ -4,7 +4,10 @@ use serde::{Deserialize, Serialize};
 use std::slice;
 
 use crate::{
-    aggregate_utils::in_aggregate_context, flatten, json_inout_funcs, palloc::Internal, pg_type,
+    aggregate_utils::in_aggregate_context,
+    datum_utils::{interval_to_micros, micros_to_interval},
+    flatten,
+    json_inout_funcs, palloc::Internal, pg_type,
 };
 use flat_serialize::*;
 use pgx::*;
@@ -12,6 +15,7 @@ use pgx::*;
 use time_series::TSPoint;
 
 use time_weighted_average::{
+    duration_in::{DurationIn, ThresholdOp},
     TimeWeightError, TimeWeightMethod,
     TimeWeightSummary as TimeWeightSummaryInternal,
 };
@@ -19,6 +23,18 @@ use time_weighted_average::{
 #[allow(non_camel_case_types)]
 type bytea = pg_sys::Datum;
 
+#[allow(non_camel_case_types)]
+type interval = pg_sys::Datum;
+
+// The version records which methods and fields the summary may use, so that
+// older versions don't misread the ones they don't know about. Summaries only
+// use the newest version they need, so that they can still be read by those
+// older versions.
+//  - version 1: LOCF and Linear
+//  - version 2: adds NOCB and Midpoint
+//  - version 3: adds max_gap and covered, for summaries with a max_gap
+//  - version 4: adds w_sum_sq, for summaries with the sum of squares, those
+//    without a max_gap store i64::MAX, as no gap is longer than that
 pg_type! {
     #[derive(Debug)]
     struct TimeWeightSummary {
@@ -26,6 +42,12 @@ pg_type! {
         last: TSPoint,
         w_sum: f64,
         method: TimeWeightMethod,
+        #[serde(default)]
+        max_gap: [i64; (self.version >= 3) as u8],
+        #[serde(default)]
+        covered: [i64; (self.version >= 3) as u8],
+        #[serde(default)]
+        w_sum_sq: [f64; (self.version >= 4) as u8],
     }
 }
 
@@ -33,14 +55,49 @@ json_inout_funcs!(TimeWeightSummary);
 
 varlena_type!(TimeWeightSummary);
 
+const TIME_WEIGHT_SUMMARY_VERSION: u8 = 4;
+
+fn summary_version(summary: &TimeWeightSummaryInternal) -> u8 {
+    match (summary.method, summary.max_gap, summary.w_sum_sq) {
+        (_, _, Some(_)) => 4,
+        (_, Some(_), None) => 3,
+        (TimeWeightMethod::LOCF, None, None) | (TimeWeightMethod::Linear, None, None) => 1,
+        (TimeWeightMethod::NOCB, None, None) | (TimeWeightMethod::Midpoint, None, None) => 2,
+    }
+}
+
+fn parse_method(method: &str) -> TimeWeightMethod {
+    // TODO technically not portable to ASCII-compatible charsets
+    match method.to_lowercase().as_str() {
+        "linear" => TimeWeightMethod::Linear,
+        "locf" => TimeWeightMethod::LOCF,
+        "nocb" => TimeWeightMethod::NOCB,
+        "midpoint" => TimeWeightMethod::Midpoint,
+        _ => error!("unknown time weight method \"{}\", expected \"linear\", \"locf\", \"nocb\" or \"midpoint\"", method),
+    }
+}
+
 impl<'input> TimeWeightSummary<'input> {
     #[allow(non_snake_case)]
     fn to_internal(&self) -> TimeWeightSummaryInternal {
+        if *self.version > TIME_WEIGHT_SUMMARY_VERSION {
+            error!("unsupported TimeWeightSummary version {}, the newest supported version is {}", self.version, TIME_WEIGHT_SUMMARY_VERSION)
+        }
+        // summaries from before version 3 have no max_gap, so they cover all their time
+        let covered = match self.covered.first() {
+            Some(&covered) => covered,
+            None => self.last.ts - self.first.ts,
+        };
         TimeWeightSummaryInternal {
             method: *self.method,
             first: *self.first,
             last: *self.last,
             w_sum: *self.w_sum,
+            max_gap: self.max_gap.first().copied().filter(|&max_gap| max_gap != i64::MAX),
+            covered,
+            // summaries from before version 4 don't have the sum of squares, so
+            // they have no variance
+            w_sum_sq: self.w_sum_sq.first().copied(),
         }
     }
 }
@@ -50,6 +107,7 @@ pub struct TimeWeightTransState {
     #[serde(skip)]
     point_buffer: Vec<TSPoint>,
     method: TimeWeightMethod,
+    max_gap: Option<i64>,
     summary_buffer: Vec<TimeWeightSummaryInternal>,
 }
 
@@ -64,8 +122,12 @@ impl TimeWeightTransState {
         }
         self.point_buffer.sort_unstable_by_key(|p| p.ts);
         self.summary_buffer.push(
-            TimeWeightSummaryInternal::new_from_sorted_iter(&self.point_buffer, self.method)
-                .unwrap(),
+            TimeWeightSummaryInternal::new_from_sorted_iter_with_max_gap(
+                &self.point_buffer,
+                self.method,
+                self.max_gap,
+            )
+            .unwrap(),
         );
         self.point_buffer.clear();
     }
@@ -83,8 +145,11 @@ impl TimeWeightTransState {
             return;
         }
         self.summary_buffer.sort_unstable_by_key(|s| s.first.ts);
-        self.summary_buffer =
-            vec![TimeWeightSummaryInternal::combine_sorted_iter(&self.summary_buffer).unwrap()];
+        self.summary_buffer = match TimeWeightSummaryInternal::combine_sorted_iter(&self.summary_buffer) {
+            Ok(summary) => vec![summary],
+            Err(TimeWeightError::MaxGapMismatch) => error!("TimeWeightSummaries must use the same max_gap"),
+            Err(e) => Err(e).unwrap(),
+        };
     }
 }
 
@@ -109,6 +174,30 @@ pub fn time_weight_trans(
     ts: Option<pg_sys::TimestampTz>,
     val: Option<f64>,
     fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<TimeWeightTransState>> {
+    time_weight_trans_inner(state, method, ts, val, None, fcinfo)
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn time_weight_max_gap_trans(
+    state: Option<Internal<TimeWeightTransState>>,
+    method: String,
+    ts: Option<pg_sys::TimestampTz>,
+    val: Option<f64>,
+    max_gap: Option<interval>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<TimeWeightTransState>> {
+    let max_gap = max_gap.map(|max_gap| unsafe { interval_to_micros(max_gap) });
+    time_weight_trans_inner(state, method, ts, val, max_gap, fcinfo)
+}
+
+fn time_weight_trans_inner(
+    state: Option<Internal<TimeWeightTransState>>,
+    method: String,
+    ts: Option<pg_sys::TimestampTz>,
+    val: Option<f64>,
+    max_gap: Option<i64>,
+    fcinfo: pg_sys::FunctionCallInfo,
 ) -> Option<Internal<TimeWeightTransState>> {
     unsafe {
         in_aggregate_context(fcinfo, || {
@@ -122,12 +211,8 @@ pub fn time_weight_trans(
                 None => {
                     let mut s = TimeWeightTransState {
                         point_buffer: vec![],
-                        // TODO technically not portable to ASCII-compatible charsets
-                        method: match method.to_lowercase().as_str() {
-                            "linear" => TimeWeightMethod::Linear,
-                            "locf" => TimeWeightMethod::LOCF,
-                            _ => panic!("unknown method"),
-                        },
+                        method: parse_method(&method),
+                        max_gap,
                         summary_buffer: vec![],
                     };
                     s.push_point(p);
@@ -147,24 +232,54 @@ pub fn time_weight_summary_trans(
     state: Option<Internal<TimeWeightTransState>>,
     next: Option<TimeWeightSummary>,
     fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<TimeWeightTransState>> {
+    time_weight_summary_trans_inner(state, next.map(|n| n.to_internal()), fcinfo)
+}
+
+// Summaries without a max_gap are given this one, which is exact as long as
+// they cover periods no longer than the max_gap.
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn time_weight_summary_max_gap_trans(
+    state: Option<Internal<TimeWeightTransState>>,
+    next: Option<TimeWeightSummary>,
+    max_gap: Option<interval>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<TimeWeightTransState>> {
+    let max_gap = max_gap.map(|max_gap| unsafe { interval_to_micros(max_gap) });
+    let next = next.map(|n| match (n.to_internal(), max_gap) {
+        (summary, None) => summary,
+        (summary, Some(max_gap)) => match summary.with_max_gap(max_gap) {
+            Ok(summary) => summary,
+            Err(_) => error!("TimeWeightSummaries must use the same max_gap"),
+        },
+    });
+    time_weight_summary_trans_inner(state, next, fcinfo)
+}
+
+fn time_weight_summary_trans_inner(
+    state: Option<Internal<TimeWeightTransState>>,
+    next: Option<TimeWeightSummaryInternal>,
+    fcinfo: pg_sys::FunctionCallInfo,
 ) -> Option<Internal<TimeWeightTransState>> {
     unsafe {
         in_aggregate_context(fcinfo, || match (state, next) {
             (None, None) => None,
             (None, Some(next)) => Some(
                 TimeWeightTransState {
-                    summary_buffer: vec![next.to_internal()],
+                    summary_buffer: vec![next],
                     point_buffer: vec![],
-                    method: *next.method,
+                    method: next.method,
+                    max_gap: next.max_gap,
                 }
                 .into(),
             ),
             (Some(state), None) => Some(state),
             (Some(mut state), Some(next)) => {
                 let next = TimeWeightTransState {
-                    summary_buffer: vec![next.to_internal()],
+                    summary_buffer: vec![next],
                     point_buffer: vec![],
-                    method: *next.method,
+                    method: next.method,
+                    max_gap: next.max_gap,
                 };
                 state.push_summary(&next);
                 Some(state.into())
@@ -221,15 +336,26 @@ fn time_weight_final(
             debug_assert!(state.summary_buffer.len() <= 1);
             match state.summary_buffer.pop() {
                 None => None,
-                Some(st) => Some(
-                    flatten!(TimeWeightSummary {
-                        method: &st.method,
-                        first: &st.first,
-                        last: &st.last,
-                        w_sum: &st.w_sum,
-                    })
-                    .into(),
-                ),
+                Some(st) => {
+                    let version = summary_version(&st);
+                    let (max_gap, covered) = match version {
+                        1 | 2 => (vec![], vec![]),
+                        _ => (vec![st.max_gap.unwrap_or(i64::MAX)], vec![st.covered]),
+                    };
+                    let w_sum_sq: Vec<f64> = st.w_sum_sq.into_iter().collect();
+                    Some(
+                        flatten!(TimeWeightSummary version: version, {
+                            method: &st.method,
+                            first: &st.first,
+                            last: &st.last,
+                            w_sum: &st.w_sum,
+                            max_gap: &max_gap,
+                            covered: &covered,
+                            w_sum_sq: &w_sum_sq,
+                        })
+                        .into(),
+                    )
+                }
             }
         })
     }
@@ -258,6 +384,28 @@ CREATE AGGREGATE rollup(tws TimeWeightSummary)
     deserialfunc = time_weight_trans_deserialize,
     parallel = restricted
 );
+
+CREATE AGGREGATE timescale_analytics_experimental.time_weight(method text, ts timestamptz, value DOUBLE PRECISION, max_gap interval)
+(
+    sfunc = timescale_analytics_experimental.time_weight_max_gap_trans,
+    stype = internal,
+    finalfunc = time_weight_final,
+    combinefunc = time_weight_combine,
+    serialfunc = time_weight_trans_serialize,
+    deserialfunc = time_weight_trans_deserialize,
+    parallel = restricted
+);
+
+CREATE AGGREGATE timescale_analytics_experimental.rollup(tws TimeWeightSummary, max_gap interval)
+(
+    sfunc = timescale_analytics_experimental.time_weight_summary_max_gap_trans,
+    stype = internal,
+    finalfunc = time_weight_final,
+    combinefunc = time_weight_combine,
+    serialfunc = time_weight_trans_serialize,
+    deserialfunc = time_weight_trans_deserialize,
+    parallel = restricted
+);
 "#
 );
 
@@ -282,6 +430,252 @@ pub fn time_weighted_average_average(
     }
 }
 
+// Average over the interval `[start, start + interval)`, using the summaries of the previous and
+// next intervals to interpolate the values at its bounds.
+#[pg_extern(immutable, parallel_safe, name = "interpolated_average", schema = "timescale_analytics_experimental")]
+pub fn time_weighted_average_interpolated_average(
+    tws: Option<TimeWeightSummary>,
+    start: Option<pg_sys::TimestampTz>,
+    interval: Option<interval>,
+    prev: Option<TimeWeightSummary>,
+    next: Option<TimeWeightSummary>,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<f64> {
+    let interval = unsafe { interval_to_micros(interval?) };
+    let prev = prev.map(|p| p.to_internal());
+    let next = next.map(|n| n.to_internal());
+    let interpolated = tws?.to_internal()
+        .interpolate(start?, interval, prev.as_ref(), next.as_ref())
+        .unwrap_or_else(|e| interval_error(e));
+    match interpolated.time_weighted_average() {
+        Ok(a) => Some(a),
+        // as with average, a single value without bounds has no average
+        Err(TimeWeightError::ZeroDuration) => None,
+        Err(e) => Err(e).unwrap(),
+    }
+}
+
+fn interval_error(err: TimeWeightError) -> ! {
+    match err {
+        TimeWeightError::MethodMismatch => error!("TimeWeightSummaries must use the same interpolation method"),
+        _ => error!("TimeWeightSummaries must lie within their interval, with the previous one ending before it and the next one starting after it"),
+    }
+}
+
+// The area under the curve, the weighted sum is in value-microseconds so it is scaled to the
+// requested unit of time.
+#[pg_extern(immutable, parallel_safe, strict, name = "integral", schema = "timescale_analytics_experimental")]
+pub fn time_weighted_average_integral(
+    tws: TimeWeightSummary,
+    unit: String,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> f64 {
+    // TODO technically not portable to ASCII-compatible charsets
+    let unit_micros = match unit.to_lowercase().trim_end_matches('s') {
+        "microsecond" => 1.0,
+        "millisecond" => 1_000.0,
+        "second" => 1_000_000.0,
+        "minute" => 60.0 * 1_000_000.0,
+        "hour" => 60.0 * 60.0 * 1_000_000.0,
+        "day" => 24.0 * 60.0 * 60.0 * 1_000_000.0,
+        _ => error!("unknown unit \"{}\", expected microsecond, millisecond, second, minute, hour or day", unit),
+    };
+    *tws.w_sum / unit_micros
+}
+
+#[pg_extern(immutable, parallel_safe, strict, name = "first_val", schema = "timescale_analytics_experimental")]
+pub fn time_weighted_average_first_val(
+    tws: TimeWeightSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> f64 {
+    tws.first.val
+}
+
+#[pg_extern(immutable, parallel_safe, strict, name = "last_val", schema = "timescale_analytics_experimental")]
+pub fn time_weighted_average_last_val(
+    tws: TimeWeightSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> f64 {
+    tws.last.val
+}
+
+#[pg_extern(immutable, parallel_safe, strict, name = "first_time", schema = "timescale_analytics_experimental")]
+pub fn time_weighted_average_first_time(
+    tws: TimeWeightSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> pg_sys::TimestampTz {
+    tws.first.ts
+}
+
+#[pg_extern(immutable, parallel_safe, strict, name = "last_time", schema = "timescale_analytics_experimental")]
+pub fn time_weighted_average_last_time(
+    tws: TimeWeightSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> pg_sys::TimestampTz {
+    tws.last.ts
+}
+
+// The fraction of the interval `[start, start + interval)` that is covered by
+// data, after interpolating to its bounds as interpolated_average does.
+#[pg_extern(immutable, parallel_safe, name = "coverage", schema = "timescale_analytics_experimental")]
+pub fn time_weighted_average_coverage(
+    tws: Option<TimeWeightSummary>,
+    start: Option<pg_sys::TimestampTz>,
+    interval: Option<interval>,
+    prev: Option<TimeWeightSummary>,
+    next: Option<TimeWeightSummary>,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<f64> {
+    let interval = unsafe { interval_to_micros(interval?) };
+    let prev = prev.map(|p| p.to_internal());
+    let next = next.map(|n| n.to_internal());
+    let coverage = tws?.to_internal()
+        .coverage(start?, interval, prev.as_ref(), next.as_ref())
+        .unwrap_or_else(|e| interval_error(e));
+    Some(coverage)
+}
+
+// The variance and standard deviation are over the same time as the average, as
+// with average a single value has none. Neither can be computed for summaries
+// stored before the sum of squares was tracked, or rolled up from them.
+#[pg_extern(immutable, parallel_safe, strict, name = "time_weighted_variance", schema = "timescale_analytics_experimental")]
+pub fn time_weighted_average_variance(
+    tws: TimeWeightSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<f64> {
+    match tws.to_internal().time_weighted_variance() {
+        Ok(v) => Some(v),
+        Err(TimeWeightError::ZeroDuration) | Err(TimeWeightError::MissingSumOfSquares) => None,
+        Err(e) => Err(e).unwrap(),
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, strict, name = "time_weighted_stddev", schema = "timescale_analytics_experimental")]
+pub fn time_weighted_average_stddev(
+    tws: TimeWeightSummary,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<f64> {
+    match tws.to_internal().time_weighted_stddev() {
+        Ok(s) => Some(s),
+        Err(TimeWeightError::ZeroDuration) | Err(TimeWeightError::MissingSumOfSquares) => None,
+        Err(e) => Err(e).unwrap(),
+    }
+}
+
+fn parse_threshold_op(op: &str) -> ThresholdOp {
+    match op.trim() {
+        "<" => ThresholdOp::LessThan,
+        "<=" => ThresholdOp::LessOrEqual,
+        ">" => ThresholdOp::GreaterThan,
+        ">=" => ThresholdOp::GreaterOrEqual,
+        _ => error!("unknown threshold operator \"{}\", expected \"<\", \"<=\", \">\" or \">=\"", op),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DurationInTransState {
+    points: Vec<TSPoint>,
+    method: TimeWeightMethod,
+    op: ThresholdOp,
+    threshold: f64,
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn duration_in_trans(
+    state: Option<Internal<DurationInTransState>>,
+    threshold_op: String,
+    threshold: f64,
+    ts: Option<pg_sys::TimestampTz>,
+    val: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<DurationInTransState>> {
+    duration_in_trans_inner(state, "locf", threshold_op, threshold, ts, val, fcinfo)
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn duration_in_method_trans(
+    state: Option<Internal<DurationInTransState>>,
+    method: String,
+    threshold_op: String,
+    threshold: f64,
+    ts: Option<pg_sys::TimestampTz>,
+    val: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<DurationInTransState>> {
+    duration_in_trans_inner(state, &method, threshold_op, threshold, ts, val, fcinfo)
+}
+
+fn duration_in_trans_inner(
+    state: Option<Internal<DurationInTransState>>,
+    method: &str,
+    threshold_op: String,
+    threshold: f64,
+    ts: Option<pg_sys::TimestampTz>,
+    val: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<DurationInTransState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let p = match (ts, val) {
+                (Some(ts), Some(val)) => TSPoint { ts, val },
+                _ => return state,
+            };
+
+            let mut state = match state {
+                None => DurationInTransState {
+                    points: vec![],
+                    method: parse_method(method),
+                    op: parse_threshold_op(&threshold_op),
+                    threshold,
+                }
+                .into(),
+                Some(state) => state,
+            };
+            state.points.push(p);
+            Some(state)
+        })
+    }
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+fn duration_in_final(
+    state: Option<Internal<DurationInTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<interval> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let mut state = state?.clone();
+            state.points.sort_unstable_by_key(|p| p.ts);
+            let duration = DurationIn::new_from_sorted_iter(
+                &state.points,
+                state.method,
+                state.op,
+                state.threshold,
+            )
+            .unwrap();
+            Some(micros_to_interval(duration.duration.round() as i64))
+        })
+    }
+}
+
+extension_sql!(
+    r#"
+CREATE AGGREGATE timescale_analytics_experimental.duration_in(threshold_op text, threshold DOUBLE PRECISION, ts timestamptz, value DOUBLE PRECISION)
+(
+    sfunc = timescale_analytics_experimental.duration_in_trans,
+    stype = internal,
+    finalfunc = timescale_analytics_experimental.duration_in_final
+);
+
+CREATE AGGREGATE timescale_analytics_experimental.duration_in(method text, threshold_op text, threshold DOUBLE PRECISION, ts timestamptz, value DOUBLE PRECISION)
+(
+    sfunc = timescale_analytics_experimental.duration_in_method_trans,
+    stype = internal,
+    finalfunc = timescale_analytics_experimental.duration_in_final
+);
+"#
+);
+
 #[cfg(any(test, feature = "pg_test"))]
 mod tests {
     use pgx::*;
@@ -343,4 +737,267 @@ mod tests {
             assert_eq!(select_one!(client, stmt, f64), 17.75);
         });
     }
+
+    #[pg_test]
+    fn test_time_weight_interpolated_average() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test(bucket timestamptz, ts timestamptz, val DOUBLE PRECISION)", None, None);
+            client.select("SET search_path TO timescale_analytics_experimental, public", None, None);
+            client.select("INSERT INTO test VALUES \
+                ('2020-01-01 00:00:00+00', '2020-01-01 00:00:00+00', 10.0), \
+                ('2020-01-01 00:00:00+00', '2020-01-01 00:04:00+00', 20.0), \
+                ('2020-01-01 00:05:00+00', '2020-01-01 00:06:00+00', 30.0), \
+                ('2020-01-01 00:05:00+00', '2020-01-01 00:08:00+00', 10.0), \
+                ('2020-01-01 00:10:00+00', '2020-01-01 00:12:00+00', 20.0)", None, None);
+
+            let interpolated = |method: &str| format!("WITH s AS ( \
+                    SELECT bucket, time_weight('{}', ts, val) AS tws FROM test GROUP BY bucket \
+                ), i AS ( \
+                    SELECT bucket, interpolated_average(tws, bucket, '5 min', \
+                        LAG(tws) OVER (ORDER BY bucket), LEAD(tws) OVER (ORDER BY bucket)) AS average \
+                    FROM s \
+                ) SELECT average FROM i WHERE bucket = '2020-01-01 00:05:00+00'", method);
+
+            // 20 from the previous bucket carried to 00:06, 30 until 00:08, then 10 until the end
+            assert_eq!(select_one!(client, &interpolated("LOCF"), f64), (20.0 + 30.0 * 2.0 + 10.0 * 2.0) / 5.0);
+            // 25 at the start and 15 at the end, interpolated from the neighbouring points
+            assert_eq!(select_one!(client, &interpolated("Linear"), f64), (27.5 + 20.0 * 2.0 + 12.5 * 2.0) / 5.0);
+
+            // without neighbours locf still extends to the end of the bucket
+            let stmt = "SELECT interpolated_average(time_weight('LOCF', ts, val), '2020-01-01 00:05:00+00', '5 min', NULL, NULL) \
+                FROM test WHERE bucket = '2020-01-01 00:05:00+00'";
+            assert_eq!(select_one!(client, stmt, f64), (30.0 * 2.0 + 10.0 * 2.0) / 4.0);
+        });
+    }
+
+    #[pg_test(error = "TimeWeightSummaries must lie within their interval, with the previous one ending before it and the next one starting after it")]
+    fn test_time_weight_interpolated_average_outside_interval() {
+        Spi::execute(|client| {
+            client.select("SET search_path TO timescale_analytics_experimental, public", None, None);
+            client.select("SELECT interpolated_average(\
+                time_weight('LOCF', ts, 1.0), '2020-01-01 00:00:00+00', '5 min', NULL, NULL) \
+                FROM generate_series('2020-01-01 00:00:00+00'::timestamptz, '2020-01-01 00:10:00+00', '1 min') ts", None, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_time_weight_accessors() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test(ts timestamptz, val DOUBLE PRECISION)", None, None);
+            client.select("SET search_path TO timescale_analytics_experimental, public", None, None);
+            client.select("INSERT INTO test VALUES \
+                ('2020-01-01 00:00:00+00', 10.0), ('2020-01-01 00:04:00+00', 20.0), \
+                ('2020-01-01 00:06:00+00', 30.0), ('2020-01-01 00:08:00+00', 10.0), \
+                ('2020-01-01 00:12:00+00', 20.0)", None, None);
+
+            // 10 * 4 + 20 * 2 + 30 * 2 + 10 * 4 value-minutes
+            let stmt = "SELECT integral(time_weight('LOCF', ts, val), 'minute') FROM test";
+            assert_eq!(select_one!(client, stmt, f64), 180.0);
+            let stmt = "SELECT integral(time_weight('LOCF', ts, val), 'Seconds') FROM test";
+            assert_eq!(select_one!(client, stmt, f64), 10800.0);
+            let stmt = "SELECT integral(time_weight('LOCF', ts, val), 'hour') FROM test";
+            assert_eq!(select_one!(client, stmt, f64), 3.0);
+
+            let stmt = "SELECT first_val(time_weight('LOCF', ts, val)) FROM test";
+            assert_eq!(select_one!(client, stmt, f64), 10.0);
+            let stmt = "SELECT last_val(time_weight('LOCF', ts, val)) FROM test";
+            assert_eq!(select_one!(client, stmt, f64), 20.0);
+            let stmt = "SELECT first_time(time_weight('LOCF', ts, val)) = '2020-01-01 00:00:00+00' FROM test";
+            assert!(select_one!(client, stmt, bool));
+            let stmt = "SELECT last_time(time_weight('LOCF', ts, val)) = '2020-01-01 00:12:00+00' FROM test";
+            assert!(select_one!(client, stmt, bool));
+        });
+    }
+
+    #[pg_test]
+    fn test_time_weight_methods() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test(ts timestamptz, val DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test VALUES \
+                ('2020-01-01 00:00:00+00', 10.0), ('2020-01-01 00:01:00+00', 20.0), ('2020-01-01 00:03:00+00', 40.0)", None, None);
+
+            let stmt = "SELECT average(time_weight('LOCF', ts, val)) FROM test";
+            assert_eq!(select_one!(client, stmt, f64), (10.0 + 20.0 * 2.0) / 3.0);
+            // each value holds since the previous point
+            let stmt = "SELECT average(time_weight('nocb', ts, val)) FROM test";
+            assert_eq!(select_one!(client, stmt, f64), (20.0 + 40.0 * 2.0) / 3.0);
+            // each value holds for half the time to its neighbours
+            let stmt = "SELECT average(time_weight('Midpoint', ts, val)) FROM test";
+            assert_eq!(select_one!(client, stmt, f64), (15.0 + 30.0 * 2.0) / 3.0);
+
+            // the new methods survive rollup
+            let stmt = "WITH t AS (SELECT date_trunc('minute', ts), time_weight('NOCB', ts, val) AS tws FROM test GROUP BY 1) SELECT average(rollup(tws)) FROM t";
+            assert_eq!(select_one!(client, stmt, f64), (20.0 + 40.0 * 2.0) / 3.0);
+
+            // summaries from older versions can still be read
+            let stmt = "SELECT average(regexp_replace(replace(time_weight('LOCF', ts, val)::text, \
+                '\"version\":4', '\"version\":1'), ',\"max_gap\".*', '}')::TimeWeightSummary) FROM test";
+            assert_eq!(select_one!(client, stmt, f64), (10.0 + 20.0 * 2.0) / 3.0);
+
+            // only summaries with the sum of squares need the newest version, those
+            // without it only need the new version if they use the new methods
+            let stmt = "SELECT time_weight('LOCF', ts, val)::text LIKE '{\"version\":4,%' FROM test";
+            assert!(select_one!(client, stmt, bool));
+            let stmt = "SELECT rollup(regexp_replace(replace(time_weight('LOCF', ts, val)::text, \
+                '\"version\":4', '\"version\":1'), ',\"max_gap\".*', '}')::TimeWeightSummary)::text \
+                LIKE '{\"version\":1,%' FROM test";
+            assert!(select_one!(client, stmt, bool));
+            let stmt = "SELECT rollup(regexp_replace(replace(time_weight('NOCB', ts, val)::text, \
+                '\"version\":4', '\"version\":2'), ',\"max_gap\".*', '}')::TimeWeightSummary)::text \
+                LIKE '{\"version\":2,%' FROM test";
+            assert!(select_one!(client, stmt, bool));
+        });
+    }
+
+    #[pg_test]
+    fn test_time_weight_max_gap() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test(ts timestamptz, val DOUBLE PRECISION)", None, None);
+            client.select("SET search_path TO timescale_analytics_experimental, public", None, None);
+            client.select("INSERT INTO test VALUES \
+                ('2020-01-01 00:00:00+00', 10.0), ('2020-01-01 00:01:00+00', 20.0), \
+                ('2020-01-01 00:10:00+00', 30.0), ('2020-01-01 00:11:00+00', 40.0)", None, None);
+
+            // the 9 minutes between 00:01 and 00:10 are ignored
+            let stmt = "SELECT average(time_weight('LOCF', ts, val, '5 min')) FROM test";
+            assert_eq!(select_one!(client, stmt, f64), (10.0 + 30.0) / 2.0);
+            let stmt = "SELECT average(time_weight('Linear', ts, val, '5 min')) FROM test";
+            assert_eq!(select_one!(client, stmt, f64), (15.0 + 35.0) / 2.0);
+            let stmt = "SELECT integral(time_weight('LOCF', ts, val, '5 min'), 'minute') FROM test";
+            assert_eq!(select_one!(client, stmt, f64), 40.0);
+            // 00:00 to 00:01, 00:10 to 00:11, and 40 carried forward to the end of the bucket
+            let coverage = |max_gap: &str, filter: &str| format!("SELECT coverage(time_weight('LOCF', ts, val{}), \
+                '2020-01-01 00:00:00+00', '15 min', NULL, NULL) FROM test {}", max_gap, filter);
+            assert_eq!(select_one!(client, &coverage(", '5 min'", ""), f64), 6.0 / 15.0);
+
+            // a long enough max_gap, or none, covers everything
+            assert_eq!(select_one!(client, &coverage(", '10 min'", ""), f64), 1.0);
+            assert_eq!(select_one!(client, &coverage("", ""), f64), 1.0);
+            // a single point is carried forward for max_gap
+            assert_eq!(select_one!(client, &coverage(", '5 min'", "WHERE val = 10.0"), f64), 5.0 / 15.0);
+            // a sensor that goes offline partway through the bucket doesn't cover the rest of it
+            let stmt = "SELECT coverage(time_weight('LOCF', ts, val, '5 min'), '2020-01-01 00:00:00+00', '30 min', NULL, NULL) FROM test";
+            assert_eq!(select_one!(client, stmt, f64), 7.0 / 30.0);
+
+            // the neighbouring buckets are only interpolated from when they are close enough
+            let stmt = |max_gap: &str| format!("WITH t AS ( \
+                    SELECT CASE WHEN ts < '2020-01-01 00:10:00+00' THEN '2020-01-01 00:00:00+00'::timestamptz \
+                        ELSE '2020-01-01 00:10:00+00' END AS bucket, \
+                        time_weight('LOCF', ts, val, '{}') AS tws \
+                    FROM test GROUP BY 1 \
+                ), c AS ( \
+                    SELECT bucket, coverage(tws, bucket, '10 min', LAG(tws) OVER w, LEAD(tws) OVER w) AS coverage \
+                    FROM t WINDOW w AS (ORDER BY bucket) \
+                ) SELECT array_agg(coverage ORDER BY bucket)::text FROM c", max_gap);
+            assert_eq!(select_one!(client, &stmt("5 min"), String), "{0.6,0.6}");
+            assert_eq!(select_one!(client, &stmt("10 min"), String), "{1,1}");
+
+            // the gaps between summaries are handled the same way
+            let stmt = "WITH t AS (SELECT date_trunc('minute', ts), time_weight('LOCF', ts, val, '5 min') AS tws FROM test GROUP BY 1) \
+                SELECT average(rollup(tws)) FROM t";
+            assert_eq!(select_one!(client, stmt, f64), 20.0);
+            let stmt = "WITH t AS (SELECT date_trunc('minute', ts), time_weight('LOCF', ts, val) AS tws FROM test GROUP BY 1) \
+                SELECT average(rollup(tws, '5 min')) FROM t";
+            assert_eq!(select_one!(client, stmt, f64), 20.0);
+
+            // only summaries with a max_gap need the new version, if they don't have
+            // the sum of squares
+            let stmt = "SELECT rollup(regexp_replace(replace(time_weight('LOCF', ts, val, '5 min')::text, \
+                '\"version\":4', '\"version\":3'), ',\"w_sum_sq\".*', '}')::TimeWeightSummary)::text \
+                LIKE '{\"version\":3,%' FROM test";
+            assert!(select_one!(client, stmt, bool));
+            let stmt = "SELECT average(time_weight('LOCF', ts, val, '5 min')::text::TimeWeightSummary) FROM test";
+            assert_eq!(select_one!(client, stmt, f64), 20.0);
+        });
+    }
+
+    #[pg_test(error = "TimeWeightSummaries must use the same max_gap")]
+    fn test_time_weight_max_gap_mismatch() {
+        Spi::execute(|client| {
+            client.select("SET search_path TO timescale_analytics_experimental, public", None, None);
+            client.select("WITH t AS (SELECT date_trunc('minute', ts), time_weight('LOCF', ts, 1.0, '5 min') AS tws \
+                FROM generate_series('2020-01-01 00:00:00+00'::timestamptz, '2020-01-01 00:10:00+00', '30 s') ts GROUP BY 1) \
+                SELECT average(rollup(tws, '10 min')) FROM t", None, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_time_weight_stddev() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test(ts timestamptz, val DOUBLE PRECISION)", None, None);
+            client.select("SET search_path TO timescale_analytics_experimental, public", None, None);
+            client.select("INSERT INTO test VALUES \
+                ('2020-01-01 00:00:00+00', 10.0), ('2020-01-01 00:01:00+00', 20.0), ('2020-01-01 00:03:00+00', 40.0)", None, None);
+
+            // 10 for a minute and 20 for two, the mean square is 300 and the mean 50 / 3
+            let variance = 300.0 - (50.0f64 / 3.0).powi(2);
+            let stmt = "SELECT time_weighted_variance(time_weight('LOCF', ts, val)) FROM test";
+            assert!((select_one!(client, stmt, f64) - variance).abs() < 1e-9);
+            let stmt = "SELECT time_weighted_stddev(time_weight('LOCF', ts, val)) FROM test";
+            assert!((select_one!(client, stmt, f64) - variance.sqrt()).abs() < 1e-9);
+
+            // the sum of squares survives rollup
+            let stmt = "WITH t AS (SELECT date_trunc('minute', ts), time_weight('LOCF', ts, val) AS tws FROM test GROUP BY 1) \
+                SELECT time_weighted_stddev(rollup(tws)) FROM t";
+            assert!((select_one!(client, stmt, f64) - variance.sqrt()).abs() < 1e-9);
+
+            let stmt = "SELECT time_weighted_stddev(time_weight('LOCF', ts, val)) FROM test WHERE val = 10.0";
+            assert!(client.select(stmt, None, None).first().get_one::<f64>().is_none());
+
+            // summaries stored before the sum of squares was tracked have no variance,
+            // nor does anything they are rolled up with
+            let stmt = "SELECT time_weighted_stddev(regexp_replace(replace(time_weight('LOCF', ts, val)::text, \
+                '\"version\":4', '\"version\":1'), ',\"max_gap\".*', '}')::TimeWeightSummary) FROM test";
+            assert!(client.select(stmt, None, None).first().get_one::<f64>().is_none());
+            let stmt = "WITH t AS (SELECT date_trunc('minute', ts) AS bucket, time_weight('LOCF', ts, val) AS tws FROM test GROUP BY 1) \
+                SELECT time_weighted_stddev(rollup(CASE WHEN bucket = '2020-01-01 00:00:00+00' \
+                    THEN regexp_replace(replace(tws::text, '\"version\":4', '\"version\":1'), ',\"max_gap\".*', '}')::TimeWeightSummary \
+                    ELSE tws END)) FROM t";
+            assert!(client.select(stmt, None, None).first().get_one::<f64>().is_none());
+        });
+    }
+
+    #[pg_test]
+    fn test_duration_in() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test(ts timestamptz, val DOUBLE PRECISION)", None, None);
+            client.select("SET search_path TO timescale_analytics_experimental, public", None, None);
+            client.select("INSERT INTO test VALUES \
+                ('2020-01-01 00:00:00+00', 10.0), ('2020-01-01 00:01:00+00', 20.0), ('2020-01-01 00:03:00+00', 40.0)", None, None);
+
+            let stmt = "SELECT duration_in('>', 15, ts, val) = '2 min' FROM test";
+            assert!(select_one!(client, stmt, bool));
+            let stmt = "SELECT duration_in('<=', 15, ts, val ORDER BY random()) = '1 min' FROM test";
+            assert!(select_one!(client, stmt, bool));
+            // the line from 10 to 20 crosses 15 halfway
+            let stmt = "SELECT duration_in('linear', '>', 15, ts, val) = '2 min 30 s' FROM test";
+            assert!(select_one!(client, stmt, bool));
+            let stmt = "SELECT duration_in('LOCF', '>', 15, ts, val) = '2 min' FROM test";
+            assert!(select_one!(client, stmt, bool));
+        });
+    }
+
+    #[pg_test(error = "unknown threshold operator \"=\", expected \"<\", \"<=\", \">\" or \">=\"")]
+    fn test_duration_in_unknown_op() {
+        Spi::execute(|client| {
+            client.select("SET search_path TO timescale_analytics_experimental, public", None, None);
+            client.select("SELECT duration_in('=', 1.0, '2020-01-01 00:00:00+00'::timestamptz, 1.0)", None, None);
+        });
+    }
+
+    #[pg_test(error = "unknown time weight method \"step\", expected \"linear\", \"locf\", \"nocb\" or \"midpoint\"")]
+    fn test_time_weight_unknown_method() {
+        Spi::execute(|client| {
+            client.select("SELECT time_weight('step', '2020-01-01 00:00:00+00'::timestamptz, 1.0)", None, None);
+        });
+    }
+
+    #[pg_test(error = "unsupported TimeWeightSummary version 200, the newest supported version is 4")]
+    fn test_time_weight_unknown_version() {
+        Spi::execute(|client| {
+            client.select("SELECT average(replace(\
+                time_weight('NOCB', ts, 1.0)::text, '\"version\":4', '\"version\":200')::TimeWeightSummary) \
+                FROM generate_series('2020-01-01 00:00:00+00'::timestamptz, '2020-01-01 00:10:00+00', '1 min') ts", None, None);
+        });
+    }
 }
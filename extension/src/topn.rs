@@ -0,0 +1,450 @@
+use std::convert::TryInto;
+
+use serde::{Deserialize, Serialize};
+
+use pg_sys::Datum;
+use pgx::*;
+
+use flat_serialize::*;
+
+use crate::{
+    aggregate_utils::{get_collation, in_aggregate_context},
+    datum_utils::{DatumHashBuilder, HashFunction},
+    flatten, json_inout_funcs,
+    palloc::Internal,
+    pg_type,
+    serialization::{PgCollationId, ShortTypeId},
+};
+
+use space_saving::{Counter, SpaceSaving};
+
+#[allow(non_camel_case_types)]
+type int = i32;
+type AnyElement = Datum;
+
+#[allow(non_camel_case_types)]
+type bytea = pg_sys::Datum;
+
+// The values are tracked by their binary representation, the same one the
+// `xxhash64` hash function uses, so values that compare equal but are stored
+// differently, such as `1.0` and `1.00` as `numeric`s, are counted separately.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TopNTrans {
+    // the type and collation of the values, this uses `HashFunction::XxHash64`
+    // so that nondeterministic collations are rejected
+    datum_info: DatumHashBuilder,
+    sketch: SpaceSaving<Vec<u8>>,
+}
+
+impl TopNTrans {
+    fn merge(&mut self, other: &TopNTrans) {
+        if self.sketch.capacity() != other.sketch.capacity() {
+            error!(
+                "topn sketches must have the same size, not {} and {}",
+                self.sketch.capacity(), other.sketch.capacity()
+            )
+        }
+        if self.datum_info.type_id != other.datum_info.type_id {
+            error!("topn sketches must be over values of the same type")
+        }
+        if self.datum_info.collation != other.datum_info.collation {
+            error!("topn sketches must use the same collation")
+        }
+        self.sketch.merge(&other.sketch)
+    }
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn topn_agg_trans(
+    state: Option<Internal<TopNTrans>>,
+    n: int,
+    value: Option<AnyElement>,
+    fc: pg_sys::FunctionCallInfo,
+) -> Option<Internal<TopNTrans>> {
+    unsafe {
+        in_aggregate_context(fc, || {
+            let value = match value {
+                None => return state,
+                Some(value) => value,
+            };
+            let mut state = match state {
+                None => {
+                    if n < 1 {
+                        error!("topn_agg size must be at least 1")
+                    }
+                    let typ = pgx::get_getarg_type(fc, 2);
+                    let collation = get_collation(fc);
+                    let trans = TopNTrans {
+                        datum_info: DatumHashBuilder::from_type_id(typ, collation, HashFunction::XxHash64),
+                        sketch: SpaceSaving::new(n.try_into().unwrap()),
+                    };
+                    trans.into()
+                }
+                Some(state) => state,
+            };
+            let TopNTrans { datum_info, sketch } = &mut *state;
+            datum_info.with_value_bytes(value, |bytes| sketch.add(bytes));
+            Some(state)
+        })
+    }
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn topn_rollup_trans(
+    state: Option<Internal<TopNTrans>>,
+    value: Option<timescale_analytics_experimental::TopN>,
+    fc: pg_sys::FunctionCallInfo,
+) -> Option<Internal<TopNTrans>> {
+    unsafe {
+        in_aggregate_context(fc, || {
+            let value = match value {
+                None => return state,
+                Some(value) => value.to_trans(),
+            };
+            match state {
+                None => Some(value.into()),
+                Some(mut state) => {
+                    state.merge(&value);
+                    Some(state)
+                }
+            }
+        })
+    }
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn topn_agg_combine(
+    state1: Option<Internal<TopNTrans>>,
+    state2: Option<Internal<TopNTrans>>,
+    fc: pg_sys::FunctionCallInfo,
+) -> Option<Internal<TopNTrans>> {
+    unsafe {
+        in_aggregate_context(fc, || match (state1, state2) {
+            (None, None) => None,
+            (None, Some(state2)) => Some(state2.clone().into()),
+            (Some(state1), None) => Some(state1.clone().into()),
+            (Some(state1), Some(state2)) => {
+                let mut merged = state1.clone();
+                merged.merge(&state2);
+                Some(merged.into())
+            }
+        })
+    }
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn topn_agg_serialize(state: Internal<TopNTrans>) -> bytea {
+    crate::do_serialize!(state)
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental", strict)]
+pub fn topn_agg_deserialize(
+    bytes: bytea,
+    _internal: Option<Internal<()>>,
+) -> Internal<TopNTrans> {
+    crate::do_deserialize!(bytes, TopNTrans)
+}
+
+pg_type! {
+    #[derive(Debug)]
+    struct TopN {
+        element_type: ShortTypeId,
+        collation: PgCollationId,
+        // the maximum number of values the sketch tracks
+        capacity: u32,
+        num_values: u32,
+        // the number of values added to the sketch
+        total: u64,
+        values_bytes: u64,
+        counts: [u64; self.num_values],
+        errors: [u64; self.num_values],
+        // the values are stored as the concatenation of their binary
+        // representations, value_ends[i] is where the i-th value ends.
+        value_ends: [u64; self.num_values],
+        values: [u8; self.values_bytes],
+    }
+}
+
+// hack to allow us to qualify names with "timescale_analytics_experimental"
+// so that pgx generates the correct SQL
+mod timescale_analytics_experimental {
+    pub(crate) use super::*;
+
+    varlena_type!(TopN);
+}
+
+json_inout_funcs!(TopN);
+
+impl<'input> TopN<'input> {
+    fn datum_info(&self) -> DatumHashBuilder {
+        unsafe {
+            DatumHashBuilder::from_type_id(
+                self.element_type.0,
+                self.collation.to_option_oid(),
+                HashFunction::XxHash64,
+            )
+        }
+    }
+
+    fn to_trans(&self) -> TopNTrans {
+        let capacity = *self.capacity as usize;
+        if capacity < 1 || self.counts.len() > capacity {
+            error!("invalid topn, {} values for size {}", self.counts.len(), capacity)
+        }
+
+        let starts = std::iter::once(0).chain(self.value_ends.iter().copied());
+        let counters = self.value_ends.iter().zip(starts)
+            .zip(self.counts.iter().zip(self.errors.iter()))
+            .map(|((&end, start), (&count, &error))| {
+                let value = match self.values.get(start as usize..end as usize) {
+                    Some(value) => value.to_vec(),
+                    None => error!("invalid topn, malformed values"),
+                };
+                if error > count {
+                    error!("invalid topn, error larger than count")
+                }
+                Counter { value, count, error }
+            })
+            .collect();
+
+        TopNTrans {
+            datum_info: self.datum_info(),
+            sketch: SpaceSaving::from_counters(capacity, *self.total, counters),
+        }
+    }
+
+    fn from_trans(trans: &TopNTrans) -> TopN<'static> {
+        let top = trans.sketch.top();
+        let mut counts = Vec::with_capacity(top.len());
+        let mut errors = Vec::with_capacity(top.len());
+        let mut value_ends = Vec::with_capacity(top.len());
+        let mut values = vec![];
+        for counter in top {
+            counts.push(counter.count);
+            errors.push(counter.error);
+            values.extend_from_slice(&counter.value);
+            value_ends.push(values.len() as u64);
+        }
+
+        unsafe {
+            flatten!(TopN {
+                element_type: &ShortTypeId(trans.datum_info.type_id),
+                collation: &PgCollationId(trans.datum_info.collation),
+                capacity: &(trans.sketch.capacity() as u32),
+                num_values: &(counts.len() as u32),
+                total: &trans.sketch.total(),
+                values_bytes: &(values.len() as u64),
+                counts: &counts,
+                errors: &errors,
+                value_ends: &value_ends,
+                values: &values,
+            })
+            .into()
+        }
+    }
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+fn topn_agg_final(
+    state: Option<Internal<TopNTrans>>,
+    fc: pg_sys::FunctionCallInfo,
+) -> Option<timescale_analytics_experimental::TopN<'static>> {
+    unsafe {
+        in_aggregate_context(fc, || {
+            let state = match state {
+                None => return None,
+                Some(state) => state,
+            };
+            TopN::from_trans(&state).into()
+        })
+    }
+}
+
+extension_sql!(r#"
+CREATE AGGREGATE timescale_analytics_experimental.topn_agg(n int, value AnyElement)
+(
+    sfunc = timescale_analytics_experimental.topn_agg_trans,
+    stype = internal,
+    finalfunc = timescale_analytics_experimental.topn_agg_final,
+    combinefunc = timescale_analytics_experimental.topn_agg_combine,
+    serialfunc = timescale_analytics_experimental.topn_agg_serialize,
+    deserialfunc = timescale_analytics_experimental.topn_agg_deserialize,
+    parallel = safe
+);
+
+CREATE AGGREGATE timescale_analytics_experimental.rollup(sketch timescale_analytics_experimental.TopN)
+(
+    sfunc = timescale_analytics_experimental.topn_rollup_trans,
+    stype = internal,
+    finalfunc = timescale_analytics_experimental.topn_agg_final,
+    combinefunc = timescale_analytics_experimental.topn_agg_combine,
+    serialfunc = timescale_analytics_experimental.topn_agg_serialize,
+    deserialfunc = timescale_analytics_experimental.topn_agg_deserialize,
+    parallel = safe
+);
+"#);
+
+// The values are returned as text since a function returning `anyelement`
+// needs an `anyelement` argument to determine its type.
+#[pg_extern(name="topn", schema = "timescale_analytics_experimental", strict, immutable)]
+pub fn topn_topn(
+    sketch: timescale_analytics_experimental::TopN,
+    k: int,
+) -> impl std::iter::Iterator<Item = (name!(value,String),name!(approx_count,i64),name!(error,i64))> + '_ {
+    if k < 0 {
+        error!("k must not be negative")
+    }
+    let trans = sketch.to_trans();
+    let rows: Vec<_> = trans.sketch.top().into_iter()
+        .take(k as usize)
+        .map(|counter| unsafe {
+            let value = trans.datum_info.datum_from_bytes(&counter.value);
+            (trans.datum_info.output(value), counter.count as i64, counter.error as i64)
+        })
+        .collect();
+    rows.into_iter()
+}
+
+// An upper bound on how many times `value` was added to the sketch. Values the
+// sketch is not tracking may have been added up to the smallest tracked count
+// times, so that is returned for them.
+#[pg_extern(name="approx_count", schema = "timescale_analytics_experimental", strict, immutable)]
+pub fn topn_approx_count(
+    sketch: timescale_analytics_experimental::TopN,
+    value: AnyElement,
+    fc: pg_sys::FunctionCallInfo,
+) -> i64 {
+    let typ = unsafe { pgx::get_getarg_type(fc, 1) };
+    if typ != sketch.element_type.0 {
+        error!("value must be of the same type as the values in the topn sketch")
+    }
+    let TopNTrans { datum_info, mut sketch } = sketch.to_trans();
+    unsafe { datum_info.with_value_bytes(value, |bytes| sketch.estimate(bytes)) as i64 }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+mod tests {
+    use pgx::*;
+
+    #[pg_test]
+    fn test_topn_aggregate() {
+        Spi::execute(|client| {
+            client.select("SET search_path TO timescale_analytics_experimental, public", None, None);
+            // value v occurs 11 - v times
+            client.select("CREATE TABLE test AS \
+                SELECT v FROM generate_series(1, 10) v, generate_series(v, 10) r", None, None);
+
+            let text = client
+                .select("SELECT topn_agg(20, v)::TEXT FROM test WHERE v <= 3", None, None)
+                .first()
+                .get_one::<String>();
+            let expected = "{\"version\":1,\"element_type\":\"INT4\",\"collation\":null,\"capacity\":20,\"num_values\":3,\"total\":27,\"values_bytes\":12,\"counts\":[10,9,8],\"errors\":[0,0,0],\"value_ends\":[4,8,12],\"values\":[1,0,0,0,2,0,0,0,3,0,0,0]}";
+            assert_eq!(text.unwrap(), expected);
+
+            // below capacity every count is exact
+            let mut rows = client.select(
+                "SELECT value, approx_count, error FROM topn((SELECT topn_agg(20, v) FROM test), 3)",
+                None,
+                None,
+            );
+            for (value, count) in &[("1", 10), ("2", 9), ("3", 8)] {
+                let row = rows.next().unwrap();
+                assert_eq!(row.by_ordinal(1).unwrap().value::<String>().unwrap(), *value);
+                assert_eq!(row.by_ordinal(2).unwrap().value::<i64>().unwrap(), *count);
+                assert_eq!(row.by_ordinal(3).unwrap().value::<i64>().unwrap(), 0);
+            }
+            assert!(rows.next().is_none());
+
+            let (count, missing) = client
+                .select("SELECT approx_count(sketch, 4), approx_count(sketch, 40) \
+                    FROM (SELECT topn_agg(20, v) AS sketch FROM test) s", None, None)
+                .first()
+                .get_two::<i64, i64>();
+            assert_eq!(count, Some(7));
+            assert_eq!(missing, Some(0));
+
+            // when the sketch is full the counts are upper bounds, so the
+            // largest is at least the count of the most frequent value
+            let (count, error) = client
+                .select("SELECT approx_count, error \
+                    FROM topn((SELECT topn_agg(3, v) FROM test), 1)", None, None)
+                .first()
+                .get_two::<i64, i64>();
+            assert!(count.unwrap() >= 10);
+            assert!(error.unwrap() <= count.unwrap());
+        });
+    }
+
+    #[pg_test]
+    fn test_topn_text() {
+        Spi::execute(|client| {
+            client.select("SET search_path TO timescale_analytics_experimental, public", None, None);
+            client.select("CREATE TABLE urls AS \
+                SELECT '/page/' || (v % 7) AS url FROM generate_series(1, 1000) v \
+                UNION ALL SELECT '/index.html' FROM generate_series(1, 2000)", None, None);
+
+            let (value, count) = client
+                .select("SELECT value, approx_count \
+                    FROM topn((SELECT topn_agg(10, url) FROM urls), 1)", None, None)
+                .first()
+                .get_two::<String, i64>();
+            assert_eq!(value.unwrap(), "/index.html");
+            assert_eq!(count, Some(2000));
+
+            let count = client
+                .select("SELECT approx_count(topn_agg(10, url), '/page/3'::text) FROM urls", None, None)
+                .first()
+                .get_one::<i64>();
+            assert_eq!(count, Some(143));
+        });
+    }
+
+    #[pg_test]
+    fn test_topn_rollup() {
+        Spi::execute(|client| {
+            client.select("SET search_path TO timescale_analytics_experimental, public", None, None);
+            client.select("CREATE TABLE test AS \
+                SELECT v % 4 AS bucket, v FROM generate_series(1, 10) v, generate_series(v, 10) r", None, None);
+
+            let rolled = client
+                .select("SELECT rollup(sketch)::TEXT \
+                    FROM (SELECT topn_agg(20, v) AS sketch FROM test GROUP BY bucket) s", None, None)
+                .first()
+                .get_one::<String>();
+            let direct = client
+                .select("SELECT topn_agg(20, v)::TEXT FROM test", None, None)
+                .first()
+                .get_one::<String>();
+            assert_eq!(rolled, direct);
+        });
+    }
+
+    #[pg_test(error = "topn sketches must have the same size, not 10 and 20")]
+    fn test_topn_rollup_size_mismatch() {
+        Spi::execute(|client| {
+            client.select("SET search_path TO timescale_analytics_experimental, public", None, None);
+            client.select("SELECT rollup(sketch) FROM \
+                (SELECT topn_agg(10, v) AS sketch FROM generate_series(1, 10) v \
+                UNION ALL SELECT topn_agg(20, v) FROM generate_series(1, 10) v) s", None, None);
+        });
+    }
+
+    #[pg_test(error = "topn sketches must be over values of the same type")]
+    fn test_topn_rollup_type_mismatch() {
+        Spi::execute(|client| {
+            client.select("SET search_path TO timescale_analytics_experimental, public", None, None);
+            client.select("SELECT rollup(sketch) FROM \
+                (SELECT topn_agg(10, v) AS sketch FROM generate_series(1, 10) v \
+                UNION ALL SELECT topn_agg(10, v::bigint) FROM generate_series(1, 10) v) s", None, None);
+        });
+    }
+
+    #[pg_test(error = "value must be of the same type as the values in the topn sketch")]
+    fn test_topn_approx_count_type_mismatch() {
+        Spi::execute(|client| {
+            client.select("SET search_path TO timescale_analytics_experimental, public", None, None);
+            client.select("SELECT approx_count(topn_agg(10, v), 1.0::float) \
+                FROM generate_series(1, 10) v", None, None);
+        });
+    }
+}
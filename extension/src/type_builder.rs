@@ -230,6 +230,21 @@ macro_rules! json_inout_funcs {
 
 #[macro_export]
 macro_rules! flatten {
+    ($typ:ident version: $version:expr, { $($field:ident: $value:expr),* $(,)? }) => {
+        {
+            let data = ::paste::paste! {
+                [<$typ Data>] {
+                    header: &0,
+                    version: &$version,
+                    padding: &[0; 3],
+                    $(
+                        $field: $value
+                    ),*
+                }
+            };
+            data.flatten()
+        }
+    };
     ($typ:ident { $($field:ident: $value:expr),* $(,)? }) => {
         {
             let data = ::paste::paste! {
@@ -30,6 +30,21 @@ pub fn uddsketch_trans(
     max_error: f64,
     value: Option<f64>,
     fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<UddSketchInternal>> {
+    uddsketch_weighted_trans(state, size, max_error, value, Some(1), fcinfo)
+}
+
+// PG function for adding weighted values to a sketch, a value with weight n
+// is treated as n copies of that value.
+// Null values and weights are ignored, as are values with weight 0.
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn uddsketch_weighted_trans(
+    state: Option<Internal<UddSketchInternal>>,
+    size: int,
+    max_error: f64,
+    value: Option<f64>,
+    weight: Option<i64>,
+    fcinfo: pg_sys::FunctionCallInfo,
 ) -> Option<Internal<UddSketchInternal>> {
     unsafe {
         in_aggregate_context(fcinfo, || {
@@ -37,11 +52,16 @@ pub fn uddsketch_trans(
                 None => return state,
                 Some(value) => value,
             };
+            let weight = match weight {
+                None | Some(0) => return state,
+                Some(weight) if weight < 0 => error!("uddsketch weights must not be negative"),
+                Some(weight) => weight as u64,
+            };
             let mut state = match state {
                 None => UddSketchInternal::new(size as u64, max_error).into(),
                 Some(state) => state,
             };
-            state.add_value(value);
+            state.add_weighted_value(value, weight);
             Some(state)
         })
     }
@@ -54,10 +74,89 @@ pub fn percentile_agg_trans(
     state: Option<Internal<UddSketchInternal>>,
     value: Option<f64>,
     fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<UddSketchInternal>> {
+    percentile_agg_weighted_trans(state, value, Some(1), fcinfo)
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn percentile_agg_weighted_trans(
+    state: Option<Internal<UddSketchInternal>>,
+    value: Option<f64>,
+    weight: Option<i64>,
+    fcinfo: pg_sys::FunctionCallInfo,
 ) -> Option<Internal<UddSketchInternal>> {
     let default_size = 200;
     let default_max_error = 0.001;
-    uddsketch_trans(state, default_size, default_max_error, value, fcinfo)
+    uddsketch_weighted_trans(state, default_size, default_max_error, value, weight, fcinfo)
+}
+
+// the inverse transition function for the moving-aggregate form of uddsketch.
+// Returning NULL makes Postgres recompute the aggregate over the current
+// window, which we need to do when the sketch can't remove a value, and once
+// the window is empty so that the aggregate returns NULL. We also recompute
+// when the minimum or maximum is removed, since the sketch can only estimate
+// the new one, and once the sketch has been compacted, since the window built
+// from scratch may need fewer compactions and so have a smaller error.
+#[pg_extern()]
+pub fn uddsketch_inv(
+    state: Option<Internal<UddSketchInternal>>,
+    size: int,
+    max_error: f64,
+    value: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<UddSketchInternal>> {
+    uddsketch_weighted_inv(state, size, max_error, value, Some(1), fcinfo)
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn uddsketch_weighted_inv(
+    state: Option<Internal<UddSketchInternal>>,
+    _size: int,
+    _max_error: f64,
+    value: Option<f64>,
+    weight: Option<i64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<UddSketchInternal>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let value = match value {
+                None => return state,
+                Some(value) => value,
+            };
+            let weight = match weight {
+                None | Some(0) => return state,
+                Some(weight) => weight as u64,
+            };
+            let mut state = state?;
+            if state.times_compacted() > 0 || value <= state.min() || value >= state.max() {
+                return None;
+            }
+            match state.remove_weighted_value(value, weight) {
+                Ok(()) if state.count() > 0 => Some(state),
+                _ => None,
+            }
+        })
+    }
+}
+
+#[pg_extern()]
+pub fn percentile_agg_inv(
+    state: Option<Internal<UddSketchInternal>>,
+    value: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<UddSketchInternal>> {
+    percentile_agg_weighted_inv(state, value, Some(1), fcinfo)
+}
+
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn percentile_agg_weighted_inv(
+    state: Option<Internal<UddSketchInternal>>,
+    value: Option<f64>,
+    weight: Option<i64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<UddSketchInternal>> {
+    // the size and error are only needed to create a sketch
+    uddsketch_weighted_inv(state, 0, 0.0, value, weight, fcinfo)
 }
 
 // PG function for merging sketches.
@@ -111,6 +210,8 @@ struct SerializedUddSketch {
     compactions: u32,
     count: u64,
     sum: f64,
+    min: f64,
+    max: f64,
     buckets: CompressedBuckets,
 }
 
@@ -124,6 +225,8 @@ impl From<&UddSketchInternal> for SerializedUddSketch {
             compactions: sketch.times_compacted(),
             count: sketch.count(),
             sum: sketch.sum(),
+            min: sketch.min(),
+            max: sketch.max(),
             buckets,
         }
     }
@@ -131,7 +234,7 @@ impl From<&UddSketchInternal> for SerializedUddSketch {
 
 impl Into<UddSketchInternal> for SerializedUddSketch {
     fn into(self) -> UddSketchInternal {
-        UddSketchInternal::new_from_data(self.max_buckets as u64, self.alpha, self.compactions as u64, self.count, self.sum, self.keys(), self.counts())
+        UddSketchInternal::new_from_data(self.max_buckets as u64, self.alpha, self.compactions as u64, self.count, self.sum, self.min, self.max, self.keys(), self.counts())
     }
 }
 
@@ -146,6 +249,13 @@ impl SerializedUddSketch {
 }
 
 // PG object for the sketch.
+// The negative store, zero count and positive store of the sketch are each
+// stored separately, with the negative indexes in order of increasing value.
+// The layout is versioned: later versions may only add fields after the
+// existing ones, so that older sketches can still be read, and a sketch with
+// a version newer than `UDDSKETCH_VERSION` is rejected rather than misread.
+//  - version 1: the original layout
+//  - version 2: adds the exact `min` and `max` of the values
 pg_type! {
     #[derive(Debug)]
     struct UddSketch {
@@ -164,52 +274,70 @@ pg_type! {
         negative_counts: [u8; self.neg_buckets_bytes],
         positive_indexes: [u8; self.pos_indexes_bytes],
         positive_counts: [u8; self.pos_buckets_bytes],
+        #[serde(default)]
+        min: [f64; (self.version >= 2) as u8],
+        #[serde(default)]
+        max: [f64; (self.version >= 2) as u8],
     }
 }
 
 varlena_type!(UddSketch);
 json_inout_funcs!(UddSketch);
 
+const UDDSKETCH_VERSION: u8 = 2;
+
 impl<'input> UddSketch<'input> {
+    fn check_version(&self) {
+        if *self.version > UDDSKETCH_VERSION {
+            error!("unsupported UddSketch version {}, the newest supported version is {}", self.version, UDDSKETCH_VERSION)
+        }
+    }
+
     fn keys(&self) -> impl Iterator<Item=SketchHashKey> + '_ {
+        self.check_version();
         decompress_keys(self.negative_indexes, *self.zero_bucket_count != 0, self.positive_indexes)
     }
 
     fn counts(&self) -> impl Iterator<Item=u64> + '_ {
+        self.check_version();
         decompress_counts(self.negative_counts, *self.zero_bucket_count, self.positive_counts)
     }
 
-    fn to_uddsketch(&self) -> UddSketchInternal {
-        UddSketchInternal::new_from_data(*self.max_buckets as u64, *self.alpha, *self.compactions, *self.count, *self.sum, self.keys(), self.counts())
+    // Sketches written before version 2 don't store their bounds, for those
+    // they're estimated from the outermost buckets.
+    fn min(&self) -> f64 {
+        match self.min.first() {
+            Some(&min) => min,
+            None => uddsketch::estimate_quantile(0.0, *self.alpha, uddsketch::gamma(*self.alpha), *self.count, self.keys().zip(self.counts())),
+        }
     }
-}
-
-// PG function to generate a user-facing UddSketch object from a UddSketchInternal.
-#[pg_extern()]
-fn uddsketch_final(
-    state: Option<Internal<UddSketchInternal>>,
-    fcinfo: pg_sys::FunctionCallInfo,
-) -> Option<UddSketch<'static>> {
-    unsafe {
-        in_aggregate_context(fcinfo, || {
-            let state = match state {
-                None => return None,
-                Some(state) => state,
-            };
 
-            let CompressedBuckets {
-                negative_indexes,
-                negative_counts,
-                zero_bucket_count,
-                positive_indexes,
-                positive_counts,
-            } = compress_buckets(&*state);
+    fn max(&self) -> f64 {
+        match self.max.first() {
+            Some(&max) => max,
+            None => uddsketch::estimate_quantile(1.0, *self.alpha, uddsketch::gamma(*self.alpha), *self.count, self.keys().zip(self.counts())),
+        }
+    }
 
+    fn trimmed_mean(&self, low: f64, high: f64) -> (f64, f64) {
+        validate_trimmed_mean_range(low, high);
+        uddsketch::trimmed_mean(low, high, *self.alpha, uddsketch::gamma(*self.alpha), *self.count, self.keys().zip(self.counts()))
+    }
 
-            // we need to flatten the vector to a single buffer that contains
-            // both the size, the data, and the varlen header
+    fn from_internal(state: &UddSketchInternal) -> UddSketch<'static> {
+        let CompressedBuckets {
+            negative_indexes,
+            negative_counts,
+            zero_bucket_count,
+            positive_indexes,
+            positive_counts,
+        } = compress_buckets(state);
+
+        // we need to flatten the vector to a single buffer that contains
+        // both the size, the data, and the varlen header
+        unsafe {
             flatten!(
-                UddSketch {
+                UddSketch version: UDDSKETCH_VERSION, {
                     alpha: &state.max_error(),
                     max_buckets: &(state.max_allowed_buckets() as u32),
                     num_buckets: &(state.current_buckets_count() as u32),
@@ -225,8 +353,32 @@ fn uddsketch_final(
                     negative_counts: &negative_counts,
                     positive_indexes: &positive_indexes,
                     positive_counts: &positive_counts,
+                    min: &[state.min()],
+                    max: &[state.max()],
                 }
-            ).into()
+            )
+        }
+    }
+
+    fn to_uddsketch(&self) -> UddSketchInternal {
+        UddSketchInternal::new_from_data(*self.max_buckets as u64, *self.alpha, *self.compactions, *self.count, *self.sum, self.min(), self.max(), self.keys(), self.counts())
+    }
+}
+
+// PG function to generate a user-facing UddSketch object from a UddSketchInternal.
+#[pg_extern()]
+fn uddsketch_final(
+    state: Option<Internal<UddSketchInternal>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<UddSketch<'static>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let state = match state {
+                None => return None,
+                Some(state) => state,
+            };
+
+            UddSketch::from_internal(&state).into()
         })
     }
 }
@@ -243,25 +395,16 @@ struct CompressedBuckets {
 fn compress_buckets(sketch: &UddSketchInternal) -> CompressedBuckets {
     let mut negative_indexes = prefix_varint::I64Compressor::with(delta::i64_encoder());
     let mut negative_counts = prefix_varint::U64Compressor::with(delta::u64_encoder());
-    let mut zero_bucket_count = 0;
     let mut positive_indexes = prefix_varint::I64Compressor::with(delta::i64_encoder());
     let mut positive_counts = prefix_varint::U64Compressor::with(delta::u64_encoder());
-    for (k, b) in sketch.bucket_iter() {
-        match k {
-            SketchHashKey::Negative(i) => {
-                negative_indexes.push(i);
-                negative_counts.push(b);
-            },
-            SketchHashKey::Zero => {
-                zero_bucket_count = b
-            },
-            SketchHashKey::Positive(i) => {
-                positive_indexes.push(i);
-                positive_counts.push(b);
-            },
-            SketchHashKey::Invalid => unreachable!(),
-        }
-
+    for (i, b) in sketch.negative_buckets() {
+        negative_indexes.push(i);
+        negative_counts.push(b);
+    }
+    let zero_bucket_count = sketch.zero_count();
+    for (i, b) in sketch.positive_buckets() {
+        positive_indexes.push(i);
+        positive_counts.push(b);
     }
     let negative_indexes = negative_indexes.finish();
     let negative_counts = negative_counts.finish();
@@ -317,6 +460,10 @@ CREATE AGGREGATE uddsketch(
     combinefunc = uddsketch_combine,
     serialfunc = uddsketch_serialize,
     deserialfunc = uddsketch_deserialize,
+    msfunc = uddsketch_trans,
+    minvfunc = uddsketch_inv,
+    mstype = internal,
+    mfinalfunc = uddsketch_final,
     parallel = safe
 );
 "#);
@@ -330,6 +477,45 @@ CREATE AGGREGATE percentile_agg(value DOUBLE PRECISION)
     combinefunc = uddsketch_combine,
     serialfunc = uddsketch_serialize,
     deserialfunc = uddsketch_deserialize,
+    msfunc = percentile_agg_trans,
+    minvfunc = percentile_agg_inv,
+    mstype = internal,
+    mfinalfunc = uddsketch_final,
+    parallel = safe
+);
+"#);
+
+extension_sql!(r#"
+CREATE AGGREGATE timescale_analytics_experimental.uddsketch(
+    size int, max_error DOUBLE PRECISION, value DOUBLE PRECISION, weight BIGINT
+) (
+    sfunc = timescale_analytics_experimental.uddsketch_weighted_trans,
+    stype = internal,
+    finalfunc = uddsketch_final,
+    combinefunc = uddsketch_combine,
+    serialfunc = uddsketch_serialize,
+    deserialfunc = uddsketch_deserialize,
+    msfunc = timescale_analytics_experimental.uddsketch_weighted_trans,
+    minvfunc = timescale_analytics_experimental.uddsketch_weighted_inv,
+    mstype = internal,
+    mfinalfunc = uddsketch_final,
+    parallel = safe
+);
+"#);
+
+extension_sql!(r#"
+CREATE AGGREGATE timescale_analytics_experimental.percentile_agg(value DOUBLE PRECISION, weight BIGINT)
+(
+    sfunc = timescale_analytics_experimental.percentile_agg_weighted_trans,
+    stype = internal,
+    finalfunc = uddsketch_final,
+    combinefunc = uddsketch_combine,
+    serialfunc = uddsketch_serialize,
+    deserialfunc = uddsketch_deserialize,
+    msfunc = timescale_analytics_experimental.percentile_agg_weighted_trans,
+    minvfunc = timescale_analytics_experimental.percentile_agg_weighted_inv,
+    mstype = internal,
+    mfinalfunc = uddsketch_final,
     parallel = safe
 );
 "#);
@@ -370,6 +556,48 @@ CREATE AGGREGATE rollup(
 );
 "#);
 
+// PG function for rolling up sketches into one with at most `size` buckets,
+// each sketch is resized before it's merged so that sketches of any size can
+// be combined.
+#[pg_extern(schema = "timescale_analytics_experimental")]
+pub fn uddsketch_compound_resize_trans(
+    state: Option<Internal<UddSketchInternal>>,
+    value: Option<UddSketch>,
+    size: int,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<UddSketchInternal>> {
+    validate_size(size);
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let mut value = match value {
+                None => return state,
+                Some(value) => value.to_uddsketch(),
+            };
+            value.resize(size as u64);
+            let mut state = match state {
+                None => return Some(value.into()),
+                Some(state) => state,
+            };
+            state.merge_sketch(&value);
+            state.into()
+        })
+    }
+}
+
+extension_sql!(r#"
+CREATE AGGREGATE timescale_analytics_experimental.rollup(
+    sketch uddsketch, size int
+) (
+    sfunc = timescale_analytics_experimental.uddsketch_compound_resize_trans,
+    stype = internal,
+    finalfunc = uddsketch_final,
+    combinefunc = uddsketch_combine,
+    serialfunc = uddsketch_serialize,
+    deserialfunc = uddsketch_deserialize,
+    parallel = safe
+);
+"#);
+
 //---- Available PG operations on the sketch
 
 // Approximate the value at the given approx_percentile (0.0-1.0)
@@ -378,11 +606,13 @@ pub fn uddsketch_approx_percentile(
     percentile: f64,
     sketch: UddSketch,
 ) -> f64 {
-    uddsketch::estimate_quantile(
+    uddsketch::estimate_quantile_with_bounds(
         percentile,
         *sketch.alpha,
         uddsketch::gamma(*sketch.alpha),
         *sketch.count,
+        sketch.min(),
+        sketch.max(),
         sketch.keys().zip(sketch.counts()),
     )
 }
@@ -401,6 +631,117 @@ pub fn uddsketch_approx_percentile_rank(
     )
 }
 
+// Approximate the values at each of the given percentiles (0.0-1.0), decoding the sketch only once.
+#[pg_extern(immutable, parallel_safe, schema = "timescale_analytics_experimental", name="approx_percentile_array")]
+pub fn uddsketch_approx_percentile_array(
+    percentiles: Vec<f64>,
+    sketch: UddSketch,
+) -> Vec<f64> {
+    let gamma = uddsketch::gamma(*sketch.alpha);
+    let (min, max) = (sketch.min(), sketch.max());
+    let buckets: Vec<_> = sketch.keys().zip(sketch.counts()).collect();
+    percentiles.into_iter()
+        .map(|percentile| uddsketch::estimate_quantile_with_bounds(
+            percentile,
+            *sketch.alpha,
+            gamma,
+            *sketch.count,
+            min,
+            max,
+            buckets.iter().copied(),
+        ))
+        .collect()
+}
+
+// Count the values falling in each of the bins delimited by the given edges.
+// Returns one more count than there are edges, see `uddsketch::histogram` for the bin boundaries.
+#[pg_extern(immutable, parallel_safe, schema = "timescale_analytics_experimental", name="histogram")]
+pub fn uddsketch_histogram(
+    sketch: UddSketch,
+    edges: Vec<f64>,
+) -> Vec<i64> {
+    validate_histogram_edges(&edges);
+    uddsketch::histogram(
+        &edges,
+        *sketch.alpha,
+        uddsketch::gamma(*sketch.alpha),
+        sketch.keys().zip(sketch.counts()),
+    ).into_iter().map(|count| count as i64).collect()
+}
+
+// Histogram edges are shared by the uddsketch and tdigest versions, they must
+// be strictly increasing so that every value falls in exactly one bin.
+pub(crate) fn validate_histogram_edges(edges: &[f64]) {
+    if edges.iter().any(|edge| edge.is_nan()) || edges.windows(2).any(|w| w[0] >= w[1]) {
+        error!("histogram edges must be strictly increasing")
+    }
+}
+
+// Reduce the sketch to at most `size` buckets, see `UDDSketch::resize`.
+#[pg_extern(immutable, parallel_safe, schema = "timescale_analytics_experimental", name="resize")]
+pub fn uddsketch_resize(
+    sketch: UddSketch,
+    size: int,
+) -> UddSketch<'static> {
+    validate_size(size);
+    let mut sketch = sketch.to_uddsketch();
+    sketch.resize(size as u64);
+    UddSketch::from_internal(&sketch)
+}
+
+fn validate_size(size: int) {
+    if (size as u64) < uddsketch::MIN_BUCKETS {
+        error!("uddsketch size must be at least {}", uddsketch::MIN_BUCKETS)
+    }
+}
+
+// Approximate the mean of the values between the `low` and `high` percentiles (0.0-1.0),
+// computed from the buckets, see `uddsketch::trimmed_mean`.
+#[pg_extern(immutable, parallel_safe, schema = "timescale_analytics_experimental", name="approx_trimmed_mean")]
+pub fn uddsketch_trimmed_mean(
+    low: f64,
+    high: f64,
+    sketch: UddSketch,
+) -> f64 {
+    sketch.trimmed_mean(low, high).0
+}
+
+// The maximum absolute error of `approx_trimmed_mean` for the same arguments.
+#[pg_extern(immutable, parallel_safe, schema = "timescale_analytics_experimental", name="approx_trimmed_mean_error")]
+pub fn uddsketch_trimmed_mean_error(
+    low: f64,
+    high: f64,
+    sketch: UddSketch,
+) -> f64 {
+    sketch.trimmed_mean(low, high).1
+}
+
+// Approximate the mean of the values above the given percentile (0.0-1.0).
+#[pg_extern(immutable, parallel_safe, schema = "timescale_analytics_experimental", name="approx_tail_mean")]
+pub fn uddsketch_tail_mean(
+    percentile: f64,
+    sketch: UddSketch,
+) -> f64 {
+    sketch.trimmed_mean(percentile, 1.0).0
+}
+
+// The maximum absolute error of `approx_tail_mean` for the same arguments.
+#[pg_extern(immutable, parallel_safe, schema = "timescale_analytics_experimental", name="approx_tail_mean_error")]
+pub fn uddsketch_tail_mean_error(
+    percentile: f64,
+    sketch: UddSketch,
+) -> f64 {
+    sketch.trimmed_mean(percentile, 1.0).1
+}
+
+// Trimmed means are shared by the uddsketch and tdigest versions, the range
+// must be a non-empty subrange of [0, 1].
+pub(crate) fn validate_trimmed_mean_range(low: f64, high: f64) {
+    if !(0.0 <= low && low < high && high <= 1.0) {
+        error!("trimmed mean range must satisfy 0 <= low < high <= 1")
+    }
+}
+
 // Number of elements from which the sketch was built.
 #[pg_extern(immutable, parallel_safe, name="num_vals")]
 pub fn uddsketch_num_vals(
@@ -409,6 +750,22 @@ pub fn uddsketch_num_vals(
     *sketch.count as f64
 }
 
+// Minimum value entered in the sketch.
+#[pg_extern(immutable, parallel_safe, name="min_val")]
+pub fn uddsketch_min(
+    sketch: UddSketch,
+) -> f64 {
+    sketch.min()
+}
+
+// Maximum value entered in the sketch.
+#[pg_extern(immutable, parallel_safe, name="max_val")]
+pub fn uddsketch_max(
+    sketch: UddSketch,
+) -> f64 {
+    sketch.max()
+}
+
 // Average of all the values entered in the sketch.
 // Note that this is not an approximation, though there may be loss of precision.
 #[pg_extern(immutable, parallel_safe, name="mean")]
@@ -558,6 +915,415 @@ mod tests {
         });
     }
 
+    #[pg_test]
+    fn test_moving_aggregate() {
+        Spi::execute(|client| {
+            client.select("SET search_path TO timescale_analytics_experimental, public", None, None);
+            client.select("CREATE TABLE moving (i INTEGER, value DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO moving SELECT i, (i * 37) % 101 - 50 FROM generate_series(1, 1000) i", None, None);
+            client.select("INSERT INTO moving VALUES (1001, NULL), (1002, 'Infinity'), (1003, 5)", None, None);
+
+            // removing values as the window moves gives the same results as building each window from scratch
+            let mismatches = client
+                .select("SELECT count(*) FROM ( \
+                        SELECT i, \
+                            approx_percentile(0.5, percentile_agg(value, 1) OVER w) AS median, \
+                            num_vals(percentile_agg(value, 1) OVER w) AS count, \
+                            min_val(percentile_agg(value, 1) OVER w) AS min, \
+                            max_val(percentile_agg(value, 1) OVER w) AS max, \
+                            approx_percentile(0, percentile_agg(value, 1) OVER w) AS p0, \
+                            approx_percentile(1, percentile_agg(value, 1) OVER w) AS p100 \
+                        FROM moving WINDOW w AS (ORDER BY i ROWS 10 PRECEDING) \
+                    ) moving_window \
+                    WHERE (median, count, min, max, p0, p100) IS DISTINCT FROM ( \
+                        SELECT \
+                            approx_percentile(0.5, percentile_agg(value)), \
+                            num_vals(percentile_agg(value)), \
+                            min_val(percentile_agg(value)), \
+                            max_val(percentile_agg(value)), \
+                            approx_percentile(0, percentile_agg(value)), \
+                            approx_percentile(1, percentile_agg(value)) \
+                        FROM moving WHERE i BETWEEN moving_window.i - 10 AND moving_window.i)", None, None)
+                .first()
+                .get_one::<i64>();
+            assert_eq!(mismatches, Some(0));
+
+            // windows that no longer contain any values are NULL
+            let empty = client
+                .select("SELECT is_null FROM ( \
+                        SELECT i, percentile_agg(value, 1) OVER (ORDER BY i ROWS BETWEEN 1 PRECEDING AND 1 PRECEDING) IS NULL AS is_null \
+                        FROM moving \
+                    ) moving_window WHERE i = 1002", None, None)
+                .first()
+                .get_one::<bool>();
+            assert_eq!(empty, Some(true));
+        });
+    }
+
+    #[pg_test]
+    fn test_unweighted_moving_aggregate() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE unweighted_moving (i INTEGER, value DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO unweighted_moving SELECT i, (i * 37) % 101 - 50 FROM generate_series(1, 1000) i", None, None);
+            client.select("INSERT INTO unweighted_moving VALUES (1001, NULL), (1002, 'Infinity'), (1003, 5)", None, None);
+
+            // the released aggregates remove values as the window moves too, and
+            // match building each window from scratch
+            let mismatches = client
+                .select("SELECT count(*) FROM ( \
+                        SELECT i, \
+                            approx_percentile(0.5, percentile_agg(value) OVER w) AS median, \
+                            num_vals(percentile_agg(value) OVER w) AS count, \
+                            approx_percentile(0.9, uddsketch(100, 0.01, value) OVER w) AS p90 \
+                        FROM unweighted_moving WINDOW w AS (ORDER BY i ROWS 10 PRECEDING) \
+                    ) moving_window \
+                    WHERE (median, count, p90) IS DISTINCT FROM ( \
+                        SELECT \
+                            approx_percentile(0.5, percentile_agg(value)), \
+                            num_vals(percentile_agg(value)), \
+                            approx_percentile(0.9, uddsketch(100, 0.01, value)) \
+                        FROM unweighted_moving WHERE i BETWEEN moving_window.i - 10 AND moving_window.i)", None, None)
+                .first()
+                .get_one::<i64>();
+            assert_eq!(mismatches, Some(0));
+        });
+    }
+
+    #[pg_test]
+    fn test_weighted_aggregate() {
+        Spi::execute(|client| {
+            // each value v has weight v
+            client.select("CREATE TABLE weighted (value DOUBLE PRECISION, weight BIGINT)", None, None);
+            client.select("INSERT INTO weighted SELECT v, v FROM generate_series(1, 1000) v", None, None);
+            // NULL and zero weights are ignored
+            client.select("INSERT INTO weighted VALUES (5000, NULL), (6000, 0), (NULL, 10)", None, None);
+
+            client.select("CREATE VIEW sketch AS \
+                SELECT timescale_analytics_experimental.uddsketch(100, 0.005, value, weight) \
+                FROM weighted", None, None);
+
+            let (mean, count) = client
+                .select("SELECT \
+                    mean(uddsketch), \
+                    num_vals(uddsketch) \
+                    FROM sketch", None, None)
+                .first()
+                .get_two::<f64, f64>();
+
+            // sum(v * v) / sum(v) for v in 1..=1000
+            apx_eql(mean.unwrap(), 667.0, 0.0001);
+            apx_eql(count.unwrap(), 500500.0, 0.000001);
+
+            let error = client
+                .select("SELECT error(uddsketch) FROM sketch", None, None)
+                .first()
+                .get_one::<f64>()
+                .unwrap();
+
+            for i in 1..100 {
+                let quantile = i as f64 / 100.0;
+                // the exact weighted quantile is the smallest value whose cumulative weight exceeds the rank
+                let (estimate, expected) = client
+                    .select(&format!("SELECT \
+                            approx_percentile({q}, (SELECT uddsketch FROM sketch)), \
+                            min(value) \
+                        FROM ( \
+                            SELECT value, sum(weight) OVER (ORDER BY value) AS cumulative FROM weighted WHERE weight > 0 \
+                        ) w WHERE cumulative > floor({q} * 500500)", q = quantile), None, None)
+                    .first()
+                    .get_two::<f64, f64>();
+                pct_eql(estimate.unwrap(), expected.unwrap(), error);
+            }
+
+            // percentile_agg uses the same defaults as the unweighted version
+            let (weighted, repeated) = client
+                .select("SELECT \
+                        approx_percentile(0.5, (SELECT timescale_analytics_experimental.percentile_agg(value, weight) FROM weighted)), \
+                        approx_percentile(0.5, percentile_agg(value)) \
+                    FROM weighted, generate_series(1, weight)", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            assert_eq!(weighted, repeated);
+        });
+    }
+
+    #[pg_test]
+    fn test_signed_values() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE signed (value DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO signed SELECT generate_series(-1000, 1000)", None, None);
+            client.select("CREATE VIEW signed_sketch AS \
+                SELECT uddsketch(200, 0.001, value) FROM signed", None, None);
+
+            let error = client
+                .select("SELECT error(uddsketch) FROM signed_sketch", None, None)
+                .first()
+                .get_one::<f64>()
+                .unwrap();
+
+            for &(percentile, expected) in [(0.01, -980.0), (0.25, -500.0), (0.49, -20.0), (0.51, 20.0), (0.75, 500.0)].iter() {
+                let estimate = client
+                    .select(&format!("SELECT approx_percentile({}, uddsketch) FROM signed_sketch", percentile), None, None)
+                    .first()
+                    .get_one::<f64>()
+                    .unwrap();
+                apx_eql(estimate, expected, expected.abs() * error + 1.0);
+            }
+
+            let (median, rank) = client
+                .select("SELECT approx_percentile(0.5, uddsketch), approx_percentile_rank(0, uddsketch) \
+                    FROM signed_sketch", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            assert_eq!(median, Some(0.0));
+            assert_eq!(rank, Some(0.5));
+
+            // the text format round-trips
+            let round_trip = client
+                .select("SELECT uddsketch::text::uddsketch::text = uddsketch::text FROM signed_sketch", None, None)
+                .first()
+                .get_one::<bool>();
+            assert_eq!(round_trip, Some(true));
+        });
+    }
+
+    #[pg_test(error = "unsupported UddSketch version 200, the newest supported version is 2")]
+    fn test_unknown_version() {
+        Spi::execute(|client| {
+            client.select("SELECT approx_percentile(0.5, \
+                replace(uddsketch(100, 0.001, v)::text, '\"version\":2', '\"version\":200')::uddsketch) \
+                FROM generate_series(1, 10) v", None, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_min_max() {
+        Spi::execute(|client| {
+            // min_val and max_val are released, so they don't need the experimental schema
+            client.select("CREATE TABLE bounds (device INTEGER, value DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO bounds SELECT d, v / 7.0 FROM generate_series(1, 3) d, generate_series(-50 * d, 100 * d) v", None, None);
+            client.select("CREATE VIEW bounds_sketch AS \
+                SELECT device, percentile_agg(value) FROM bounds GROUP BY device", None, None);
+
+            let (min, max) = client
+                .select("SELECT min_val(percentile_agg), max_val(percentile_agg) \
+                    FROM bounds_sketch WHERE device = 1", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            assert_eq!(min, Some(-50.0 / 7.0));
+            assert_eq!(max, Some(100.0 / 7.0));
+
+            // the extreme percentiles are exact
+            let (low, high) = client
+                .select("SELECT approx_percentile(0.0, percentile_agg), approx_percentile(1.0, percentile_agg) \
+                    FROM bounds_sketch WHERE device = 1", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            assert_eq!(low, min);
+            assert_eq!(high, max);
+
+            // the bounds survive rollup
+            let (min, max) = client
+                .select("SELECT min_val(rollup(percentile_agg)), max_val(rollup(percentile_agg)) \
+                    FROM bounds_sketch", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            assert_eq!(min, Some(-150.0 / 7.0));
+            assert_eq!(max, Some(300.0 / 7.0));
+        });
+    }
+
+    #[pg_test]
+    fn test_resize() {
+        Spi::execute(|client| {
+            client.select("SET search_path TO timescale_analytics_experimental, public", None, None);
+            client.select("CREATE TABLE resize_test (device INTEGER, value DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO resize_test SELECT d, v / d FROM generate_series(1, 2) d, generate_series(1, 10000) v", None, None);
+            // sketches of different sizes can only be combined by resizing them
+            client.select("CREATE VIEW resize_sketches AS \
+                SELECT device, uddsketch(100 * device, 0.001, value) FROM resize_test GROUP BY device", None, None);
+
+            let (error, resized_error) = client
+                .select("SELECT error(uddsketch), error(resize(uddsketch, 10)) \
+                    FROM resize_sketches WHERE device = 1", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            assert_eq!(error, Some(0.001));
+            assert!(resized_error.unwrap() > 0.001);
+
+            // only the resolution is lost, the count and bounds are unchanged
+            let matches = client
+                .select("SELECT num_vals(uddsketch) = num_vals(resize(uddsketch, 10)) \
+                        AND min_val(uddsketch) = min_val(resize(uddsketch, 10)) \
+                        AND max_val(uddsketch) = max_val(resize(uddsketch, 10)) \
+                    FROM resize_sketches WHERE device = 1", None, None)
+                .first()
+                .get_one::<bool>();
+            assert_eq!(matches, Some(true));
+
+            let (count, median) = client
+                .select("SELECT num_vals(sketch), approx_percentile(0.5, sketch) \
+                    FROM (SELECT rollup(uddsketch, 10) AS sketch FROM resize_sketches) rolled", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            assert_eq!(count, Some(20000.0));
+            let rolled_error = client
+                .select("SELECT error(rollup(uddsketch, 10)) FROM resize_sketches", None, None)
+                .first()
+                .get_one::<f64>()
+                .unwrap();
+            // a third of the first device's values and two thirds of the second's are below 10000 / 3
+            apx_eql(median.unwrap(), 3333.3, 3333.3 * rolled_error + 1.0);
+        });
+    }
+
+    #[pg_test(error = "uddsketch size must be at least 6")]
+    fn test_resize_too_small() {
+        Spi::execute(|client| {
+            client.select("SELECT timescale_analytics_experimental.resize(percentile_agg(v), 4) \
+                FROM generate_series(1, 10) v", None, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_resize_infinities() {
+        Spi::execute(|client| {
+            client.select("SET search_path TO timescale_analytics_experimental, public", None, None);
+            // infinities are never combined with the finite buckets, but the sketch still fits the minimum size
+            let (count, min, max) = client
+                .select("SELECT num_vals(sketch), min_val(sketch), max_val(sketch) FROM ( \
+                        SELECT resize(uddsketch(200, 0.001, v), 6) AS sketch \
+                        FROM unnest(ARRAY['-Infinity', -2, -0.5, 0.5, 2, 'Infinity']::float8[]) v \
+                    ) resized", None, None)
+                .first()
+                .get_three::<f64, f64, f64>();
+            assert_eq!(count, Some(6.0));
+            assert_eq!(min, Some(f64::NEG_INFINITY));
+            assert_eq!(max, Some(f64::INFINITY));
+        });
+    }
+
+    #[pg_test]
+    fn test_version_1_sketches() {
+        Spi::execute(|client| {
+            client.select("SET search_path TO timescale_analytics_experimental, public", None, None);
+            client.select("CREATE VIEW v2 AS SELECT uddsketch(100, 0.001, v / 10.0) FROM generate_series(-100, 1000) v", None, None);
+            // strip the bounds to get the sketch as version 1 would have stored it
+            client.select("CREATE VIEW v1 AS SELECT regexp_replace( \
+                    replace(uddsketch::text, '\"version\":2', '\"version\":1'), \
+                    ',\"min\":\\[[^]]*\\],\"max\":\\[[^]]*\\]', '')::uddsketch AS uddsketch \
+                FROM v2", None, None);
+
+            let stripped = client
+                .select("SELECT uddsketch::text NOT LIKE '%min%' FROM v1", None, None)
+                .first()
+                .get_one::<bool>();
+            assert_eq!(stripped, Some(true));
+
+            // the bounds are estimated from the buckets, everything else is unchanged
+            let (min, max) = client
+                .select("SELECT min_val(uddsketch), max_val(uddsketch) FROM v1", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            apx_eql(min.unwrap(), -10.0, 10.0 * 0.001);
+            apx_eql(max.unwrap(), 100.0, 100.0 * 0.001);
+
+            let matches = client
+                .select("SELECT approx_percentile(0.5, v1.uddsketch) = approx_percentile(0.5, v2.uddsketch) \
+                    AND num_vals(v1.uddsketch) = num_vals(v2.uddsketch) \
+                    FROM v1, v2", None, None)
+                .first()
+                .get_one::<bool>();
+            assert_eq!(matches, Some(true));
+
+            // rolling up a version 1 sketch produces a current one
+            let version = client
+                .select("SELECT rollup(uddsketch)::text LIKE '{\"version\":2%' FROM v1", None, None)
+                .first()
+                .get_one::<bool>();
+            assert_eq!(version, Some(true));
+        });
+    }
+
+    #[pg_test]
+    fn test_percentile_array_and_histogram() {
+        Spi::execute(|client| {
+            client.select("SET search_path TO timescale_analytics_experimental, public", None, None);
+            client.select("CREATE TABLE array_test (value DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO array_test SELECT generate_series(1, 100)", None, None);
+            client.select("CREATE VIEW array_sketch AS \
+                SELECT uddsketch(100, 0.001, value) FROM array_test", None, None);
+
+            let matches = client
+                .select("SELECT \
+                    approx_percentile_array(ARRAY[0.01, 0.5, 0.9, 0.99], uddsketch) = ARRAY[\
+                        approx_percentile(0.01, uddsketch), \
+                        approx_percentile(0.5, uddsketch), \
+                        approx_percentile(0.9, uddsketch), \
+                        approx_percentile(0.99, uddsketch)] \
+                    FROM array_sketch", None, None)
+                .first()
+                .get_one::<bool>();
+            assert_eq!(matches, Some(true));
+
+            let histogram = client
+                .select("SELECT histogram(uddsketch, ARRAY[10.5, 50.5, 1000])::TEXT FROM array_sketch", None, None)
+                .first()
+                .get_one::<String>();
+            assert_eq!(histogram.as_deref(), Some("{10,40,50,0}"));
+
+            let histogram = client
+                .select("SELECT histogram(uddsketch, ARRAY[]::DOUBLE PRECISION[])::TEXT FROM array_sketch", None, None)
+                .first()
+                .get_one::<String>();
+            assert_eq!(histogram.as_deref(), Some("{100}"));
+        });
+    }
+
+    #[pg_test]
+    fn test_trimmed_and_tail_mean() {
+        Spi::execute(|client| {
+            client.select("SET search_path TO timescale_analytics_experimental, public", None, None);
+            client.select("CREATE TABLE trim_test (value DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO trim_test SELECT generate_series(1, 100)", None, None);
+            client.select("CREATE VIEW trim_sketch AS \
+                SELECT uddsketch(100, 0.001, value) FROM trim_test", None, None);
+
+            let (mean, error) = client
+                .select("SELECT approx_trimmed_mean(0.1, 0.9, uddsketch), approx_trimmed_mean_error(0.1, 0.9, uddsketch) \
+                    FROM trim_sketch", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            let (mean, error) = (mean.unwrap(), error.unwrap());
+            assert!((mean - 50.5).abs() <= error, "{} ± {}", mean, error);
+            apx_eql(error, 0.0505, 0.001);
+
+            let (mean, error) = client
+                .select("SELECT approx_tail_mean(0.95, uddsketch), approx_tail_mean_error(0.95, uddsketch) \
+                    FROM trim_sketch", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            let (mean, error) = (mean.unwrap(), error.unwrap());
+            assert!((mean - 98.0).abs() <= error, "{} ± {}", mean, error);
+        });
+    }
+
+    #[pg_test(error = "trimmed mean range must satisfy 0 <= low < high <= 1")]
+    fn test_trimmed_mean_invalid_range() {
+        Spi::execute(|client| {
+            client.select("SELECT timescale_analytics_experimental.approx_trimmed_mean(0.9, 0.1, percentile_agg(v)) \
+                FROM generate_series(1, 10) v", None, None);
+        });
+    }
+
+    #[pg_test(error = "histogram edges must be strictly increasing")]
+    fn test_histogram_unsorted_edges() {
+        Spi::execute(|client| {
+            client.select("SELECT timescale_analytics_experimental.histogram(uddsketch(100, 0.001, v), ARRAY[2.0, 1.0]) \
+                FROM generate_series(1, 10) v", None, None);
+        });
+    }
+
     #[pg_test]
     fn test_percentile_agg() {
         Spi::execute(|client| {
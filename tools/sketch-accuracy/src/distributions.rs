@@ -0,0 +1,69 @@
+use rand::{distributions::Uniform, rngs::StdRng, Rng, SeedableRng};
+use rand_distr::{LogNormal, Normal, Pareto};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputDistribution {
+    Uniform,
+    Normal,
+    LogNormal,
+    Pareto,
+    Bimodal,
+    Sorted,
+}
+
+impl InputDistribution {
+    pub const ALL: &'static [InputDistribution] = &[
+        InputDistribution::Uniform,
+        InputDistribution::Normal,
+        InputDistribution::LogNormal,
+        InputDistribution::Pareto,
+        InputDistribution::Bimodal,
+        InputDistribution::Sorted,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Uniform => "uniform",
+            Self::Normal => "normal",
+            Self::LogNormal => "lognormal",
+            Self::Pareto => "pareto",
+            Self::Bimodal => "bimodal",
+            Self::Sorted => "sorted",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|d| d.name() == name)
+    }
+
+    // Generate `n` values from the distribution. All of them are in random
+    // order except for `Sorted`, which is lognormal data in increasing order,
+    // the worst case for sketches that are sensitive to insertion order.
+    pub fn generate(self, n: usize, seed: u64) -> Vec<f64> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        match self {
+            Self::Uniform => sample(&mut rng, Uniform::new(0.0, 1000.0), n),
+            Self::Normal => sample(&mut rng, Normal::new(500.0, 100.0).unwrap(), n),
+            Self::LogNormal => sample(&mut rng, LogNormal::new(0.0, 1.0).unwrap(), n),
+            Self::Pareto => sample(&mut rng, Pareto::new(1.0, 1.5).unwrap(), n),
+            Self::Bimodal => {
+                // a large, narrow mode for the common case, and a small, wide
+                // one far away from it, like the latencies of cache hits and misses
+                let hits = Normal::new(100.0, 10.0).unwrap();
+                let misses = Normal::new(1000.0, 200.0).unwrap();
+                (0..n)
+                    .map(|_| if rng.gen_bool(0.8) { rng.sample(hits) } else { rng.sample(misses) })
+                    .collect()
+            }
+            Self::Sorted => {
+                let mut values = Self::LogNormal.generate(n, seed);
+                values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                values
+            }
+        }
+    }
+}
+
+fn sample<D: rand::distributions::Distribution<f64>>(rng: &mut StdRng, dist: D, n: usize) -> Vec<f64> {
+    rng.sample_iter(dist).take(n).collect()
+}
@@ -0,0 +1,228 @@
+use std::{
+    cmp::Ordering,
+    io::{self, Write},
+    process::exit,
+    str::FromStr,
+};
+
+use clap::clap_app;
+
+use distributions::InputDistribution;
+use sketches::SketchConfig;
+
+mod distributions;
+mod sketches;
+
+const QUANTILES: &[f64] = &[0.001, 0.01, 0.1, 0.25, 0.5, 0.75, 0.9, 0.99, 0.999];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Csv,
+    Markdown,
+}
+
+struct Row {
+    distribution: InputDistribution,
+    sketch: String,
+    parts: usize,
+    quantile: f64,
+    exact: f64,
+    estimate: f64,
+    rank_error: f64,
+    relative_error: f64,
+    buckets: usize,
+    memory_bytes: usize,
+}
+
+fn main() {
+    let matches = clap_app!(("sketch-accuracy") =>
+        (@arg VALUES: -n --values [count] "number of values to generate for each distribution (default 100000)")
+        (@arg SEED: -s --seed [seed] "seed for the random number generator (default 0)")
+        (@arg FORMAT: -f --format [format] possible_value[csv markdown] "output format (default markdown)")
+        (@arg DISTRIBUTIONS: -d --distributions [distributions] "comma-separated list of distributions to test; \
+            any of uniform, normal, lognormal, pareto, bimodal, sorted (default all)")
+        (@arg SIZES: --sizes [sizes] "comma-separated list of sketch sizes to test (default 50,100,200)")
+        (@arg PARTS: --parts [parts] "comma-separated list of the number of partial sketches \
+            to build and merge for each test (default 1,10,1000)")
+        (@arg UDD_ERROR: --("udd-error") [error] "initial maximum relative error of the uddsketches (default 0.001)")
+    ).get_matches();
+
+    let num_values: usize = parse_or(matches.value_of("VALUES"), 100_000, "values");
+    let seed: u64 = parse_or(matches.value_of("SEED"), 0, "seed");
+    let udd_error: f64 = parse_or(matches.value_of("UDD_ERROR"), 0.001, "udd-error");
+    let format = match matches.value_of("FORMAT") {
+        Some("csv") => Format::Csv,
+        _ => Format::Markdown,
+    };
+    let distributions: Vec<InputDistribution> = match matches.value_of("DISTRIBUTIONS") {
+        None => InputDistribution::ALL.to_vec(),
+        Some(names) => names.split(',')
+            .map(|name| InputDistribution::from_name(name.trim())
+                .unwrap_or_else(|| fail(&format!("unknown distribution `{}`", name))))
+            .collect(),
+    };
+    let sizes: Vec<usize> = parse_list(matches.value_of("SIZES"), &[50, 100, 200], "sizes");
+    let parts: Vec<usize> = parse_list(matches.value_of("PARTS"), &[1, 10, 1000], "parts");
+    if num_values == 0 || sizes.contains(&0) || parts.contains(&0) {
+        fail("values, sizes and parts must all be greater than 0")
+    }
+
+    let configs: Vec<SketchConfig> = sizes.iter()
+        .map(|&size| SketchConfig::TDigest { size })
+        .chain(sizes.iter().map(|&size| SketchConfig::UddSketch { size: size as u64, max_error: udd_error }))
+        .collect();
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    write_header(&mut out, format);
+    for &distribution in &distributions {
+        let values = distribution.generate(num_values, seed);
+        let mut sorted = values.clone();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        for config in &configs {
+            for &parts in &parts {
+                let sketch = config.build(&values, parts);
+                for &quantile in QUANTILES {
+                    let exact = exact_quantile(&sorted, quantile);
+                    let estimate = sketch.estimate_quantile(quantile);
+                    let row = Row {
+                        distribution,
+                        sketch: config.name(),
+                        parts,
+                        quantile,
+                        exact,
+                        estimate,
+                        rank_error: (rank(&sorted, estimate) - quantile).abs(),
+                        relative_error: relative_error(exact, estimate),
+                        buckets: sketch.buckets(),
+                        memory_bytes: sketch.memory_bytes(),
+                    };
+                    write_row(&mut out, format, &row);
+                }
+            }
+        }
+    }
+}
+
+fn exact_quantile(sorted: &[f64], quantile: f64) -> f64 {
+    let index = ((quantile * sorted.len() as f64) as usize).min(sorted.len() - 1);
+    sorted[index]
+}
+
+// The fraction of the values less than `value`, counting values equal to it
+// as half below and half above so that runs of duplicates are not penalized.
+fn rank(sorted: &[f64], value: f64) -> f64 {
+    let below = count_where(sorted, |v| v < value);
+    let not_above = count_where(sorted, |v| v <= value);
+    (below + not_above) as f64 / 2.0 / sorted.len() as f64
+}
+
+// The number of values at the start of `sorted` for which `pred` holds, it
+// must hold for a prefix of the values and not for the rest.
+fn count_where(sorted: &[f64], pred: impl Fn(f64) -> bool) -> usize {
+    sorted.binary_search_by(|&v| if pred(v) { Ordering::Less } else { Ordering::Greater })
+        .unwrap_err()
+}
+
+fn relative_error(exact: f64, estimate: f64) -> f64 {
+    if exact == 0.0 {
+        return estimate.abs();
+    }
+    ((estimate - exact) / exact).abs()
+}
+
+fn write_header(out: &mut impl Write, format: Format) {
+    const COLUMNS: &[&str] = &[
+        "distribution",
+        "sketch",
+        "parts",
+        "quantile",
+        "exact",
+        "estimate",
+        "rank_error",
+        "relative_error",
+        "buckets",
+        "memory_bytes",
+    ];
+    let result = match format {
+        Format::Csv => writeln!(out, "{}", COLUMNS.join(",")),
+        Format::Markdown => writeln!(out, "| {} |\n|{}", COLUMNS.join(" | "), "---|".repeat(COLUMNS.len())),
+    };
+    result.expect("cannot write output")
+}
+
+fn write_row(out: &mut impl Write, format: Format, row: &Row) {
+    let result = match format {
+        Format::Csv => writeln!(
+            out,
+            "{},\"{}\",{},{},{},{},{:.6},{:.6},{},{}",
+            row.distribution.name(),
+            row.sketch,
+            row.parts,
+            row.quantile,
+            row.exact,
+            row.estimate,
+            row.rank_error,
+            row.relative_error,
+            row.buckets,
+            row.memory_bytes,
+        ),
+        Format::Markdown => writeln!(
+            out,
+            "| {} | {} | {} | {} | {:.4} | {:.4} | {:.6} | {:.6} | {} | {} |",
+            row.distribution.name(),
+            row.sketch,
+            row.parts,
+            row.quantile,
+            row.exact,
+            row.estimate,
+            row.rank_error,
+            row.relative_error,
+            row.buckets,
+            row.memory_bytes,
+        ),
+    };
+    result.expect("cannot write output")
+}
+
+fn parse_or<T: FromStr>(value: Option<&str>, default: T, name: &str) -> T {
+    match value {
+        None => default,
+        Some(value) => value.trim().parse()
+            .unwrap_or_else(|_| fail(&format!("invalid {} `{}`", name, value))),
+    }
+}
+
+fn parse_list<T: FromStr + Clone>(value: Option<&str>, default: &[T], name: &str) -> Vec<T> {
+    match value {
+        None => default.to_vec(),
+        Some(values) => values.split(',')
+            .map(|value| parse_or(Some(value), default[0].clone(), name))
+            .collect(),
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("error: {}", message);
+    exit(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rank() {
+        let sorted = [1.0, 2.0, 2.0, 2.0, 3.0, 4.0, 5.0, 6.0];
+        assert_eq!(rank(&sorted, 0.5), 0.0);
+        assert_eq!(rank(&sorted, 1.5), 1.0 / 8.0);
+        // the duplicates count as half below and half above
+        assert_eq!(rank(&sorted, 2.0), 2.5 / 8.0);
+        assert_eq!(rank(&sorted, 4.0), 5.5 / 8.0);
+        assert_eq!(rank(&sorted, 7.0), 1.0);
+
+        assert_eq!(exact_quantile(&sorted, 0.0), 1.0);
+        assert_eq!(exact_quantile(&sorted, 0.5), 3.0);
+        assert_eq!(exact_quantile(&sorted, 1.0), 6.0);
+    }
+}
@@ -0,0 +1,116 @@
+use std::mem::size_of;
+
+use tdigest::{Centroid, TDigest};
+use uddsketch::{SketchHashKey, UDDSketch};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SketchConfig {
+    TDigest { size: usize },
+    UddSketch { size: u64, max_error: f64 },
+}
+
+impl SketchConfig {
+    pub fn name(&self) -> String {
+        match self {
+            SketchConfig::TDigest { size } => format!("tdigest({})", size),
+            SketchConfig::UddSketch { size, max_error } => format!("uddsketch({}, {})", size, max_error),
+        }
+    }
+
+    // Build a sketch over `values`, split into `parts` roughly equal pieces
+    // that are sketched separately and then merged, as a rollup would.
+    pub fn build(&self, values: &[f64], parts: usize) -> Sketch {
+        let chunk_size = ((values.len() + parts - 1) / parts).max(1);
+        match *self {
+            SketchConfig::TDigest { size } => {
+                let digests = values.chunks(chunk_size)
+                    .map(|chunk| build_tdigest(size, chunk))
+                    .collect();
+                Sketch::TDigest(TDigest::merge_digests(digests))
+            }
+            SketchConfig::UddSketch { size, max_error } => {
+                let mut sketch = UDDSketch::new(size, max_error);
+                for chunk in values.chunks(chunk_size) {
+                    let mut part = UDDSketch::new(size, max_error);
+                    for &value in chunk {
+                        part.add_value(value);
+                    }
+                    sketch.merge_sketch(&part);
+                }
+                Sketch::UddSketch(sketch)
+            }
+        }
+    }
+}
+
+// Like the extension, buffer the values and merge them into the digest a
+// digest-size batch at a time.
+fn build_tdigest(size: usize, values: &[f64]) -> TDigest {
+    values.chunks(size)
+        .fold(TDigest::new_with_size(size), |digest, batch| digest.merge_unsorted(batch.to_vec()))
+}
+
+pub enum Sketch {
+    TDigest(TDigest),
+    UddSketch(UDDSketch),
+}
+
+impl Sketch {
+    pub fn estimate_quantile(&self, quantile: f64) -> f64 {
+        match self {
+            Sketch::TDigest(digest) => digest.estimate_quantile(quantile),
+            Sketch::UddSketch(sketch) => sketch.estimate_quantile(quantile),
+        }
+    }
+
+    pub fn buckets(&self) -> usize {
+        match self {
+            Sketch::TDigest(digest) => digest.num_buckets(),
+            Sketch::UddSketch(sketch) => sketch.current_buckets_count(),
+        }
+    }
+
+    // An estimate of the memory used by the sketch, ignoring allocator and
+    // hash table overhead.
+    pub fn memory_bytes(&self) -> usize {
+        match self {
+            Sketch::TDigest(digest) => size_of::<TDigest>() + digest.num_buckets() * size_of::<Centroid>(),
+            // each bucket stores its key, its count, and the key of the next bucket
+            Sketch::UddSketch(sketch) => size_of::<UDDSketch>()
+                + sketch.current_buckets_count() * (2 * size_of::<SketchHashKey>() + size_of::<u64>()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build() {
+        let values: Vec<f64> = (1..=1000).map(f64::from).collect();
+        let configs = [
+            SketchConfig::TDigest { size: 100 },
+            SketchConfig::UddSketch { size: 200, max_error: 0.01 },
+        ];
+        for config in &configs {
+            let whole = config.build(&values, 1);
+            assert!((whole.estimate_quantile(0.5) - 500.0).abs() <= 10.0, "{}", config.name());
+            assert!(whole.buckets() > 0);
+            assert!(whole.memory_bytes() > 0);
+
+            // the parts don't need to divide the values evenly, and there may
+            // be more parts than values
+            for &parts in &[3, 7, 2000] {
+                let merged = config.build(&values, parts);
+                assert!((merged.estimate_quantile(0.5) - 500.0).abs() <= 10.0, "{} {}", config.name(), parts);
+            }
+        }
+
+        let sketch = SketchConfig::UddSketch { size: 200, max_error: 0.01 }.build(&values, 4);
+        match sketch {
+            Sketch::UddSketch(sketch) => assert_eq!(sketch.count(), 1000),
+            Sketch::TDigest(_) => unreachable!(),
+        }
+    }
+}